@@ -0,0 +1,1340 @@
+//! Command-line parsing: the clap definition of every subcommand and flag, and how they, the config file
+//! and the environment end up in the [`Options`].
+
+use crate::config::{apply_env, Config, DEFAULT_CONFIG};
+use crate::options::{Command, ConfirmTo, Options};
+use crate::report::{ReportFormat, Units, SCRIPT_TYPES};
+use crate::rpc::Passphrase;
+use crate::transfer::SendAmount;
+use crate::{CapstoneError, LogLevel};
+use bitcoin::absolute::LockTime;
+use bitcoincore_rpc::bitcoin::address::NetworkUnchecked;
+use bitcoincore_rpc::bitcoin::{Address, Amount, OutPoint, Txid};
+use clap::builder::{PossibleValuesParser, RangedU64ValueParser};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const AFTER_HELP: &str = "\
+Environment (overrides the config file and the flags; empty values are ignored):
+  BITCOIN_RPC_URL, BITCOIN_RPC_USER, BITCOIN_RPC_PASS  Node RPC endpoint and credentials
+  CAPSTONE_OUT_PATH     Where to write the ten-line report
+  BITCOIND              The bitcoind binary --spawn-node runs (default bitcoind on PATH)
+
+Pruned nodes:
+  When an input spends an output the node has pruned and the wallet doesn't hold, the run still finishes:
+  the input address, input amount and fee are written as \"unavailable (pruned)\" in the terminal report,
+  out.txt, out.csv and --fee-file (out.json keeps them zero with \"inputs_pruned\": true), and the balance
+  check is skipped with a warning.";
+
+// The command line: a subcommand (`run` when none is given) and the flags, which may come before or after it.
+// A `//` comment, as clap would print a doc comment above the usage.
+#[derive(Debug, Parser)]
+#[command(
+    name = "rust",
+    bin_name = "rust",
+    after_help = AFTER_HELP,
+    disable_help_subcommand = true
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+    #[command(flatten)]
+    pub flags: Flags,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum CliCommand {
+    /// The capstone pipeline: mine to maturity, pay the Trader, confirm and report (default)
+    Run,
+    /// Only the payment stage of run: send, confirm and report, mining nothing to maturity
+    Send,
+    /// Pay every --recipients entry in one transaction (sendmany) from the Miner's balance, confirm it, and
+    /// report each output
+    SendMany,
+    /// Only the report stage: rebuild and write the reports of the confirmed payment --txid
+    Report,
+    /// Keep reporting every new payment the Trader receives
+    Watch,
+    /// Fee-bump the unconfirmed Miner transaction --txid
+    Bump,
+    /// Pretty-print any transaction the node knows (--txid) as JSON
+    Decode,
+    /// Compare two out.txt files field by field (no node needed)
+    Diff { file_a: PathBuf, file_b: PathBuf },
+    /// Mine --count blocks to a new Miner address and show the chain state
+    Mine,
+    /// Mine a block to a Miner address every --interval-secs until Ctrl-C (or --max-blocks)
+    Generator,
+    /// List every wallet with its balance and labelled addresses
+    Wallets,
+    /// Pay --amount along --route, one wallet to the next, mining a block to confirm each hop
+    Route,
+    /// Check report extraction against built-in fixtures (no node needed)
+    #[command(name = "selftest")]
+    SelfTest,
+}
+
+// Every flag. Each one is optional, so only those given override the config file.
+#[derive(Debug, clap::Args)]
+pub struct Flags {
+    /// Settings file (default ./capstone.toml if present): [rpc] url, user, pass; [wallets] miner, trader,
+    /// extra; [labels] mining, received; [route] path; [send] amount, comment, out. Flags override it
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    /// Where to write the ten-line report (default ../out.txt)
+    #[arg(long, global = true, value_name = "PATH")]
+    pub out: Option<PathBuf>,
+    /// Also write just the fee (line 8 of out.txt, same units) to this file
+    #[arg(long, global = true, value_name = "PATH")]
+    pub fee_file: Option<PathBuf>,
+    /// Also write the confirmed payment's raw transaction hex to this file
+    #[arg(long, global = true, value_name = "PATH")]
+    pub raw_tx_file: Option<PathBuf>,
+    /// Comma-separated report formats: txt, json, csv (default txt); wallets: json prints JSON. txt also
+    /// writes the full report to out.json (the same file json writes); csv appends one row per run to
+    /// out.csv, so runs accumulate
+    #[arg(long, global = true, value_name = "LIST", value_delimiter = ',')]
+    pub format: Vec<ReportFormat>,
+    /// Amount run sends to the Trader, in BTC or as a percentage of the Miner balance (default 20); refused
+    /// before anything is mined or sent if the Miner can't cover it
+    #[arg(long, global = true, value_name = "BTC|N%")]
+    pub amount: Option<SendAmount>,
+    /// mine: how many blocks to mine to a new Miner address
+    #[arg(long, global = true, value_name = "N")]
+    pub count: Option<u64>,
+    /// Upper bound on blocks mined (mine: caps --count; run: caps the maturity loop, default maturity + 50;
+    /// generator: stop after n blocks, default never)
+    #[arg(long, global = true, value_name = "N")]
+    pub max_blocks: Option<u64>,
+    /// generator: mine one block to a Miner address every s seconds until Ctrl-C
+    #[arg(long, global = true, value_name = "S")]
+    pub interval_secs: Option<u64>,
+    /// run: mine the maturity blocks round-robin across n Miner wallets (Miner, Miner2, ...; default 1)
+    #[arg(long, global = true, value_name = "N", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub miners: Option<usize>,
+    /// run: mine only the first block to Miner and the maturity blocks on top of it to an unspendable burn
+    /// address, so the Miner holds exactly one block reward. The first block, not the 101st, is the Miner's:
+    /// a reward mined after 100 burned blocks would itself need 100 more to mature. Burned coins can never be
+    /// spent, but they stay in the UTXO set as P2WSH(OP_RETURN) outputs, which --verify-supply counts
+    #[arg(long, global = true)]
+    pub maturity_to_burn: bool,
+    /// run: read the Miner balance every k blocks while mining to maturity (default 1)
+    #[arg(long, global = true, value_name = "K", value_parser = clap::value_parser!(u64).range(1..))]
+    pub check_every: Option<u64>,
+    /// run: pause this long before each of those balance checks (default 0)
+    #[arg(long, global = true, value_name = "MS")]
+    pub poll_interval_ms: Option<u64>,
+    /// run: don't mine to maturity; wait for coins paid in from elsewhere (e.g. a faucet) to cover --amount
+    #[arg(long, global = true)]
+    pub wait_for_funds: bool,
+    /// How long --wait-for-funds waits before failing (default 600)
+    #[arg(long, global = true, value_name = "SECS")]
+    pub funds_timeout: Option<u64>,
+    /// Also print (and put in out.json) each input's scriptSig, witness items and spend type
+    #[arg(long, global = true)]
+    pub show_scripts: bool,
+    /// Also print each out.txt value with a note on what it means (out.txt is unchanged)
+    #[arg(long, global = true)]
+    pub explain: bool,
+    /// Blank txid, addresses, hashes and times in out.json so runs compare (see golden/README.md)
+    #[arg(long, global = true)]
+    pub normalize: bool,
+    /// Coinbase maturity of a custom regtest chain; mines n + 1 blocks (default 100)
+    #[arg(long, global = true, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    pub maturity: Option<u64>,
+    /// Decimal places (1-8) for BTC amounts printed to the terminal; files keep 8 (default 8)
+    #[arg(long, global = true, value_name = "N", value_parser = RangedU64ValueParser::<usize>::new().range(1..=8))]
+    pub display_decimals: Option<usize>,
+    /// Write the amount lines of out.txt as integer satoshis or BTC (default btc)
+    #[arg(long, global = true, value_name = "SAT|BTC")]
+    pub units: Option<Units>,
+    /// watch: mine a block when a receipt is still unconfirmed
+    #[arg(long, global = true)]
+    pub auto_confirm: bool,
+    /// bump: the unconfirmed Miner transaction to fee-bump; decode: any known transaction; report: a
+    /// confirmed Miner payment (its Trader outputs, or those to --trader-address)
+    #[arg(long, global = true)]
+    pub txid: Option<Txid>,
+    /// Label for the Miner reward address (default "Mining Reward")
+    #[arg(long, global = true, value_name = "LABEL")]
+    pub mining_label: Option<String>,
+    /// Label for the Trader receiving address (default "Received")
+    #[arg(long, global = true, value_name = "LABEL")]
+    pub received_label: Option<String>,
+    /// Abort before confirming if the payment's fee exceeds this (default 0.01)
+    #[arg(long, global = true, value_name = "BTC", value_parser = btc)]
+    pub max_fee: Option<Amount>,
+    /// Only warn about a fee above --max-fee
+    #[arg(long, global = true)]
+    pub allow_high_fee: bool,
+    /// Build the payment as a PSBT with this nLockTime (height below 500000000, else Unix time) and mine
+    /// until it is final before broadcasting it
+    #[arg(long, global = true, value_name = "N")]
+    pub locktime: Option<u32>,
+    /// Fund the payment from exactly this Miner UTXO; repeat for several (default: wallet coin selection)
+    #[arg(long, global = true, value_name = "TXID:VOUT")]
+    pub spend_utxo: Vec<OutPoint>,
+    /// run/send: build and sign the payment from the Miner's current coins and print its txid, fee and
+    /// outputs, without mining, broadcasting or writing anything
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+    /// Send the Miner's whole balance to the Trader with sendall, leaving no change (ignores --amount)
+    #[arg(long, global = true)]
+    pub sweep: bool,
+    /// Take the fee out of the Trader's payment instead of the Miner's change
+    #[arg(long, global = true)]
+    pub subtract_fee_from_recipient: bool,
+    /// Wallet comment stored with the payment, checked after confirmation (default "Payment to Trader")
+    #[arg(long, global = true, value_name = "TEXT")]
+    pub comment: Option<String>,
+    /// Split the payment equally across n fresh Trader "Received" addresses (default 1)
+    #[arg(long, global = true, value_name = "N", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub trader_addresses: Option<usize>,
+    /// Also set up this wallet beside the Miner and Trader (repeatable; adds to wallets.extra)
+    #[arg(long, global = true, value_name = "NAME")]
+    pub wallet: Vec<String>,
+    /// route: the wallets the payment goes through, in order (default Miner, Trader, then each extra wallet);
+    /// later hops forward what they received, their fee taken out of it
+    #[arg(long, global = true, value_name = "W1,W2,...", value_delimiter = ',', value_parser = trimmed)]
+    pub route: Vec<String>,
+    /// Pay this address instead of a fresh Trader "Received" address
+    #[arg(long, global = true, value_name = "ADDRESS")]
+    pub trader_address: Option<Address<NetworkUnchecked>>,
+    /// run: make the payments in this file one after the other, each reported to a numbered out file
+    /// (out_1.txt, out_2.txt, ...); a JSON list, or [[payment]] tables in a .toml file, whose entries hold
+    /// "amount" (BTC), "address" or "wallet" for the recipient, and an optional "fee_rate" (sat/vB)
+    #[arg(long, global = true, value_name = "PATH")]
+    pub input_file: Option<PathBuf>,
+    /// send-many: who to pay, as a JSON object of address -> amount in BTC, or (for a .csv file)
+    /// address,amount rows with an optional header row; each address at most once
+    #[arg(long, global = true, value_name = "PATH")]
+    pub recipients: Option<PathBuf>,
+    /// Mine the confirming block to this address (default: new Miner "Block Confirmation" address). Neither
+    /// wallet then sees that block's reward, so both balances show only the payment; the coins (subsidy
+    /// plus the payment's fee) are out of reach unless the address is yours
+    #[arg(long, global = true, value_name = "ADDRESS")]
+    pub confirm_address: Option<Address<NetworkUnchecked>>,
+    /// Mine the confirming block to a new miner (default) or trader wallet address. Either way out.txt is
+    /// the same, and the reward is immature for 100 blocks. miner: the Miner's balance gains that immature
+    /// reward beside its change, and the fee comes back to it once the reward matures. trader: the Miner's
+    /// balance is just what the spend left, but the Trader holds an immature reward on top of what it was paid
+    #[arg(long, global = true, value_name = "MINER|TRADER")]
+    pub confirm_to: Option<ConfirmTo>,
+    /// Unlock an encrypted Miner wallet for the send, and lock it again right after
+    #[arg(long, global = true, value_name = "PASSPHRASE", value_parser = passphrase)]
+    pub wallet_passphrase: Option<Passphrase>,
+    /// Unload the run's wallets (Miner, Trader and any extra ones) after a successful run
+    #[arg(long, global = true)]
+    pub unload_on_exit: bool,
+    /// Mine with mocked timestamps starting here (uses setmocktime, one block per call)
+    #[arg(long, global = true, value_name = "UNIX")]
+    pub block_time_start: Option<u64>,
+    /// Seconds between mocked block timestamps (default 600; needs --block-time-start)
+    #[arg(long, global = true, value_name = "SECS")]
+    pub block_time_step: Option<u64>,
+    /// Print only the ten report lines on stdout; everything else goes to stderr
+    #[arg(long, global = true)]
+    pub stdout_only: bool,
+    /// Start a private bitcoind -regtest (temporary datadir, cookie auth, -txindex) for this run and stop and
+    /// delete it at exit, instead of using the node at the RPC URL
+    #[arg(long, global = true)]
+    pub spawn_node: bool,
+    /// Like --spawn-node, but in a pinned Bitcoin Core container (RPC published on a free loopback port,
+    /// generated rpcauth), removed at exit; needs only Docker
+    #[arg(long, global = true)]
+    pub docker: bool,
+    /// The image --docker runs (default bitcoin/bitcoin:28.1)
+    #[arg(long, global = true, value_name = "REF")]
+    pub docker_image: Option<String>,
+    /// Tries at reaching a node that is down or still loading, with growing pauses (default 8); a call the
+    /// node rejects mid-run because it is warming up again (-28) is retried the same way
+    #[arg(long, global = true, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub connect_attempts: Option<u32>,
+    /// Most time spent pausing between those tries, and waiting for the node to finish syncing its headers
+    /// (default 60)
+    #[arg(long, global = true, value_name = "SECS")]
+    pub connect_timeout: Option<u64>,
+    /// Progress output to show: error, warn, info or debug (default info); the report lines, `Error:` line
+    /// and exit summary always appear
+    #[arg(long, global = true, value_name = "LEVEL")]
+    pub log_level: Option<LogLevel>,
+    /// text (default) or json: one {timestamp, level, message, target, span: {stage}} object per log line
+    #[arg(long, global = true, value_name = "FORMAT")]
+    pub log_format: Option<LogFormat>,
+    /// Log each JSON-RPC method, params and truncated response, with its time, as debug events (implies
+    /// --log-level debug unless another level is given)
+    #[arg(long, global = true)]
+    pub trace_rpc: bool,
+    /// Look up the payment's previous transactions one by one instead of in one batch
+    #[arg(long, global = true)]
+    pub no_rpc_batch: bool,
+    /// Check the UTXO set total against the block subsidy owed so far (gettxoutsetinfo; slow)
+    #[arg(long, global = true)]
+    pub verify_supply: bool,
+    /// Fail unless the payment confirmed within n blocks of being broadcast
+    #[arg(long, global = true, value_name = "N")]
+    pub assert_confirmed_in: Option<u64>,
+    /// Fail unless the Trader output is p2pkh, p2sh, p2wpkh, p2wsh or p2tr as given
+    #[arg(long, global = true, value_name = "TYPE", ignore_case = true, value_parser = PossibleValuesParser::new(SCRIPT_TYPES))]
+    pub expect_trader_type: Option<String>,
+    /// How long the closing animation runs on a terminal; Ctrl-C cuts it short, 0 skips it (default 7.2)
+    #[arg(long, global = true, value_name = "N", value_parser = seconds, conflicts_with = "no_celebration")]
+    pub celebrate_seconds: Option<Duration>,
+    /// Keep the closing animation silent
+    #[arg(long, global = true)]
+    pub no_bell: bool,
+    /// Validation run: build, print and check the report but write no report files
+    #[arg(long, global = true)]
+    pub no_file: bool,
+    /// Then send half back from Trader to Miner and write out_roundtrip.txt for it
+    #[arg(long, global = true)]
+    pub round_trip: bool,
+    /// Append a JSON line per significant action (wallets, addresses, blocks, tx, report)
+    #[arg(long, global = true, value_name = "PATH")]
+    pub event_log: Option<PathBuf>,
+    /// run: first delete what earlier runs left at this run's output paths (reports, fee and hex files, event
+    /// log, run state, *.tmp leftovers), listing each file removed
+    #[arg(long, global = true)]
+    pub clean: bool,
+    /// run: mine and pay again, ignoring an earlier run's out_state.json (by default a re-run with the same
+    /// options resumes the payment it recorded there)
+    #[arg(long, global = true)]
+    pub fresh: bool,
+    /// run: pay from one matured UTXO an earlier run left the Miner, when it covers the amount plus
+    /// --max-fee, instead of mining a new reward (default: mine, then spend that reward)
+    #[arg(long, global = true)]
+    pub reuse_utxo: bool,
+    /// For CI: print only the report file path and errors (no progress, metrics summary, animation or bell;
+    /// overrides --log-level)
+    #[arg(long, global = true)]
+    pub quiet: bool,
+    /// Skip the closing animation and its bell (same as --celebrate-seconds 0)
+    #[arg(long, global = true)]
+    pub no_celebration: bool,
+}
+
+/// What `--log-format` selects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Parses the arguments that follow the program name. `var` looks up an environment variable and
+/// `read_config` reads a config file; `main` passes the process's own, tests their stand-ins. `--help`
+/// returns before any config is read, so a broken config can't hide the usage.
+pub fn parse_options(
+    args: impl IntoIterator<Item = String>,
+    var: impl Fn(&str) -> Option<String>,
+    read_config: impl Fn(&Path) -> std::io::Result<String>,
+) -> Result<Options, CapstoneError> {
+    let cli = match Cli::try_parse_from(std::iter::once("rust".to_string()).chain(args)) {
+        Ok(cli) => cli,
+        // Asked for, so not an error: nothing else is parsed or checked
+        Err(err) if err.kind() == ErrorKind::DisplayHelp => {
+            return Ok(Options {
+                command: Command::Help,
+                ..Options::default()
+            })
+        }
+        Err(err) => return Err(CapstoneError::Usage(usage_error(&err))),
+    };
+
+    // The config file goes under the flags, and the environment over both
+    let mut options = Options::default();
+    let path = cli
+        .flags
+        .config
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG));
+    match read_config(&path) {
+        Ok(text) => Config::parse(&path, &text)?.apply(&mut options),
+        // Only the default config is optional
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound && cli.flags.config.is_none() => {}
+        Err(err) => {
+            return Err(CapstoneError::Config {
+                path,
+                message: err.to_string(),
+            })
+        }
+    }
+    cli.apply(&mut options);
+    // Last, so credentials a CI container injects win over anything in the config or on the command line
+    apply_env(&mut options, var);
+    validate(&mut options)?;
+    Ok(options)
+}
+
+/// The help `-h`/`--help` prints.
+pub fn help() -> String {
+    Cli::command().render_help().to_string()
+}
+
+/// The usage line `main` prints under a usage error.
+pub fn usage() -> String {
+    format!(
+        "{}\n\nFor more information, try '--help'.",
+        Cli::command().render_usage()
+    )
+}
+
+// clap's message on one line, without its `error: ` prefix, tips and usage, which `main` prints on its own
+fn usage_error(err: &clap::Error) -> String {
+    let rendered = err.render().to_string();
+    let message = rendered
+        .lines()
+        .take_while(|line| !line.trim().is_empty())
+        .map(str::trim)
+        .collect::<Vec<_>>()
+        .join(" ");
+    message
+        .strip_prefix("error: ")
+        .unwrap_or(&message)
+        .to_string()
+}
+
+impl Cli {
+    /// Applies the subcommand and each flag given to `options`, leaving everything else as it is.
+    pub fn apply(self, options: &mut Options) {
+        options.command = match self.command.unwrap_or(CliCommand::Run) {
+            CliCommand::Run => Command::Run,
+            CliCommand::Send => Command::Send,
+            CliCommand::SendMany => Command::SendMany,
+            CliCommand::Report => Command::Report,
+            CliCommand::Watch => Command::Watch,
+            CliCommand::Bump => Command::Bump,
+            CliCommand::Decode => Command::Decode,
+            CliCommand::Diff { file_a, file_b } => {
+                options.diff_files = vec![file_a, file_b];
+                Command::Diff
+            }
+            CliCommand::Mine => Command::Mine,
+            CliCommand::Generator => Command::Generator,
+            CliCommand::Wallets => Command::Wallets,
+            CliCommand::Route => Command::Route,
+            CliCommand::SelfTest => Command::SelfTest,
+        };
+        let flags = self.flags;
+        set(&mut options.out_path, flags.out);
+        set(&mut options.fee_file, flags.fee_file.map(Some));
+        set(&mut options.raw_tx_file, flags.raw_tx_file.map(Some));
+        if !flags.format.is_empty() {
+            options.formats = flags.format;
+        }
+        set(&mut options.amount, flags.amount);
+        set(&mut options.count, flags.count.map(Some));
+        set(&mut options.max_blocks, flags.max_blocks.map(Some));
+        set(
+            &mut options.interval,
+            flags
+                .interval_secs
+                .map(|secs| Some(Duration::from_secs(secs))),
+        );
+        set(&mut options.miners, flags.miners);
+        options.maturity_to_burn |= flags.maturity_to_burn;
+        set(&mut options.check_every, flags.check_every);
+        set(
+            &mut options.poll_interval,
+            flags.poll_interval_ms.map(Duration::from_millis),
+        );
+        options.wait_for_funds |= flags.wait_for_funds;
+        set(
+            &mut options.funds_timeout,
+            flags.funds_timeout.map(Duration::from_secs),
+        );
+        options.show_scripts |= flags.show_scripts;
+        options.explain |= flags.explain;
+        options.normalize |= flags.normalize;
+        set(&mut options.maturity, flags.maturity);
+        set(&mut options.display_decimals, flags.display_decimals);
+        set(&mut options.units, flags.units);
+        options.auto_confirm |= flags.auto_confirm;
+        set(&mut options.txid, flags.txid.map(Some));
+        set(&mut options.mining_label, flags.mining_label);
+        set(&mut options.received_label, flags.received_label);
+        set(&mut options.max_fee, flags.max_fee);
+        options.allow_high_fee |= flags.allow_high_fee;
+        set(
+            &mut options.locktime,
+            flags
+                .locktime
+                .map(|locktime| Some(LockTime::from_consensus(locktime))),
+        );
+        if !flags.spend_utxo.is_empty() {
+            options.spend_utxos = flags.spend_utxo;
+        }
+        options.dry_run |= flags.dry_run;
+        options.sweep |= flags.sweep;
+        options.subtract_fee |= flags.subtract_fee_from_recipient;
+        set(&mut options.comment, flags.comment);
+        set(&mut options.trader_addresses, flags.trader_addresses);
+        options.extra_wallets.extend(flags.wallet);
+        if !flags.route.is_empty() {
+            options.route = flags.route;
+        }
+        set(&mut options.trader_address, flags.trader_address.map(Some));
+        set(&mut options.input_file, flags.input_file.map(Some));
+        set(&mut options.recipients_file, flags.recipients.map(Some));
+        set(
+            &mut options.confirm_address,
+            flags.confirm_address.map(Some),
+        );
+        set(&mut options.confirm_to, flags.confirm_to);
+        set(
+            &mut options.wallet_passphrase,
+            flags.wallet_passphrase.map(Some),
+        );
+        options.unload_on_exit |= flags.unload_on_exit;
+        set(
+            &mut options.block_time_start,
+            flags.block_time_start.map(Some),
+        );
+        set(
+            &mut options.block_time_step,
+            flags.block_time_step.map(Some),
+        );
+        options.stdout_only |= flags.stdout_only;
+        options.spawn_node |= flags.spawn_node;
+        options.docker |= flags.docker;
+        set(&mut options.docker_image, flags.docker_image);
+        set(&mut options.rpc.retry.max_attempts, flags.connect_attempts);
+        set(
+            &mut options.rpc.retry.timeout,
+            flags.connect_timeout.map(Duration::from_secs),
+        );
+        set(
+            &mut options.log_level,
+            flags
+                .log_level
+                .or(flags.trace_rpc.then_some(LogLevel::Debug)),
+        );
+        set(
+            &mut options.log_json,
+            flags.log_format.map(|format| format == LogFormat::Json),
+        );
+        options.rpc.trace |= flags.trace_rpc;
+        options.no_rpc_batch |= flags.no_rpc_batch;
+        options.verify_supply |= flags.verify_supply;
+        set(
+            &mut options.assert_confirmed_in,
+            flags.assert_confirmed_in.map(Some),
+        );
+        set(
+            &mut options.expect_trader_type,
+            flags
+                .expect_trader_type
+                .map(|kind| Some(kind.to_lowercase())),
+        );
+        set(&mut options.celebrate, flags.celebrate_seconds);
+        if flags.no_celebration {
+            options.celebrate = Duration::ZERO;
+        }
+        options.no_bell |= flags.no_bell;
+        options.no_file |= flags.no_file;
+        options.round_trip |= flags.round_trip;
+        set(&mut options.event_log, flags.event_log.map(Some));
+        options.clean |= flags.clean;
+        options.fresh |= flags.fresh;
+        options.reuse_utxo |= flags.reuse_utxo;
+        options.quiet |= flags.quiet;
+    }
+}
+
+fn set<T>(option: &mut T, flag: Option<T>) {
+    if let Some(value) = flag {
+        *option = value;
+    }
+}
+
+// --max-fee: an amount in BTC
+fn btc(value: &str) -> Result<Amount, String> {
+    Amount::from_str_in(value, bitcoin::Denomination::Bitcoin).map_err(|err| err.to_string())
+}
+
+// --celebrate-seconds: a non-negative, possibly fractional number of seconds
+fn seconds(value: &str) -> Result<Duration, String> {
+    value
+        .parse()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| format!("{value:?} is not a number of seconds"))
+}
+
+// --route: "Miner, Exchange" names the wallets Miner and Exchange
+fn trimmed(value: &str) -> Result<String, Infallible> {
+    Ok(value.trim().to_string())
+}
+
+fn passphrase(value: &str) -> Result<Passphrase, Infallible> {
+    Ok(Passphrase(value.to_string()))
+}
+
+// Checks that the merged options fit together, and settles what --quiet implies
+fn validate(options: &mut Options) -> Result<(), CapstoneError> {
+    // Checked once every source is merged, whichever of them named the wallets
+    if options.miner_wallet.is_empty()
+        || options.trader_wallet.is_empty()
+        || options.miner_wallet == options.trader_wallet
+    {
+        return Err(CapstoneError::InvalidOptions(
+            "wallets.miner and wallets.trader must name two different wallets".into(),
+        ));
+    }
+    if matches!(
+        options.command,
+        Command::Bump | Command::Decode | Command::Report
+    ) && options.txid.is_none()
+    {
+        return Err(CapstoneError::InvalidOptions(
+            "`bump`, `decode` and `report` need --txid <txid>".into(),
+        ));
+    }
+    if options.command == Command::Mine {
+        match (options.count, options.max_blocks) {
+            (None | Some(0), _) => {
+                return Err(CapstoneError::InvalidOptions(
+                    "`mine` needs --count <n> with n > 0".into(),
+                ))
+            }
+            (Some(count), Some(max)) if count > max => {
+                return Err(CapstoneError::InvalidOptions(format!(
+                    "--count {count} exceeds --max-blocks {max}"
+                )))
+            }
+            _ => {}
+        }
+    }
+    if options.command == Command::Generator && options.interval.unwrap_or_default().is_zero() {
+        return Err(CapstoneError::InvalidOptions(
+            "`generator` needs --interval-secs <s> with s > 0".into(),
+        ));
+    }
+    if options.verify_supply && !options.command.sends() {
+        return Err(CapstoneError::InvalidOptions(
+            "--verify-supply only applies to `run` and `send`".into(),
+        ));
+    }
+    if options.clean && !options.command.sends() {
+        return Err(CapstoneError::InvalidOptions(
+            "--clean only applies to run and send".into(),
+        ));
+    }
+    let wallet_set = options.wallet_set()?;
+    if options.command == Command::Route {
+        let hops = options.route_hops();
+        if hops.len() < 2 {
+            return Err(CapstoneError::InvalidOptions(
+                "`route` needs at least two wallets to pay between".into(),
+            ));
+        }
+        if let Some(unknown) = hops.iter().find(|name| !wallet_set.contains(name)) {
+            return Err(CapstoneError::InvalidOptions(format!(
+                "The route goes through {unknown}, which is not one of the wallets ({}); declare it with --wallet or wallets.extra",
+                wallet_set.names().join(", ")
+            )));
+        }
+        if let Some(hop) = hops.windows(2).find(|hop| hop[0] == hop[1]) {
+            return Err(CapstoneError::InvalidOptions(format!(
+                "The route pays {} to itself",
+                hop[0]
+            )));
+        }
+    }
+    for (flag, label) in [
+        ("--mining-label", &options.mining_label),
+        ("--received-label", &options.received_label),
+    ] {
+        // Core reserves "*" (RPC_WALLET_INVALID_LABEL_NAME), and the label checks look addresses up by label
+        if label.trim().is_empty() || label == "*" {
+            return Err(CapstoneError::InvalidOptions(format!(
+                "{flag} {label:?} can't label an address; pick a name"
+            )));
+        }
+    }
+    if (options.command == Command::SendMany) != options.recipients_file.is_some() {
+        return Err(CapstoneError::InvalidOptions(
+            "`send-many` needs --recipients <file>, which only `send-many` takes".into(),
+        ));
+    }
+    if options.fresh && options.command != Command::Run {
+        return Err(CapstoneError::InvalidOptions(
+            "--fresh only applies to run".into(),
+        ));
+    }
+    if options.reuse_utxo && options.command != Command::Run {
+        return Err(CapstoneError::InvalidOptions(
+            "--reuse-utxo only applies to run".into(),
+        ));
+    }
+    if options.reuse_utxo && options.fresh {
+        return Err(CapstoneError::InvalidOptions(
+            "--reuse-utxo pays from an earlier run's coins, --fresh starts over; pick one".into(),
+        ));
+    }
+    let mut seen = HashSet::new();
+    if let Some(twice) = options.spend_utxos.iter().find(|utxo| !seen.insert(*utxo)) {
+        return Err(CapstoneError::InvalidOptions(format!(
+            "--spend-utxo {twice} is given twice"
+        )));
+    }
+    if options.confirm_address.is_some() && options.confirm_to != ConfirmTo::Miner {
+        return Err(CapstoneError::InvalidOptions(
+            "--confirm-address and --confirm-to are alternatives; pick one".into(),
+        ));
+    }
+    if options.trader_addresses > 1
+        && (options.trader_address.is_some()
+            || options.locktime.is_some()
+            || !options.spend_utxos.is_empty())
+    {
+        return Err(CapstoneError::InvalidOptions("--trader-addresses can't be combined with --trader-address, --locktime or --spend-utxo"
+        .into()));
+    }
+    if options.sweep
+        && (options.trader_addresses > 1
+            || options.locktime.is_some()
+            || !options.spend_utxos.is_empty()
+            || options.subtract_fee)
+    {
+        return Err(CapstoneError::InvalidOptions("--sweep can't be combined with --trader-addresses, --locktime, --spend-utxo or --subtract-fee-from-recipient"
+        .into()));
+    }
+    if options.dry_run
+        && (!options.command.sends()
+            || options.input_file.is_some()
+            || options.trader_addresses > 1
+            || options.sweep
+            || options.locktime.is_some()
+            || !options.spend_utxos.is_empty()
+            || options.wait_for_funds
+            || options.round_trip)
+    {
+        return Err(CapstoneError::InvalidOptions("--dry-run previews the plain `run`/`send` payment: it can't be combined with --input-file, --trader-addresses, --sweep, --locktime, --spend-utxo, --wait-for-funds or --round-trip"
+        .into()));
+    }
+    if options.input_file.is_some()
+        && (!options.command.sends()
+            || options.trader_address.is_some()
+            || options.trader_addresses > 1
+            || options.sweep
+            || options.locktime.is_some()
+            || !options.spend_utxos.is_empty())
+    {
+        return Err(CapstoneError::InvalidOptions("--input-file only applies to `run` and `send`, and takes each recipient and amount from the file: it can't be combined with --trader-address, --trader-addresses, --sweep, --locktime or --spend-utxo"
+        .into()));
+    }
+    if options.subtract_fee && (options.locktime.is_some() || !options.spend_utxos.is_empty()) {
+        return Err(CapstoneError::InvalidOptions("--subtract-fee-from-recipient only applies to the plain send, not --locktime or --spend-utxo"
+        .into()));
+    }
+    if options.explain && options.stdout_only {
+        return Err(CapstoneError::InvalidOptions(
+            "--explain writes to stdout, which --stdout-only keeps for the ten report lines".into(),
+        ));
+    }
+    if options.quiet {
+        // Errors only; the report path is printed on its own (see `write_report_files`)
+        options.log_level = LogLevel::Error;
+        options.celebrate = Duration::ZERO;
+    }
+    if options.spawn_node && options.docker {
+        return Err(CapstoneError::InvalidOptions(
+            "--spawn-node and --docker each start a node; pick one".into(),
+        ));
+    }
+    if options.wait_for_funds && options.miners > 1 {
+        return Err(CapstoneError::InvalidOptions(
+            "--miners only applies when mining, not with --wait-for-funds".into(),
+        ));
+    }
+    if options.maturity_to_burn && (options.wait_for_funds || options.miners > 1) {
+        return Err(CapstoneError::InvalidOptions(
+            "--maturity-to-burn can't be combined with --wait-for-funds or --miners".into(),
+        ));
+    }
+    if options.block_time_step.is_some() && options.block_time_start.is_none() {
+        return Err(CapstoneError::InvalidOptions(
+            "--block-time-step needs --block-time-start".into(),
+        ));
+    }
+    if options.block_time_start == Some(0) {
+        return Err(CapstoneError::InvalidOptions(
+            "--block-time-start must be a positive Unix timestamp".into(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mining::COINBASE_MATURITY;
+    use crate::node::DOCKER_IMAGE;
+    use crate::options::{
+        DEFAULT_FUNDS_TIMEOUT, DEFAULT_MAX_FEE, DEFAULT_OUT_PATH, PAYMENT_COMMENT,
+    };
+    use crate::pipeline::DEFAULT_CELEBRATION;
+    use crate::rpc::{RetryPolicy, RpcSettings};
+    use crate::test_support::*;
+    use std::collections::HashMap;
+
+    const TXID: &str = "87992cbfe278b2d9e71623efc4333e0566f1c8dda3bd09b7059a0a460d7991bd";
+    const REGTEST_ADDRESS: &str = "bcrt1qv5plgft75j0hegtvf6zs5pajh7k0gxg2dhj224";
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    // Parses `list` with no environment and no config file, whatever the machine running the tests has
+    fn parse(list: &[&str]) -> Result<Options, CapstoneError> {
+        parse_options(
+            args(list),
+            |_| None,
+            |_| Err(std::io::ErrorKind::NotFound.into()),
+        )
+    }
+
+    #[test]
+    fn out_path_defaults_to_repo_root() {
+        let options = parse(&[]).unwrap();
+        assert_eq!(options.out_path, PathBuf::from(DEFAULT_OUT_PATH));
+    }
+
+    #[test]
+    fn out_flag_overrides_path() {
+        let options = parse(&["--out", "reports/run1.txt"]).unwrap();
+        assert_eq!(options.out_path, PathBuf::from("reports/run1.txt"));
+        assert!(parse(&["--out"]).is_err());
+    }
+
+    #[test]
+    fn block_times_are_optional_and_step_needs_start() {
+        let options = parse(&[]).unwrap();
+        assert_eq!(
+            (options.block_time_start, options.block_time_step),
+            (None, None)
+        );
+
+        let options = parse(&[
+            "--block-time-start",
+            "1700000000",
+            "--block-time-step",
+            "60",
+        ])
+        .unwrap();
+        assert_eq!(options.block_time_start, Some(1_700_000_000));
+        assert_eq!(options.block_time_step, Some(60));
+
+        assert!(parse(&["--block-time-step", "60"]).is_err());
+        assert!(parse(&["--block-time-start", "0"]).is_err());
+    }
+
+    #[test]
+    fn amount_defaults_to_twenty_btc_and_stays_under_the_supply() {
+        assert_eq!(
+            parse(&[]).unwrap().amount,
+            SendAmount::Absolute(Amount::from_int_btc(20))
+        );
+        assert_eq!(
+            parse(&["--amount", "50%"]).unwrap().amount,
+            SendAmount::Percent(50.0)
+        );
+        assert!(parse(&["--amount", "21000001"]).is_err());
+    }
+
+    #[test]
+    fn no_file_is_opt_in() {
+        assert!(!parse(&[]).unwrap().no_file);
+        assert!(parse(&["--no-file"]).unwrap().no_file);
+    }
+
+    #[test]
+    fn confirm_to_defaults_to_miner_and_excludes_confirm_address() {
+        assert_eq!(parse(&[]).unwrap().confirm_to, ConfirmTo::Miner);
+        assert_eq!(
+            parse(&["--confirm-to", "trader"]).unwrap().confirm_to,
+            ConfirmTo::Trader
+        );
+        assert!(parse(&["--confirm-to", "fees"]).is_err());
+        assert!(parse(&["--confirm-to", "trader", "--confirm-address", TRADER]).is_err());
+    }
+
+    #[test]
+    fn maturity_defaults_to_standard_and_must_be_positive() {
+        assert_eq!(parse(&[]).unwrap().maturity, COINBASE_MATURITY);
+        assert_eq!(parse(&["--maturity", "10"]).unwrap().maturity, 10);
+        assert!(parse(&["--maturity", "0"]).is_err());
+        assert!(parse(&["--maturity", "-5"]).is_err());
+    }
+
+    #[test]
+    fn balance_check_interval_and_poll_delay_are_parsed() {
+        let options = parse(&[]).unwrap();
+        assert_eq!(options.check_every, 1);
+        assert_eq!(options.poll_interval, Duration::ZERO);
+        let options = parse(&["--check-every", "25", "--poll-interval-ms", "50"]).unwrap();
+        assert_eq!(options.check_every, 25);
+        assert_eq!(options.poll_interval, Duration::from_millis(50));
+        assert!(parse(&["--check-every", "0"]).is_err());
+    }
+
+    #[test]
+    fn wait_for_funds_is_parsed_and_excludes_extra_miners() {
+        let options = parse(&[]).unwrap();
+        assert!(!options.wait_for_funds);
+        assert_eq!(options.funds_timeout, DEFAULT_FUNDS_TIMEOUT);
+        let options = parse(&["--wait-for-funds", "--funds-timeout", "30"]).unwrap();
+        assert!(options.wait_for_funds);
+        assert_eq!(options.funds_timeout, Duration::from_secs(30));
+        assert!(parse(&["--wait-for-funds", "--miners", "2"]).is_err());
+    }
+
+    #[test]
+    fn miners_default_to_one_and_must_be_positive() {
+        assert_eq!(parse(&[]).unwrap().miners, 1);
+        assert_eq!(parse(&["--miners", "3"]).unwrap().miners, 3);
+        assert!(parse(&["--miners", "0"]).is_err());
+    }
+
+    #[test]
+    fn maturity_to_burn_needs_a_lone_miner_that_doesnt_wait() {
+        assert!(!parse(&[]).unwrap().maturity_to_burn);
+        assert!(parse(&["--maturity-to-burn"]).unwrap().maturity_to_burn);
+        assert!(parse(&["--maturity-to-burn", "--miners", "2"]).is_err());
+        assert!(parse(&["--maturity-to-burn", "--wait-for-funds"]).is_err());
+    }
+
+    #[test]
+    fn config_file_is_read_before_the_flags() {
+        // Config files as parse reads them: node.toml by --config, and a capstone.toml in the working directory
+        let node = "[rpc]\nuser = \"bob\"\n\n[send]\namount = 5";
+        let same = "[wallets]\nminer = \"Trader\"";
+        let files = HashMap::from([
+            (PathBuf::from("node.toml"), node),
+            (PathBuf::from("same.toml"), same),
+            (PathBuf::from(DEFAULT_CONFIG), "[rpc]\nuser = \"carol\""),
+        ]);
+        let read = |path: &Path| {
+            files
+                .get(path)
+                .map(|text| text.to_string())
+                .ok_or_else(|| std::io::ErrorKind::NotFound.into())
+        };
+        let parse_with = |list: &[&str]| parse_options(args(list), |_| None, read);
+        let options = parse_with(&["--config", "node.toml"]).unwrap();
+        assert_eq!(
+            options.amount,
+            SendAmount::Absolute(Amount::from_int_btc(5))
+        );
+        let options = parse_with(&["--amount", "7", "--config", "node.toml"]).unwrap();
+        assert_eq!(
+            options.amount,
+            SendAmount::Absolute(Amount::from_int_btc(7))
+        );
+        assert_eq!(options.rpc.user, "bob");
+        assert_eq!(parse_with(&[]).unwrap().rpc.user, "carol");
+        assert!(parse_with(&["--config", "node.toml.missing"]).is_err());
+        // The config alone is fine; the merged options then name the Trader twice
+        assert!(Config::parse(Path::new("same.toml"), same).is_ok());
+        assert!(parse_with(&["--config", "same.toml"]).is_err());
+        // Only a flag is a flag: a --comment of "--config" reads no config
+        let options = parse_with(&["--comment=--config", "--amount", "3"]).unwrap();
+        assert_eq!(options.comment, "--config");
+        assert_eq!(options.rpc.user, "carol");
+        assert_eq!(parse(&[]).unwrap().rpc, RpcSettings::default());
+    }
+
+    #[test]
+    fn help_needs_no_config_and_only_flag_errors_carry_the_usage() {
+        let broken = |_: &Path| Ok("[rpc\nuser".to_string());
+        assert!(parse_options(args(&[]), |_| None, broken).is_err());
+        let options = parse_options(args(&["--help"]), |_| None, broken).unwrap();
+        assert_eq!(options.command, Command::Help);
+        let unreadable = |_: &Path| Err(std::io::ErrorKind::PermissionDenied.into());
+        let options = parse_options(args(&["-h"]), |_| None, unreadable).unwrap();
+        assert_eq!(options.command, Command::Help);
+
+        // Unknown, repeated or clashing flags and missing or malformed values are usage errors, so no flag
+        // silently overrides another; options that only conflict once merged aren't
+        for bad_flag in [
+            &["--no-such-flag"][..],
+            &["--out"],
+            &["--count", "many"],
+            &["--amount", "1", "--amount", "2"],
+            &["--no-celebration", "--celebrate-seconds", "3"],
+            &["run", "send"],
+        ] {
+            let err = parse(bad_flag).unwrap_err();
+            assert!(
+                matches!(err, CapstoneError::Usage(_)),
+                "{bad_flag:?}: {err}"
+            );
+            assert!(!err.to_string().contains("Usage:"));
+        }
+        let err = parse(&["--fresh", "--reuse-utxo"]).unwrap_err();
+        assert!(matches!(err, CapstoneError::InvalidOptions(_)), "{err}");
+        assert!(!err.to_string().contains("Usage:"));
+    }
+
+    #[test]
+    fn environment_overrides_config_and_flags() {
+        let env = |name: &str| (name == "BITCOIN_RPC_USER").then(|| "ci".to_string());
+        let config = |_: &Path| Ok("[rpc]\nuser = \"bob\"".to_string());
+        let options = parse_options(args(&[]), env, config).unwrap();
+        assert_eq!(options.rpc.user, "ci");
+        let options = parse_options(args(&[]), |_| None, config).unwrap();
+        assert_eq!(options.rpc.user, "bob");
+    }
+
+    #[test]
+    fn spawn_node_runs_the_bitcoind_on_the_path() {
+        assert!(!parse(&[]).unwrap().spawn_node);
+        let options = parse(&["--spawn-node"]).unwrap();
+        assert!(options.spawn_node);
+        assert_eq!(options.bitcoind, "bitcoind");
+    }
+
+    #[test]
+    fn docker_runs_the_pinned_image_instead_of_a_spawned_node() {
+        let options = parse(&["--docker"]).unwrap();
+        assert!(options.docker);
+        assert_eq!(options.docker_image, DOCKER_IMAGE);
+        assert!(parse(&["--docker", "--spawn-node"]).is_err());
+    }
+
+    #[test]
+    fn quiet_mode_leaves_only_errors_and_no_celebration() {
+        let options = parse(&["--no-celebration"]).unwrap();
+        assert_eq!(options.celebrate, Duration::ZERO);
+        assert_eq!(options.log_level, LogLevel::Info);
+
+        // --quiet wins over a --log-level given with it, in either order
+        for flags in [
+            ["--quiet", "--log-level", "debug"],
+            ["--log-level", "debug", "--quiet"],
+        ] {
+            let options = parse(&flags).unwrap();
+            assert_eq!(options.log_level, LogLevel::Error);
+            assert_eq!(options.celebrate, Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn log_level_and_format_flags() {
+        let options = parse(&[]).unwrap();
+        assert_eq!(
+            (options.log_level, options.log_json),
+            (LogLevel::Info, false)
+        );
+        let options = parse(&["--log-level", "warn", "--log-format", "json"]).unwrap();
+        assert_eq!(
+            (options.log_level, options.log_json),
+            (LogLevel::Warn, true)
+        );
+        assert!(parse(&["--log-level", "trace"]).is_err());
+        // --trace-rpc logs at the debug level, so it shows its events unless told otherwise
+        assert_eq!(parse(&["--trace-rpc"]).unwrap().log_level, LogLevel::Debug);
+        let options = parse(&["--trace-rpc", "--log-level", "warn"]).unwrap();
+        assert_eq!(options.log_level, LogLevel::Warn);
+        assert_eq!(
+            parse(&["--trace-rpc", "--quiet"]).unwrap().log_level,
+            LogLevel::Error
+        );
+        assert!(parse(&["--log-format", "yaml"]).is_err());
+    }
+
+    #[test]
+    fn wallet_passphrase_never_shows_in_debug_output() {
+        let options = parse(&["--wallet-passphrase", "hunter2"]).unwrap();
+        assert_eq!(
+            options.wallet_passphrase,
+            Some(Passphrase("hunter2".to_string()))
+        );
+        assert!(!format!("{options:?}").contains("hunter2"));
+    }
+
+    #[test]
+    fn input_file_only_goes_with_a_plain_send() {
+        let options = parse(&["--input-file", "payments.json", "--fee-file", "fee.txt"]).unwrap();
+        assert_eq!(options.input_file, Some(PathBuf::from("payments.json")));
+        assert!(parse(&["--input-file", "p.json", "--sweep"]).is_err());
+        assert!(parse(&["mine", "--count", "1", "--input-file", "p.json"]).is_err());
+    }
+
+    #[test]
+    fn verify_supply_only_goes_with_a_run_that_mines_the_chain() {
+        assert!(!parse(&[]).unwrap().verify_supply);
+        assert!(parse(&["--verify-supply"]).unwrap().verify_supply);
+        assert!(parse(&["mine", "--count", "1", "--verify-supply"]).is_err());
+    }
+
+    #[test]
+    fn generator_needs_a_positive_interval() {
+        let options = parse(&["generator", "--interval-secs", "5"]).unwrap();
+        assert_eq!(options.command, Command::Generator);
+        assert_eq!(options.interval, Some(Duration::from_secs(5)));
+        assert!(parse(&["generator"]).is_err());
+        assert!(parse(&["generator", "--interval-secs", "0"]).is_err());
+    }
+
+    #[test]
+    fn mine_needs_a_positive_count_within_max_blocks() {
+        let options = parse(&["mine", "--count", "10"]).unwrap();
+        assert_eq!((options.command, options.count), (Command::Mine, Some(10)));
+
+        assert!(parse(&["mine"]).is_err());
+        assert!(parse(&["mine", "--count", "0"]).is_err());
+        assert!(parse(&["mine", "--count", "10", "--max-blocks", "5"]).is_err());
+        assert!(parse(&["mine", "--count", "5", "--max-blocks", "5"]).is_ok());
+    }
+
+    #[test]
+    fn labels_default_to_the_grader_values() {
+        let options = parse(&[]).unwrap();
+        assert_eq!(options.mining_label, "Mining Reward");
+        assert_eq!(options.received_label, "Received");
+
+        let options = parse(&["--received-label", "Inbox"]).unwrap();
+        assert_eq!(options.received_label, "Inbox");
+        assert!(parse(&["--mining-label", "*"]).is_err());
+        assert!(parse(&["--received-label", " "]).is_err());
+    }
+
+    #[test]
+    fn wallets_stay_loaded_unless_asked() {
+        assert!(!parse(&[]).unwrap().unload_on_exit);
+        assert!(parse(&["--unload-on-exit"]).unwrap().unload_on_exit);
+    }
+
+    #[test]
+    fn connect_retry_limits_are_parsed() {
+        assert_eq!(parse(&[]).unwrap().rpc.retry, RetryPolicy::default());
+        let options = parse(&["--connect-attempts", "3", "--connect-timeout", "5"]).unwrap();
+        assert_eq!(
+            options.rpc.retry,
+            RetryPolicy {
+                max_attempts: 3,
+                timeout: Duration::from_secs(5),
+            }
+        );
+        assert!(parse(&["--connect-attempts", "0"]).is_err());
+    }
+
+    #[test]
+    fn extra_output_files_are_opt_in() {
+        let options = parse(&[]).unwrap();
+        assert_eq!((options.raw_tx_file, options.fee_file), (None, None));
+        let options = parse(&["--raw-tx-file", "tx.hex", "--fee-file", "fee.txt"]).unwrap();
+        assert_eq!(options.raw_tx_file, Some(PathBuf::from("tx.hex")));
+        assert_eq!(options.fee_file, Some(PathBuf::from("fee.txt")));
+    }
+
+    #[test]
+    fn celebration_is_configurable() {
+        let options = parse(&[]).unwrap();
+        assert_eq!(options.celebrate, DEFAULT_CELEBRATION);
+        assert!(!options.no_bell);
+        let options = parse(&["--celebrate-seconds", "2", "--no-bell"]).unwrap();
+        assert_eq!(options.celebrate, Duration::from_secs(2));
+        assert!(options.no_bell);
+        let options = parse(&["--celebrate-seconds", "7.2"]).unwrap();
+        assert_eq!(options.celebrate, Duration::from_millis(7_200));
+        assert!(parse(&["--celebrate-seconds", "-1"]).is_err());
+        assert!(parse(&["--celebrate-seconds", "NaN"]).is_err());
+    }
+
+    #[test]
+    fn display_decimals_are_bounded() {
+        assert_eq!(
+            parse(&["--display-decimals", "2"])
+                .unwrap()
+                .display_decimals,
+            2
+        );
+        assert_eq!(parse(&[]).unwrap().display_decimals, 8);
+        for bad in ["0", "9", "two"] {
+            assert!(parse(&["--display-decimals", bad]).is_err());
+        }
+    }
+
+    #[test]
+    fn fresh_and_reuse_utxo_only_apply_to_run() {
+        assert!(parse(&["--fresh"]).unwrap().fresh);
+        assert!(parse(&["send", "--fresh"]).is_err());
+        assert!(parse(&["--reuse-utxo"]).unwrap().reuse_utxo);
+        assert!(parse(&["send", "--reuse-utxo"]).is_err());
+        assert!(parse(&["--reuse-utxo", "--fresh"]).is_err());
+    }
+
+    #[test]
+    fn format_flag_accepts_a_list() {
+        let options = parse(&["--format", "txt,json"]).unwrap();
+        assert_eq!(
+            options.formats,
+            vec![ReportFormat::Text, ReportFormat::Json]
+        );
+        assert!(parse(&["--format", "yaml"]).is_err());
+    }
+
+    #[test]
+    fn report_sections_and_units_are_chosen_by_flag() {
+        let options = parse(&[]).unwrap();
+        assert!(!options.show_scripts && !options.explain);
+        assert_eq!(options.units, Units::Btc);
+        assert!(parse(&["--show-scripts"]).unwrap().show_scripts);
+        assert_eq!(parse(&["--units", "sat"]).unwrap().units, Units::Sat);
+        assert!(parse(&["--units", "msat"]).is_err());
+        assert!(parse(&["--explain"]).unwrap().explain);
+        assert!(parse(&["--explain", "--stdout-only"]).is_err());
+    }
+
+    #[test]
+    fn expected_trader_type_must_be_a_known_script_type() {
+        let options = parse(&["--expect-trader-type", "P2TR"]).unwrap();
+        assert_eq!(options.expect_trader_type.as_deref(), Some("p2tr"));
+        assert!(parse(&["--expect-trader-type", "p2pk"]).is_err());
+    }
+
+    #[test]
+    fn payment_comment_is_configurable() {
+        assert_eq!(parse(&[]).unwrap().comment, PAYMENT_COMMENT);
+        assert_eq!(parse(&["--comment", "rent"]).unwrap().comment, "rent");
+    }
+
+    #[test]
+    fn fee_limit_is_configurable() {
+        let options = parse(&[]).unwrap();
+        assert_eq!(options.max_fee, DEFAULT_MAX_FEE);
+        assert!(!options.allow_high_fee);
+        let options = parse(&["--max-fee", "0.001", "--allow-high-fee"]).unwrap();
+        assert_eq!(options.max_fee, Amount::from_sat(100_000));
+        assert!(options.allow_high_fee);
+        assert!(parse(&["--max-fee", "lots"]).is_err());
+    }
+
+    #[test]
+    fn locktime_is_a_height_or_a_timestamp() {
+        assert_eq!(parse(&[]).unwrap().locktime, None);
+        let options = parse(&["--locktime", "250"]).unwrap();
+        assert_eq!(options.locktime, Some(LockTime::from_height(250).unwrap()));
+        let options = parse(&["--locktime", "1700000000"]).unwrap();
+        assert!(matches!(options.locktime, Some(LockTime::Seconds(_))));
+        assert!(parse(&["--locktime", "-1"]).is_err());
+    }
+
+    #[test]
+    fn spend_utxo_takes_distinct_outpoints() {
+        let txid: Txid = TXID.parse().unwrap();
+        let options = parse(&["--spend-utxo", &format!("{txid}:0")]).unwrap();
+        assert_eq!(options.spend_utxos, [OutPoint::new(txid, 0)]);
+        assert!(parse(&["--spend-utxo", TXID]).is_err());
+        let twice = format!("{txid}:0");
+        assert!(parse(&["--spend-utxo", &twice, "--spend-utxo", &twice]).is_err());
+    }
+
+    #[test]
+    fn trader_addresses_are_parsed_and_checked() {
+        assert_eq!(parse(&[]).unwrap().trader_addresses, 1);
+        assert_eq!(
+            parse(&["--trader-addresses", "3"])
+                .unwrap()
+                .trader_addresses,
+            3
+        );
+        assert!(parse(&["--trader-addresses", "0"]).is_err());
+        assert!(parse(&["--trader-addresses", "2", "--locktime", "150"]).is_err());
+        assert!(parse(&[
+            "--trader-addresses",
+            "2",
+            "--trader-address",
+            REGTEST_ADDRESS,
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn trader_address_must_parse() {
+        let options = parse(&["--trader-address", REGTEST_ADDRESS]).unwrap();
+        assert!(options
+            .trader_address
+            .unwrap()
+            .is_valid_for_network(bitcoin::Network::Regtest));
+        assert!(parse(&["--trader-address", "not-an-address"]).is_err());
+    }
+
+    #[test]
+    fn sweep_pays_a_single_trader_address() {
+        assert!(parse(&["--sweep"]).unwrap().sweep);
+        assert!(parse(&["--sweep", "--trader-addresses", "2"]).is_err());
+    }
+
+    #[test]
+    fn subtract_fee_from_recipient_excludes_a_locktime() {
+        assert!(
+            parse(&["--subtract-fee-from-recipient"])
+                .unwrap()
+                .subtract_fee
+        );
+        assert!(parse(&["--subtract-fee-from-recipient", "--locktime", "150"]).is_err());
+    }
+
+    #[test]
+    fn dry_run_only_previews_a_plain_payment() {
+        assert!(parse(&["--dry-run"]).unwrap().dry_run);
+        assert!(
+            parse(&["send", "--dry-run", "--amount", "1"])
+                .unwrap()
+                .dry_run
+        );
+        assert!(!parse(&[]).unwrap().dry_run);
+        for conflicting in [
+            &["mine", "--dry-run"][..],
+            &["--dry-run", "--sweep"],
+            &["--dry-run", "--locktime", "150"],
+            &["--dry-run", "--round-trip"],
+            &["--dry-run", "--wait-for-funds"],
+            &["--dry-run", "--trader-addresses", "2"],
+        ] {
+            assert!(parse(conflicting).is_err(), "{conflicting:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn route_flags_add_wallets_and_pick_the_hops() {
+        let options = parse(&["route", "--wallet", "Exchange", "--miners", "2"]).unwrap();
+        assert_eq!(options.command, Command::Route);
+        assert_eq!(options.extra_wallets, ["Exchange"]);
+        assert_eq!(options.miners, 2);
+        let options = parse(&["route", "--route", "Miner,Trader"]).unwrap();
+        assert_eq!(options.route, ["Miner", "Trader"]);
+
+        for invalid in [
+            &["--wallet", "Trader"][..],
+            &["--wallet", " "],
+            &["route", "--route", "Miner,Exchange"],
+            &["route", "--route", "Miner"],
+            &[
+                "route",
+                "--wallet",
+                "Exchange",
+                "--route",
+                "Miner,Exchange,Exchange",
+            ],
+        ] {
+            assert!(parse(invalid).is_err(), "{invalid:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn send_many_needs_a_recipients_file() {
+        assert!(parse(&["send-many"]).is_err());
+        assert!(parse(&["--recipients", "r.json"]).is_err());
+        let options = parse(&["send-many", "--recipients", "r.json"]).unwrap();
+        assert_eq!(options.command, Command::SendMany);
+        assert_eq!(options.recipients_file, Some(PathBuf::from("r.json")));
+    }
+}
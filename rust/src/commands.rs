@@ -0,0 +1,479 @@
+//! The commands besides the payment pipelines: `watch`, `bump`, `mine`, `generator`, `wallets`, `decode`
+//! and `diff`.
+
+use crate::mining::*;
+use crate::options::Options;
+use crate::pipeline::{catch_ctrl_c, INTERRUPTED};
+use crate::report::*;
+use crate::wallet::addresses_by_label;
+use crate::*;
+use bitcoincore_rpc::bitcoin::{Amount, Txid};
+use bitcoincore_rpc::json::{Bip125Replaceable, GetTransactionResultDetailCategory};
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::result::Result;
+use std::sync::atomic::Ordering;
+use std::{thread, time::Duration};
+use tracing::{info, warn};
+
+// How long `watch` sleeps between `listsinceblock` polls
+const WATCH_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+// How many times `bump` polls the mempool for the replacement, and how long it waits between polls
+const REPLACEMENT_POLLS: usize = 25;
+const REPLACEMENT_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Polls the Trader wallet with `listsinceblock` and prints a report for every newly confirmed receipt.
+/// Runs until the process is interrupted (Ctrl-C).
+pub fn watch(
+    ctx: &Context,
+    options: &Options,
+    miner: &Client,
+    trader: &Client,
+) -> Result<(), CapstoneError> {
+    let decimals = options.display_decimals;
+    // Only payments that arrive from now on are interesting, so start from the current tip
+    let mut since = trader.get_best_block_hash()?;
+    let mut reported: HashSet<Txid> = HashSet::new();
+    let mut confirm_address = None;
+
+    info!("Watching the Trader wallet for incoming payments (Ctrl-C to stop)...");
+    loop {
+        let changes = trader.list_since_block(Some(&since), None, None, None)?;
+        let mut pending = false;
+
+        for entry in changes.transactions.iter() {
+            if entry.detail.category != GetTransactionResultDetailCategory::Receive
+                || reported.contains(&entry.info.txid)
+            {
+                continue;
+            }
+            if entry.info.confirmations < 1 {
+                pending = true;
+                continue;
+            }
+
+            let receiving_address = entry
+                .detail
+                .address
+                .clone()
+                .map(|a| a.assume_checked().to_string())
+                .unwrap_or_default();
+            // Ownership is checked against the Miner, so change is only reported for payments the Miner sent
+            reported.insert(entry.info.txid);
+            match TxReport::from_txid(ctx, miner, &entry.info.txid, &[&receiving_address]) {
+                Ok(report) => {
+                    info!("\nNew payment received by Trader:");
+                    info!("\n{report:.decimals$}");
+                }
+                // One receipt that can't be reported (an untraceable input, an odd output) mustn't end the
+                // watch; it is skipped from now on like a reported one
+                Err(err) => warn!("⚠️ Skipping Trader receipt {}: {err}", entry.info.txid),
+            }
+        }
+
+        if pending && options.auto_confirm {
+            // Mine to a Miner address so the confirming coinbase doesn't show up as a Trader receipt
+            let address = match &confirm_address {
+                Some(address) => address,
+                None => confirm_address.insert(
+                    miner
+                        .get_new_address(Some(&options.mining_label), None)?
+                        .require_network(bitcoincore_rpc::bitcoin::Network::Regtest)?,
+                ),
+            };
+            mine_blocks(ctx, miner, 1, address)?;
+            info!("Mined 1 block to confirm pending Trader receipts");
+            continue;
+        }
+
+        // Confirmed receipts are already reported; unconfirmed ones are still returned after advancing
+        since = changes.lastblock;
+        thread::sleep(WATCH_POLL_INTERVAL);
+    }
+}
+
+/// What `bumpfee` returns; the crate has no typed wrapper for it, so it is called by name.
+#[derive(Debug, Deserialize)]
+struct BumpFeeResult {
+    txid: Txid,
+    #[serde(with = "bitcoin::amount::serde::as_btc")]
+    origfee: Amount,
+    #[serde(with = "bitcoin::amount::serde::as_btc")]
+    fee: Amount,
+    #[serde(default)]
+    errors: Vec<String>,
+}
+
+/// Replaces an unconfirmed, RBF-signaling Miner transaction with a higher-fee version via `bumpfee`,
+/// then waits until the replacement shows up in the mempool.
+pub fn bump(options: &Options, miner: &Client) -> Result<(), CapstoneError> {
+    let decimals = options.display_decimals;
+    let txid = options.txid.ok_or(CapstoneError::MissingFlag {
+        command: "bump",
+        flag: "--txid <txid>",
+    })?;
+
+    // Only a transaction that is still waiting in the mempool and opted in to RBF can be replaced
+    let original = miner.get_transaction(&txid, None)?;
+    if original.info.confirmations > 0 {
+        return Err(CapstoneError::AlreadyConfirmed(txid));
+    }
+    if original.info.bip125_replaceable != Bip125Replaceable::Yes {
+        return Err(CapstoneError::NotReplaceable(txid));
+    }
+
+    let bumped = miner.call::<BumpFeeResult>("bumpfee", &[json!(txid)])?;
+    for error in &bumped.errors {
+        warn!("⚠️ bumpfee: {error}");
+    }
+    info!("Replacement transaction: {}", bumped.txid);
+    info!("New fee: {:.decimals$} BTC", bumped.fee.to_btc());
+
+    let mut in_mempool = false;
+    for _ in 0..REPLACEMENT_POLLS {
+        if miner.get_raw_mempool()?.contains(&bumped.txid) {
+            in_mempool = true;
+            break;
+        }
+        thread::sleep(REPLACEMENT_POLL_INTERVAL);
+    }
+    if !in_mempool {
+        return Err(CapstoneError::ReplacementMissing(bumped.txid));
+    }
+    info!("Replacement is in the mempool.");
+
+    info!(
+        "Fee bumped from {:.decimals$} BTC to {:.decimals$} BTC (+{:.decimals$} BTC)",
+        bumped.origfee.to_btc(),
+        bumped.fee.to_btc(),
+        (bumped.fee - bumped.origfee).to_btc()
+    );
+    Ok(())
+}
+
+/// Mines `--count` blocks to a fresh Miner `--mining-label` address and prints the resulting chain state.
+/// Nothing is sent and no report is written; this is for preparing a chain before other commands.
+pub fn mine(
+    ctx: &Context,
+    options: &Options,
+    miner: &Client,
+    metrics: &mut Metrics,
+) -> Result<(), CapstoneError> {
+    let decimals = options.display_decimals;
+    let count = options.count.ok_or(CapstoneError::MissingFlag {
+        command: "mine",
+        flag: "--count <n>",
+    })?;
+    metrics.phase("mine");
+    let address = miner
+        .get_new_address(Some(&options.mining_label), None)?
+        .require_network(bitcoincore_rpc::bitcoin::Network::Regtest)?;
+    info!("Mining {count} block(s) to {address}");
+
+    let hashes = mine_blocks(ctx, miner, count, &address)?;
+    metrics.blocks_mined += hashes.len() as u64;
+
+    let height = miner.get_block_count()?;
+    let tip = miner.get_best_block_hash()?;
+    let balances = miner.get_balances()?;
+    info!("Height: {height}");
+    info!("Tip: {tip}");
+    info!(
+        "Miner spendable: {:.decimals$} BTC",
+        balances.mine.trusted.to_btc()
+    );
+    info!(
+        "Miner immature coinbase: {:.decimals$} BTC",
+        balances.mine.immature.to_btc()
+    );
+    Ok(())
+}
+
+/// `generator`: mines one block to a fresh Miner `--mining-label` address every `--interval-secs`, printing
+/// each new height, until Ctrl-C or `--max-blocks`. A regtest block clock for demos; nothing is sent.
+pub fn generator(
+    ctx: &Context,
+    options: &Options,
+    miner: &Client,
+    metrics: &mut Metrics,
+) -> Result<(), CapstoneError> {
+    let interval = options.interval.ok_or(CapstoneError::MissingFlag {
+        command: "generator",
+        flag: "--interval-secs <s>",
+    })?;
+    metrics.phase("generate");
+    let address = miner
+        .get_new_address(Some(&options.mining_label), None)?
+        .require_network(bitcoincore_rpc::bitcoin::Network::Regtest)?;
+    info!(
+        "Mining a block to {address} every {}s (Ctrl-C to stop)...",
+        interval.as_secs()
+    );
+
+    catch_ctrl_c(true);
+    let generated = generate_every(
+        interval,
+        options.max_blocks,
+        || INTERRUPTED.load(Ordering::SeqCst),
+        || {
+            mine_blocks(ctx, miner, 1, &address)?;
+            metrics.blocks_mined += 1;
+            info!("Height: {}", miner.get_block_count()?);
+            Ok(())
+        },
+    );
+    catch_ctrl_c(false);
+    info!("Generator stopped after {} block(s).", generated?);
+    Ok(())
+}
+
+/// One wallet as the `wallets` command shows it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct WalletSummary {
+    name: String,
+    loaded: bool, // Only loaded wallets can be asked for balances and addresses
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        with = "bitcoin::amount::serde::as_btc::opt"
+    )]
+    spendable: Option<Amount>,
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        with = "bitcoin::amount::serde::as_btc::opt"
+    )]
+    immature: Option<Amount>,
+    labels: BTreeMap<String, Vec<String>>, // Label → its addresses, both sorted; "" is the unlabelled group
+}
+
+impl std::fmt::Display for WalletSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let decimals = f.precision().unwrap_or(8); // `{summary:.2}` shows two decimals
+        if !self.loaded {
+            return write!(f, "{} (not loaded)", self.name);
+        }
+        writeln!(f, "{}", self.name)?;
+        write!(
+            f,
+            "  Balance: {:.decimals$} BTC spendable, {:.decimals$} BTC immature",
+            self.spendable.unwrap_or(Amount::ZERO).to_btc(),
+            self.immature.unwrap_or(Amount::ZERO).to_btc()
+        )?;
+        for (label, addresses) in &self.labels {
+            let label = if label.is_empty() {
+                "(no label)".to_string()
+            } else {
+                format!("\"{label}\"")
+            };
+            write!(f, "\n  {label}: {} address(es)", addresses.len())?;
+            for address in addresses {
+                write!(f, "\n    {address}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Describes every wallet the node knows, loaded or only on disk, with balances and labelled addresses.
+/// Nothing is created, loaded, mined or sent; `--format json` prints the same as JSON.
+pub fn wallets(options: &Options, rpc: &Client) -> Result<(), CapstoneError> {
+    let decimals = options.display_decimals;
+    let loaded = rpc.list_wallets()?;
+    let mut names: Vec<String> = rpc.list_wallet_dir()?;
+    names.extend(loaded.iter().cloned());
+    names.sort();
+    names.dedup();
+
+    let mut summaries = Vec::new();
+    for name in names {
+        if !loaded.contains(&name) {
+            summaries.push(WalletSummary {
+                name,
+                loaded: false,
+                spendable: None,
+                immature: None,
+                labels: BTreeMap::new(),
+            });
+            continue;
+        }
+        let wallet = options.rpc.connect_wallet(&name)?;
+        let balances = wallet.get_balances()?;
+        let mut labels = BTreeMap::new();
+        for label in wallet.call::<Vec<String>>("listlabels", &[])? {
+            let addresses = addresses_by_label(&wallet, &label)?;
+            labels.insert(label, addresses);
+        }
+        summaries.push(WalletSummary {
+            name,
+            loaded: true,
+            spendable: Some(balances.mine.trusted),
+            immature: Some(balances.mine.immature),
+            labels,
+        });
+    }
+
+    if options.formats.contains(&ReportFormat::Json) {
+        println!("{}", serde_json::to_string_pretty(&summaries)?);
+    } else {
+        for summary in &summaries {
+            println!("{summary:.decimals$}");
+        }
+    }
+    Ok(())
+}
+
+/// Prints the full decoded transaction (inputs, outputs, scripts, amounts) as indented JSON.
+/// Works for mempool and confirmed transactions alike; nothing is written to disk.
+pub fn decode(options: &Options, rpc: &Client) -> Result<(), CapstoneError> {
+    let txid = options.txid.ok_or(CapstoneError::MissingFlag {
+        command: "decode",
+        flag: "--txid <txid>",
+    })?;
+    let raw = rpc.get_raw_transaction_info(&txid, None)?;
+    println!("{}", serde_json::to_string_pretty(&raw)?);
+    Ok(())
+}
+
+// Amount differences below this are float noise from parsing, not a real mismatch (half a satoshi)
+const DIFF_AMOUNT_TOLERANCE: f64 = 0.000_000_005;
+
+/// Compares two ten-line report files field by field and explains each difference.
+/// Fails (so the exit status is non-zero) when any field differs.
+pub fn diff(path_a: &Path, path_b: &Path) -> Result<(), CapstoneError> {
+    let read = |path: &Path| -> Result<Vec<String>, CapstoneError> {
+        let lines: Vec<String> = fs::read_to_string(path)
+            .map_err(|err| CapstoneError::UnreadableFile {
+                what: "report",
+                path: path.to_path_buf(),
+                message: err.to_string(),
+            })?
+            .lines()
+            .map(str::to_string)
+            .collect();
+        if lines.len() != OUTPUT_FIELDS.len() {
+            return Err(CapstoneError::ReportLineCount {
+                path: path.to_path_buf(),
+                found: lines.len(),
+                expected: OUTPUT_FIELDS.len(),
+            });
+        }
+        Ok(lines)
+    };
+    let differences = field_diffs(&read(path_a)?, &read(path_b)?);
+    if differences.is_empty() {
+        println!("All {} fields match", OUTPUT_FIELDS.len());
+        return Ok(());
+    }
+    for difference in &differences {
+        println!("{difference}");
+    }
+    Err(CapstoneError::FieldsDiffer {
+        differ: differences.len(),
+        total: OUTPUT_FIELDS.len(),
+    })
+}
+
+/// Describes every field where `a` and `b` disagree. Amounts are compared numerically within
+/// `DIFF_AMOUNT_TOLERANCE`, and a delta the size of either file's fee is called out as such.
+fn field_diffs(a: &[String], b: &[String]) -> Vec<String> {
+    let fee = |lines: &[String]| lines[7].parse::<f64>().ok().map(f64::abs);
+    let fees = [fee(a), fee(b)];
+    let mut differences = Vec::new();
+    for (i, name) in OUTPUT_FIELDS.iter().enumerate() {
+        let (left, right) = (&a[i], &b[i]);
+        let numeric = matches!(
+            *name,
+            "input_amount" | "trader_amount" | "change_amount" | "fee" | "block_height"
+        );
+        match (left.parse::<f64>(), right.parse::<f64>()) {
+            (Ok(x), Ok(y)) if numeric => {
+                let delta = y - x;
+                if delta.abs() <= DIFF_AMOUNT_TOLERANCE {
+                    continue;
+                }
+                let by_fee = *name != "fee"
+                    && fees
+                        .iter()
+                        .flatten()
+                        .any(|fee| (delta.abs() - fee).abs() <= DIFF_AMOUNT_TOLERANCE);
+                let delta = if *name == "block_height" {
+                    format!("{delta:+}")
+                } else {
+                    format!("{delta:+.8} BTC")
+                };
+                differences.push(format!(
+                    "{name}: {left} → {right} ({delta}{})",
+                    if by_fee { ", off by the fee" } else { "" }
+                ));
+            }
+            _ if left != right => differences.push(format!("{name}: {left} → {right}")),
+            _ => {}
+        }
+    }
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::MINING_LABEL;
+    use crate::test_support::*;
+
+    #[test]
+    fn field_diffs_label_fields_and_tolerate_float_noise() {
+        let report = sample_report();
+        let expected = report.lines().to_vec();
+        assert!(field_diffs(&expected, &expected).is_empty());
+
+        let mut actual = expected.clone();
+        actual[6] = "29.99998590000001".to_string(); // Same amount, different rendering
+        actual[4] = "19.9999859".to_string(); // Fee taken from the payment instead of the change
+        actual[9] = "11".repeat(32);
+        let differences = field_diffs(&expected, &actual);
+
+        assert_eq!(differences.len(), 2, "{differences:?}");
+        assert_eq!(
+            differences[0],
+            "trader_amount: 20.00000000 → 19.9999859 (-0.00001410 BTC, off by the fee)"
+        );
+        assert!(differences[1].starts_with("block_hash: "));
+    }
+
+    #[test]
+    fn wallet_summaries_group_addresses_by_label() {
+        let miner = WalletSummary {
+            name: "Miner".to_string(),
+            loaded: true,
+            spendable: Some(Amount::from_int_btc(50)),
+            immature: Some(Amount::from_sat(1_250_000_000)),
+            labels: BTreeMap::from([
+                (String::new(), vec![CHANGE.to_string()]),
+                (MINING_LABEL.to_string(), vec![MINER_INPUT.to_string()]),
+            ]),
+        };
+        assert_eq!(
+            miner.to_string(),
+            format!(
+                "Miner\n  Balance: 50.00000000 BTC spendable, 12.50000000 BTC immature\n  \
+                 (no label): 1 address(es)\n    {CHANGE}\n  \"Mining Reward\": 1 address(es)\n    {MINER_INPUT}"
+            )
+        );
+
+        let trader = WalletSummary {
+            name: "Trader".to_string(),
+            loaded: false,
+            spendable: None,
+            immature: None,
+            labels: BTreeMap::new(),
+        };
+        assert_eq!(trader.to_string(), "Trader (not loaded)");
+        let json = serde_json::to_value([&miner, &trader]).unwrap();
+        assert_eq!(json[0]["spendable"], 50.0);
+        assert_eq!(json[0]["labels"]["Mining Reward"][0], MINER_INPUT);
+        assert!(json[1].get("spendable").is_none());
+    }
+}
@@ -0,0 +1,283 @@
+//! Settings that don't come from flags: the `capstone.toml` config file, applied before the flags, and the
+//! environment variables applied after them.
+
+use crate::options::Options;
+use crate::rpc::Passphrase;
+use crate::transfer::SendAmount;
+use crate::CapstoneError;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+// Read from the working directory when present, unless --config names another file
+pub const DEFAULT_CONFIG: &str = "capstone.toml";
+
+/// The config file. Every setting is optional; unknown ones are errors, so a typo can't be silently ignored.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub rpc: RpcConfig,
+    pub wallets: WalletsConfig,
+    pub route: RouteConfig,
+    pub send: SendConfig,
+    pub labels: LabelsConfig,
+}
+
+/// `[rpc]`: the node's RPC endpoint and credentials.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RpcConfig {
+    pub url: Option<String>,
+    pub user: Option<String>,
+    pub pass: Option<Passphrase>,
+}
+
+/// `[wallets]`: the names of the Miner and Trader, and the wallets set up beside them.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WalletsConfig {
+    pub miner: Option<String>,
+    pub trader: Option<String>,
+    pub extra: Option<Vec<String>>, // e.g. ["Exchange", "ColdStorage"]
+}
+
+/// `[route]`: the wallets `route` pays through, in order.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RouteConfig {
+    pub path: Option<Vec<String>>,
+}
+
+/// `[send]`: the payment `run` makes and where its report goes.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SendConfig {
+    pub amount: Option<SendAmount>, // BTC as a number, or a "50%" share of the balance
+    pub out: Option<PathBuf>,
+    pub comment: Option<String>,
+}
+
+/// `[labels]`: the Miner's reward and the Trader's receiving address labels.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LabelsConfig {
+    pub mining: Option<String>,
+    pub received: Option<String>,
+}
+
+impl Config {
+    /// Parses the `text` of the config file at `path`, which only names it in the error.
+    pub fn parse(path: &Path, text: &str) -> Result<Config, CapstoneError> {
+        toml::from_str(text).map_err(|err| CapstoneError::Config {
+            path: path.to_path_buf(),
+            message: toml_message(text, &err),
+        })
+    }
+
+    /// Applies the settings the file gives to `options`, leaving the others as they are.
+    pub fn apply(self, options: &mut Options) {
+        let Config {
+            rpc,
+            wallets,
+            route,
+            send,
+            labels,
+        } = self;
+        set(&mut options.rpc.url, rpc.url);
+        set(&mut options.rpc.user, rpc.user);
+        set(&mut options.rpc.pass, rpc.pass);
+        set(&mut options.miner_wallet, wallets.miner);
+        set(&mut options.trader_wallet, wallets.trader);
+        set(&mut options.extra_wallets, wallets.extra);
+        set(&mut options.route, route.path);
+        set(&mut options.amount, send.amount);
+        set(&mut options.out_path, send.out);
+        set(&mut options.comment, send.comment);
+        set(&mut options.mining_label, labels.mining);
+        set(&mut options.received_label, labels.received);
+    }
+}
+
+// A TOML parse error of `text` on one line: the line it was found on, then what's wrong
+pub(crate) fn toml_message(text: &str, err: &toml::de::Error) -> String {
+    match err.span() {
+        Some(span) => format!(
+            "line {}: {}",
+            text[..span.start].matches('\n').count() + 1,
+            err.message()
+        ),
+        None => err.message().to_string(),
+    }
+}
+
+fn set<T>(option: &mut T, setting: Option<T>) {
+    if let Some(value) = setting {
+        *option = value;
+    }
+}
+
+/// Applies the `BITCOIN_RPC_*` and `CAPSTONE_OUT_PATH` environment variables, as read by `var`.
+pub fn apply_env(options: &mut Options, var: impl Fn(&str) -> Option<String>) {
+    let var = |name| var(name).filter(|value: &String| !value.is_empty());
+    set(&mut options.rpc.url, var("BITCOIN_RPC_URL"));
+    set(&mut options.rpc.user, var("BITCOIN_RPC_USER"));
+    set(
+        &mut options.rpc.pass,
+        var("BITCOIN_RPC_PASS").map(Passphrase),
+    );
+    set(
+        &mut options.out_path,
+        var("CAPSTONE_OUT_PATH").map(PathBuf::from),
+    );
+    set(&mut options.bitcoind, var("BITCOIND"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::DEFAULT_OUT_PATH;
+    use crate::rpc::{RpcSettings, RPC_PASS};
+    use bitcoin::Amount;
+    use std::collections::HashMap;
+
+    // Applies config `text` to `options` as if the default config file held it
+    fn apply_config(options: &mut Options, text: &str) -> Result<(), CapstoneError> {
+        Config::parse(Path::new(DEFAULT_CONFIG), text).map(|config| config.apply(options))
+    }
+
+    #[test]
+    fn config_file_sets_what_it_names() {
+        let mut options = Options::default();
+        let config = r#"
+            # Another regtest node
+            [rpc]
+            url = "http://10.0.0.2:18443"  # its RPC port
+            user = "bob"
+            pass = "secret"
+
+            [wallets]
+            miner = "Alice"
+            trader = "Bob"
+
+            [send]
+            amount = 5
+            out = "reports/out.txt"
+        "#;
+        apply_config(&mut options, config).unwrap();
+        assert_eq!(options.rpc.url, "http://10.0.0.2:18443");
+        assert_eq!(options.rpc.user, "bob");
+        assert_eq!(options.rpc.pass, Passphrase("secret".to_string()));
+        assert!(!format!("{options:?}").contains("secret"));
+        assert_eq!(
+            (
+                options.miner_wallet.as_str(),
+                options.trader_wallet.as_str()
+            ),
+            ("Alice", "Bob")
+        );
+        assert_eq!(
+            options.amount,
+            SendAmount::Absolute(Amount::from_int_btc(5))
+        );
+        assert_eq!(options.out_path, PathBuf::from("reports/out.txt"));
+        assert_eq!(options.comment, Options::default().comment); // Not in the file, so left alone
+
+        let percent = "[send]\namount = \"50%\"";
+        apply_config(&mut options, percent).unwrap();
+        assert_eq!(options.amount, SendAmount::Percent(50.0));
+        assert_eq!(options.rpc.user, "bob");
+    }
+
+    #[test]
+    fn config_errors_name_the_file_and_line() {
+        for bad in [
+            "[rpc]\nport = 18443",
+            "[rpc]\nurl = http://localhost",
+            "[rpc]\nurl = \"a\"\nurl = \"b\"",
+            "[rpc]\nuser",
+            "[send]\namount = -1",
+            "[send]\namount = \"96%\"",
+            "[wallets]\nextra = \"Exchange, ColdStorage\"",
+            "[wallet]\nminer = \"Alice\"",
+        ] {
+            assert!(
+                apply_config(&mut Options::default(), bad).is_err(),
+                "accepted {bad:?}"
+            );
+        }
+        let err =
+            apply_config(&mut Options::default(), "[rpc]\nurl = \"a\"\n\nport = 2").unwrap_err();
+        assert!(
+            matches!(&err, CapstoneError::Config { path, message }
+                if path == Path::new(DEFAULT_CONFIG) && message.starts_with("line 4: unknown field `port`")),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn config_declares_extra_wallets_and_the_route_through_them() {
+        let mut options = Options::default();
+        let config = r#"
+            [wallets]
+            extra = ["Exchange", "ColdStorage"]
+
+            [route]
+            path = ["Miner", "Exchange", "ColdStorage"]
+        "#;
+        apply_config(&mut options, config).unwrap();
+        assert_eq!(options.extra_wallets, ["Exchange", "ColdStorage"]);
+        assert_eq!(options.route, ["Miner", "Exchange", "ColdStorage"]);
+    }
+
+    #[test]
+    fn amount_comment_and_labels_come_from_config() {
+        let mut options = Options::default();
+        let config = r#"
+            [send]
+            amount = 12.5
+            comment = "Invoice 42"
+
+            [labels]
+            mining = "Coinbase"
+            received = "Incoming"
+        "#;
+        apply_config(&mut options, config).unwrap();
+        assert_eq!(
+            options.amount,
+            SendAmount::Absolute(Amount::from_btc(12.5).unwrap())
+        );
+        assert_eq!(options.comment, "Invoice 42");
+        assert_eq!(options.mining_label, "Coinbase");
+        assert_eq!(options.received_label, "Incoming");
+    }
+
+    #[test]
+    fn environment_overrides_the_settings_it_names() {
+        let mut options = Options {
+            out_path: PathBuf::from("flag.txt"),
+            ..Options::default()
+        };
+        apply_config(&mut options, "[rpc]\nuser = \"bob\"").unwrap();
+        let env = HashMap::from([
+            ("BITCOIN_RPC_URL", "http://bitcoind:18443"),
+            ("BITCOIN_RPC_USER", "ci"),
+            ("BITCOIN_RPC_PASS", ""),
+            ("CAPSTONE_OUT_PATH", "/artifacts/out.txt"),
+            ("BITCOIND", "/opt/bitcoin/bin/bitcoind"),
+        ]);
+        apply_env(&mut options, |name| {
+            env.get(name).map(|value| value.to_string())
+        });
+        assert_eq!(options.rpc.url, "http://bitcoind:18443");
+        assert_eq!(options.rpc.user, "ci");
+        // Empty means unset, so the default password stays
+        assert_eq!(options.rpc.pass, Passphrase(RPC_PASS.to_string()));
+        assert_eq!(options.out_path, PathBuf::from("/artifacts/out.txt"));
+        assert_eq!(options.bitcoind, "/opt/bitcoin/bin/bitcoind");
+
+        let mut untouched = Options::default();
+        apply_env(&mut untouched, |_| None);
+        assert_eq!(untouched.rpc, RpcSettings::default());
+        assert_eq!(untouched.out_path, PathBuf::from(DEFAULT_OUT_PATH));
+        assert_eq!(untouched.bitcoind, Options::default().bitcoind);
+    }
+}
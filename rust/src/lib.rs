@@ -0,0 +1,944 @@
+//! The capstone's building blocks, shared by the `rust` binary and usable from integration tests:
+//! [`rpc`] to reach the node, [`wallet`] and [`mining`] to set up funds, [`transfer`] to pay the Trader,
+//! and [`report`] to extract and write what the payment did (see [`report::extract_tx_details`]).
+//! [`node`] can start a private regtest node for runs that shouldn't need one set up beforehand.
+//!
+//! Progress is logged through `tracing`, each phase of a run in a `stage` span; [`init_logging`] installs the
+//! subscriber the binary prints it with.
+
+use crate::{mining::BlockClock, report::*, rpc::*, transfer::*};
+use bitcoincore_rpc::bitcoin::{Amount, BlockHash, Network, OutPoint, Txid};
+use serde_json::json;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use tracing::level_filters::LevelFilter;
+use tracing::span::EnteredSpan;
+use tracing::{warn, Level, Subscriber};
+use tracing_subscriber::fmt::format::{self, FormatEvent, FormatFields};
+use tracing_subscriber::fmt::writer::MakeWriterExt;
+use tracing_subscriber::fmt::{FmtContext, MakeWriter};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Failures detected by the capstone logic itself, plus RPC and IO errors tagged with the step that hit them
+/// (see [`CapstoneError::in_step`]).
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum CapstoneError {
+    /// The traced inputs add up to less than the outputs, so the input total must be wrong.
+    #[error(
+        "Outputs ({:.8} BTC) exceed inputs ({:.8} BTC); the input total was extracted incorrectly",
+        .outputs.to_btc(),
+        .inputs.to_btc()
+    )]
+    FeeUnderflow { inputs: Amount, outputs: Amount },
+    /// Summing amounts exceeded the representable range (named by which sum overflowed).
+    #[error("Amount overflow while computing the {0}")]
+    AmountOverflow(&'static str),
+    /// Our own weight-based vsize disagrees with the node's by more than rounding allows.
+    #[error(
+        "Decoded transaction is {computed} vB but the node reports {reported} vB; decoding is off"
+    )]
+    VsizeMismatch { computed: u64, reported: u64 },
+    /// The named wallet is encrypted and locked, and no --wallet-passphrase was given.
+    #[error("The {0} wallet is locked; pass --wallet-passphrase to unlock it for the send")]
+    WalletLocked(&'static str),
+    /// The wallet paid more than `--max-fee` for the send; usually a fee estimation or config problem.
+    #[error(
+        "Fee {:.8} BTC exceeds --max-fee {:.8} BTC; not confirming it (check fallbackfee/paytxfee, or pass --allow-high-fee)",
+        .fee.to_btc(),
+        .max.to_btc()
+    )]
+    FeeTooHigh { fee: Amount, max: Amount },
+    /// `--wait-for-funds` gave up after `waited` with the Miner balance still too low for the send.
+    #[error(
+        "No sufficient funds after waiting {}s (Miner balance {:.8} BTC); did the faucet payment go out?",
+        .waited.as_secs(),
+        .balance.to_btc()
+    )]
+    FundsTimeout { waited: Duration, balance: Amount },
+    /// A report sink couldn't write its file (the IO or serialization error, as text).
+    #[error("Could not write the report to {}: {message}", .path.display())]
+    ReportWrite { path: PathBuf, message: String },
+    /// The Trader's outputs don't add up to what the send should have paid them (the amount, less the fee
+    /// under `--subtract-fee-from-recipient`).
+    #[error(
+        "Trader should have received {:.8} BTC but got {:.8} BTC; does --subtract-fee-from-recipient match how the fee was paid, or was the wrong output picked?",
+        .expected.to_btc(),
+        .received.to_btc()
+    )]
+    TraderAmountMismatch { expected: Amount, received: Amount },
+    /// `gettxoutsetinfo` disagrees with the block subsidy owed to a chain of this height (`--verify-supply`).
+    #[error(
+        "The UTXO set holds {:.8} BTC but {height} blocks of subsidy come to {:.8} BTC; were blocks miscounted?",
+        .actual.to_btc(),
+        .expected.to_btc()
+    )]
+    SupplyMismatch {
+        height: u64,
+        expected: Amount,
+        actual: Amount,
+    },
+    /// The node couldn't be reached at all during `step` (refused connection, timeout, bad URL or credentials).
+    #[error("Could not reach the node during {step} ({message}); is bitcoind running, and do the RPC URL and credentials match?")]
+    NodeUnreachable { step: &'static str, message: String },
+    /// The node answered a call made during `step` with an error; `code` is the RPC error code when it sent one.
+    #[error("{}", rpc_failure(.step, *.code, .message))]
+    Rpc {
+        step: &'static str,
+        code: Option<i32>,
+        message: String,
+    },
+    /// A wallet `step` needed isn't loaded on the node (RPC_WALLET_NOT_FOUND, -18).
+    #[error("A wallet needed during {step} is not loaded: {message}")]
+    WalletNotFound { step: &'static str, message: String },
+    /// The maturity loop gave up after `blocks_mined` blocks with the Miner's coinbase still immature.
+    #[error("Failed to achieve spendable balance after mining maximum blocks ({blocks_mined}); the coinbase is still immature")]
+    ImmatureCoinbase { blocks_mined: u64 },
+    /// The transaction isn't in a block on the active chain, so there is nothing to report on yet.
+    #[error("Transaction {0} is not in a block")]
+    MissingBlock(Txid),
+    /// A file `step` needed couldn't be read or written.
+    #[error("IO error during {step}: {message}")]
+    Io { step: &'static str, message: String },
+    /// `--amount` asks for more than the Miner can spend; the fee comes on top unless the recipient pays it.
+    #[error(
+        "--amount {:.8} BTC{} is more than the Miner's {:.8} BTC spendable; lower --amount (or use a percentage like 50%)",
+        .requested.to_btc(),
+        if *.fee_on_top { " plus the fee" } else { "" },
+        .spendable.to_btc()
+    )]
+    InsufficientFunds {
+        requested: Amount,
+        spendable: Amount,
+        fee_on_top: bool,
+    },
+    /// The node kept catching up with its headers for longer than the connect timeout.
+    #[error("Node still not ready after {}s ({progress})", .waited.as_secs())]
+    NodeNotReady { waited: Duration, progress: String },
+    /// The node runs some other chain than regtest.
+    #[error("Connected to a {0} node; start bitcoind with -regtest")]
+    NotRegtest(Network),
+    /// The RPC URL can't be used: not a URL, or one that already names a wallet.
+    #[error("RPC URL `{url}` {message}")]
+    InvalidRpcUrl { url: String, message: String },
+    /// The node predates the `createwallet` RPC (its `getnetworkinfo` version).
+    #[error(
+        "Bitcoin Core {} has no createwallet RPC; use 0.17 or newer",
+        rpc::core_version(*.version)
+    )]
+    CoreTooOld { version: usize },
+    /// `createwallet` failed for `wallet` on Bitcoin Core `core`.
+    #[error("createwallet {wallet} failed on Bitcoin Core {core}: {message}")]
+    CreateWallet {
+        wallet: String,
+        core: String,
+        message: String,
+    },
+    /// A wallet name of the set is empty.
+    #[error("Wallet names can't be empty")]
+    EmptyWalletName,
+    /// The same wallet is declared twice in the set.
+    #[error("Wallet {0} is declared twice")]
+    DuplicateWallet(String),
+    /// Every address the wallet handed out in `attempts` tries had already received coins.
+    #[error("No unused receiving address after {attempts} attempts")]
+    NoUnusedAddress { attempts: usize },
+    /// The Miner's stored comment for the payment isn't the one it was sent with.
+    #[error("Miner wallet has comment {stored:?} for the payment, expected \"{expected}\"; was it sent from another wallet?")]
+    CommentMismatch {
+        stored: Option<String>,
+        expected: String,
+    },
+    /// `scantxoutset` found no unspent output of `txid` paying `amount` to `address`.
+    #[error(
+        "No unspent output of {txid} pays {} BTC to {address}; the Trader does not hold the funds",
+        .amount.to_btc()
+    )]
+    TraderUtxoMissing {
+        txid: Txid,
+        amount: Amount,
+        address: String,
+    },
+    /// The payment stayed out of every block for `extra_blocks` more blocks.
+    #[error("Payment still unconfirmed after {extra_blocks} extra blocks")]
+    StillUnconfirmed { extra_blocks: u64 },
+    /// Confirmation took `distance` blocks, more than `--assert-confirmed-in` allows.
+    #[error("Transaction took {distance} blocks to confirm, more than the asserted {limit}")]
+    ConfirmedTooLate { distance: u64, limit: u64 },
+    /// The confirming block of `txid` kept changing under reorgs.
+    #[error("Could not pin down a stable confirming block for {0}")]
+    UnstableBlock(Txid),
+    /// The transaction has no inputs to trace.
+    #[error("Transaction {0} has no inputs")]
+    NoInputs(Txid),
+    /// An input spends an output its previous transaction doesn't have.
+    #[error("Invalid input reference {0}: its transaction has no such output")]
+    InvalidInputReference(OutPoint),
+    /// Bytes from the node (`what`) didn't decode.
+    #[error("Could not decode the {what}: {message}")]
+    Decode { what: &'static str, message: String },
+    /// An address (from the node, a flag or a file) didn't parse.
+    #[error("Could not decode the address: {0}")]
+    InvalidAddress(#[from] bitcoin::address::ParseError),
+    /// The raw transaction doesn't decode back to `txid` and re-encode to the same bytes.
+    #[error("Raw transaction hex does not round-trip to {0}")]
+    RawTxMismatch(Txid),
+    /// The traced inputs don't equal the Trader output, change and fee together (beyond dust).
+    #[error(
+        "Accounting mismatch: input {:.8} BTC but trader {:.8} + change {:.8} + fee {:.8} BTC (off by {} sat)",
+        .input.to_btc(),
+        .trader.to_btc(),
+        .change.to_btc(),
+        .fee.to_btc(),
+        sat_apart(*.input, *.trader + *.change + *.fee)
+    )]
+    AccountingMismatch {
+        input: Amount,
+        trader: Amount,
+        change: Amount,
+        fee: Amount,
+    },
+    /// A sweep left `change` behind at `address`.
+    #[error(
+        "Sweep left {:.8} BTC of change at {address:?}; sendall should pay everything to the Trader",
+        .change.to_btc()
+    )]
+    SweepLeftChange { change: Amount, address: String },
+    /// Splitting `amount` `count` ways leaves shares of `share` below dust.
+    #[error(
+        "Splitting {:.8} BTC {count} ways gives {} sat shares, below the {} sat dust limit",
+        .amount.to_btc(),
+        .share.to_sat(),
+        DUST_TOLERANCE.to_sat()
+    )]
+    DustShares {
+        amount: Amount,
+        count: usize,
+        share: Amount,
+    },
+    /// A `--spend-utxo` isn't an unspent output of the Miner wallet.
+    #[error("--spend-utxo {0} is not an unspent output of the Miner wallet")]
+    UnknownUtxo(OutPoint),
+    /// A `--spend-utxo` is the Miner's but not spendable by it.
+    #[error("--spend-utxo {0} is not spendable by the Miner wallet")]
+    UnspendableUtxo(OutPoint),
+    /// `program` (bitcoind or docker) couldn't be run at all.
+    #[error("{}", spawn_failure(.program, .message))]
+    NodeSpawn { program: String, message: String },
+    /// The spawned bitcoind exited before it was up.
+    #[error("{program} exited during startup ({status})")]
+    NodeExited { program: String, status: String },
+    /// The spawned bitcoind wrote no RPC cookie in `waited`.
+    #[error("{program} wrote no RPC cookie within {}s", .waited.as_secs())]
+    NoCookie { program: String, waited: Duration },
+    /// The RPC cookie file isn't `user:password`.
+    #[error("Malformed RPC cookie file")]
+    MalformedCookie,
+    /// `docker run` of `image` failed.
+    #[error("docker run {image} failed ({message})")]
+    DockerRun { image: String, message: String },
+    /// `value` isn't one of the `expected` spellings of a `what` (log level, report format, units).
+    #[error("Unknown {what} `{value}` (expected {expected})")]
+    UnknownChoice {
+        what: &'static str,
+        value: String,
+        expected: &'static str,
+    },
+    /// `--amount` isn't a BTC amount or a percentage.
+    #[error("Invalid amount `{value}` ({message})")]
+    InvalidAmount { value: String, message: String },
+    /// `--amount` is a percentage outside what a payment can take.
+    #[error("--amount {0} is out of range; use more than 0% and at most {MAX_SEND_PERCENT}% to leave room for the fee")]
+    PercentOutOfRange(String),
+    /// `--amount` is zero.
+    #[error("--amount must be more than zero")]
+    ZeroAmount,
+    /// `--amount` is more than can ever exist.
+    #[error("--amount {0} is more than the 21 million BTC that can ever exist")]
+    AboveMaxMoney(String),
+    /// The command line itself is wrong: an unknown flag, or a flag's value missing or malformed.
+    #[error("{0}")]
+    Usage(String),
+    /// Flags (or settings) that parse but don't fit together, like `--fresh` with `--reuse-utxo`.
+    #[error("{0}")]
+    InvalidOptions(String),
+    /// The config file at `path` couldn't be read, or holds a setting that isn't one or has a bad value.
+    #[error("Invalid config {}: {message}", .path.display())]
+    Config { path: PathBuf, message: String },
+    /// `command` can't run without `flag`.
+    #[error("`{command}` needs {flag}")]
+    MissingFlag {
+        command: &'static str,
+        flag: &'static str,
+    },
+    /// The address given with `flag` is for another network.
+    #[error("{flag} {address:?} is not a regtest address")]
+    NotRegtestAddress { flag: &'static str, address: String },
+    /// A wallet the command pays through isn't among the wallets set up.
+    #[error("Wallet {0} is not set up")]
+    WalletNotSetUp(String),
+    /// The first wallet of `route` holds too little to start it, and isn't the Miner, so can't mine more.
+    #[error(
+        "{wallet} has only {:.8} BTC spendable, not enough to start the route",
+        .balance.to_btc()
+    )]
+    RouteUnfunded { wallet: String, balance: Amount },
+    /// A hop of `route` was still unconfirmed after the block mined for it.
+    #[error("Hop {from} → {to} ({txid}) did not confirm")]
+    HopUnconfirmed {
+        from: String,
+        to: String,
+        txid: Txid,
+    },
+    /// `send` mines nothing, and the Miner's balance doesn't cover the payment.
+    #[error(
+        "`send` mines nothing, and the Miner has only {:.8} BTC spendable; mine some first (e.g. `mine --count {blocks}`)",
+        .balance.to_btc()
+    )]
+    SendUnfunded { balance: Amount, blocks: u64 },
+    /// The `--spend-utxo` inputs don't cover the amount and a fee.
+    #[error(
+        "--spend-utxo inputs hold {:.8} BTC, not enough to send {:.8} BTC plus the fee",
+        .pinned.to_btc(),
+        .amount.to_btc()
+    )]
+    PinnedTooSmall { pinned: Amount, amount: Amount },
+    /// The Trader output has another script type than `--expect-trader-type` asked for.
+    #[error("Trader output is {found} but --expect-trader-type asked for {expected}; has the wallet's default address type changed?")]
+    UnexpectedTraderType { found: String, expected: String },
+    /// `report --txid` names a transaction the Trader wallet doesn't know.
+    #[error("{txid} is not a Trader transaction ({message}); pass --trader-address")]
+    NotTraderTransaction { txid: Txid, message: String },
+    /// `report --txid` names a transaction that pays nothing to the Trader wallet.
+    #[error("{0} pays nothing to the Trader wallet; pass --trader-address")]
+    NothingPaidToTrader(Txid),
+    /// An input file (`what` names it, like `--input-file`) couldn't be read.
+    #[error("Could not read {what} {}: {message}", .path.display())]
+    UnreadableFile {
+        what: &'static str,
+        path: PathBuf,
+        message: String,
+    },
+    /// An input file was read but its contents are wrong.
+    #[error("Invalid {what} {}: {message}", .path.display())]
+    InvalidFile {
+        what: &'static str,
+        path: PathBuf,
+        message: String,
+    },
+    /// JSON couldn't be built or parsed.
+    #[error("JSON error: {0}")]
+    Json(String),
+    /// The block that confirmed the payment has no transactions at all.
+    #[error("Confirming block {0} has no coinbase")]
+    NoCoinbase(BlockHash),
+    /// `bump` was given a transaction that is already in a block.
+    #[error("Transaction {0} is already confirmed; nothing to bump")]
+    AlreadyConfirmed(Txid),
+    /// `bump` was given a transaction that doesn't signal BIP125 replaceability.
+    #[error("Transaction {0} does not signal opt-in RBF (BIP125)")]
+    NotReplaceable(Txid),
+    /// The `bumpfee` replacement never showed up in the mempool.
+    #[error("Replacement {0} never appeared in the mempool")]
+    ReplacementMissing(Txid),
+    /// A file `diff` compares doesn't have one line per report field.
+    #[error("{} has {found} lines, expected {expected}", .path.display())]
+    ReportLineCount {
+        path: PathBuf,
+        found: usize,
+        expected: usize,
+    },
+    /// `diff` found `differ` of the `total` report fields changed.
+    #[error("{differ} of {total} fields differ")]
+    FieldsDiffer { differ: usize, total: usize },
+    /// `selftest` saw `failed` of its `total` fixtures extract the wrong values.
+    #[error("{failed} of {total} self-test fixtures failed")]
+    SelfTestFailed { failed: usize, total: usize },
+}
+
+/// The step an RPC or IO error converted with `?` carries until [`CapstoneError::in_step`]
+/// names the one it escaped from.
+pub const ANY_STEP: &str = "the run";
+
+impl From<bitcoincore_rpc::Error> for CapstoneError {
+    fn from(err: bitcoincore_rpc::Error) -> Self {
+        CapstoneError::from_rpc(ANY_STEP, &err)
+    }
+}
+
+impl From<std::io::Error> for CapstoneError {
+    fn from(err: std::io::Error) -> Self {
+        CapstoneError::Io {
+            step: ANY_STEP,
+            message: err.to_string(),
+        }
+    }
+}
+
+impl From<bitcoin::consensus::encode::Error> for CapstoneError {
+    fn from(err: bitcoin::consensus::encode::Error) -> Self {
+        CapstoneError::Decode {
+            what: "transaction",
+            message: err.to_string(),
+        }
+    }
+}
+
+impl From<serde_json::Error> for CapstoneError {
+    fn from(err: serde_json::Error) -> Self {
+        CapstoneError::Json(err.to_string())
+    }
+}
+
+/// The node's RPC_WALLET_NOT_FOUND error code.
+const RPC_WALLET_NOT_FOUND: i32 = -18;
+
+impl CapstoneError {
+    /// Classifies an RPC failure during `step`: a transport failure means the node is unreachable, which
+    /// is worth telling apart from the node answering with an error.
+    pub fn from_rpc(step: &'static str, err: &bitcoincore_rpc::Error) -> Self {
+        use bitcoincore_rpc::jsonrpc;
+        match err {
+            bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Transport(transport)) => {
+                CapstoneError::NodeUnreachable {
+                    step,
+                    message: transport.to_string(),
+                }
+            }
+            bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(rpc_err))
+                if rpc_err.code == RPC_WALLET_NOT_FOUND =>
+            {
+                CapstoneError::WalletNotFound {
+                    step,
+                    message: rpc_err.message.clone(),
+                }
+            }
+            bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(rpc_err)) => CapstoneError::Rpc {
+                step,
+                code: Some(rpc_err.code),
+                message: rpc_err.message.clone(),
+            },
+            other => CapstoneError::Rpc {
+                step,
+                code: None,
+                message: other.to_string(),
+            },
+        }
+    }
+
+    /// Names `step` as where an RPC or IO error happened, unless an earlier `in_step` already did.
+    pub fn in_step(self, step: &'static str) -> Self {
+        match self {
+            CapstoneError::NodeUnreachable {
+                step: ANY_STEP,
+                message,
+            } => CapstoneError::NodeUnreachable { step, message },
+            CapstoneError::Rpc {
+                step: ANY_STEP,
+                code,
+                message,
+            } => CapstoneError::Rpc {
+                step,
+                code,
+                message,
+            },
+            CapstoneError::WalletNotFound {
+                step: ANY_STEP,
+                message,
+            } => CapstoneError::WalletNotFound { step, message },
+            CapstoneError::Io {
+                step: ANY_STEP,
+                message,
+            } => CapstoneError::Io { step, message },
+            other => other,
+        }
+    }
+}
+
+// The message of a `CapstoneError::Rpc`: rejected with an error code, or failed without one
+fn rpc_failure(step: &str, code: Option<i32>, message: &str) -> String {
+    match code {
+        Some(code) => {
+            format!("The node rejected a call during {step}: {message} (RPC error {code})")
+        }
+        None => format!("RPC call failed during {step}: {message}"),
+    }
+}
+
+// The message of a `CapstoneError::NodeSpawn`, whose fix depends on which program is missing
+fn spawn_failure(program: &str, message: &str) -> String {
+    if program == "docker" {
+        format!("Could not run docker ({message}); is Docker installed and on PATH?")
+    } else {
+        format!("Could not start {program} ({message}); install Bitcoin Core or set BITCOIND to its path")
+    }
+}
+
+// How far apart two amounts are, in satoshis
+fn sat_apart(a: Amount, b: Amount) -> u64 {
+    if a > b { a - b } else { b - a }.to_sat()
+}
+
+/// How much of the progress output `--log-level` lets through; each level includes the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl std::str::FromStr for LogLevel {
+    type Err = CapstoneError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            other => Err(CapstoneError::UnknownChoice {
+                what: "log level",
+                value: other.to_string(),
+                expected: "error, warn, info or debug",
+            }),
+        }
+    }
+}
+
+impl LogLevel {
+    pub fn name(self) -> &'static str {
+        match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        }
+    }
+
+    pub fn filter(self) -> LevelFilter {
+        match self {
+            LogLevel::Error => LevelFilter::ERROR,
+            LogLevel::Warn => LevelFilter::WARN,
+            LogLevel::Info => LevelFilter::INFO,
+            LogLevel::Debug => LevelFilter::DEBUG,
+        }
+    }
+}
+
+/// Installs the process's `tracing` subscriber, unless one already is: events at `level` and above, on
+/// stdout, or stderr under --stdout-only to keep stdout clean for graders; errors always go to stderr.
+pub fn init_logging(level: LogLevel, json: bool, stdout_only: bool) {
+    let subscriber = if stdout_only {
+        log_subscriber(level, json, std::io::stderr)
+    } else {
+        log_subscriber(
+            level,
+            json,
+            std::io::stderr
+                .with_max_level(Level::ERROR)
+                .or_else(std::io::stdout),
+        )
+    };
+    let _ = tracing::subscriber::set_global_default(subscriber);
+}
+
+/// A subscriber writing events at `level` and above to `writer`: the bare message, or with `json` a
+/// `{"timestamp", "level", "message", "target", "span"}` object per line, `span` naming the stage.
+pub fn log_subscriber<W>(
+    level: LogLevel,
+    json: bool,
+    writer: W,
+) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level.filter())
+        .with_ansi(false)
+        .with_writer(writer);
+    if json {
+        Box::new(
+            builder
+                .json()
+                .flatten_event(true)
+                .with_current_span(true)
+                .with_span_list(false)
+                .finish(),
+        )
+    } else {
+        Box::new(builder.event_format(Message).finish())
+    }
+}
+
+// Text log lines are the message alone, as the progress output always read
+struct Message;
+
+impl<S, N> FormatEvent<S, N> for Message
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: format::Writer<'_>,
+        event: &tracing::Event<'_>,
+    ) -> std::fmt::Result {
+        ctx.format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+/// What a run was told about the chain and where it records events, handed to every step that records
+/// events or mines: `--event-log`, `--no-rpc-batch` and the mocked block clock. Shared by reference with
+/// the `overlap` worker, hence the lock. Progress output goes through `tracing` instead (see [`init_logging`]).
+#[derive(Debug)]
+pub struct Context {
+    pub rpc_batch: bool, // Cleared by --no-rpc-batch: input tracing then looks previous transactions up one by one
+    pub block_clock: BlockClock,
+    pub event_log: Mutex<Option<File>>, // One JSON object per significant action is appended here
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Context {
+            rpc_batch: true,
+            block_clock: BlockClock::default(),
+            event_log: Mutex::new(None),
+        }
+    }
+}
+
+impl Context {
+    /// Appends `event` with its `fields` and a timestamp to the --event-log file, if one was given.
+    /// Every line is flushed straight away so the timeline survives a crash mid-run. A failed write
+    /// only warns: the event log is an audit aid and must not take the run down with it.
+    pub fn log_event(&self, event: &str, fields: serde_json::Value) {
+        let mut log = self
+            .event_log
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let Some(file) = log.as_mut() else {
+            return;
+        };
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let line = event_line(event, fields, now);
+        if let Err(err) = writeln!(file, "{line}").and_then(|()| file.flush()) {
+            warn!("⚠️ Could not write to the event log: {err}");
+        }
+    }
+}
+
+pub mod cli;
+pub mod commands;
+pub mod config;
+pub mod mining;
+pub mod node;
+pub mod options;
+pub mod pipeline;
+pub mod report;
+pub mod rpc;
+pub mod selftest;
+pub mod transfer;
+pub mod wallet;
+
+#[cfg(test)]
+mod test_support;
+
+// One event-log line: `{"time": .., "time_iso": .., "event": .., ..fields}`
+pub fn event_line(event: &str, fields: serde_json::Value, unix: u64) -> String {
+    let mut line = json!({ "time": unix, "time_iso": iso8601(unix), "event": event });
+    if let (Some(line), serde_json::Value::Object(fields)) = (line.as_object_mut(), fields) {
+        line.extend(fields);
+    }
+    line.to_string()
+}
+
+/// Where the time went and how much work was done, printed as a table at the end of the run
+/// when built with the `metrics` feature.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub phases: Vec<(&'static str, Duration)>, // Finished phases, in order
+    pub current: Option<(&'static str, Instant)>,
+    pub blocks_mined: u64,
+    pub fee: Option<Amount>,
+    stage: Option<EnteredSpan>, // The running phase's span, exited when it ends
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            phases: Vec::new(),
+            current: None,
+            blocks_mined: 0,
+            fee: None,
+            stage: None,
+        }
+    }
+
+    /// Closes the running phase (if any) and starts timing `name`, entering a `stage` span for it so the
+    /// log events until the next phase carry its name.
+    pub fn phase(&mut self, name: &'static str) {
+        self.end_phase();
+        self.current = Some((name, Instant::now()));
+        self.stage = Some(tracing::info_span!("stage", stage = name).entered());
+    }
+
+    /// The phase running now, which is the step an error escaping at this point came from.
+    pub fn current_phase(&self) -> Option<&'static str> {
+        self.current.map(|(name, _)| name)
+    }
+
+    pub fn end_phase(&mut self) {
+        self.stage = None;
+        if let Some((name, started)) = self.current.take() {
+            self.phases.push((name, started.elapsed()));
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    pub fn print_summary(&self) {
+        use tracing::info;
+        info!("\n{:<12} {:>12}", "Phase", "Duration");
+        for (name, duration) in &self.phases {
+            info!("{:<12} {:>10.3} s", name, duration.as_secs_f64());
+        }
+        info!(
+            "{:<12} {:>12}",
+            "RPC calls",
+            RPC_CALLS.load(std::sync::atomic::Ordering::Relaxed)
+        );
+        info!("{:<12} {:>12}", "Blocks mined", self.blocks_mined);
+        if let Some(fee) = self.fee {
+            info!("{:<12} {:>12.8} BTC", "Fee", fee.to_btc());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use bitcoin::hashes::Hash;
+    use bitcoincore_rpc::jsonrpc;
+    use std::sync::Arc;
+
+    #[test]
+    fn event_lines_carry_time_and_fields() {
+        let line = event_line("block_mined", json!({ "count": 101 }), 1_700_000_000);
+        let event: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(event["event"], "block_mined");
+        assert_eq!(event["count"], 101);
+        assert_eq!(event["time"], 1_700_000_000);
+        assert_eq!(event["time_iso"], "2023-11-14T22:13:20Z");
+        assert!(!line.contains('\n'), "one event per line");
+    }
+
+    // A log writer the test reads back afterwards
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn log_lines(level: LogLevel, json: bool, run: impl FnOnce()) -> Vec<String> {
+        let buffer = Arc::new(Mutex::new(Vec::new()));
+        let writer = {
+            let buffer = buffer.clone();
+            move || Captured(buffer.clone())
+        };
+        tracing::subscriber::with_default(log_subscriber(level, json, writer), run);
+        let text = String::from_utf8(buffer.lock().unwrap().clone()).unwrap();
+        text.lines().map(str::to_string).collect()
+    }
+
+    // Answers getblockcount with 101 and anything else with null
+    struct Answering;
+
+    impl jsonrpc::Transport for Answering {
+        fn send_request(
+            &self,
+            request: jsonrpc::Request,
+        ) -> Result<jsonrpc::Response, jsonrpc::Error> {
+            let result = (request.method == "getblockcount").then(|| jsonrpc::arg(101));
+            Ok(jsonrpc::Response {
+                result: Some(result.unwrap_or_else(|| jsonrpc::arg(()))),
+                error: None,
+                id: request.id.clone(),
+                jsonrpc: Some("2.0".to_string()),
+            })
+        }
+
+        fn send_batch(
+            &self,
+            requests: &[jsonrpc::Request],
+        ) -> Result<Vec<jsonrpc::Response>, jsonrpc::Error> {
+            requests
+                .iter()
+                .map(|request| self.send_request(request.clone()))
+                .collect()
+        }
+
+        fn fmt_target(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "answering mock node")
+        }
+    }
+
+    #[test]
+    fn rpc_trace_is_debug_events_with_the_method_and_time() {
+        use bitcoincore_rpc::RpcApi;
+        let client = bitcoincore_rpc::Client::from_jsonrpc(jsonrpc::Client::with_transport(
+            TracingTransport(Answering),
+        ));
+        let calls = || {
+            assert_eq!(client.get_block_count().unwrap(), 101);
+            client
+                .call::<()>("walletpassphrase", &[json!("hunter2"), json!(60)])
+                .unwrap();
+        };
+
+        // Hidden at the default level, like any other debug output
+        assert!(log_lines(LogLevel::Info, true, calls).is_empty());
+        let events: Vec<serde_json::Value> = log_lines(LogLevel::Debug, true, calls)
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(events.len(), 4);
+        assert!(events.iter().all(|event| event["level"] == "DEBUG"));
+        assert_eq!(events[0]["method"], "getblockcount");
+        assert!(events[0]["message"]
+            .as_str()
+            .unwrap()
+            .starts_with("→ getblockcount"));
+        assert_eq!(events[1]["method"], "getblockcount");
+        assert!(events[1]["elapsed_ms"].is_u64());
+        assert!(events[1]["message"].as_str().unwrap().contains("101"));
+        assert_eq!(events[2]["message"], "→ walletpassphrase <redacted>");
+        assert!(!events
+            .iter()
+            .any(|event| event.to_string().contains("hunter2")));
+    }
+
+    #[test]
+    fn metrics_record_phases_in_order_and_tag_their_log_lines() {
+        let mut metrics = Metrics::new();
+        let lines = log_lines(LogLevel::Info, true, || {
+            metrics.phase("mine");
+            tracing::info!("Mined 101 blocks");
+            metrics.phase("send");
+            tracing::debug!("Not shown at the info level");
+            tracing::warn!("⚠️ Fee above 0.001 BTC");
+            metrics.end_phase();
+            metrics.end_phase(); // Nothing running any more, so this records nothing
+            tracing::info!("Done");
+        });
+
+        let names: Vec<_> = metrics.phases.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, ["mine", "send"]);
+        let events: Vec<serde_json::Value> = lines
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let tagged: Vec<_> = events
+            .iter()
+            .map(|event| (&event["level"], &event["message"], &event["span"]["stage"]))
+            .collect();
+        assert_eq!(
+            tagged,
+            [
+                (&json!("INFO"), &json!("Mined 101 blocks"), &json!("mine")),
+                (
+                    &json!("WARN"),
+                    &json!("⚠️ Fee above 0.001 BTC"),
+                    &json!("send")
+                ),
+                (&json!("INFO"), &json!("Done"), &serde_json::Value::Null),
+            ]
+        );
+        assert!(events.iter().all(|event| event["timestamp"].is_string()));
+
+        // Text lines are the bare message, whatever the stage
+        let lines = log_lines(LogLevel::Warn, false, || {
+            Metrics::new().phase("mine");
+            tracing::info!("Mined 101 blocks");
+            tracing::error!("Error: the node went away");
+        });
+        assert_eq!(lines, ["Error: the node went away"]);
+    }
+
+    #[test]
+    fn errors_are_tagged_with_their_step_and_kind() {
+        let refused = bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Transport(
+            "Connection refused (os error 111)".into(),
+        ));
+        let no_wallet =
+            bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(jsonrpc::error::RpcError {
+                code: -18,
+                message: "Requested wallet does not exist or is not loaded".to_string(),
+                data: None,
+            }));
+        assert_eq!(
+            CapstoneError::from(refused).in_step("connect"),
+            CapstoneError::NodeUnreachable {
+                step: "connect",
+                message: "Connection refused (os error 111)".to_string(),
+            }
+        );
+        assert!(matches!(
+            CapstoneError::from(no_wallet).in_step("send"),
+            CapstoneError::WalletNotFound { step: "send", .. }
+        ));
+        assert_eq!(
+            CapstoneError::from(no_such_transaction()).in_step("confirm"),
+            CapstoneError::Rpc {
+                step: "confirm",
+                code: Some(-5),
+                message: "No such mempool or blockchain transaction".to_string(),
+            }
+        );
+        let missing = std::io::Error::new(std::io::ErrorKind::NotFound, "No such file");
+        assert!(matches!(
+            CapstoneError::from(missing).in_step("report"),
+            CapstoneError::Io { step: "report", .. }
+        ));
+        // Errors that carry no step pass through untouched
+        let txid = Txid::from_byte_array([7; 32]);
+        assert_eq!(
+            CapstoneError::MissingBlock(txid).in_step("report"),
+            CapstoneError::MissingBlock(txid)
+        );
+        // A library error converted with `?` gets the step of the first `in_step` it meets, and keeps it
+        let converted = CapstoneError::from(no_such_transaction());
+        assert_eq!(
+            converted.to_string(),
+            "The node rejected a call during the run: No such mempool or blockchain transaction (RPC error -5)"
+        );
+        let tagged = converted.in_step("confirm").in_step("report");
+        assert!(matches!(
+            tagged,
+            CapstoneError::Rpc {
+                step: "confirm",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn each_log_level_lets_through_the_ones_before_it() {
+        assert!(LogLevel::Error < LogLevel::Warn && LogLevel::Info < LogLevel::Debug);
+        assert_eq!(LogLevel::Warn.filter(), LevelFilter::WARN);
+        assert_eq!(LogLevel::Debug.filter(), LevelFilter::DEBUG);
+    }
+}
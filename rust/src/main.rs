@@ -2,308 +2,367 @@
 //!
 //! This Rust program connects to a Bitcoin Core regtest node, creates two wallets ('Miner' and 'Trader'), mines spendable coins,
 //! sends a transaction from Miner to Trader, confirms it by mining a block, extracts relevant transaction details, and writes
-//! the results to a file (`out.txt`) for test evaluation.
+//! the results to a file (`out.txt`) for test evaluation. The work itself is in the library; this binary parses the
+//! command line and dispatches to it.
 
-#![allow(unused)]
-use bitcoin::hex::DisplayHex;
-use bitcoincore_rpc::bitcoin::{Amount, BlockHash};
-use bitcoincore_rpc::{Auth, Client, RpcApi};
-use serde::Deserialize;
+use bitcoincore_rpc::RpcApi;
 use serde_json::json;
-use std::error::Error;
-use std::fmt::Debug;
-use std::fs::File;
-use std::io::{stdout, Write};
+use std::fs;
+use std::io::Write;
 use std::result::Result;
-use std::{thread, time::Duration};
-
-// Node access params; these are constants necessary for connecting to RPC core
-const RPC_URL: &str = "http://127.0.0.1:18443"; // Default regtest RPC port
-const RPC_USER: &str = "alice";
-const RPC_PASS: &str = "password";
-
-// Raw RPC call demo (not used in final code)
-fn send(rpc: &Client, addr: &str) -> bitcoincore_rpc::Result<String> {
-    let args = [
-        json!([{addr : 100 }]),
-        json!(null),
-        json!(null),
-        json!(null),
-        json!(null),
-    ];
-
-    #[derive(Deserialize)]
-    struct SendResult {
-        complete: bool,
-        txid: String,
+
+use rust::{
+    cli::*, commands::*, node::*, options::*, pipeline::*, report::*, rpc::*, selftest::selftest,
+    wallet::*, *,
+};
+use tracing::{debug, error, info};
+
+fn main() -> std::process::ExitCode {
+    // Replaced by the options' own once they are parsed, so only a parse error logs with the defaults
+    let mut ctx = Context::default();
+    let outcome = capstone(&mut ctx);
+    if let Err(err) = &outcome {
+        // Plain lines at the info level when the options never got to install their own logging
+        init_logging(LogLevel::Info, false, false);
+        error!("Error: {err}");
+        if matches!(err, CapstoneError::Usage(_)) {
+            eprintln!("{}", usage());
+        }
+    }
+    // Always the very last line on stderr, --quiet or not: the contract CI scripts grep for
+    write_exit_summary(&mut std::io::stderr(), &outcome);
+    if outcome.is_ok() {
+        std::process::ExitCode::SUCCESS
+    } else {
+        std::process::ExitCode::FAILURE
     }
-    let send_result = rpc.call::<SendResult>("send", &args)?;
-    assert!(send_result.complete);
-    Ok(send_result.txid)
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    // Connect to Bitcoin Core RPC; the bitcoincore_rpc crate, wraps the JSON-RPC API into Rust methods.
-    let rpc = Client::new(
-        RPC_URL,
-        Auth::UserPass(RPC_USER.to_owned(), RPC_PASS.to_owned()),
-    )?;
+/// One JSON object on a line of its own summing up how the process ended: `status` "ok" with the
+/// payment's txid, fee and confirming height (null for commands that don't send one), or `status`
+/// "error" with the message.
+fn write_exit_summary(out: &mut impl Write, outcome: &Result<Option<TxReport>, CapstoneError>) {
+    let summary = match outcome {
+        Ok(report) => json!({
+            "status": "ok",
+            "txid": report.as_ref().map(|r| r.txid),
+            "fee_btc": report.as_ref().map(|r| r.fee.to_btc()),
+            "block_height": report.as_ref().map(|r| r.block_height),
+        }),
+        Err(err) => json!({ "status": "error", "message": err.to_string() }),
+    };
+    // Nothing sensible is left to do if stderr itself is gone
+    let _ = writeln!(out, "{summary}");
+}
 
-    println!("\n Connected to Bitcoin Core RPC at {RPC_URL}");
+/// Everything `main` does, with `ctx` set up from the options; returns the payment's report when the
+/// command made one.
+fn capstone(ctx: &mut Context) -> Result<Option<TxReport>, CapstoneError> {
+    let mut options = parse_options(
+        std::env::args().skip(1),
+        |name| std::env::var(name).ok(),
+        |path| fs::read_to_string(path),
+    )?;
+    if options.command == Command::Help {
+        print!("{}", help());
+        return Ok(None);
+    }
+    // Before the event log is opened, so a stale one goes too
+    let removed = if options.clean {
+        clean_stale_artifacts(&options)?
+    } else {
+        Vec::new()
+    };
+    init_logging(options.log_level, options.log_json, options.stdout_only);
+    *ctx = options.context()?;
+    let ctx = &*ctx;
+    for path in removed {
+        info!("Removed stale {}", path.display());
+    }
+    // Validated before connecting, so a bad entry stops the batch before any payment goes out
+    let payments = options
+        .input_file
+        .as_deref()
+        .map(load_payments)
+        .transpose()?;
+    match options.command {
+        Command::Diff => return diff(&options.diff_files[0], &options.diff_files[1]).map(|_| None),
+        Command::SelfTest => return selftest(ctx).map(|_| None),
+        _ => {}
+    }
+    let mut metrics = Metrics::new();
+    metrics.phase("connect");
+    // Stopped and deleted when this goes out of scope, after the command, however it ended
+    let node = if options.spawn_node {
+        Some(SpawnedNode::start(
+            &options.bitcoind,
+            options.rpc.retry.timeout,
+        )?)
+    } else if options.docker {
+        Some(SpawnedNode::start_docker(&options.docker_image)?)
+    } else {
+        None
+    };
+    if let Some(node) = &node {
+        options.rpc = RpcSettings {
+            trace: options.rpc.trace,
+            retry: options.rpc.retry,
+            ..node.rpc.clone()
+        };
+    }
+    // Whatever escapes is tagged with the phase it escaped from, so "node unreachable" during connect
+    // reads differently from a failed call during send
+    let report = connect_and_dispatch(ctx, &options, payments.as_deref(), &mut metrics)
+        .map_err(|err| err.in_step(metrics.current_phase().unwrap_or("connect")))?;
+
+    metrics.end_phase();
+    #[cfg(feature = "metrics")]
+    if !options.quiet {
+        metrics.print_summary();
+    }
+    Ok(report)
+}
 
-    // Fetch and display blockchain info using get blockchain info
-    let blockchain_info = rpc.get_blockchain_info()?;
-    println!("Blockchain Info: {blockchain_info:?}");
+/// Connects to the node, sets up the wallets and runs the command, timing it in `metrics`.
+fn connect_and_dispatch(
+    ctx: &Context,
+    options: &Options,
+    payments: Option<&[BatchPayment]>,
+    metrics: &mut Metrics,
+) -> Result<Option<TxReport>, CapstoneError> {
+    // Connect to Bitcoin Core RPC; the bitcoincore_rpc crate, wraps the JSON-RPC API into Rust methods.
+    // A node still starting up (unreachable, warming up or syncing) is waited for rather than failing the run
+    let (rpc, blockchain_info, network_info) = connect_with_retry(&options.rpc)?;
+
+    info!("\n Connected to Bitcoin Core RPC at {}", options.rpc.url);
+
+    // Display the blockchain info fetched by get blockchain info
+    debug!("Blockchain Info: {blockchain_info:?}");
+    preflight(&rpc, &blockchain_info)?;
+    info!(
+        "Bitcoin Core {} ({})",
+        core_version(network_info.version),
+        network_info.subversion
+    );
 
-    // Ensure 'Miner' and 'Trader' wallets exist; this function is to ensure a wallet exists. If not, create it.
-    fn ensure_wallet_exists(rpc: &Client, wallet_name: &str) -> Result<(), Box<dyn Error>> {
-        let loaded_wallets = rpc.list_wallets()?;
-        if !loaded_wallets.contains(&wallet_name.to_string()) {
-            println!("Creating wallet: {wallet_name}");
-            rpc.create_wallet(wallet_name, None, None, None, None)?;
-        } else {
-            println!("Wallet already exists: {wallet_name}");
-        }
-        Ok(())
+    if options.command == Command::Wallets {
+        // Read-only: looked at before Miner and Trader get created or loaded
+        metrics.phase("wallets");
+        return wallets(options, &rpc).map(|_| None);
     }
 
-    ensure_wallet_exists(&rpc, "Miner")?;
-    ensure_wallet_exists(&rpc, "Trader")?;
+    // Blocks get mocked timestamps from here on; the node's real clock comes back on every exit path,
+    // errors included, so later runs aren't stuck in our mocked past
+    let _clock_reset = options
+        .block_time_start
+        .map(|_| mining::MockTimeReset(&rpc));
+
+    metrics.phase("wallet-setup");
+    let wallet_set = options.wallet_set()?;
+    wallet_set.ensure_all(ctx, &rpc)?;
 
     // Create wallet-specific clients (This function checks if a wallet is already loaded, and if not, creates it. )
     //Wallets in Bitcoin Core must be explicitly referenced in the RPC endpoint like `/wallet/Miner` because Bitcoin Core does not automatically create wallets.
     //You must manually create and load them by name.
 
-    let miner = Client::new(
-        &format!("{RPC_URL}/wallet/Miner"),
-        Auth::UserPass(RPC_USER.to_string(), RPC_PASS.to_string()),
-    )?;
-    let trader = Client::new(
-        &format!("{RPC_URL}/wallet/Trader"),
-        Auth::UserPass(RPC_USER.to_string(), RPC_PASS.to_string()),
-    )?;
-
-    println!("Wallets Miner and Trader are ready.");
-
-    // Generate spendable balance by mining until matured coinbase / positive coin balance.
-    let miner_address = miner
-        .get_new_address(Some("Mining Reward"), None)? // Changed to exact label "Mining Reward" as specified in the test specification
-        .require_network(bitcoincore_rpc::bitcoin::Network::Regtest)?;
-
-    println!("Miner address: {miner_address}");
+    let clients = wallet_set.connect(&options.rpc)?;
+    let miner = wallet_client(&clients, &options.miner_wallet).expect("the Miner is in the set");
+    let trader = wallet_client(&clients, &options.trader_wallet).expect("the Trader is in the set");
+    // The extra miners of --miners, each with its own wallet client; they follow the Miner in the set
+    let peers = &clients[1..options.miners];
 
-    // Mine blocks until coinbase reward is spendable (requires maturity of 100 blocks)
-    let mut blocks_mined = 0;
-    let max_blocks = 150; // Safety limit
+    info!("Wallets {} are ready.", wallet_set.names().join(", "));
 
-    // Mine 1 block to the miner's address
-    loop {
-        if blocks_mined >= max_blocks {
-            return Err("Failed to achieve spendable balance after mining maximum blocks".into());
+    // A hard error propagates straight out of here, so the wallets stay loaded for inspection in that case
+    let report = match options.command {
+        Command::Run | Command::Send if options.dry_run => {
+            metrics.phase("send");
+            dry_run(options, miner, trader).map(|_| None)?
         }
+        Command::Run | Command::Send => Some(match payments {
+            Some(payments) => {
+                run_batch(ctx, options, payments, &rpc, miner, peers, trader, metrics)?
+            }
+            None => run(ctx, options, miner, peers, trader, metrics)?,
+        }),
+        Command::Report => {
+            metrics.phase("report");
+            Some(report_txid(ctx, options, miner, trader)?)
+        }
+        Command::Watch => {
+            metrics.phase("watch");
+            watch(ctx, options, miner, trader).map(|_| None)?
+        }
+        Command::Bump => {
+            metrics.phase("bump");
+            bump(options, miner).map(|_| None)?
+        }
+        Command::Mine => mine(ctx, options, miner, metrics).map(|_| None)?,
+        Command::Decode => {
+            metrics.phase("decode");
+            decode(options, miner).map(|_| None)?
+        }
+        Command::Generator => generator(ctx, options, miner, metrics).map(|_| None)?,
+        Command::Route => route(ctx, options, &clients, metrics).map(|_| None)?,
+        Command::SendMany => {
+            metrics.phase("send");
+            Some(send_many_payment(ctx, options, miner, trader, metrics)?)
+        }
+        Command::Diff | Command::SelfTest | Command::Help => {
+            unreachable!("diff, selftest and help return before connecting")
+        }
+        Command::Wallets => unreachable!("wallets returns before the wallets are set up"),
+    };
 
-        miner.generate_to_address(1, &miner_address)?;
-        blocks_mined += 1;
-
-        // Check spendable balance
-        let balance = miner.get_balance(None, None)?;
-        println!("Block {} → Balance: {} BTC", blocks_mined, balance.to_btc());
-
-        if balance.to_btc() > 0.0 {
-            println!("Spendable balance achieved after {blocks_mined} blocks mined.");
-            break;
+    if options.unload_on_exit {
+        for wallet_name in wallet_set.names() {
+            rpc.unload_wallet(Some(wallet_name))?;
+            info!("Unloaded wallet: {wallet_name}");
         }
     }
+    Ok(report)
+}
 
-    /*When I ran the code, the wallet balance became positive only after mining 101 blocks.
-
-    Coinbase rewards (mining rewards) take 100 blocks to mature before they show in your wallet as spendable, protecting the network from fraud and block manipulation by miners.
-
-    Newly mined coins (aka coinbase rewards) can’t be spent until they get these 100 confirmations (i.e., 100 more blocks are added on top), because of this Bitcoin rule.
-    This rule exists to prevent cheating by miners. Without it, a miner could: Mine a block, spend the reward immediately, and then rewrite the chain (reorganize it) to keep the reward and erase the spend.
-
-    With a 100-block delay, Bitcoin makes it very hard to reverse that block or cheat.*/
-
-    // Generate Trader receiving address (this is the recipient of the 20 BTC transaction.) with exact label "Received" just as it was specified in test specification
-    let trader_address = trader
-        .get_new_address(Some("Received"), None)? //generates a fresh BTC address from Trader wallet with correct label
-        .require_network(bitcoincore_rpc::bitcoin::Network::Regtest)?;
-    println!("Trader receiving address: {trader_address}");
-
-    // Send 20 BTC from Miner wallet to Trader's receiving address (Defines 20.0 BTC using the Amount::from_btc() helper.)
-    let amount_to_send = Amount::from_btc(20.0)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use bitcoincore_rpc::bitcoin::{Amount, BlockHash, Txid};
+    use std::path::PathBuf;
 
-    // The send_to_address RPC sends the specified amount to the given address (Sends that amount from the Miner wallet to the Trader's address using `send_to_address`. This broadcasts the transaction but doesn't confirm it yet.)
-    let txid = miner.send_to_address(
-        &trader_address,
-        amount_to_send,
-        Some("Payment to Trader"),
-        None,
-        None,
-        None,
-        None,
-        None,
-    )?;
-    println!("You have Sent 20 BTC 🪙 to Trader. TxID: {txid}");
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
 
-    let tx_info = miner.get_transaction(&txid, None)?;
-    println!(
-        "Transaction confirmed in Miner's wallet: {}",
-        tx_info.info.txid
-    );
+    // Parses `list` with no environment and no config file, whatever the machine running the tests has
+    fn parse(list: &[&str]) -> Result<Options, CapstoneError> {
+        parse_options(
+            args(list),
+            |_| None,
+            |_| Err(std::io::ErrorKind::NotFound.into()),
+        )
+    }
 
-    // Check if TX is in mempool (`get_raw_mempool()` is used to confirm if a transaction is pending (i.e., unconfirmed). If it's listed, that means it is awaiting inclusion in a block.)
-    let mempool = miner.get_raw_mempool()?;
-    if mempool.contains(&txid) {
-        println!("Transaction is in the mempool.");
+    // What the summary needs of a confirmed payment's report: its txid, fee and height
+    fn report() -> TxReport {
+        TxReport {
+            txid: Txid::all_zeros(),
+            input_address: String::new(),
+            input_amount: Amount::from_int_btc(50),
+            trader_address: String::new(),
+            trader_amount: Amount::from_int_btc(20),
+            change_address: String::new(),
+            change_amount: Amount::from_sat(2_999_998_590),
+            fee: Amount::from_sat(1_410),
+            block_height: 102,
+            block_hash: BlockHash::all_zeros(),
+            trader_script_type: "p2wpkh",
+            block_time: 1_700_000_000,
+            block_time_iso: iso8601(1_700_000_000),
+            confirmations: 1,
+            inputs_pruned: false,
+            rbf_signaled: true,
+            locktime: 0,
+            mempool: None,
+            input_scripts: None,
+            outputs: None,
+        }
+    }
 
-        // Fetch the unconfirmed transaction from the node's mempool as requested in instructions (using getmempoolentry)
-        let mempool_entry = miner.get_mempool_entry(&txid)?;
-        println!("Mempool entry details: {mempool_entry:?}");
-    } else {
-        println!("⚠️ Transaction not found in mempool.");
+    #[test]
+    fn watch_subcommand_is_parsed() {
+        let options = parse(&["watch", "--auto-confirm"]).unwrap();
+        assert_eq!(options.command, Command::Watch);
+        assert!(options.auto_confirm);
+        assert_eq!(parse(&[]).unwrap().command, Command::Run);
+        assert_eq!(parse(&["wallets"]).unwrap().command, Command::Wallets);
+        assert_eq!(parse(&["selftest"]).unwrap().command, Command::SelfTest);
     }
 
-    // Animation for better user experience as transaaction processed
-    fn play_celebration_animation() {
-        let spinner = [
-            "🌕", "🌖", "😮", "🌗", "🌘", "🤭", "🌑", "🌒", "🥰", "🌓", "😆", "😅", "😂", "🤣",
-            "🌔", "🤑",
-        ];
-        let delay = Duration::from_millis(150); // Give time for transaction to be processed
-        let mut stdout = stdout();
-
-        print!("Celebrating success ");
-        for i in 0..spinner.len() * 3 {
-            print!("\rCelebrating success {}", spinner[i % spinner.len()]);
-            print!("\x07"); // Plays bell sound
-            stdout.flush().unwrap();
-            thread::sleep(delay); //makes delay variable functional
+    #[test]
+    fn help_is_a_command_not_an_error() {
+        for flag in ["-h", "--help"] {
+            let options = parse(&["mine", flag, "--no-such-flag"]).unwrap();
+            assert_eq!(options.command, Command::Help);
         }
+    }
 
-        println!("\r Your Transaction is confirmed and saved successfully! 🙂, Now you can go 🙄");
+    #[test]
+    fn send_and_report_run_single_stages() {
+        let options = parse(&["send", "--amount", "5", "--clean"]).unwrap();
+        assert_eq!(options.command, Command::Send);
+        assert!(options.command.sends() && Command::Run.sends());
+        assert!(!Command::Report.sends());
+
+        assert!(parse(&["report"]).is_err());
+        let txid = "87992cbfe278b2d9e71623efc4333e0566f1c8dda3bd09b7059a0a460d7991bd";
+        let options = parse(&["report", "--txid", txid, "--format", "txt,json"]).unwrap();
+        assert_eq!(options.command, Command::Report);
+        assert_eq!(options.txid, Some(txid.parse().unwrap()));
+        assert!(parse(&["report", "--txid", txid, "--clean"]).is_err());
+        assert!(parse(&["mine", "--count", "1", "--clean"]).is_err());
     }
 
-    // Mine 1 block to confirm the transaction
-    let _ = miner.generate_to_address(1, &miner_address)?;
-    println!("1 block has been mined to confirm your transaction");
+    #[test]
+    fn bump_requires_a_txid() {
+        assert!(parse(&["bump"]).is_err());
+
+        let txid = "87992cbfe278b2d9e71623efc4333e0566f1c8dda3bd09b7059a0a460d7991bd";
+        let options = parse(&["bump", "--txid", txid]).unwrap();
+        assert_eq!(options.command, Command::Bump);
+        assert_eq!(options.txid, Some(txid.parse().unwrap()));
+    }
 
-    // Extract transaction details
-    let raw = miner.get_raw_transaction_info(&txid, None)?;
-    let decoded_tx = &raw.transaction()?; // Access transaction directly, not call .transaction()
+    #[test]
+    fn decode_requires_a_txid() {
+        assert!(parse(&["decode"]).is_err());
 
-    // Trace miner's tx input address using the vin source
-    if decoded_tx.input.is_empty() {
-        return Err("Transaction has no inputs".into());
+        let txid = "87992cbfe278b2d9e71623efc4333e0566f1c8dda3bd09b7059a0a460d7991bd";
+        let options = parse(&["decode", "--txid", txid]).unwrap();
+        assert_eq!(options.command, Command::Decode);
     }
 
-    let input_txid = decoded_tx.input[0].previous_output.txid;
-    let input_vout = decoded_tx.input[0].previous_output.vout;
-    let prev_tx = miner.get_raw_transaction_info(&input_txid, None)?;
+    #[test]
+    fn diff_takes_exactly_two_files() {
+        let options = parse(&["diff", "a.txt", "b.txt"]).unwrap();
+        assert_eq!(options.command, Command::Diff);
+        assert_eq!(
+            options.diff_files,
+            [PathBuf::from("a.txt"), PathBuf::from("b.txt")]
+        );
+        assert!(parse(&["diff", "a.txt"]).is_err());
+        assert!(parse(&["a.txt"]).is_err()); // Positionals only mean something to diff
+    }
 
-    if prev_tx.vout.len() <= input_vout as usize {
-        Err("Invalid input reference".into())
-    } else {
-        let prev_output = &prev_tx.vout[input_vout as usize];
-
-        // let miner_input_address = format!("{:?}", prev_output.script_pub_key);
-        let script = prev_output.script_pub_key.script()?; // Unwrap script safely
-        let miner_input_address = bitcoin::Address::from_script(&script, bitcoin::Network::Regtest)
-            .map(|addr| addr.to_string())
-            .unwrap_or_else(|_| "Unknown".to_string());
-
-        //Using amount as Amount type
-        let miner_input_amount = prev_output.value;
-
-        // Identify Trader output and Miner change output
-        let mut trader_output_address = String::new();
-        let mut trader_output_amount = Amount::ZERO; // Fix 3: Use Amount::ZERO
-        let mut miner_change_address = String::new();
-        let mut miner_change_amount = Amount::ZERO; // Fix 4: Use Amount::ZERO
-
-        // Match address to identify which is Trader and which is change back to Miner
-        for output in decoded_tx.output.iter() {
-            let value = output.value;
-
-            let address =
-                bitcoin::Address::from_script(&output.script_pubkey, bitcoin::Network::Regtest)
-                    .map(|a| a.to_string())
-                    .unwrap_or_default();
-
-            if address == trader_address.to_string() {
-                trader_output_address = address;
-                trader_output_amount = value; // Fix 6: Keep as Amount
-            } else {
-                miner_change_address = address;
-                miner_change_amount = value; // Fix 7: Keep as Amount
-            }
-        }
-        // Extract other required fields
-        // Fee calculation with Amount types
-        let total_output: Amount = decoded_tx.output.iter().map(|out| out.value).sum();
-        let fee = miner_input_amount
-            .checked_sub(total_output)
-            .unwrap_or(Amount::ZERO);
-
-        // Get block info from raw transaction result
-        let tx_block_hash = raw.blockhash.ok_or("Transaction not in a block")?;
-        let block_info = miner.get_block_info(&tx_block_hash)?;
-        let block_height = block_info.height;
-        let block_hash = tx_block_hash.to_string();
-
-        // Print all details to terminal for verification
-        println!("\nTransaction Details:");
-        println!("Transaction ID: {txid}");
-        println!("Miner Input Address: {miner_input_address}");
-        println!("Miner Input Amount: {:.8} BTC", miner_input_amount.to_btc()); // values are formatted to 8 decimal places using `{:.8}` for Bitcoin precision.
-        println!("Trader Output Address: {trader_output_address}");
-        println!(
-            "Trader Output Amount: {:.8} BTC",
-            trader_output_amount.to_btc()
+    #[test]
+    fn exit_summary_is_the_last_stderr_line() {
+        let report = report();
+        let last_line = |outcome| {
+            let mut stderr = b"Mining block 1 of 101\n".to_vec();
+            write_exit_summary(&mut stderr, &outcome);
+            let stderr = String::from_utf8(stderr).unwrap();
+            serde_json::from_str::<serde_json::Value>(stderr.lines().last().unwrap()).unwrap()
+        };
+
+        assert_eq!(
+            last_line(Ok(Some(report.clone()))),
+            json!({
+                "status": "ok",
+                "txid": report.txid.to_string(),
+                "fee_btc": 0.0000141,
+                "block_height": report.block_height,
+            })
+        );
+        assert_eq!(
+            last_line(Ok(None)),
+            json!({ "status": "ok", "txid": null, "fee_btc": null, "block_height": null })
         );
-        println!("Miner Change Address: {miner_change_address}");
-        println!(
-            "Miner Change Amount: {:.8} BTC",
-            miner_change_amount.to_btc()
+        assert_eq!(
+            last_line(Err(CapstoneError::WalletNotSetUp("Miner".to_string()))),
+            json!({ "status": "error", "message": "Wallet Miner is not set up" })
         );
-        println!("Fee: {:.8} BTC", fee.to_btc());
-        println!("Block Height: {block_height}");
-        println!("Block Hash: {block_hash}");
-
-        // Carefullly write all 10 required transaction details to the main directory ../out.txt
-        let mut file = File::create("../out.txt")?;
-        writeln!(file, "{txid}")?;
-        writeln!(file, "{miner_input_address}")?;
-        writeln!(file, "{}", miner_input_amount.to_btc())?; // Use .to_btc() for proper decimal formatting
-        writeln!(file, "{trader_output_address}")?;
-        writeln!(file, "{}", trader_output_amount.to_btc())?;
-        writeln!(file, "{miner_change_address}")?;
-        writeln!(file, "{}", miner_change_amount.to_btc())?;
-        writeln!(file, "{}", fee.to_btc())?;
-        writeln!(file, "{block_height}")?;
-        writeln!(file, "{block_hash}")?;
-
-        //I placed a file flush here so that data will be written immediately
-        file.flush()?;
-
-        println!("\n All required values written to out.txt for test evaluation"); // Updated message to reflect correct file location
-        play_celebration_animation();
-        Ok(())
-
-        /*
-        Each line maps directly to the required fields in the test file:
-           1. Transaction ID
-           2. Input address (ASM)
-           3. Input amount
-           4. Trader's address
-           5. Trader's amount
-           6. Miner's change address
-           7. Miner's change amount
-           8. Fee (BTC)
-           9. Block height
-          10. Block hash
-
-        This completes the pipeline from wallet → transaction → confirmation → file output.
-        */
+        // Only the usage errors main prints the usage for; the summary never carries it
+        let err = parse(&["--fresh", "--reuse-utxo"]).unwrap_err();
+        assert!(!last_line(Err(err)).to_string().contains("Usage:"));
     }
 }
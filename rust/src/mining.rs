@@ -0,0 +1,693 @@
+//! Mining on regtest: blocks to an address (with retries and mocked timestamps), the maturity and
+//! inclusion loops, and the subsidy schedule behind `--verify-supply`.
+
+use crate::*;
+use bitcoincore_rpc::bitcoin::{Address, Amount, BlockHash};
+use bitcoincore_rpc::{Client, RpcApi};
+use serde_json::json;
+use std::result::Result;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use std::{thread, time::Duration};
+use tracing::{info, warn};
+
+/// Mocked clock for --block-time-start/--block-time-step: the timestamp for the next mined block (0 = the
+/// node's own clock) and how far each block advances it. Atomic because mining also happens on the `overlap`
+/// worker thread.
+#[derive(Debug, Default)]
+pub struct BlockClock {
+    next: AtomicU64,
+    step: u64,
+}
+
+impl BlockClock {
+    pub fn new(start: u64, step: u64) -> Self {
+        BlockClock {
+            next: AtomicU64::new(start),
+            step,
+        }
+    }
+
+    pub fn is_mocked(&self) -> bool {
+        self.next.load(Ordering::SeqCst) != 0
+    }
+}
+
+/// Hands the node its real clock back (`setmocktime 0`) when dropped, so a run that mocked block
+/// timestamps leaves the clock running whether it succeeds, fails or unwinds.
+pub struct MockTimeReset<'a>(pub &'a Client);
+
+impl Drop for MockTimeReset<'_> {
+    fn drop(&mut self) {
+        if let Err(err) = self.0.call::<()>("setmocktime", &[json!(0)]) {
+            warn!(
+                "⚠️ Could not reset the node's mocked clock (run `setmocktime 0` by hand): {err}"
+            );
+        }
+    }
+}
+
+// Seconds between mocked block timestamps when only --block-time-start is given (the mainnet block target)
+pub const DEFAULT_BLOCK_TIME_STEP: u64 = 600;
+
+// How often a failed `generatetoaddress` is retried, and the pause before each retry
+pub const MINE_RETRIES: usize = 3;
+pub const MINE_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+// Blocks a coinbase output must wait before it can be spent; the mining loop expects to need one more than this
+pub const COINBASE_MATURITY: u64 = 100;
+
+// Regtest halves the block subsidy every 150 blocks, starting from 50 BTC
+pub const REGTEST_HALVING_INTERVAL: u64 = 150;
+pub const INITIAL_SUBSIDY: Amount = Amount::from_int_btc(50);
+
+// Extra blocks the mining loop may spend beyond maturity + 1 before giving up (150 in total by default)
+pub const MATURITY_SLACK: u64 = 49;
+
+// Without a terminal for the progress bar, the mining loop logs a line every this many blocks
+pub const MINING_LOG_EVERY: u64 = 25;
+
+/// Mines `count` blocks to `address`, retrying transient `generatetoaddress` failures, on the block clock of `ctx`.
+pub fn mine_blocks(
+    ctx: &Context,
+    rpc: &Client,
+    count: u64,
+    address: &Address,
+) -> bitcoincore_rpc::Result<Vec<BlockHash>> {
+    let clock = &ctx.block_clock;
+    let hashes = mine_with_retries(
+        count,
+        || rpc.get_block_count(),
+        |height| rpc.get_block_hash(height),
+        |remaining| {
+            let time = clock.next.load(Ordering::SeqCst);
+            if time == 0 {
+                return rpc.generate_to_address(remaining, address);
+            }
+            // One block per call so every block gets its own mocked timestamp; the clock only advances on success
+            rpc.call::<()>("setmocktime", &[json!(time)])?;
+            let hashes = rpc.generate_to_address(1, address)?;
+            clock.next.store(time + clock.step, Ordering::SeqCst);
+            Ok(hashes)
+        },
+    )?;
+    ctx.log_event(
+        "block_mined",
+        json!({ "count": hashes.len(), "address": address.to_string(), "last": hashes.last() }),
+    );
+    Ok(hashes)
+}
+
+/// Retry loop behind `mine_blocks`: a failed `generate` is retried up to `MINE_RETRIES` times.
+/// The chain height is compared against where we started before every attempt, so blocks that a
+/// "failed" call did manage to produce are counted and never mined twice. Their hashes never came back,
+/// so when fewer than `count` did, the list is rebuilt from `hash_at` for the `count` heights after the
+/// start (on a shared node, some of those may be another process's blocks).
+pub fn mine_with_retries(
+    count: u64,
+    height: impl Fn() -> bitcoincore_rpc::Result<u64>,
+    hash_at: impl Fn(u64) -> bitcoincore_rpc::Result<BlockHash>,
+    generate: impl Fn(u64) -> bitcoincore_rpc::Result<Vec<BlockHash>>,
+) -> bitcoincore_rpc::Result<Vec<BlockHash>> {
+    let start = height()?;
+    let mut hashes = Vec::new();
+    let mut retries = 0;
+    loop {
+        let produced = height()?.saturating_sub(start);
+        if produced >= count {
+            if (hashes.len() as u64) < count {
+                hashes = (start + 1..=start + count)
+                    .map(&hash_at)
+                    .collect::<bitcoincore_rpc::Result<_>>()?;
+            }
+            return Ok(hashes);
+        }
+        match generate(count - produced) {
+            Ok(new_hashes) => hashes.extend(new_hashes),
+            Err(err) if retries < MINE_RETRIES => {
+                retries += 1;
+                warn!("⚠️ Mining failed ({err}); retry {retries}/{MINE_RETRIES}");
+                thread::sleep(MINE_RETRY_DELAY);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// The maturity loop of `run`: calls `mine_next` (with the number of blocks mined so far) until `balance`
+/// reports a positive spendable balance, and returns how many blocks that took. The balance is only read every
+/// `check_every` blocks, and always on reaching `target_blocks`, the first height it can possibly be positive
+/// at, so a coarse interval never costs extra blocks in the usual case. Fails after `max_blocks`.
+pub fn mine_until_spendable(
+    target_blocks: u64,
+    max_blocks: u64,
+    check_every: u64,
+    mut mine_next: impl FnMut(u64) -> Result<(), CapstoneError>,
+    mut balance: impl FnMut(u64) -> Result<Amount, CapstoneError>,
+) -> Result<u64, CapstoneError> {
+    let mut blocks_mined = 0;
+    loop {
+        if blocks_mined >= max_blocks {
+            return Err(CapstoneError::ImmatureCoinbase { blocks_mined });
+        }
+
+        mine_next(blocks_mined)?;
+        blocks_mined += 1;
+
+        // Check spendable balance
+        let due = blocks_mined.is_multiple_of(check_every) || blocks_mined == target_blocks;
+        if due && balance(blocks_mined)? > Amount::ZERO {
+            return Ok(blocks_mined);
+        }
+    }
+}
+
+// Which of `miners` mines the block after `blocks_mined` others; the Miner (index 0) takes the first block
+pub fn round_robin(blocks_mined: u64, miners: usize) -> usize {
+    (blocks_mined % miners as u64) as usize
+}
+
+// Which of `miners` is paid for the block after `blocks_mined` others, or None for the burn address: under
+// --maturity-to-burn the Miner keeps the first block and every block after it only serves to mature that one
+pub fn maturity_recipient(blocks_mined: u64, miners: usize, burn: bool) -> Option<usize> {
+    if burn && blocks_mined > 0 {
+        return None;
+    }
+    Some(round_robin(blocks_mined, miners))
+}
+
+// Provably unspendable: a P2WSH whose witness script is a bare OP_RETURN, so no witness can ever satisfy it.
+// Block rewards mined here under --maturity-to-burn can't be spent, yet stay in the UTXO set like any output.
+pub fn burn_address() -> Address {
+    let script = bitcoin::blockdata::script::Builder::new()
+        .push_opcode(bitcoin::opcodes::all::OP_RETURN)
+        .into_script();
+    Address::p2wsh(&script, bitcoin::Network::Regtest)
+}
+
+/// Shows how far the maturity-mining loop has come: a progress bar when stdout is a terminal
+/// (with the `progress` feature), otherwise a log line every `MINING_LOG_EVERY` blocks. Progress
+/// filtered out by the log level (`--quiet`) gets neither.
+pub enum MiningProgress {
+    #[cfg(feature = "progress")]
+    Bar(indicatif::ProgressBar),
+    Log,
+}
+
+impl MiningProgress {
+    #[cfg_attr(not(feature = "progress"), allow(unused_variables))]
+    pub fn new(target_blocks: u64) -> Self {
+        #[cfg(feature = "progress")]
+        if std::io::IsTerminal::is_terminal(&std::io::stdout())
+            && tracing::enabled!(tracing::Level::INFO)
+        {
+            let bar = indicatif::ProgressBar::new(target_blocks);
+            bar.set_style(
+                indicatif::ProgressStyle::with_template(
+                    "Mining {bar:40} {pos}/{len} blocks  {msg}",
+                )
+                .expect("progress template is valid"),
+            );
+            return MiningProgress::Bar(bar);
+        }
+        MiningProgress::Log
+    }
+
+    pub fn update(&self, blocks_mined: u64, balance: Amount) {
+        match self {
+            #[cfg(feature = "progress")]
+            MiningProgress::Bar(bar) => {
+                bar.set_position(blocks_mined);
+                bar.set_message(format!("balance {} BTC", balance.to_btc()));
+            }
+            MiningProgress::Log => {
+                if blocks_mined.is_multiple_of(MINING_LOG_EVERY) {
+                    info!("Block {} → Balance: {} BTC", blocks_mined, balance.to_btc());
+                }
+            }
+        }
+    }
+
+    pub fn finish(&self) {
+        #[cfg(feature = "progress")]
+        if let MiningProgress::Bar(bar) = self {
+            bar.finish();
+        }
+    }
+}
+
+/// Shows that a wait of unknown length is still going: a spinner with the elapsed time and the latest step
+/// when stdout is a terminal (with the `progress` feature), otherwise each step as a log line.
+pub enum WaitSpinner {
+    #[cfg(feature = "progress")]
+    Spinner(indicatif::ProgressBar),
+    Log,
+}
+
+impl WaitSpinner {
+    pub fn new(message: &str) -> Self {
+        #[cfg(feature = "progress")]
+        if std::io::IsTerminal::is_terminal(&std::io::stdout())
+            && tracing::enabled!(tracing::Level::INFO)
+        {
+            let spinner = indicatif::ProgressBar::new_spinner().with_style(
+                indicatif::ProgressStyle::with_template("{spinner} {msg} [{elapsed}]")
+                    .expect("spinner template is valid"),
+            );
+            spinner.set_message(message.to_string());
+            // Keeps turning while the RPC calls of the wait block this thread
+            spinner.enable_steady_tick(SPINNER_TICK);
+            return WaitSpinner::Spinner(spinner);
+        }
+        info!("{message}");
+        WaitSpinner::Log
+    }
+
+    /// Reports progress within the wait; replaces the spinner's message rather than printing past it.
+    pub fn step(&self, message: &str) {
+        match self {
+            #[cfg(feature = "progress")]
+            WaitSpinner::Spinner(spinner) => spinner.set_message(message.to_string()),
+            WaitSpinner::Log => info!("{message}"),
+        }
+    }
+
+    pub fn finish(&self, message: &str) {
+        match self {
+            #[cfg(feature = "progress")]
+            WaitSpinner::Spinner(spinner) => spinner.finish_with_message(message.to_string()),
+            WaitSpinner::Log => info!("{message}"),
+        }
+    }
+}
+
+// How often a `WaitSpinner` redraws
+#[cfg(feature = "progress")]
+const SPINNER_TICK: Duration = Duration::from_millis(120);
+
+// How often the generator's pause between blocks looks at the Ctrl-C flag
+pub const GENERATOR_STOP_POLL: Duration = Duration::from_millis(100);
+
+/// The `generator` loop: `mine_next`, then wait `interval`, until `stopped` (checked during the wait too, so
+/// Ctrl-C doesn't sit out a long interval) or `max_blocks` blocks. Returns how many blocks were mined.
+pub fn generate_every(
+    interval: Duration,
+    max_blocks: Option<u64>,
+    stopped: impl Fn() -> bool,
+    mut mine_next: impl FnMut() -> Result<(), CapstoneError>,
+) -> Result<u64, CapstoneError> {
+    let mut generated = 0;
+    while !stopped() && max_blocks.is_none_or(|max| generated < max) {
+        mine_next()?;
+        generated += 1;
+        if max_blocks == Some(generated) {
+            break;
+        }
+        let next = Instant::now() + interval;
+        while !stopped() {
+            let remaining = next.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            thread::sleep(remaining.min(GENERATOR_STOP_POLL));
+        }
+    }
+    Ok(generated)
+}
+
+/// `--verify-supply`: the UTXO set must hold exactly the subsidy of every block mined so far. Fees only move
+/// coins between outputs and burned rewards sit unspendable in the set, so on a fresh regtest chain any
+/// difference means the block count is off (or a coinbase claimed less than it could).
+pub fn verify_supply(node: &Client) -> Result<(), CapstoneError> {
+    let info = node.get_tx_out_set_info(None, None, None)?;
+    let expected = check_supply(info.height, info.total_amount)?;
+    info!(
+        "Supply at height {}: expected {} BTC, UTXO set holds {} BTC",
+        info.height,
+        expected.to_btc(),
+        info.total_amount.to_btc()
+    );
+    Ok(())
+}
+
+/// Compares `actual` with the subsidy owed to a chain of `height` blocks, returning the expected total.
+pub fn check_supply(height: u64, actual: Amount) -> Result<Amount, CapstoneError> {
+    let expected = total_subsidy(height);
+    if actual != expected {
+        return Err(CapstoneError::SupplyMismatch {
+            height,
+            expected,
+            actual,
+        });
+    }
+    Ok(expected)
+}
+
+/// The block subsidy of blocks 1..=height under regtest's halving schedule. The genesis coinbase is left
+/// out: it was never added to the UTXO set.
+pub fn total_subsidy(height: u64) -> Amount {
+    let mut total = Amount::ZERO;
+    let mut halving = 0;
+    let mut start = 1;
+    while start <= height && halving < 64 {
+        let end = ((halving + 1) * REGTEST_HALVING_INTERVAL - 1).min(height);
+        let blocks = end - start + 1;
+        total += Amount::from_sat(INITIAL_SUBSIDY.to_sat() >> halving) * blocks;
+        halving += 1;
+        start = end + 1;
+    }
+    total
+}
+
+// Extra blocks mined when the confirming block left the payment out, before giving up on it
+pub const CONFIRM_EXTRA_BLOCKS: u64 = 5;
+
+/// Makes sure the confirming block really took the payment: while `included` says it is still unconfirmed
+/// (a block template can leave it out, e.g. behind a full mempool of higher-fee transactions), mines
+/// another block with `mine_next`, up to `max_extra` of them. Returns the last extra block's hash, or
+/// None when the first block already had it.
+pub fn mine_until_included(
+    max_extra: u64,
+    mut included: impl FnMut() -> Result<bool, CapstoneError>,
+    mut mine_next: impl FnMut() -> Result<BlockHash, CapstoneError>,
+) -> Result<Option<BlockHash>, CapstoneError> {
+    let mut last_mined = None;
+    for attempt in 1..=max_extra {
+        if included()? {
+            return Ok(last_mined);
+        }
+        warn!("⚠️ Payment not in a block yet; mining another (attempt {attempt} of {max_extra})");
+        last_mined = Some(mine_next()?);
+    }
+    if included()? {
+        return Ok(last_mined);
+    }
+    Err(CapstoneError::StillUnconfirmed {
+        extra_blocks: max_extra,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use bitcoin::hashes::Hash;
+    use std::cell::Cell;
+
+    // Runs the maturity loop aiming for `target` blocks on a chain where the balance turns positive at
+    // `spendable_at`; returns the outcome and how many balance checks it made
+    fn mine_with_check_every(
+        target: u64,
+        spendable_at: u64,
+        max: u64,
+        check_every: u64,
+    ) -> (Result<u64, CapstoneError>, u64) {
+        let height = Cell::new(0);
+        let checks = Cell::new(0);
+        let result = mine_until_spendable(
+            target,
+            max,
+            check_every,
+            |mined| {
+                assert_eq!(mined, height.get());
+                height.set(mined + 1);
+                Ok(())
+            },
+            |mined| {
+                checks.set(checks.get() + 1);
+                Ok(if mined >= spendable_at {
+                    Amount::from_int_btc(50)
+                } else {
+                    Amount::ZERO
+                })
+            },
+        );
+        (result, checks.get())
+    }
+
+    #[test]
+    fn maturity_loop_terminates_with_sparse_balance_checks() {
+        // Every block: 101 checks, done at 101
+        assert_eq!(mine_with_check_every(101, 101, 150, 1), (Ok(101), 101));
+        // Every 25th block plus the target itself: 25, 50, 75, 100, 101
+        assert_eq!(mine_with_check_every(101, 101, 150, 25), (Ok(101), 5));
+        // A balance that lags the target is picked up at the next regular check
+        assert_eq!(mine_with_check_every(101, 110, 150, 25), (Ok(125), 6));
+        assert_eq!(
+            mine_with_check_every(101, 101, 100, 25).0,
+            Err(CapstoneError::ImmatureCoinbase { blocks_mined: 100 })
+        );
+    }
+
+    // The hash the mock chain gives the block at `height`
+    fn hash_at(height: u64) -> BlockHash {
+        BlockHash::hash(&height.to_le_bytes())
+    }
+
+    #[test]
+    fn mining_retries_after_a_transient_failure() {
+        let height = Cell::new(100);
+        let calls = Cell::new(0);
+
+        let hashes = mine_with_retries(
+            1,
+            || Ok(height.get()),
+            |_| unreachable!("every hash came back"),
+            |count| {
+                calls.set(calls.get() + 1);
+                if calls.get() == 1 {
+                    return Err(node_busy());
+                }
+                height.set(height.get() + count);
+                Ok(vec![BlockHash::all_zeros()])
+            },
+        )
+        .unwrap();
+
+        assert_eq!(calls.get(), 2);
+        assert_eq!(height.get(), 101);
+        assert_eq!(hashes.len(), 1);
+    }
+
+    #[test]
+    fn mining_does_not_repeat_a_block_that_landed_despite_the_error() {
+        let height = Cell::new(100);
+        let calls = Cell::new(0);
+
+        // The block gets mined but the response is lost, so the call reports an error
+        let hashes = mine_with_retries(
+            1,
+            || Ok(height.get()),
+            |at| Ok(hash_at(at)),
+            |count| {
+                calls.set(calls.get() + 1);
+                height.set(height.get() + count);
+                Err(node_busy())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(calls.get(), 1);
+        assert_eq!(height.get(), 101);
+        // Its hash is looked up by height instead, so callers still get one per block
+        assert_eq!(hashes, [hash_at(101)]);
+    }
+
+    #[test]
+    fn blocks_another_process_mined_during_a_retry_are_looked_up() {
+        let height = Cell::new(100);
+        let calls = Cell::new(0);
+
+        // The first call fails outright; meanwhile someone else mines two blocks on the shared node
+        let hashes = mine_with_retries(
+            2,
+            || Ok(height.get()),
+            |at| Ok(hash_at(at)),
+            |_| {
+                calls.set(calls.get() + 1);
+                height.set(102);
+                Err(node_busy())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(calls.get(), 1);
+        assert_eq!(hashes, [hash_at(101), hash_at(102)]);
+    }
+
+    #[test]
+    fn mining_gives_up_after_the_retry_limit() {
+        let calls = Cell::new(0);
+        let result = mine_with_retries(
+            1,
+            || Ok(100),
+            |_| unreachable!("nothing was mined"),
+            |_| {
+                calls.set(calls.get() + 1);
+                Err(node_busy())
+            },
+        );
+
+        assert!(result.is_err());
+        assert_eq!(calls.get(), MINE_RETRIES + 1);
+    }
+
+    #[test]
+    fn left_out_payment_gets_more_blocks_until_included() {
+        let node = std::cell::RefCell::new(MockNode::default());
+        let payment = sample_tx();
+        let txid = payment.compute_txid();
+        node.borrow_mut().add_transaction(&payment);
+        node.borrow_mut().mine(&[]); // The confirming block, which left the payment out
+        let included = || Ok(node.borrow().wallet_confirmations(&txid)? >= 1);
+
+        // The next block leaves it out too; the one after takes it
+        let mined = Cell::new(0);
+        let last = mine_until_included(CONFIRM_EXTRA_BLOCKS, included, || {
+            mined.set(mined.get() + 1);
+            let txids = if mined.get() < 2 { vec![] } else { vec![txid] };
+            Ok(node.borrow_mut().mine(&txids))
+        })
+        .unwrap();
+        assert_eq!(mined.get(), 2);
+        assert_eq!(
+            last,
+            node.borrow().raw_transaction(&txid).unwrap().blockhash
+        );
+        let block = confirmed_block(&*node.borrow(), &txid, last).unwrap();
+        assert_eq!(block.height, 2);
+
+        // Already included: nothing more is mined
+        assert_eq!(
+            mine_until_included(CONFIRM_EXTRA_BLOCKS, included, || unreachable!()).unwrap(),
+            None
+        );
+
+        // Never included: gives up after the limit
+        let left_out = payment_tx(&[(TRADER, 1_000_000_000)]);
+        node.borrow_mut().add_transaction(&left_out);
+        let mined = Cell::new(0);
+        let never = mine_until_included(
+            3,
+            || {
+                Ok(node
+                    .borrow()
+                    .wallet_confirmations(&left_out.compute_txid())?
+                    >= 1)
+            },
+            || {
+                mined.set(mined.get() + 1);
+                Ok(node.borrow_mut().mine(&[]))
+            },
+        );
+        assert!(never.is_err());
+        assert_eq!(mined.get(), 3);
+    }
+
+    #[test]
+    fn extra_miners_take_turns_after_the_miner() {
+        let turns: Vec<usize> = (0..5).map(|mined| round_robin(mined, 3)).collect();
+        assert_eq!(turns, [0, 1, 2, 0, 1]);
+        assert!((0..5).all(|mined| round_robin(mined, 1) == 0));
+    }
+
+    #[test]
+    fn maturity_to_burn_leaves_the_miner_one_block_reward() {
+        // The 101 blocks of the maturity phase on a fresh chain, each paying the 50 BTC subsidy
+        let subsidy = Amount::from_int_btc(50);
+        let (mut miner_balance, mut burned) = (Amount::ZERO, Amount::ZERO);
+        for mined in 0..COINBASE_MATURITY + 1 {
+            match maturity_recipient(mined, 1, true) {
+                Some(0) => miner_balance += subsidy,
+                Some(other) => panic!("no miner {other} with --miners 1"),
+                None => burned += subsidy,
+            }
+        }
+        assert_eq!(miner_balance, subsidy); // Only block 1, which is exactly mature after 101 blocks
+        assert_eq!(burned, Amount::from_int_btc(5_000));
+        assert!((0..101).all(|mined| maturity_recipient(mined, 1, false) == Some(0)));
+
+        let burn = burn_address();
+        assert!(burn.to_string().starts_with("bcrt1"));
+        assert_eq!(burn.address_type(), Some(bitcoin::AddressType::P2wsh));
+    }
+
+    #[test]
+    fn total_subsidy_follows_the_regtest_halvings() {
+        assert_eq!(total_subsidy(0), Amount::ZERO);
+        assert_eq!(total_subsidy(1), Amount::from_int_btc(50));
+        assert_eq!(total_subsidy(101), Amount::from_int_btc(5050));
+        // Blocks 1..=149 pay 50 BTC, 150..=299 pay 25
+        assert_eq!(total_subsidy(149), Amount::from_int_btc(7450));
+        assert_eq!(total_subsidy(150), Amount::from_int_btc(7475));
+        assert_eq!(
+            total_subsidy(300),
+            Amount::from_int_btc(7450 + 3750 + 12) + Amount::from_sat(50_000_000)
+        );
+        // Every subsidy is gone after 64 halvings, which caps the supply just under 150 * 100 BTC
+        assert_eq!(
+            total_subsidy(u64::MAX),
+            total_subsidy(64 * REGTEST_HALVING_INTERVAL)
+        );
+        assert!(total_subsidy(u64::MAX) < Amount::from_int_btc(15_000));
+    }
+
+    #[test]
+    fn supply_check_compares_the_utxo_set_with_the_subsidies() {
+        assert_eq!(
+            check_supply(101, Amount::from_int_btc(5050)),
+            Ok(Amount::from_int_btc(5050))
+        );
+        assert_eq!(
+            check_supply(102, Amount::from_int_btc(5050)),
+            Err(CapstoneError::SupplyMismatch {
+                height: 102,
+                expected: Amount::from_int_btc(5100),
+                actual: Amount::from_int_btc(5050),
+            })
+        );
+    }
+
+    #[test]
+    fn generator_mines_until_stopped_or_capped() {
+        // --max-blocks caps it, without waiting out the interval after the last block
+        let mined = Cell::new(0);
+        let start = Instant::now();
+        let generated = generate_every(
+            Duration::from_secs(60),
+            Some(1),
+            || false,
+            || {
+                mined.set(mined.get() + 1);
+                Ok(())
+            },
+        );
+        assert_eq!(generated.unwrap(), 1);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // A stop (Ctrl-C) cuts into the interval instead of waiting it out
+        let mined = Cell::new(0);
+        let start = Instant::now();
+        let generated = generate_every(
+            Duration::from_secs(60),
+            None,
+            || mined.get() == 1 && start.elapsed() > Duration::from_millis(200),
+            || {
+                mined.set(mined.get() + 1);
+                Ok(())
+            },
+        );
+        assert_eq!(generated.unwrap(), 1);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let stopped = generate_every(Duration::ZERO, None, || true, || unreachable!());
+        assert_eq!(stopped.unwrap(), 0);
+        let failing = generate_every(
+            Duration::ZERO,
+            None,
+            || false,
+            || Err(std::io::Error::other("node gone").into()),
+        );
+        assert!(failing.is_err());
+    }
+}
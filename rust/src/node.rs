@@ -0,0 +1,340 @@
+//! A throwaway `bitcoind -regtest` for self-contained runs: a local process (`--spawn-node`) started on free
+//! ports with a temporary datadir and cookie auth, or a pinned Bitcoin Core container (`--docker`) with a
+//! generated rpcauth. Either is stopped and cleaned up again when dropped.
+
+use crate::*;
+use bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+use std::collections::hash_map::RandomState;
+use std::fs;
+use std::hash::{BuildHasher, Hasher};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::result::Result;
+use std::{thread, time::Duration};
+use tracing::{info, warn};
+
+// The binary to run unless BITCOIND names another (a path, or a name looked up on PATH)
+pub const BITCOIND: &str = "bitcoind";
+
+// The Bitcoin Core image --docker runs unless --docker-image names another; pinned so runs are reproducible
+pub const DOCKER_IMAGE: &str = "bitcoin/bitcoin:28.1";
+const CONTAINER_RPC_PORT: u16 = 18443; // regtest's default, published on a free loopback port of the host
+const DOCKER_RPC_USER: &str = "capstone";
+
+// How often `SpawnedNode::start` looks for the cookie file while bitcoind starts
+const COOKIE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A `bitcoind -regtest` this process started; `rpc` reaches it. Dropping it stops the node and deletes
+/// its datadir or container.
+pub struct SpawnedNode {
+    pub rpc: RpcSettings,
+    handle: Handle,
+}
+
+// What dropping a `SpawnedNode` has to stop and remove
+enum Handle {
+    Process { child: Child, datadir: PathBuf },
+    Container(String), // The container ID; started with --rm, so stopping it removes it
+}
+
+impl SpawnedNode {
+    /// Starts `bitcoind` with a fresh datadir and waits up to `timeout` for it to write its RPC cookie.
+    /// The node may still be warming up; `connect_with_retry` waits that out.
+    pub fn start(bitcoind: &str, timeout: Duration) -> Result<Self, CapstoneError> {
+        let datadir = std::env::temp_dir().join(format!(
+            "capstone-regtest-{}-{}",
+            std::process::id(),
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |since| since.subsec_nanos())
+        ));
+        fs::create_dir_all(&datadir)?;
+        let (rpc_port, p2p_port) = free_ports()?;
+        let child = Command::new(bitcoind)
+            .args(bitcoind_args(&datadir, rpc_port, p2p_port))
+            .stdout(Stdio::null())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|err| {
+                let _ = fs::remove_dir_all(&datadir);
+                CapstoneError::NodeSpawn {
+                    program: bitcoind.to_string(),
+                    message: err.to_string(),
+                }
+            })?;
+        let cookie_path = datadir.join("regtest").join(".cookie");
+        // From here on dropping `node` cleans up, whatever fails
+        let mut node = SpawnedNode {
+            rpc: RpcSettings {
+                url: format!("http://127.0.0.1:{rpc_port}"),
+                ..RpcSettings::default()
+            },
+            handle: Handle::Process { child, datadir },
+        };
+        let mut waited = Duration::ZERO;
+        let cookie = loop {
+            if let Ok(cookie) = fs::read_to_string(&cookie_path) {
+                break cookie;
+            }
+            let Handle::Process { child, .. } = &mut node.handle else {
+                unreachable!("just started as a process")
+            };
+            if let Some(status) = child.try_wait()? {
+                return Err(CapstoneError::NodeExited {
+                    program: bitcoind.to_string(),
+                    status: status.to_string(),
+                });
+            }
+            if waited >= timeout {
+                return Err(CapstoneError::NoCookie {
+                    program: bitcoind.to_string(),
+                    waited,
+                });
+            }
+            thread::sleep(COOKIE_POLL_INTERVAL);
+            waited += COOKIE_POLL_INTERVAL;
+        };
+        let (user, pass) = parse_cookie(&cookie)?;
+        node.rpc.user = user;
+        node.rpc.pass = Passphrase(pass);
+        info!(
+            "Started {bitcoind} -regtest at {} (datadir {})",
+            node.rpc.url,
+            node.datadir().expect("a process node").display()
+        );
+        Ok(node)
+    }
+
+    /// Starts a detached `image` container running `bitcoind -regtest`, its RPC port published on a free
+    /// loopback port and guarded by a freshly generated rpcauth. Returns as soon as docker has started it;
+    /// `connect_with_retry` waits for the node inside to come up.
+    pub fn start_docker(image: &str) -> Result<Self, CapstoneError> {
+        let (host_port, _) = free_ports()?;
+        let pass = random_hex(32);
+        let output = Command::new("docker")
+            .args(docker_args(
+                image,
+                host_port,
+                &rpcauth(DOCKER_RPC_USER, &random_hex(16), &pass),
+            ))
+            .output()
+            .map_err(|err| CapstoneError::NodeSpawn {
+                program: "docker".to_string(),
+                message: err.to_string(),
+            })?;
+        if !output.status.success() {
+            return Err(CapstoneError::DockerRun {
+                image: image.to_string(),
+                message: format!(
+                    "{}: {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ),
+            });
+        }
+        let id = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        info!(
+            "Started container {} ({image}) with RPC at 127.0.0.1:{host_port}",
+            &id[..id.len().min(12)]
+        );
+        Ok(SpawnedNode {
+            rpc: RpcSettings {
+                url: format!("http://127.0.0.1:{host_port}"),
+                user: DOCKER_RPC_USER.to_string(),
+                pass: Passphrase(pass),
+                ..RpcSettings::default()
+            },
+            handle: Handle::Container(id),
+        })
+    }
+
+    /// The temporary datadir of a `--spawn-node` process; a container keeps its own.
+    pub fn datadir(&self) -> Option<&Path> {
+        match &self.handle {
+            Handle::Process { datadir, .. } => Some(datadir),
+            Handle::Container(_) => None,
+        }
+    }
+}
+
+impl Drop for SpawnedNode {
+    fn drop(&mut self) {
+        match &mut self.handle {
+            Handle::Process { child, datadir } => {
+                // SIGTERM lets bitcoind flush and shut down cleanly; elsewhere it can only be killed
+                #[cfg(unix)]
+                unsafe {
+                    libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+                }
+                #[cfg(not(unix))]
+                let _ = child.kill();
+                let _ = child.wait();
+                let _ = fs::remove_dir_all(&*datadir);
+                info!("Stopped the spawned node and removed {}", datadir.display());
+            }
+            Handle::Container(id) => {
+                let stopped = Command::new("docker")
+                    .args(["stop", id.as_str()])
+                    .stdout(Stdio::null())
+                    .status();
+                match stopped {
+                    Ok(status) if status.success() => {
+                        info!("Stopped and removed the node container")
+                    }
+                    _ => warn!(
+                        "⚠️ Could not stop container {id}; remove it with `docker rm -f {id}`"
+                    ),
+                }
+            }
+        }
+    }
+}
+
+/// The command line for a private regtest node: its own datadir and ports, no peers, cookie auth, and
+/// the `-txindex` and fallback fee the run relies on.
+pub fn bitcoind_args(datadir: &Path, rpc_port: u16, p2p_port: u16) -> Vec<String> {
+    vec![
+        "-regtest".to_string(),
+        format!("-datadir={}", datadir.display()),
+        format!("-rpcport={rpc_port}"),
+        format!("-port={p2p_port}"),
+        "-rpcbind=127.0.0.1".to_string(),
+        "-rpcallowip=127.0.0.1".to_string(),
+        "-listen=0".to_string(),
+        "-server=1".to_string(),
+        "-txindex=1".to_string(),
+        "-fallbackfee=0.0002".to_string(),
+        "-printtoconsole=0".to_string(),
+    ]
+}
+
+/// The `docker run` arguments for a regtest node in `image`: detached, removed once stopped, RPC published on
+/// `host_port` of the host's loopback only, and `rpcauth` as its sole credentials.
+pub fn docker_args(image: &str, host_port: u16, rpcauth: &str) -> Vec<String> {
+    vec![
+        "run".to_string(),
+        "--detach".to_string(),
+        "--rm".to_string(),
+        "--publish".to_string(),
+        format!("127.0.0.1:{host_port}:{CONTAINER_RPC_PORT}"),
+        image.to_string(),
+        "-regtest".to_string(),
+        // Inside the container the RPC server has to accept the published port's traffic
+        "-rpcbind=0.0.0.0".to_string(),
+        "-rpcallowip=0.0.0.0/0".to_string(),
+        format!("-rpcauth={rpcauth}"),
+        "-txindex=1".to_string(),
+        "-fallbackfee=0.0002".to_string(),
+    ]
+}
+
+/// A `-rpcauth` value as Bitcoin Core's share/rpcauth/rpcauth.py makes it: `user:salt$hmac`, the HMAC-SHA256
+/// of the password keyed by the salt, so the password itself never appears in the container's command line.
+pub fn rpcauth(user: &str, salt: &str, pass: &str) -> String {
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(salt.as_bytes());
+    engine.input(pass.as_bytes());
+    let hash = hmac::Hmac::<sha256::Hash>::from_engine(engine);
+    format!("{user}:{salt}${hash}")
+}
+
+// `bytes` random bytes as hex, drawn from the OS-seeded keys std gives each `RandomState`
+fn random_hex(bytes: usize) -> String {
+    (0..bytes.div_ceil(8))
+        .flat_map(|_| RandomState::new().build_hasher().finish().to_le_bytes())
+        .take(bytes)
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Splits the `.cookie` file bitcoind writes (`__cookie__:<password>`) into user and password.
+pub fn parse_cookie(cookie: &str) -> Result<(String, String), CapstoneError> {
+    match cookie.trim().split_once(':') {
+        Some((user, pass)) if !user.is_empty() && !pass.is_empty() => {
+            Ok((user.to_string(), pass.to_string()))
+        }
+        _ => Err(CapstoneError::MalformedCookie),
+    }
+}
+
+// Two distinct ports nothing is listening on right now, picked by the OS (both held open while picking)
+fn free_ports() -> Result<(u16, u16), CapstoneError> {
+    let rpc = TcpListener::bind("127.0.0.1:0")?;
+    let p2p = TcpListener::bind("127.0.0.1:0")?;
+    Ok((rpc.local_addr()?.port(), p2p.local_addr()?.port()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[ignore = "needs bitcoind on PATH (or BITCOIND)"]
+    fn spawned_node_answers_and_is_cleaned_up() {
+        let bitcoind = std::env::var("BITCOIND").unwrap_or(BITCOIND.to_string());
+        let node = SpawnedNode::start(&bitcoind, RetryPolicy::default().timeout).unwrap();
+        let datadir = node.datadir().unwrap().to_path_buf();
+
+        let (_, info, _) = connect_with_retry(&node.rpc).unwrap();
+        assert_eq!(info.chain, bitcoin::Network::Regtest);
+        assert_eq!(info.blocks, 0);
+
+        drop(node);
+        assert!(!datadir.exists());
+    }
+
+    #[test]
+    #[ignore = "needs Docker and the bitcoin/bitcoin image"]
+    fn docker_node_answers_and_is_removed() {
+        let node = SpawnedNode::start_docker(DOCKER_IMAGE).unwrap();
+        assert_eq!(node.datadir(), None);
+        let (_, info, _) = connect_with_retry(&node.rpc).unwrap();
+        assert_eq!(info.chain, bitcoin::Network::Regtest);
+        drop(node);
+    }
+
+    #[test]
+    fn spawned_node_is_a_private_regtest_node() {
+        let node_args = bitcoind_args(Path::new("/tmp/capstone-regtest-1"), 18543, 18544);
+        for arg in [
+            "-regtest",
+            "-datadir=/tmp/capstone-regtest-1",
+            "-rpcport=18543",
+            "-port=18544",
+            "-txindex=1",
+            "-listen=0",
+        ] {
+            assert!(node_args.iter().any(|a| a == arg), "missing {arg}");
+        }
+        // Cookie auth: nothing in the command line to leak
+        assert!(!node_args.iter().any(|a| a.starts_with("-rpcpassword")));
+    }
+
+    #[test]
+    fn cookie_is_a_user_and_a_password() {
+        assert_eq!(
+            parse_cookie("__cookie__:5f1c9e\n").unwrap(),
+            ("__cookie__".to_string(), "5f1c9e".to_string())
+        );
+        assert!(parse_cookie("").is_err());
+        assert!(parse_cookie("__cookie__:").is_err());
+    }
+
+    #[test]
+    fn docker_node_is_pinned_and_guarded_by_rpcauth() {
+        // The HMAC Bitcoin Core's rpcauth.py computes for this salt and password
+        let auth = rpcauth("capstone", "cb73b8a3d1a1b9fb4cd2a4e79f5ca5f8", "hunter2");
+        assert_eq!(
+            auth,
+            "capstone:cb73b8a3d1a1b9fb4cd2a4e79f5ca5f8$18b73b8ae69b62a9643218273e22ef8e1b81cfa753967d38a13fe754c3eecac9"
+        );
+
+        let docker_args = docker_args(DOCKER_IMAGE, 28443, &auth);
+        assert_eq!(docker_args[..3], ["run", "--detach", "--rm"]);
+        let image_at = docker_args.iter().position(|a| a == DOCKER_IMAGE).unwrap();
+        // Docker options before the image, bitcoind's after it
+        assert!(docker_args[..image_at].contains(&"127.0.0.1:28443:18443".to_string()));
+        assert!(docker_args[image_at..].contains(&format!("-rpcauth={auth}")));
+        assert!(!docker_args.iter().any(|a| a.contains("hunter2")));
+    }
+}
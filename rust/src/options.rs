@@ -0,0 +1,328 @@
+//! What a command runs with: the [`Options`] [`crate::cli`] fills from the flags, config file and environment,
+//! and the [`Command`] they select.
+
+use crate::mining::{BlockClock, COINBASE_MATURITY, DEFAULT_BLOCK_TIME_STEP};
+use crate::node::{BITCOIND, DOCKER_IMAGE};
+use crate::pipeline::DEFAULT_CELEBRATION;
+use crate::report::{ReportFormat, Units};
+use crate::rpc::{Passphrase, RpcSettings};
+use crate::transfer::SendAmount;
+use crate::wallet::{miner_wallet_names, WalletSet};
+use crate::{CapstoneError, Context, LogLevel};
+use bitcoin::absolute::LockTime;
+use bitcoincore_rpc::bitcoin::address::NetworkUnchecked;
+use bitcoincore_rpc::bitcoin::{Address, Amount, OutPoint, Txid};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Address labels the grader looks for; overridable with --mining-label / --received-label
+pub const MINING_LABEL: &str = "Mining Reward";
+pub const PAYMENT_COMMENT: &str = "Payment to Trader"; // Wallet-local note on the Miner → Trader send
+pub const RECEIVED_LABEL: &str = "Received";
+
+// Where the ten report lines go by default; `cargo run` executes from rust/, so this lands in the repo root
+pub const DEFAULT_OUT_PATH: &str = "../out.txt";
+
+// Fee above which the send is treated as a misconfiguration (regtest fees are a few hundred satoshis)
+pub const DEFAULT_MAX_FEE: Amount = Amount::from_sat(1_000_000);
+
+// How long --wait-for-funds waits for the Miner balance by default
+pub const DEFAULT_FUNDS_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Which wallet receives the coinbase of the block that confirms the payment (`--confirm-to`).
+/// `Miner` (the default) keeps everything in one wallet, so the Miner's balance mixes the change with a
+/// fresh, immature block reward. `Trader` leaves the Miner's balance as pure spend-plus-change, which is
+/// cleaner to teach with, but the Trader then holds coins it was never paid. `--confirm-address` instead
+/// sends the reward to any address, keeping both wallets clean at the cost of losing track of those coins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfirmTo {
+    Miner,
+    Trader,
+}
+
+impl std::str::FromStr for ConfirmTo {
+    type Err = CapstoneError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "miner" => Ok(ConfirmTo::Miner),
+            "trader" => Ok(ConfirmTo::Trader),
+            other => Err(CapstoneError::UnknownChoice {
+                what: "--confirm-to",
+                value: other.to_string(),
+                expected: "miner or trader",
+            }),
+        }
+    }
+}
+
+/// What the program should do once the wallets are set up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Run,       // The one-shot capstone pipeline (default)
+    Send,      // `run` without mining to maturity: pay from the Miner's existing balance
+    Report,    // Rebuild and write the reports of an already confirmed payment (needs --txid)
+    Watch,     // Keep reporting every new payment the Trader receives
+    Bump,      // Fee-bump an unconfirmed Miner transaction (needs --txid)
+    Decode,    // Pretty-print any transaction the node knows as JSON (needs --txid)
+    Diff,      // Compare two out.txt files field by field (no node needed)
+    Mine,      // Only mine --count blocks to the Miner and show the chain state
+    Wallets,   // List every wallet with its balance and labelled addresses (read-only)
+    SelfTest,  // Run report extraction over the built-in fixtures (no node needed)
+    Generator, // Mine a block every --interval-secs until Ctrl-C (or --max-blocks)
+    Route,     // Pay --amount from wallet to wallet along --route, confirming each hop
+    SendMany,  // Pay every --recipients entry in one sendmany transaction from the Miner's balance
+    Help,      // Print the usage (-h/--help) and do nothing else
+}
+
+impl Command {
+    // `run` and `send` share the payment pipeline, and so the options that shape it
+    pub fn sends(self) -> bool {
+        matches!(self, Command::Run | Command::Send)
+    }
+}
+
+/// Everything a command runs with. The library's `parse_options` (in cli.rs) builds it from the defaults, then
+/// the config file, then the flags, then the environment, each overriding the ones before.
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub command: Command,
+    pub out_path: PathBuf, // Destination of the ten-line report
+    pub formats: Vec<ReportFormat>,
+    pub fee_file: Option<PathBuf>, // Extra file holding only the fee, for graders that want it alone
+    pub raw_tx_file: Option<PathBuf>, // Raw hex of the payment, for explorers and other decoders
+    pub input_file: Option<PathBuf>, // JSON or TOML batch of payments `run` makes instead of its single send
+    pub recipients_file: Option<PathBuf>, // `send-many`: the recipients paid together, as JSON or CSV
+    pub fee_rate: Option<f64>, // sat/vB for the plain send; only set per payment by --input-file
+    pub units: Units,          // Amount lines of out.txt in BTC (default) or satoshis
+    pub amount: SendAmount,    // What `run` pays the Trader (default 20 BTC)
+    pub auto_confirm: bool, // In watch mode, mine a block whenever a receipt is still unconfirmed
+    pub txid: Option<Txid>, // Transaction to act on for `bump`
+    pub mining_label: String, // Label of the Miner's reward address
+    pub received_label: String, // Label of the Trader's receiving address
+    pub comment: String,    // Miner wallet's comment on the payment
+    pub subtract_fee: bool, // The Trader output pays the fee (send_to_address's subtract_fee_from_amount)
+    pub sweep: bool,        // Pay the whole Miner balance with sendall, no change
+    pub dry_run: bool, // Build and sign the payment, print it, and stop before broadcasting or mining
+    pub max_fee: Amount, // Sanity limit on the payment's fee
+    pub allow_high_fee: bool, // Downgrade a fee above max_fee to a warning
+    pub locktime: Option<LockTime>, // nLockTime of the payment; sends through the PSBT path when set
+    pub trader_addresses: usize,    // Fresh Trader addresses sharing the payment
+    pub spend_utxos: Vec<OutPoint>, // Coin control: the only inputs the payment may use; empty lets the wallet choose
+    pub trader_address: Option<Address<NetworkUnchecked>>, // External payee; its network is checked before sending
+    pub confirm_address: Option<Address<NetworkUnchecked>>, // Recipient of the confirming block's coinbase
+    pub confirm_to: ConfirmTo, // Wallet that gets a fresh address for that coinbase when no --confirm-address is given
+    pub wallet_passphrase: Option<Passphrase>, // Unlocks an encrypted Miner wallet for the send only
+    pub rpc: RpcSettings,
+    pub spawn_node: bool, // Run against a bitcoind we start (and stop) ourselves instead of a running one
+    pub bitcoind: String, // The binary --spawn-node runs
+    pub docker: bool,     // Like spawn_node, but in a container of docker_image
+    pub docker_image: String,
+    pub miner_wallet: String, // Node wallet playing the Miner; extra --miners add 2, 3, ... to the name
+    pub trader_wallet: String, // Node wallet playing the Trader
+    pub extra_wallets: Vec<String>, // Wallets set up beside the Miner and Trader, e.g. Exchange, ColdStorage
+    pub route: Vec<String>, // `route`: the wallets the payment travels through, in order (empty: the default route)
+    pub unload_on_exit: bool, // Leave the node without our wallets loaded once the run succeeded
+    pub quiet: bool,
+    pub stdout_only: bool, // Ten report lines on stdout, all other output on stderr, no animation
+    pub log_level: LogLevel,
+    pub log_json: bool, // Progress messages as JSON lines tagged with level and stage
+    pub event_log: Option<PathBuf>, // JSONL timeline of the run, appended to
+    pub expect_trader_type: Option<String>, // Fail if the Trader output isn't this script type (one of SCRIPT_TYPES)
+    pub no_file: bool,                      // Run and check everything, but write no report files
+    pub celebrate: Duration,                // Length of the closing animation
+    pub no_bell: bool,                      // Animate without ringing the terminal bell
+    pub round_trip: bool, // After the main run, have the Trader pay part of it back and report that too
+    pub clean: bool,      // Delete stale artifacts at our own output paths before the run
+    pub fresh: bool,      // Ignore the payment an earlier run recorded in its state file
+    pub reuse_utxo: bool, // Pay from a UTXO an earlier run left instead of mining a new reward
+    pub count: Option<u64>, // Blocks `mine` produces
+    pub max_blocks: Option<u64>, // Cap on blocks mined by `mine`, `generator` and the maturity loop of `run`
+    pub interval: Option<Duration>, // `generator`'s pause between blocks
+    pub maturity: u64, // Coinbase maturity of the chain; the mining loop aims for maturity + 1 blocks
+    pub check_every: u64, // Blocks between balance checks in the maturity loop
+    pub poll_interval: Duration, // Pause before each of those checks, to go easy on the RPC server
+    pub explain: bool, // Print the report values with their meanings after the run
+    pub show_scripts: bool, // Per-input scriptSig and witness in the terminal report and out.json
+    pub display_decimals: usize, // Precision of terminal BTC amounts; never applies to report files
+    pub normalize: bool, // Write out.json with the run-specific fields blanked
+    pub wait_for_funds: bool, // Fund the Miner from outside instead of mining, e.g. where we can't mine
+    pub funds_timeout: Duration,
+    pub miners: usize, // Miner wallets taking turns during the maturity phase; 1 mines everything to Miner
+    pub maturity_to_burn: bool, // Burn all but the first maturity-phase block reward
+    pub diff_files: Vec<PathBuf>, // The two out.txt files `diff` compares
+    pub assert_confirmed_in: Option<u64>, // Fail unless the payment confirmed within this many blocks of broadcast
+    pub no_rpc_batch: bool,               // Trace inputs with one getrawtransaction round trip each
+    pub verify_supply: bool, // Compare the UTXO set total with the expected subsidy after the run
+    pub block_time_start: Option<u64>, // Unix time of the first mined block; None leaves timestamps to the node
+    pub block_time_step: Option<u64>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            command: Command::Run,
+            out_path: PathBuf::from(DEFAULT_OUT_PATH),
+            formats: vec![ReportFormat::Text],
+            fee_file: None,
+            raw_tx_file: None,
+            input_file: None,
+            recipients_file: None,
+            fee_rate: None,
+            units: Units::Btc,
+            amount: SendAmount::Absolute(Amount::from_int_btc(20)),
+            auto_confirm: false,
+            txid: None,
+            trader_address: None,
+            spend_utxos: Vec::new(),
+            trader_addresses: 1,
+            locktime: None,
+            max_fee: DEFAULT_MAX_FEE,
+            allow_high_fee: false,
+            mining_label: MINING_LABEL.to_string(),
+            received_label: RECEIVED_LABEL.to_string(),
+            comment: PAYMENT_COMMENT.to_string(),
+            subtract_fee: false,
+            sweep: false,
+            dry_run: false,
+            confirm_address: None,
+            confirm_to: ConfirmTo::Miner,
+            unload_on_exit: false,
+            wallet_passphrase: None,
+            rpc: RpcSettings::default(),
+            spawn_node: false,
+            bitcoind: BITCOIND.to_string(),
+            docker: false,
+            docker_image: DOCKER_IMAGE.to_string(),
+            miner_wallet: "Miner".to_string(),
+            trader_wallet: "Trader".to_string(),
+            extra_wallets: Vec::new(),
+            route: Vec::new(),
+            quiet: false,
+            stdout_only: false,
+            log_level: LogLevel::Info,
+            log_json: false,
+            event_log: None,
+            clean: false,
+            fresh: false,
+            reuse_utxo: false,
+            round_trip: false,
+            no_file: false,
+            celebrate: DEFAULT_CELEBRATION,
+            no_bell: false,
+            expect_trader_type: None,
+            assert_confirmed_in: None,
+            diff_files: Vec::new(),
+            maturity: COINBASE_MATURITY,
+            miners: 1,
+            maturity_to_burn: false,
+            explain: false,
+            show_scripts: false,
+            display_decimals: 8,
+            normalize: false,
+            wait_for_funds: false,
+            funds_timeout: DEFAULT_FUNDS_TIMEOUT,
+            check_every: 1,
+            poll_interval: Duration::ZERO,
+            count: None,
+            max_blocks: None,
+            interval: None,
+            no_rpc_batch: false,
+            verify_supply: false,
+            block_time_start: None,
+            block_time_step: None,
+        }
+    }
+}
+
+impl Options {
+    /// Every wallet the run sets up: the Miner and its `--miners` peers, the Trader, then the extra wallets.
+    pub fn wallet_set(&self) -> Result<WalletSet, CapstoneError> {
+        WalletSet::new(
+            miner_wallet_names(&self.miner_wallet, self.miners)
+                .into_iter()
+                .chain([self.trader_wallet.clone()])
+                .chain(self.extra_wallets.iter().cloned()),
+        )
+    }
+
+    // The wallets `route` pays through: --route as given, or the Miner, the Trader and each extra wallet
+    pub fn route_hops(&self) -> Vec<String> {
+        if !self.route.is_empty() {
+            return self.route.clone();
+        }
+        [&self.miner_wallet, &self.trader_wallet]
+            .into_iter()
+            .chain(&self.extra_wallets)
+            .cloned()
+            .collect()
+    }
+
+    /// The [`Context`] the library runs these options with: `--event-log` opened for appending, the block clock
+    /// `--block-time-start` sets, and `--no-rpc-batch`.
+    pub fn context(&self) -> Result<Context, CapstoneError> {
+        let event_log = match &self.event_log {
+            Some(path) => Some(
+                fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?,
+            ),
+            None => None,
+        };
+        let block_clock = match self.block_time_start {
+            Some(start) => BlockClock::new(
+                start,
+                self.block_time_step.unwrap_or(DEFAULT_BLOCK_TIME_STEP),
+            ),
+            None => BlockClock::default(),
+        };
+        Ok(Context {
+            rpc_batch: !self.no_rpc_batch,
+            block_clock,
+            event_log: Mutex::new(event_log),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wallet_set_holds_every_miner_the_trader_and_the_extra_wallets() {
+        let options = Options {
+            miners: 2,
+            extra_wallets: vec!["Exchange".to_string()],
+            ..Options::default()
+        };
+        assert_eq!(
+            options.wallet_set().unwrap().names(),
+            ["Miner", "Miner2", "Trader", "Exchange"]
+        );
+        let options = Options {
+            trader_wallet: "Miner".to_string(),
+            ..Options::default()
+        };
+        assert_eq!(
+            options.wallet_set(),
+            Err(CapstoneError::DuplicateWallet("Miner".to_string()))
+        );
+    }
+
+    #[test]
+    fn route_hops_default_to_every_wallet_in_order() {
+        let mut options = Options {
+            extra_wallets: vec!["Exchange".to_string(), "ColdStorage".to_string()],
+            ..Options::default()
+        };
+        assert_eq!(
+            options.route_hops(),
+            ["Miner", "Trader", "Exchange", "ColdStorage"]
+        );
+        options.route = vec!["Miner".to_string(), "ColdStorage".to_string()];
+        assert_eq!(options.route_hops(), ["Miner", "ColdStorage"]);
+    }
+}
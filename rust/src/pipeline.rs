@@ -0,0 +1,2548 @@
+//! The payment pipelines the binary dispatches to: `run` (mine to maturity, pay the Trader, confirm and
+//! report) and its resume, dry run, batch and round trip, plus `route`, `send-many` and `report`.
+
+use crate::config::toml_message;
+use crate::mining::*;
+use crate::options::{Command, ConfirmTo, Options};
+use crate::report::*;
+use crate::rpc::*;
+use crate::transfer::*;
+use crate::wallet::*;
+use crate::*;
+use bitcoin::absolute::LockTime;
+use bitcoincore_rpc::bitcoin::address::NetworkUnchecked;
+use bitcoincore_rpc::bitcoin::amount::CheckedSum;
+use bitcoincore_rpc::bitcoin::{Address, Amount, OutPoint, Txid};
+use bitcoincore_rpc::json::GetTransactionResultDetailCategory;
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{stdout, Write};
+use std::path::{Path, PathBuf};
+use std::result::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::{thread, time::Duration};
+use tracing::{debug, info, warn};
+
+// Set by the Ctrl-C handler while one is installed (celebration animation, `generator`), which poll it to stop
+pub static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// How often --wait-for-funds re-reads the Miner balance
+const FUNDS_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// `route`: pays `--amount` along the route, from each wallet to a fresh "Received" address of the next,
+/// mining a block to the Miner to confirm every hop. Later hops forward what the hop before delivered, their
+/// fee taken out of it, so the same coins travel the whole way. A route starting at a Miner that can't cover
+/// the amount first mines to maturity.
+pub fn route(
+    ctx: &Context,
+    options: &Options,
+    clients: &[(String, Client)],
+    metrics: &mut Metrics,
+) -> Result<(), CapstoneError> {
+    let decimals = options.display_decimals;
+    let hops = options.route_hops();
+    let wallet = |name: &str| {
+        wallet_client(clients, name).ok_or_else(|| CapstoneError::WalletNotSetUp(name.to_string()))
+    };
+    let miner = wallet(&options.miner_wallet)?;
+    let miner_address = miner
+        .get_new_address(Some(&options.mining_label), None)?
+        .require_network(bitcoincore_rpc::bitcoin::Network::Regtest)?;
+
+    metrics.phase("mine");
+    let first = wallet(&hops[0])?;
+    let mut balance = first.get_balance(None, None)?;
+    if balance == Amount::ZERO || options.amount.resolve(balance) >= balance {
+        if hops[0] != options.miner_wallet {
+            return Err(CapstoneError::RouteUnfunded {
+                wallet: hops[0].clone(),
+                balance,
+            });
+        }
+        mine_to_maturity(ctx, options, miner, &miner_address, &[], metrics)?;
+        balance = first.get_balance(None, None)?;
+    }
+    let mut amount = options.amount.resolve(balance);
+
+    metrics.phase("send");
+    for (n, hop) in hops.windows(2).enumerate() {
+        let (from, to) = (wallet(&hop[0])?, wallet(&hop[1])?);
+        let address = to
+            .get_new_address(Some(&options.received_label), None)?
+            .require_network(bitcoincore_rpc::bitcoin::Network::Regtest)?;
+        let txid = from.send_to_address(
+            &address,
+            amount,
+            Some(&options.comment),
+            None,
+            Some(n > 0 || options.subtract_fee),
+            None,
+            None,
+            None,
+        )?;
+        mine_blocks(ctx, miner, 1, &miner_address)?;
+        metrics.blocks_mined += 1;
+
+        let received = to.get_received_by_address(&address, Some(1))?;
+        if received == Amount::ZERO {
+            return Err(CapstoneError::HopUnconfirmed {
+                from: hop[0].clone(),
+                to: hop[1].clone(),
+                txid,
+            });
+        }
+        let fee = from.get_transaction(&txid, None)?.fee.unwrap_or_default();
+        info!(
+            "Hop {}: {} → {}: {:.decimals$} BTC, fee {:.decimals$} BTC, txid {txid}",
+            n + 1,
+            hop[0],
+            hop[1],
+            received.to_btc(),
+            fee.unsigned_abs().to_btc()
+        );
+        ctx.log_event(
+            "route_hop",
+            json!({ "from": hop[0], "to": hop[1], "txid": txid, "amount": received.to_btc() }),
+        );
+        amount = received;
+    }
+    Ok(())
+}
+
+/// The full capstone pipeline: mine to maturity, pay the Trader, confirm, and write the report.
+/// `peers` are the extra `--miners` wallets that take turns with the Miner while mining to maturity.
+pub fn run(
+    ctx: &Context,
+    options: &Options,
+    miner: &Client,
+    peers: &[(String, Client)],
+    trader: &Client,
+    metrics: &mut Metrics,
+) -> Result<TxReport, CapstoneError> {
+    // A re-run against the same node finishes the earlier run's payment rather than making another
+    if options.command == Command::Run && !options.fresh {
+        if let Some(report) = resume(ctx, options, miner, trader, metrics)? {
+            return Ok(report);
+        }
+    }
+    let (miner_address, spend_utxos) = fund_miner(ctx, options, miner, peers, metrics)?;
+    pay(
+        ctx,
+        options,
+        miner,
+        peers,
+        trader,
+        &miner_address,
+        &spend_utxos,
+        metrics,
+    )
+}
+
+/// Gets the Miner the coins `options.amount` needs: mined to maturity on its reward address (or an earlier
+/// run's UTXO reused), paid in from outside with --wait-for-funds, or, for `send`, already held. Returns the
+/// reward address and the UTXOs the payment must spend, if any.
+fn fund_miner(
+    ctx: &Context,
+    options: &Options,
+    miner: &Client,
+    peers: &[(String, Client)],
+    metrics: &mut Metrics,
+) -> Result<(Address, Vec<OutPoint>), CapstoneError> {
+    let decimals = options.display_decimals;
+    metrics.phase(if options.wait_for_funds {
+        "fund"
+    } else {
+        "mine"
+    });
+
+    // The Miner's funding address: mined to until the coinbase matures, or paid by a faucet with --wait-for-funds.
+    // "Mining Reward" by default, the exact label the test specification asks for; an address left with that
+    // label by an earlier run is reused, so repeated runs mine to (and report) the same input address.
+    let existing = addresses_by_label(miner, &options.mining_label)?;
+    let reused = reusable_address(&existing);
+    let miner_address = match reused {
+        Some(address) => address.parse::<Address<NetworkUnchecked>>()?,
+        None => miner.get_new_address(Some(&options.mining_label), None)?,
+    }
+    .require_network(bitcoincore_rpc::bitcoin::Network::Regtest)?;
+
+    if reused.is_some() {
+        info!(
+            "Miner address: {miner_address} (reused \"{}\" address)",
+            options.mining_label
+        );
+    } else {
+        info!("Miner address: {miner_address}");
+    }
+    ctx.log_event(
+        "address_generated",
+        json!({ "wallet": "Miner", "label": options.mining_label, "address": miner_address.to_string(), "reused": reused.is_some() }),
+    );
+
+    // Coin control: --spend-utxo, or the one UTXO of an earlier run that a re-run pays from instead of mining
+    let mut spend_utxos = options.spend_utxos.clone();
+
+    // Either mine the balance ourselves (regtest), or have it paid in from outside and wait for it
+    if options.wait_for_funds {
+        info!(
+            "Waiting up to {}s for funds to reach the Miner, e.g. at {miner_address}",
+            options.funds_timeout.as_secs()
+        );
+        let balance = wait_for_funds(
+            || Ok(miner.get_balance(None, None)?),
+            options.amount,
+            options.funds_timeout,
+            FUNDS_POLL_INTERVAL,
+        )?;
+        info!(
+            "Funds arrived: {:.decimals$} BTC spendable.",
+            balance.to_btc()
+        );
+    } else if options.command == Command::Send {
+        // `send` pays from what earlier runs (or `mine`) left the Miner; it mines nothing to get there
+        let balance = miner.get_balance(None, None)?;
+        if balance == Amount::ZERO || options.amount.resolve(balance) > balance {
+            return Err(CapstoneError::SendUnfunded {
+                balance,
+                blocks: options.maturity + 1,
+            });
+        }
+        info!(
+            "Sending from the Miner's {:.decimals$} BTC spendable.",
+            balance.to_btc()
+        );
+    } else {
+        // By default the run mines and pays from the reward that matures. With --reuse-utxo, what an earlier
+        // run mined and didn't spend is paid from instead, provided one UTXO covers the payment and the
+        // largest fee we accept: the payment keeps its single input
+        let balance = miner.get_balance(None, None)?;
+        let pinnable = !options.sweep && options.trader_addresses == 1 && spend_utxos.is_empty();
+        let needed = |balance: Amount| options.amount.resolve(balance) + options.max_fee;
+        let reusable = if pinnable && options.reuse_utxo {
+            let unspent = miner.list_unspent(None, None, None, None, None)?;
+            single_covering_utxo(&unspent, needed(balance))
+        } else {
+            None
+        };
+        if let Some(utxo) = reusable {
+            info!("Paying from {utxo}, left to the Miner by an earlier run; mining nothing (--reuse-utxo)");
+            spend_utxos.push(utxo);
+        } else {
+            if let (SendAmount::Absolute(amount), false) = (options.amount, options.sweep) {
+                // Mining to maturity matures a single block reward for the Miner; an amount beyond that
+                // is refused now rather than after a hundred blocks
+                let height = miner.get_block_count()?;
+                let reward = total_subsidy(height + 1) - total_subsidy(height);
+                check_spendable(amount, balance + reward, options.subtract_fee)?;
+            }
+            mine_to_maturity(ctx, options, miner, &miner_address, peers, metrics)?;
+            if pinnable && balance > Amount::ZERO {
+                // Coin selection could pick what earlier runs left (their change, older rewards) over the
+                // block reward that just matured; that reward pays on its own, so the input is the reward
+                let balance = miner.get_balance(None, None)?;
+                let unspent = miner.list_unspent(None, None, None, None, None)?;
+                spend_utxos.extend(newest_reward(
+                    &unspent,
+                    &miner_address.to_string(),
+                    needed(balance),
+                ));
+            }
+        }
+    }
+    if !options.sweep {
+        let spendable = miner.get_balance(None, None)?;
+        check_spendable(
+            options.amount.resolve(spendable),
+            spendable,
+            options.subtract_fee,
+        )?;
+    }
+
+    /*When I ran the code, the wallet balance became positive only after mining 101 blocks.
+
+    Coinbase rewards (mining rewards) take 100 blocks to mature before they show in your wallet as spendable, protecting the network from fraud and block manipulation by miners.
+
+    Newly mined coins (aka coinbase rewards) can’t be spent until they get these 100 confirmations (i.e., 100 more blocks are added on top), because of this Bitcoin rule.
+    This rule exists to prevent cheating by miners. Without it, a miner could: Mine a block, spend the reward immediately, and then rewrite the chain (reorganize it) to keep the reward and erase the spend.
+
+    With a 100-block delay, Bitcoin makes it very hard to reverse that block or cheat.*/
+    Ok((miner_address, spend_utxos))
+}
+
+/// Pays the Trader from what the funded Miner holds: sends `options.amount` (from `spend_utxos` when given),
+/// mines the block confirming it, and checks and writes its report. `miner_address` is the Miner's reward
+/// address, which --locktime mines to and whose label is checked.
+#[allow(clippy::too_many_arguments)]
+fn pay(
+    ctx: &Context,
+    options: &Options,
+    miner: &Client,
+    peers: &[(String, Client)],
+    trader: &Client,
+    miner_address: &Address,
+    spend_utxos: &[OutPoint],
+    metrics: &mut Metrics,
+) -> Result<TxReport, CapstoneError> {
+    let decimals = options.display_decimals; // Terminal amounts only; out.txt keeps its own format
+    metrics.phase("send");
+
+    // Generate Trader receiving address (this is the recipient of the 20 BTC transaction.) with exact label "Received" just as it was specified in test specification
+    // unless an external address was supplied with --trader-address
+    // Generates a fresh BTC address from Trader wallet with correct label, skipping any that already
+    // received funds so an old UTXO can't leak into the report
+    let fresh_trader_address = || {
+        first_unused_address(
+            || {
+                Ok(trader
+                    .get_new_address(Some(&options.received_label), None)?
+                    .require_network(bitcoincore_rpc::bitcoin::Network::Regtest)?)
+            },
+            |address| Ok(trader.get_received_by_address(address, Some(0))?),
+        )
+    };
+    let trader_address = match &options.trader_address {
+        Some(address) => address
+            .clone()
+            .require_network(bitcoincore_rpc::bitcoin::Network::Regtest)
+            .map_err(|_| CapstoneError::NotRegtestAddress {
+                flag: "--trader-address",
+                address: format!("{address:?}"),
+            })?,
+        None => fresh_trader_address()?,
+    };
+    // --trader-addresses N shares the payment between N such addresses; the first one is reported in out.txt
+    let mut trader_addresses = vec![trader_address.clone()];
+    for _ in 1..options.trader_addresses {
+        trader_addresses.push(fresh_trader_address()?);
+    }
+    for address in &trader_addresses {
+        info!("Trader receiving address: {address}");
+        ctx.log_event(
+            "address_generated",
+            json!({ "wallet": "Trader", "label": options.received_label, "address": address.to_string() }),
+        );
+    }
+
+    // Send 20 BTC (or whatever --amount asks for) from Miner wallet to Trader's receiving address.
+    // A percentage is resolved against the balance now that the mined coins have matured.
+    // --sweep sends the whole balance instead, the fee coming out of it
+    let balance = miner.get_balance(None, None)?;
+    let amount_to_send = if options.sweep {
+        balance
+    } else {
+        options.amount.resolve(balance)
+    };
+    if !spend_utxos.is_empty() {
+        let unspent = miner.list_unspent(None, None, None, None, None)?;
+        let pinned = pinned_input_total(&unspent, spend_utxos)?;
+        if pinned <= amount_to_send {
+            return Err(CapstoneError::PinnedTooSmall {
+                pinned,
+                amount: amount_to_send,
+            });
+        }
+        info!(
+            "Funding the payment from {} pinned UTXO(s) worth {:.decimals$} BTC",
+            spend_utxos.len(),
+            pinned.to_btc()
+        );
+    }
+
+    // The send_to_address RPC sends the specified amount to the given address (Sends that amount from the Miner wallet to the Trader's address using `send_to_address`. This broadcasts the transaction but doesn't confirm it yet.)
+    // The `send`, `sendrawtransaction` and `sendall` RPCs behind --spend-utxo (or a reused UTXO), --locktime
+    // and --sweep take no comment
+    let commented = spend_utxos.is_empty() && options.locktime.is_none() && !options.sweep;
+    let shares = split_shares(amount_to_send, trader_addresses.len())?;
+    let unlocked = unlock_for_send(miner, options.wallet_passphrase.as_ref())?;
+    let sent = if options.sweep {
+        sweep_to(miner, &trader_address)
+    } else if trader_addresses.len() > 1 {
+        send_split(
+            miner,
+            &trader_addresses,
+            &shares,
+            &options.comment,
+            options.subtract_fee,
+        )
+    } else if let Some(locktime) = options.locktime {
+        send_with_locktime(
+            ctx,
+            miner,
+            miner_address,
+            &trader_address,
+            amount_to_send,
+            locktime,
+            spend_utxos,
+            metrics,
+        )
+    } else if let Some(fee_rate) = options.fee_rate.filter(|_| spend_utxos.is_empty()) {
+        // Only --input-file payments carry a fee rate
+        send_at_fee_rate(
+            miner,
+            &trader_address,
+            amount_to_send,
+            &options.comment,
+            options.subtract_fee,
+            fee_rate,
+        )
+    } else if spend_utxos.is_empty() {
+        miner.send_to_address(
+            &trader_address,
+            amount_to_send,
+            Some(&options.comment),
+            None,
+            Some(options.subtract_fee),
+            None,
+            None,
+            None,
+        )
+    } else {
+        send_from_utxos(miner, &trader_address, amount_to_send, spend_utxos)
+    };
+    if unlocked {
+        // Relock whether or not the send worked, so the wallet isn't left open for UNLOCK_SECONDS
+        miner.call::<()>("walletlock", &[])?;
+        info!("Miner wallet locked again.");
+    }
+    let txid = sent?;
+    info!(
+        "You have Sent {} BTC 🪙 to Trader. TxID: {txid}",
+        amount_to_send.to_btc()
+    );
+    let broadcast_height = miner.get_block_count()?; // Tip when the payment entered the mempool
+    if options.command == Command::Run && !options.no_file {
+        let state = RunState {
+            miner_wallet: options.miner_wallet.clone(),
+            trader_wallet: options.trader_wallet.clone(),
+            settings: RunSettings::from(options),
+            txid,
+            miner_address: miner_address.as_unchecked().clone(),
+            trader_address: trader_address.as_unchecked().clone(),
+            sent: amount_to_send,
+            commented,
+        };
+        let path = state.save(options)?;
+        debug!("Recorded the payment in {}", path.display());
+    }
+    ctx.log_event(
+        "tx_sent",
+        json!({ "txid": txid, "amount": amount_to_send.to_btc(), "to": trader_address.to_string() }),
+    );
+
+    let tx_info = miner.get_transaction(&txid, None)?;
+    info!(
+        "Transaction confirmed in Miner's wallet: {}",
+        tx_info.info.txid
+    );
+    // The wallet knows what it paid; a runaway fee stops the run before we confirm it
+    if let Some(fee) = tx_info.fee {
+        check_fee(fee.unsigned_abs(), options.max_fee, options.allow_high_fee)?;
+    }
+
+    // Check if TX is in mempool (`get_raw_mempool()` is used to confirm if a transaction is pending (i.e., unconfirmed). If it's listed, that means it is awaiting inclusion in a block.)
+    let mempool_snapshot = observe_mempool(miner, &txid); // Kept for the JSON report; gone once the tx confirms
+
+    metrics.phase("confirm");
+    let spinner = WaitSpinner::new("Confirming the payment");
+
+    // On a shared node another process may already have mined our transaction; then we mine nothing
+    let confirmation = if confirmed_elsewhere(miner, &txid)? {
+        spinner.step("Transaction already confirmed by another miner; no confirming block needed");
+        None
+    } else {
+        Some(confirming_reward_address(ctx, options, miner, trader)?)
+    };
+
+    // Mine 1 block to confirm the transaction on a worker thread, tracing the inputs here in the meantime.
+    // The worker gets a connection of its own: sharing `miner` would queue both on its transport's lock.
+    // Block info is only read after the worker has reported the block exists.
+    let unconfirmed_tx = tx_info.transaction()?;
+    let paid_addresses: Vec<String> = trader_addresses.iter().map(Address::to_string).collect();
+    let paid_addresses: Vec<&str> = paid_addresses.iter().map(String::as_str).collect();
+    let trace = || trace_transaction(ctx, miner, &unconfirmed_tx, &paid_addresses);
+    let (mined_hash, details) = match &confirmation {
+        Some((_, confirm_address)) => {
+            let worker = options.rpc.connect_wallet(&options.miner_wallet)?;
+            let (mined, details) = overlap(|| mine_blocks(ctx, &worker, 1, confirm_address), trace);
+            let mined_hash = mined?.last().copied(); // The hash generatetoaddress returned
+            metrics.blocks_mined += 1;
+            spinner.step("1 block has been mined to confirm your transaction");
+            let extra = mine_until_included(
+                CONFIRM_EXTRA_BLOCKS,
+                || Ok(miner.wallet_confirmations(&txid)? >= 1),
+                || {
+                    let hash = mine_blocks(ctx, miner, 1, confirm_address)?[0];
+                    metrics.blocks_mined += 1;
+                    Ok(hash)
+                },
+            )?;
+            (extra.or(mined_hash), details)
+        }
+        None => (None, trace()),
+    };
+    spinner.finish("Payment confirmed");
+
+    metrics.phase("report");
+
+    // Extract transaction details
+    let raw = retry_not_found(|| miner.get_raw_transaction_info(&txid, None))?;
+    verify_vsize(&raw.transaction()?, raw.vsize as u64)?;
+    let hint = confirming_hash(mined_hash, raw.blockhash);
+    let block = confirmed_block(miner, &txid, hint)?;
+    let (block_height, block_hash) = (block.height, block.hash);
+    let mut report = details?.confirmed_at(block);
+    ctx.log_event(
+        "tx_confirmed",
+        json!({ "txid": txid, "height": block_height, "block_hash": block_hash }),
+    );
+    report.mempool = mempool_snapshot;
+    metrics.fee = (!report.inputs_pruned).then_some(report.fee);
+
+    let distance = confirmation_distance(broadcast_height, block_height as u64);
+    info!("Confirmed {distance} block(s) after broadcast (broadcast at height {broadcast_height})");
+    check_confirmed_within(distance, options.assert_confirmed_in)?;
+
+    info!("Trader output type: {}", report.trader_script_type);
+    info!("RBF signaled: {}", report.rbf_signaled);
+    info!("Locktime: {}", LockTime::from_consensus(report.locktime));
+    if let Some(expected) = &options.expect_trader_type {
+        if report.trader_script_type != expected {
+            return Err(CapstoneError::UnexpectedTraderType {
+                found: report.trader_script_type.to_string(),
+                expected: expected.clone(),
+            });
+        }
+    }
+
+    if options.show_scripts {
+        let scripts = input_scripts(&unconfirmed_tx);
+        info!("\nInput scripts:");
+        for input in &scripts {
+            info!("{input}");
+        }
+        report.input_scripts = Some(scripts);
+    }
+    info!("\n{report:.decimals$}");
+
+    // Accounting summary: every satoshi that went in must come out as trader output, change or fee
+    if !report.inputs_pruned {
+        info!("\nAccounting: input {:.decimals$} = trader {:.decimals$} + change {:.decimals$} + fee {:.decimals$} BTC",
+            report.input_amount.to_btc(),
+            report.trader_amount.to_btc(),
+            report.change_amount.to_btc(),
+            report.fee.to_btc()
+        );
+    }
+    info!(
+        "Fee paid by: {}",
+        if options.subtract_fee || options.sweep {
+            "Trader (deducted from the payment)"
+        } else {
+            "Miner (from its change)"
+        }
+    );
+    report.check_balance()?;
+    report.check_trader_amount(amount_to_send, options.subtract_fee || options.sweep)?;
+    if options.sweep {
+        report.check_swept()?;
+    }
+    if trader_addresses.len() == 1 {
+        verify_trader_utxo(miner, &report)?;
+    } else {
+        // The UTXO scan follows one address and one amount; the split's total was checked by check_balance
+        info!(
+            "Trader holds {:.decimals$} BTC across {} addresses",
+            report.trader_amount.to_btc(),
+            trader_addresses.len()
+        );
+    }
+    if options.verify_supply {
+        verify_supply(miner)?;
+    }
+    check_wallet_records(
+        options,
+        miner,
+        trader,
+        &txid,
+        miner_address,
+        &trader_address,
+        commented,
+    )?;
+    print_balance_breakdown(miner, peers, &report, confirmation.as_ref(), decimals)?;
+
+    write_report_files(ctx, options, &report, &raw.hex)?;
+    if options.round_trip {
+        round_trip(ctx, options, miner, trader, report.trader_amount, metrics)?;
+    }
+    if options.stdout_only {
+        // The grader-facing stream: the same ten lines as out.txt and nothing else
+        for line in report.lines_in(options.units) {
+            println!("{line}");
+        }
+    } else {
+        if options.explain {
+            // What each line of out.txt means, for students reading the file for the first time
+            println!();
+            for line in report.explained_lines(options.units) {
+                println!("{line}");
+            }
+        }
+        if !options.quiet {
+            play_celebration_animation(!options.no_file, options.celebrate, !options.no_bell);
+        }
+    }
+    Ok(report)
+
+    /*
+    Each line maps directly to the required fields in the test file; FIELD_EXPLANATIONS spells out what each
+    one means (and --explain prints them next to the values).
+
+    This completes the pipeline from wallet → transaction → confirmation → file output.
+    */
+}
+
+/// Writes `report` through every `--format` sink, plus the `--fee-file` and `--raw-tx-file` extras (`raw` is
+/// the payment's serialized bytes). Nothing is written under `--no-file`.
+pub fn write_report_files(
+    ctx: &Context,
+    options: &Options,
+    report: &TxReport,
+    raw: &[u8],
+) -> Result<(), CapstoneError> {
+    // Carefullly write all 10 required transaction details to the requested path (../out.txt by default)
+    if options.no_file {
+        info!("\n--no-file: report built and checked, nothing written to disk");
+    } else {
+        for sink in report_sinks(options) {
+            sink.write(report)?;
+            let written = fs::canonicalize(sink.path())?;
+            if options.quiet && !options.stdout_only {
+                println!("{}", written.display());
+            }
+            ctx.log_event(
+                "report_written",
+                json!({ "format": sink.format(), "path": written }),
+            );
+            match sink.format() {
+                "txt" => info!(
+                    "\n All required values written to {} for test evaluation",
+                    written.display()
+                ),
+                format => info!(
+                    "{} report written to {}",
+                    format.to_uppercase(),
+                    written.display()
+                ),
+            }
+        }
+        // The JSON report beside out.txt, unless --format json already put it there
+        if options.formats.contains(&ReportFormat::Text)
+            && !options.formats.contains(&ReportFormat::Json)
+        {
+            let written = write_json_report(
+                report,
+                &options.out_path.with_extension("json"),
+                options.normalize,
+            )?;
+            ctx.log_event(
+                "report_written",
+                json!({ "format": "json", "path": written }),
+            );
+            info!("JSON report written to {}", written.display());
+        }
+    }
+    if let Some(path) = options.fee_file.as_ref().filter(|_| !options.no_file) {
+        let written = write_fee_file(report, path, options.units)?;
+        ctx.log_event(
+            "report_written",
+            json!({ "format": "fee", "path": written }),
+        );
+        info!("Fee written to {}", written.display());
+    }
+    if let Some(path) = options.raw_tx_file.as_ref().filter(|_| !options.no_file) {
+        let written = write_raw_tx_file(raw, &report.txid, path)?;
+        ctx.log_event(
+            "report_written",
+            json!({ "format": "hex", "path": written }),
+        );
+        info!("Raw transaction hex written to {}", written.display());
+    }
+    Ok(())
+}
+
+/// `report`: rebuilds the report of an already confirmed Miner payment from `--txid` and writes it like `run`
+/// would, mining and sending nothing. The Trader outputs are those to `--trader-address`, or else every output
+/// the Trader wallet received from the transaction.
+pub fn report_txid(
+    ctx: &Context,
+    options: &Options,
+    miner: &Client,
+    trader: &Client,
+) -> Result<TxReport, CapstoneError> {
+    let decimals = options.display_decimals;
+    let txid = options.txid.ok_or(CapstoneError::MissingFlag {
+        command: "report",
+        flag: "--txid <txid>",
+    })?;
+    let paid_addresses: Vec<String> = match &options.trader_address {
+        Some(address) => vec![address.clone().assume_checked().to_string()],
+        None => trader
+            .get_transaction(&txid, None)
+            .map_err(|err| CapstoneError::NotTraderTransaction {
+                txid,
+                message: err.to_string(),
+            })?
+            .details
+            .into_iter()
+            .filter(|detail| detail.category == GetTransactionResultDetailCategory::Receive)
+            .filter_map(|detail| detail.address)
+            .map(|address| address.assume_checked().to_string())
+            .collect(),
+    };
+    if paid_addresses.is_empty() {
+        return Err(CapstoneError::NothingPaidToTrader(txid));
+    }
+    let paid_addresses: Vec<&str> = paid_addresses.iter().map(String::as_str).collect();
+
+    let report = TxReport::from_txid(ctx, miner, &txid, &paid_addresses)?;
+    report.check_balance()?;
+    info!("\n{report:.decimals$}");
+    let raw = miner.get_raw_transaction_info(&txid, None)?;
+    write_report_files(ctx, options, &report, &raw.hex)?;
+    if options.stdout_only {
+        for line in report.lines_in(options.units) {
+            println!("{line}");
+        }
+    }
+    Ok(report)
+}
+
+/// Picks who gets the confirming block's coinbase: `--confirm-address`, or a fresh "Block Confirmation"
+/// address of the `--confirm-to` wallet. Its own address keeps the reward from being confused with the change.
+/// Returns the owner's name (for the breakdown and event log) with the address.
+fn confirming_reward_address(
+    ctx: &Context,
+    options: &Options,
+    miner: &Client,
+    trader: &Client,
+) -> Result<(&'static str, Address), CapstoneError> {
+    let (reward_owner, confirm_wallet) = match options.confirm_to {
+        ConfirmTo::Miner => ("Miner", miner),
+        ConfirmTo::Trader => ("Trader", trader),
+    };
+    let (reward_owner, confirm_address) = match &options.confirm_address {
+        Some(address) => (
+            "external",
+            address
+                .clone()
+                .require_network(bitcoincore_rpc::bitcoin::Network::Regtest)
+                .map_err(|_| CapstoneError::NotRegtestAddress {
+                    flag: "--confirm-address",
+                    address: format!("{address:?}"),
+                })?,
+        ),
+        None => (
+            reward_owner,
+            confirm_wallet
+                .get_new_address(Some("Block Confirmation"), None)?
+                .require_network(bitcoincore_rpc::bitcoin::Network::Regtest)?,
+        ),
+    };
+    info!("Confirming block reward goes to: {confirm_address}");
+    ctx.log_event(
+        "address_generated",
+        json!({ "wallet": reward_owner, "label": "Block Confirmation", "address": confirm_address.to_string() }),
+    );
+    Ok((reward_owner, confirm_address))
+}
+
+/// Confirms `txid` the way every payment is: one block to the `confirming_reward_address`, then more while
+/// `mine_until_included` still finds the payment left out. Counts them all in `metrics`.
+fn confirm(
+    ctx: &Context,
+    options: &Options,
+    miner: &Client,
+    trader: &Client,
+    txid: &Txid,
+    metrics: &mut Metrics,
+) -> Result<(), CapstoneError> {
+    let (_, confirm_address) = confirming_reward_address(ctx, options, miner, trader)?;
+    mine_blocks(ctx, miner, 1, &confirm_address)?;
+    metrics.blocks_mined += 1;
+    mine_until_included(
+        CONFIRM_EXTRA_BLOCKS,
+        || Ok(miner.wallet_confirmations(txid)? >= 1),
+        || {
+            let hash = mine_blocks(ctx, miner, 1, &confirm_address)?[0];
+            metrics.blocks_mined += 1;
+            Ok(hash)
+        },
+    )?;
+    Ok(())
+}
+
+/// One payment of an `--input-file` batch: `amount` BTC to `address`, or to a fresh "Received" address of
+/// `wallet` (created if missing), at `fee_rate` sat/vB or the wallet's own estimate.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchPayment {
+    #[serde(default)]
+    address: Option<Address<NetworkUnchecked>>,
+    #[serde(default)]
+    wallet: Option<String>,
+    #[serde(with = "bitcoin::amount::serde::as_btc")]
+    amount: Amount,
+    #[serde(default)]
+    fee_rate: Option<f64>,
+}
+
+// A TOML `--input-file`: the payments as an array of `[[payment]]` tables
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BatchFile {
+    #[serde(default)]
+    payment: Vec<BatchPayment>,
+}
+
+/// Reads a `--recipients` file: a JSON object mapping each address to its amount in BTC, or, for a `.csv`
+/// file, `address,amount` rows with an optional `address,amount` header. Every address must be a regtest
+/// address listed once, and every amount positive.
+pub fn load_recipients(path: &Path) -> Result<Vec<(Address, Amount)>, CapstoneError> {
+    let invalid = |message: String| CapstoneError::InvalidFile {
+        what: "--recipients",
+        path: path.to_path_buf(),
+        message,
+    };
+    let text = fs::read_to_string(path).map_err(|err| CapstoneError::UnreadableFile {
+        what: "--recipients",
+        path: path.to_path_buf(),
+        message: err.to_string(),
+    })?;
+    let rows: Vec<(String, String)> = if path.extension().is_some_and(|ext| ext == "csv") {
+        let mut rows = Vec::new();
+        for (n, line) in (1..).zip(text.lines()) {
+            let line = line.trim();
+            if line.is_empty() || (rows.is_empty() && line == "address,amount") {
+                continue;
+            }
+            let (address, amount) = line
+                .split_once(',')
+                .ok_or_else(|| invalid(format!("line {n} is not `address,amount`")))?;
+            rows.push((address.trim().to_string(), amount.trim().to_string()));
+        }
+        rows
+    } else {
+        serde_json::from_str::<BTreeMap<String, serde_json::Number>>(&text)
+            .map_err(|err| invalid(err.to_string()))?
+            .into_iter()
+            .map(|(address, amount)| (address, amount.to_string()))
+            .collect()
+    };
+    parse_recipients(rows).map_err(invalid)
+}
+
+// Validates the (address, amount) text pairs of a recipients file
+fn parse_recipients(rows: Vec<(String, String)>) -> Result<Vec<(Address, Amount)>, String> {
+    if rows.is_empty() {
+        return Err("it lists no recipients".to_string());
+    }
+    let mut recipients: Vec<(Address, Amount)> = Vec::new();
+    for (address, amount) in rows {
+        let parsed = address
+            .parse::<Address<NetworkUnchecked>>()
+            .ok()
+            .and_then(|parsed| parsed.require_network(bitcoin::Network::Regtest).ok())
+            .ok_or_else(|| format!("{address:?} is not a regtest address"))?;
+        if recipients.iter().any(|(known, _)| *known == parsed) {
+            return Err(format!("{address} is listed twice"));
+        }
+        let amount = Amount::from_str_in(&amount, bitcoin::Denomination::Bitcoin)
+            .map_err(|err| format!("{address} has amount {amount:?} ({err})"))?;
+        if amount == Amount::ZERO {
+            return Err(format!("{address} has no amount"));
+        }
+        recipients.push((parsed, amount));
+    }
+    Ok(recipients)
+}
+
+/// `send-many`: pays every `--recipients` entry in a single transaction from the Miner's current balance
+/// (mining nothing to get there), confirms it, and reports it. out.txt reads the recipients as the Trader:
+/// the first one's address and the total paid to all of them; out.json lists every output.
+pub fn send_many_payment(
+    ctx: &Context,
+    options: &Options,
+    miner: &Client,
+    trader: &Client,
+    metrics: &mut Metrics,
+) -> Result<TxReport, CapstoneError> {
+    let decimals = options.display_decimals;
+    let path = options
+        .recipients_file
+        .as_deref()
+        .expect("send-many is parsed with --recipients");
+    let recipients = load_recipients(path)?;
+    let total = recipients
+        .iter()
+        .map(|(_, amount)| *amount)
+        .checked_sum()
+        .ok_or(CapstoneError::AmountOverflow("recipients total"))?;
+    check_spendable(total, miner.get_balance(None, None)?, options.subtract_fee)?;
+
+    let unlocked = unlock_for_send(miner, options.wallet_passphrase.as_ref())?;
+    let sent = send_many(miner, &recipients, &options.comment, options.subtract_fee);
+    if unlocked {
+        miner.call::<()>("walletlock", &[])?;
+    }
+    let txid = sent?;
+    info!(
+        "Sent {:.decimals$} BTC to {} recipients in one transaction. TxID: {txid}",
+        total.to_btc(),
+        recipients.len()
+    );
+    ctx.log_event(
+        "tx_sent",
+        json!({ "txid": txid, "amount": total.to_btc(), "recipients": recipients.len() }),
+    );
+
+    metrics.phase("confirm");
+    confirm(ctx, options, miner, trader, &txid, metrics)?;
+
+    metrics.phase("report");
+    let paid: Vec<String> = recipients
+        .iter()
+        .map(|(address, _)| address.to_string())
+        .collect();
+    let paid: Vec<&str> = paid.iter().map(String::as_str).collect();
+    let mut report = TxReport::from_txid(ctx, miner, &txid, &paid)?;
+    let raw = miner.get_raw_transaction_info(&txid, None)?;
+    let tx = raw.transaction()?;
+    let outputs = paid_outputs(&tx, &sender_owned(miner, &tx)?);
+    report.check_balance()?;
+    report.check_trader_amount(total, options.subtract_fee)?;
+    info!("\n{report:.decimals$}");
+    for output in &outputs {
+        info!(
+            "Output {}: {:.decimals$} BTC to {}{}",
+            output.vout,
+            output.amount.to_btc(),
+            output.address,
+            if output.change { " (change)" } else { "" }
+        );
+    }
+    report.outputs = Some(outputs);
+
+    write_report_files(ctx, options, &report, &raw.hex)?;
+    if options.stdout_only {
+        for line in report.lines_in(options.units) {
+            println!("{line}");
+        }
+    }
+    Ok(report)
+}
+
+/// Reads and validates an `--input-file` batch: a JSON array of payments or, for a `.toml` file, one
+/// `[[payment]]` table each. Every payment must have a positive amount, exactly one recipient (a regtest address
+/// or a wallet name) and a positive fee rate if any.
+pub fn load_payments(path: &Path) -> Result<Vec<BatchPayment>, CapstoneError> {
+    let invalid = |message: String| CapstoneError::InvalidFile {
+        what: "--input-file",
+        path: path.to_path_buf(),
+        message,
+    };
+    let text = fs::read_to_string(path).map_err(|err| CapstoneError::UnreadableFile {
+        what: "--input-file",
+        path: path.to_path_buf(),
+        message: err.to_string(),
+    })?;
+    let payments = if path.extension().is_some_and(|ext| ext == "toml") {
+        toml::from_str::<BatchFile>(&text)
+            .map_err(|err| invalid(toml_message(&text, &err)))?
+            .payment
+    } else {
+        serde_json::from_str(&text).map_err(|err| invalid(err.to_string()))?
+    };
+    validate_payments(&payments).map_err(invalid)?;
+    Ok(payments)
+}
+
+fn validate_payments(payments: &[BatchPayment]) -> Result<(), String> {
+    if payments.is_empty() {
+        return Err("it lists no payments".to_string());
+    }
+    for (n, payment) in (1..).zip(payments) {
+        if payment.amount == Amount::ZERO {
+            return Err(format!("payment {n} has no amount"));
+        }
+        match (&payment.address, &payment.wallet) {
+            (Some(address), None) => {
+                if !address.is_valid_for_network(bitcoin::Network::Regtest) {
+                    return Err(format!(
+                        "payment {n} pays {address:?}, which is not a regtest address"
+                    ));
+                }
+            }
+            (None, Some(wallet)) if !wallet.is_empty() => {}
+            _ => {
+                return Err(format!(
+                    "payment {n} needs exactly one of \"address\" and \"wallet\""
+                ))
+            }
+        }
+        if let Some(rate) = payment
+            .fee_rate
+            .filter(|rate| !(rate.is_finite() && *rate > 0.0))
+        {
+            return Err(format!(
+                "payment {n} has fee rate {rate}; it must be positive"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// `--input-file`: funds the Miner once for the whole batch, then makes each payment in turn, each confirmed
+/// by a block of its own on top of the previous payment's, and writes its reports to `out_<n>` paths. A
+/// payment an earlier run of the batch already made is resumed instead. Returns the last payment's report;
+/// the first failure stops the batch.
+#[allow(clippy::too_many_arguments)]
+pub fn run_batch(
+    ctx: &Context,
+    options: &Options,
+    payments: &[BatchPayment],
+    rpc: &Client,
+    miner: &Client,
+    peers: &[(String, Client)],
+    trader: &Client,
+    metrics: &mut Metrics,
+) -> Result<TxReport, CapstoneError> {
+    let mut funded: Option<(Address, Vec<OutPoint>)> = None;
+    let mut last = None;
+    for (n, payment) in (1..).zip(payments) {
+        info!(
+            "\nPayment {n} of {}: {} BTC to {}",
+            payments.len(),
+            payment.amount.to_btc(),
+            match (&payment.address, &payment.wallet) {
+                (Some(address), _) => address.clone().assume_checked().to_string(),
+                (None, wallet) => format!(
+                    "wallet {}",
+                    wallet.as_deref().unwrap_or(&options.trader_wallet)
+                ),
+            }
+        );
+        let recipient = match payment.wallet.as_deref() {
+            Some(name) if name != options.trader_wallet => {
+                ensure_wallet_exists(ctx, rpc, name)?;
+                Some(options.rpc.connect_wallet(name)?)
+            }
+            _ => None,
+        };
+        let recipient = recipient.as_ref().unwrap_or(trader);
+        let options = batch_options(options, payment, n, n == payments.len());
+        if options.command == Command::Run && !options.fresh {
+            if let Some(report) = resume(ctx, &options, miner, recipient, metrics)? {
+                last = Some(report);
+                continue;
+            }
+        }
+        if funded.is_none() {
+            // Everything still to pay, mined for (or checked) at once: one maturity run for the batch
+            let remaining = payments[n - 1..]
+                .iter()
+                .map(|payment| payment.amount)
+                .checked_sum()
+                .ok_or(CapstoneError::AmountOverflow("batch total"))?;
+            let batch = Options {
+                amount: SendAmount::Absolute(remaining),
+                ..options.clone()
+            };
+            funded = Some(fund_miner(ctx, &batch, miner, peers, metrics)?);
+        }
+        let (miner_address, spend_utxos) = funded.as_mut().expect("funded above");
+        // A pinned UTXO pays the first payment only; the later ones spend what the Miner holds then
+        let spend_utxos = std::mem::take(spend_utxos);
+        let report = pay(
+            ctx,
+            &options,
+            miner,
+            peers,
+            recipient,
+            miner_address,
+            &spend_utxos,
+            metrics,
+        )?;
+        last = Some(report);
+    }
+    Ok(last.expect("validated batches are never empty"))
+}
+
+// The options payment `n` of a batch is made with: its recipient, amount and fee rate, numbered output
+// paths, and the closing animation only after the last payment
+fn batch_options(options: &Options, payment: &BatchPayment, n: usize, last: bool) -> Options {
+    let mut options = options.clone();
+    options.amount = SendAmount::Absolute(payment.amount);
+    options.trader_address = payment.address.clone();
+    options.fee_rate = payment.fee_rate;
+    options.out_path = numbered_path(&options.out_path, n);
+    options.fee_file = options.fee_file.map(|path| numbered_path(&path, n));
+    options.raw_tx_file = options.raw_tx_file.map(|path| numbered_path(&path, n));
+    if !last {
+        options.celebrate = Duration::ZERO;
+    }
+    options
+}
+
+// `out.txt` → `out_<n>.txt`, keeping the directory
+fn numbered_path(path: &Path, n: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(match path.extension() {
+        Some(extension) => format!("{stem}_{n}.{}", extension.to_string_lossy()),
+        None => format!("{stem}_{n}"),
+    })
+}
+
+/// The regtest funding path of `run`: mines to `miner_address` (round-robin with any `--miners` peers)
+/// until the Miner's first coinbase has matured into a spendable balance.
+fn mine_to_maturity(
+    ctx: &Context,
+    options: &Options,
+    miner: &Client,
+    miner_address: &Address,
+    peers: &[(String, Client)],
+    metrics: &mut Metrics,
+) -> Result<(), CapstoneError> {
+    // Every miner mines to its own reward address; the Miner comes first so it still owns block 1
+    let mut miners = vec![(miner, miner_address.clone())];
+    for (name, wallet) in peers {
+        let address = wallet
+            .get_new_address(Some(&options.mining_label), None)?
+            .require_network(bitcoincore_rpc::bitcoin::Network::Regtest)?;
+        info!("{name} address: {address}");
+        ctx.log_event(
+            "address_generated",
+            json!({ "wallet": name, "label": options.mining_label, "address": address.to_string() }),
+        );
+        miners.push((wallet, address));
+    }
+
+    // Coins the Miner already holds don't count: mining goes on until a reward of this run has matured
+    let start_balance = miner.get_balance(None, None)?;
+    let burn_address = burn_address();
+    if options.maturity_to_burn {
+        info!("Burning the maturity block rewards to {burn_address}");
+    }
+
+    // Mine blocks until coinbase reward is spendable (requires maturity of 100 blocks)
+    let target_blocks = options.maturity + 1; // The first coinbase matures once `maturity` blocks sit on top of it
+    let max_blocks = options.max_blocks.unwrap_or(target_blocks + MATURITY_SLACK); // Safety limit
+    let progress = MiningProgress::new(target_blocks);
+
+    // Mine 1 block at a time, checking the spendable balance every --check-every blocks
+    let blocks_mined = mine_until_spendable(
+        target_blocks,
+        max_blocks,
+        options.check_every,
+        |blocks_mined| {
+            match maturity_recipient(blocks_mined, miners.len(), options.maturity_to_burn) {
+                Some(turn) => {
+                    let (wallet, address) = &miners[turn];
+                    mine_blocks(ctx, wallet, 1, address)?;
+                }
+                None => {
+                    mine_blocks(ctx, miner, 1, &burn_address)?;
+                }
+            }
+            metrics.blocks_mined += 1;
+            Ok(())
+        },
+        |blocks_mined| {
+            thread::sleep(options.poll_interval);
+            let balance = miner.get_balance(None, None)?;
+            progress.update(blocks_mined, balance);
+            Ok(balance.checked_sub(start_balance).unwrap_or(Amount::ZERO))
+        },
+    )?;
+    progress.finish();
+    info!("Spendable balance achieved after {blocks_mined} blocks mined.");
+    Ok(())
+}
+
+/// `--dry-run`: builds and signs the payment `run` would make from what the Miner holds now, prints its
+/// txid, fee and outputs, and stops there: nothing is mined, broadcast or written. A Miner without the coins
+/// (a fresh chain, before `run` has mined to maturity) gets the mining it would need described instead.
+pub fn dry_run(options: &Options, miner: &Client, trader: &Client) -> Result<(), CapstoneError> {
+    let balance = miner.get_balance(None, None)?;
+    let amount = options.amount.resolve(balance);
+    if balance == Amount::ZERO || amount > balance {
+        info!(
+            "Dry run: the Miner has {:.8} BTC spendable, short of the payment; `run` would first mine {} blocks to mature a block reward",
+            balance.to_btc(),
+            options.maturity + 1
+        );
+        return Ok(());
+    }
+    let trader_address = match &options.trader_address {
+        Some(address) => address
+            .clone()
+            .require_network(bitcoincore_rpc::bitcoin::Network::Regtest)
+            .map_err(|_| CapstoneError::NotRegtestAddress {
+                flag: "--trader-address",
+                address: format!("{address:?}"),
+            })?,
+        None => trader
+            .get_new_address(Some(&options.received_label), None)?
+            .require_network(bitcoincore_rpc::bitcoin::Network::Regtest)?,
+    };
+    let unlocked = unlock_for_send(miner, options.wallet_passphrase.as_ref())?;
+    let built = build_unbroadcast(miner, &trader_address, amount, options.subtract_fee);
+    if unlocked {
+        miner.call::<()>("walletlock", &[])?;
+    }
+    let (tx, fee) = built?;
+
+    info!("Dry run: nothing was broadcast or mined");
+    info!("Would-be txid: {}", tx.compute_txid());
+    info!("Fee: {:.8} BTC ({} vB)", fee.to_btc(), tx.vsize());
+    for (vout, output) in tx.output.iter().enumerate() {
+        let to = Address::from_script(&output.script_pubkey, bitcoin::Network::Regtest)
+            .map_or_else(
+                |_| output.script_pubkey.to_string(),
+                |address| address.to_string(),
+            );
+        let role = if to == trader_address.to_string() {
+            "Trader"
+        } else {
+            "change"
+        };
+        info!(
+            "Output {vout}: {:.8} BTC to {to} ({role})",
+            output.value.to_btc()
+        );
+    }
+    Ok(())
+}
+
+/// Runs `background` on a worker thread while `foreground` runs on this one, and returns both results.
+/// The worker's result only arrives over a bounded channel once it is complete, so nothing that
+/// depends on it (like reading a freshly mined block) can run before the worker is done.
+fn overlap<B: Send, F>(
+    background: impl FnOnce() -> B + Send,
+    foreground: impl FnOnce() -> F,
+) -> (B, F) {
+    // The worker logs under the caller's stage too
+    let stage = tracing::Span::current();
+    thread::scope(|scope| {
+        let (done, signal) = mpsc::sync_channel(1);
+        scope.spawn(move || {
+            let _ = done.send(stage.in_scope(background));
+        });
+        let local = foreground();
+        let remote = signal
+            .recv()
+            .expect("worker thread exited without a result");
+        (remote, local)
+    })
+}
+
+// Three turns of the spinner at the base frame delay, the animation's original length
+pub const DEFAULT_CELEBRATION: Duration = Duration::from_millis(7_200);
+const FRAME_DELAY: Duration = Duration::from_millis(150);
+const FRAME_JITTER_MS: u64 = 50; // Each frame lasts FRAME_DELAY give or take this much
+
+/// The delay before the next frame: FRAME_DELAY shifted by up to FRAME_JITTER_MS either way, picked by
+/// `seed` (a small xorshift, so the moons don't tick like a metronome).
+fn jittered_delay(seed: u64) -> Duration {
+    let mut x = seed | 1;
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    let offset = x % (2 * FRAME_JITTER_MS + 1);
+    FRAME_DELAY + Duration::from_millis(offset) - Duration::from_millis(FRAME_JITTER_MS)
+}
+
+/// While installed, Ctrl-C sets INTERRUPTED instead of killing the process, so a loop polling it can wind
+/// down cleanly; installing clears the flag, and `false` restores the default disposition.
+#[cfg(unix)]
+pub fn catch_ctrl_c(install: bool) {
+    extern "C" fn cancel(_: libc::c_int) {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+    }
+    if install {
+        INTERRUPTED.store(false, Ordering::SeqCst);
+    }
+    let handler = if install {
+        cancel as extern "C" fn(libc::c_int) as libc::sighandler_t
+    } else {
+        libc::SIG_DFL
+    };
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn catch_ctrl_c(install: bool) {
+    if install {
+        INTERRUPTED.store(false, Ordering::SeqCst);
+    }
+}
+
+// The celebration's frames, in order
+const CELEBRATION_FRAMES: [&str; 16] = [
+    "🌕", "🌖", "😮", "🌗", "🌘", "🤭", "🌑", "🌒", "🥰", "🌓", "😆", "😅", "😂", "🤣", "🌔", "🤑",
+];
+
+// Animation for better user experience as transaaction processed. Only on a terminal: piped or logged
+// output gets just the closing line, without the redraws and bells.
+fn play_celebration_animation(saved: bool, duration: Duration, bell: bool) {
+    if std::io::IsTerminal::is_terminal(&stdout()) && !duration.is_zero() {
+        animate_celebration(duration, bell);
+    }
+
+    if saved {
+        info!("Your Transaction is confirmed and saved successfully! 🙂, Now you can go 🙄");
+    } else {
+        info!("Your Transaction is confirmed and validated successfully! 🙂 (--no-file: nothing saved)");
+    }
+}
+
+// The frames, one per jittered delay until `duration` is up or Ctrl-C, drawn by indicatif's spinner
+#[cfg(feature = "progress")]
+fn animate_celebration(duration: Duration, bell: bool) {
+    // indicatif shows the last tick string once finished; the line is cleared instead, so it's never seen
+    let ticks: Vec<&str> = CELEBRATION_FRAMES.iter().copied().chain([""]).collect();
+    let spinner = indicatif::ProgressBar::new_spinner().with_style(
+        indicatif::ProgressStyle::with_template("Celebrating success {spinner}")
+            .expect("celebration template is valid")
+            .tick_strings(&ticks),
+    );
+    play_frames(duration, |_| {
+        spinner.tick();
+        if bell {
+            print!("\x07"); // Plays bell sound
+            let _ = stdout().flush();
+        }
+    });
+    spinner.finish_and_clear();
+}
+
+// Without the `progress` feature the line is redrawn by hand
+#[cfg(not(feature = "progress"))]
+fn animate_celebration(duration: Duration, bell: bool) {
+    let mut stdout = stdout();
+    print!("\x1b[?25l"); // Hide the cursor while the spinner redraws the line
+    play_frames(duration, |frame| {
+        print!(
+            "\rCelebrating success {}",
+            CELEBRATION_FRAMES[frame % CELEBRATION_FRAMES.len()]
+        );
+        if bell {
+            print!("\x07"); // Plays bell sound
+        }
+        let _ = stdout.flush();
+    });
+    // Clear the spinner line and bring the cursor back, whether the animation finished or was cut short
+    print!("\r\x1b[2K\x1b[?25h");
+    let _ = stdout.flush();
+}
+
+// The frames as the terminal sees them: seeded from the clock, really slept between, and cut short by Ctrl-C
+fn play_frames(duration: Duration, draw: impl FnMut(usize)) {
+    let seed = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_nanos() as u64);
+    catch_ctrl_c(true);
+    celebration_frames(
+        duration,
+        seed,
+        || INTERRUPTED.load(Ordering::SeqCst),
+        thread::sleep,
+        draw,
+    );
+    catch_ctrl_c(false);
+}
+
+// Calls `draw` with frame numbers 0, 1, ..., `sleep`ing a delay jittered from `seed` after each, until the
+// delays add up to `duration` or `stopped`
+fn celebration_frames(
+    duration: Duration,
+    mut seed: u64,
+    stopped: impl Fn() -> bool,
+    mut sleep: impl FnMut(Duration),
+    mut draw: impl FnMut(usize),
+) {
+    let mut elapsed = Duration::ZERO;
+    let mut frame = 0;
+    while !stopped() && elapsed < duration {
+        draw(frame);
+        seed = seed.wrapping_add(frame as u64);
+        let delay = jittered_delay(seed).min(duration - elapsed);
+        sleep(delay);
+        elapsed += delay;
+        frame += 1;
+    }
+}
+
+/// The reverse payment behind --round-trip: the Trader sends half of what it received back to a fresh
+/// Miner address, it is confirmed like the first payment, and its report goes to `out_roundtrip.txt`.
+/// Extraction runs against the Trader wallet, so Trader-owned outputs are the change and the Miner address
+/// is the external payee.
+pub fn round_trip(
+    ctx: &Context,
+    options: &Options,
+    miner: &Client,
+    trader: &Client,
+    received: Amount,
+    metrics: &mut Metrics,
+) -> Result<TxReport, CapstoneError> {
+    let decimals = options.display_decimals;
+    let miner_address = miner
+        .get_new_address(Some("Round Trip"), None)?
+        .require_network(bitcoincore_rpc::bitcoin::Network::Regtest)?;
+    let amount = received / 2;
+    let txid = trader.send_to_address(
+        &miner_address,
+        amount,
+        Some("Round trip to Miner"),
+        None,
+        None,
+        None,
+        None,
+        None,
+    )?;
+    info!(
+        "\nRound trip: Trader sent {} BTC back to Miner. TxID: {txid}",
+        amount.to_btc()
+    );
+    ctx.log_event(
+        "tx_sent",
+        json!({ "txid": txid, "amount": amount.to_btc(), "to": miner_address.to_string() }),
+    );
+
+    confirm(ctx, options, miner, trader, &txid, metrics)?;
+    let report = TxReport::from_txid(ctx, trader, &txid, &[&miner_address.to_string()])?;
+    ctx.log_event(
+        "tx_confirmed",
+        json!({ "txid": txid, "height": report.block_height, "block_hash": report.block_hash }),
+    );
+    info!("\n{report:.decimals$}");
+    report.check_balance()?;
+    if options.no_file {
+        return Ok(report);
+    }
+
+    let written = write_report(&report, &roundtrip_path(&options.out_path), options.units)?;
+    ctx.log_event(
+        "report_written",
+        json!({ "format": "txt", "path": written }),
+    );
+    info!("Round-trip report written to {}", written.display());
+    Ok(report)
+}
+
+// out.txt -> out_roundtrip.txt, in the same directory as the main report
+fn roundtrip_path(out_path: &Path) -> PathBuf {
+    let stem = out_path.file_stem().unwrap_or_default().to_string_lossy();
+    match out_path.extension() {
+        Some(ext) => out_path.with_file_name(format!("{stem}_roundtrip.{}", ext.to_string_lossy())),
+        None => out_path.with_file_name(format!("{stem}_roundtrip")),
+    }
+}
+
+// out.txt -> out_state.json, the run state kept beside the main report
+fn state_path(out_path: &Path) -> PathBuf {
+    let stem = out_path.file_stem().unwrap_or_default().to_string_lossy();
+    out_path.with_file_name(format!("{stem}_state.json"))
+}
+
+/// What `run` records in `state_path` once its payment is broadcast, so that a re-run against the same node
+/// finishes that payment instead of mining and paying all over again.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct RunState {
+    miner_wallet: String,
+    trader_wallet: String,
+    settings: RunSettings,
+    txid: Txid,
+    miner_address: Address<NetworkUnchecked>, // The reward address, labelled --mining-label
+    trader_address: Address<NetworkUnchecked>, // The first one paid, reported in out.txt
+    #[serde(with = "bitcoin::amount::serde::as_btc")]
+    sent: Amount, // The payment with a percentage or --sweep resolved
+    commented: bool,                          // Whether the send took --comment
+}
+
+/// The options that decide what `run` pays and to whom. A re-run only resumes a payment made with the same
+/// ones; with any of them changed the earlier payment is not the one asked for.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct RunSettings {
+    amount: SendAmount,
+    sweep: bool,
+    subtract_fee: bool,
+    trader_address: Option<String>,
+    trader_addresses: usize,
+    locktime: Option<u32>,
+    spend_utxos: Vec<OutPoint>,
+    reuse_utxo: bool,
+    input_file: Option<PathBuf>,
+    route: Vec<String>,
+    mining_label: String,
+    received_label: String,
+    comment: String,
+}
+
+impl From<&Options> for RunSettings {
+    fn from(options: &Options) -> Self {
+        RunSettings {
+            amount: options.amount,
+            sweep: options.sweep,
+            subtract_fee: options.subtract_fee,
+            trader_address: options
+                .trader_address
+                .as_ref()
+                .map(|address| address.clone().assume_checked().to_string()),
+            trader_addresses: options.trader_addresses,
+            locktime: options.locktime.map(LockTime::to_consensus_u32),
+            spend_utxos: options.spend_utxos.clone(),
+            reuse_utxo: options.reuse_utxo,
+            input_file: options.input_file.clone(),
+            route: options.route.clone(),
+            mining_label: options.mining_label.clone(),
+            received_label: options.received_label.clone(),
+            comment: options.comment.clone(),
+        }
+    }
+}
+
+impl RunState {
+    // The state an earlier run with these wallets and settings left beside out.txt, if any; an unreadable file
+    // is ignored
+    fn load(options: &Options) -> Option<RunState> {
+        let path = state_path(&options.out_path);
+        let text = fs::read_to_string(&path).ok()?;
+        match serde_json::from_str::<RunState>(&text) {
+            Ok(state)
+                if state.miner_wallet != options.miner_wallet
+                    || state.trader_wallet != options.trader_wallet =>
+            {
+                None // Another Miner/Trader pair's run
+            }
+            Ok(state) if state.settings != RunSettings::from(options) => {
+                info!("The earlier run's payment {} was made with other options; paying again instead of resuming it",
+                    state.txid
+                );
+                None
+            }
+            Ok(state) => Some(state),
+            Err(err) => {
+                warn!(
+                    "⚠️ Ignoring unreadable run state {} ({err})",
+                    path.display()
+                );
+                None
+            }
+        }
+    }
+
+    fn save(&self, options: &Options) -> Result<PathBuf, CapstoneError> {
+        let path = state_path(&options.out_path);
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&path, serde_json::to_string_pretty(self)? + "\n")?;
+        Ok(path)
+    }
+}
+
+/// Picks up the payment an earlier `run` recorded, if this node's Miner wallet still has it: one still in the
+/// mempool is confirmed first, then its report is rebuilt and written as `report` would. `None` when there is
+/// nothing to resume (no state, a new chain, or a payment that was replaced), and the run starts over.
+pub fn resume(
+    ctx: &Context,
+    options: &Options,
+    miner: &Client,
+    trader: &Client,
+    metrics: &mut Metrics,
+) -> Result<Option<TxReport>, CapstoneError> {
+    let Some(state) = RunState::load(options) else {
+        return Ok(None);
+    };
+    let txid = state.txid;
+    let confirmations = match miner.wallet_confirmations(&txid) {
+        Ok(confirmations) => confirmations,
+        Err(_) => {
+            info!("The Miner doesn't know the earlier run's payment {txid} (a new chain?); starting over");
+            return Ok(None);
+        }
+    };
+    if confirmations < 0 {
+        warn!("⚠️ The earlier run's payment {txid} conflicts with the chain; paying again");
+        return Ok(None);
+    }
+    if confirmations == 0 {
+        metrics.phase("confirm");
+        info!("Resuming the earlier run: its payment {txid} is still unconfirmed; confirming it");
+        let (_, confirm_address) = confirming_reward_address(ctx, options, miner, trader)?;
+        mine_until_included(
+            CONFIRM_EXTRA_BLOCKS + 1,
+            || Ok(miner.wallet_confirmations(&txid)? >= 1),
+            || {
+                let hash = mine_blocks(ctx, miner, 1, &confirm_address)?[0];
+                metrics.blocks_mined += 1;
+                Ok(hash)
+            },
+        )?;
+    } else {
+        info!("Resuming the earlier run: its payment {txid} is already confirmed; rewriting its report without paying again (--fresh pays again)");
+    }
+    metrics.phase("report");
+    let resumed = Options {
+        txid: Some(txid),
+        ..options.clone()
+    };
+    let report = report_txid(ctx, &resumed, miner, trader)?;
+    // The checks `run` made once the payment confirmed, which the interrupted run may never have reached
+    report.check_trader_amount(state.sent, options.subtract_fee || options.sweep)?;
+    check_wallet_records(
+        options,
+        miner,
+        trader,
+        &txid,
+        &state.miner_address.assume_checked(),
+        &state.trader_address.assume_checked(),
+        state.commented,
+    )?;
+    Ok(Some(report))
+}
+
+/// Checks what the wallets recorded of the payment `txid`: the Miner's comment when the send took one
+/// (`commented`), and the labels of the Miner's reward address and, unless paid to --trader-address, the
+/// Trader's receiving address.
+fn check_wallet_records(
+    options: &Options,
+    miner: &Client,
+    trader: &Client,
+    txid: &Txid,
+    miner_address: &Address,
+    trader_address: &Address,
+    commented: bool,
+) -> Result<(), CapstoneError> {
+    check_label(miner, "Miner", miner_address, &options.mining_label)?;
+    if commented {
+        check_comment(wallet_comment(miner, txid)?.as_deref(), &options.comment)?;
+        info!("Miner wallet comment: \"{}\"", options.comment);
+    }
+    if options.trader_address.is_none() {
+        check_label(trader, "Trader", trader_address, &options.received_label)?;
+    }
+    Ok(())
+}
+
+/// Every file `run` with these options may write: the reports, the fee and hex files, the event log,
+/// and a `.tmp` sibling of each as an interrupted write would leave it.
+fn run_artifacts(options: &Options) -> Vec<PathBuf> {
+    let mut outputs = vec![
+        options.out_path.clone(),
+        options.out_path.with_extension("json"),
+        options.out_path.with_extension("csv"),
+        roundtrip_path(&options.out_path),
+        state_path(&options.out_path),
+    ];
+    outputs.extend(
+        [&options.fee_file, &options.raw_tx_file, &options.event_log]
+            .into_iter()
+            .flatten()
+            .cloned(),
+    );
+    let temps: Vec<PathBuf> = outputs
+        .iter()
+        .map(|path| {
+            let mut temp = path.clone().into_os_string();
+            temp.push(".tmp");
+            PathBuf::from(temp)
+        })
+        .collect();
+    outputs.extend(temps);
+    outputs
+}
+
+/// `--clean`: deletes whichever of `run_artifacts` exist and returns them. Only those exact paths are
+/// touched, never anything else in their directories.
+pub fn clean_stale_artifacts(options: &Options) -> std::io::Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    for path in run_artifacts(options) {
+        match fs::remove_file(&path) {
+            Ok(()) => removed.push(path),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(removed)
+}
+
+/// Shows where the Miner's coins now sit, separating the payment's change from the confirming block's
+/// coinbase (subsidy plus our fee), which is still immature. With `--miners`, the other miners' holdings follow.
+fn print_balance_breakdown(
+    miner: &Client,
+    peers: &[(String, Client)],
+    report: &TxReport,
+    confirmation: Option<&(&str, Address)>,
+    decimals: usize,
+) -> Result<(), CapstoneError> {
+    let block = miner.get_block(&report.block_hash)?;
+    let coinbase_reward: Amount = block
+        .txdata
+        .first()
+        .ok_or(CapstoneError::NoCoinbase(report.block_hash))?
+        .output
+        .iter()
+        .map(|out| out.value)
+        .sum();
+    let balances = miner.get_balances()?;
+
+    info!("\nMiner balance breakdown:");
+    info!(
+        "Change from the payment: {:.decimals$} BTC → {}",
+        report.change_amount.to_btc(),
+        report.change_address
+    );
+    match confirmation {
+        Some((reward_owner, confirm_address)) => info!("Confirming block reward (immature, {reward_owner}): {:.decimals$} BTC → {confirm_address}",
+            coinbase_reward.to_btc()
+        ),
+        None => info!("Confirming block reward: {:.decimals$} BTC, mined by another process (not ours)",
+            coinbase_reward.to_btc()
+        ),
+    }
+    info!(
+        "Spendable: {:.decimals$} BTC",
+        balances.mine.trusted.to_btc()
+    );
+    info!(
+        "Immature coinbase: {:.decimals$} BTC",
+        balances.mine.immature.to_btc()
+    );
+    for (name, wallet) in peers {
+        let balances = wallet.get_balances()?;
+        info!(
+            "{name}: spendable {:.decimals$} BTC, immature {:.decimals$} BTC",
+            balances.mine.trusted.to_btc(),
+            balances.mine.immature.to_btc()
+        );
+    }
+    Ok(())
+}
+
+/// One sink per `--format`, in the order given; JSON and CSV sit next to out.txt with their own extension.
+fn report_sinks(options: &Options) -> Vec<Box<dyn ReportSink>> {
+    options
+        .formats
+        .iter()
+        .map(|format| -> Box<dyn ReportSink> {
+            match format {
+                ReportFormat::Text => Box::new(TextSink {
+                    path: options.out_path.clone(),
+                    units: options.units,
+                }),
+                ReportFormat::Json => Box::new(JsonSink {
+                    path: options.out_path.with_extension("json"),
+                    normalize: options.normalize,
+                }),
+                ReportFormat::Csv => Box::new(CsvSink {
+                    path: options.out_path.with_extension("csv"),
+                    units: options.units,
+                }),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use std::collections::HashSet;
+    use std::path::Path;
+
+    /// Keeps the lines of every report it is handed, standing in for a format that writes nowhere.
+    struct RecordingSink(std::cell::RefCell<Vec<[String; 10]>>);
+
+    impl ReportSink for RecordingSink {
+        fn format(&self) -> &'static str {
+            "recording"
+        }
+
+        fn path(&self) -> &Path {
+            Path::new("")
+        }
+
+        fn write(&self, report: &TxReport) -> Result<(), CapstoneError> {
+            self.0.borrow_mut().push(report.lines());
+            Ok(())
+        }
+    }
+
+    // The committed snapshot of a fresh run's normalized out.json (also checked in `report`)
+    const GOLDEN_REPORT: &str = include_str!("../golden/out.json");
+
+    #[test]
+    #[ignore = "needs a running regtest node"]
+    fn report_rewrites_the_report_of_a_confirmed_payment() {
+        let (miner, trader) = (node_miner(), node_trader());
+        let dir = std::env::temp_dir().join(format!("capstone-stages-{}", std::process::id()));
+        let sent = Options {
+            command: Command::Send,
+            amount: SendAmount::Absolute(Amount::from_int_btc(1)),
+            out_path: dir.join("sent.txt"),
+            celebrate: Duration::ZERO,
+            stdout_only: true,
+            ..Options::default()
+        };
+        let address = miner.get_new_address(None, None).unwrap().assume_checked();
+        mine_blocks(&Context::default(), &miner, COINBASE_MATURITY + 1, &address).unwrap();
+        let report = run(
+            &Context::default(),
+            &sent,
+            &miner,
+            &[],
+            &trader,
+            &mut Metrics::new(),
+        )
+        .unwrap();
+
+        let rebuilt = Options {
+            command: Command::Report,
+            txid: Some(report.txid),
+            out_path: dir.join("rebuilt.txt"),
+            ..sent
+        };
+        let rebuilt = report_txid(&Context::default(), &rebuilt, &miner, &trader).unwrap();
+        assert_eq!(rebuilt.lines(), report.lines());
+        let written = fs::read_to_string(dir.join("sent.txt")).unwrap();
+        let rewritten = fs::read_to_string(dir.join("rebuilt.txt")).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+        assert_eq!(written, rewritten);
+    }
+
+    #[test]
+    #[ignore = "needs a fresh regtest node"]
+    fn maturity_to_burn_mines_just_one_reward_to_the_miner() {
+        let miner = node_miner();
+        let options = Options {
+            maturity_to_burn: true,
+            ..Options::default()
+        };
+        let address = miner.get_new_address(None, None).unwrap().assume_checked();
+        mine_to_maturity(
+            &Context::default(),
+            &options,
+            &miner,
+            &address,
+            &[],
+            &mut Metrics::new(),
+        )
+        .unwrap();
+
+        let balances = miner.get_balances().unwrap();
+        assert_eq!(balances.mine.trusted, Amount::from_int_btc(50));
+        assert_eq!(balances.mine.immature, Amount::ZERO);
+    }
+
+    #[test]
+    #[ignore = "needs a running regtest node"]
+    fn input_file_runs_a_two_payment_batch() {
+        let rpc = RpcSettings::default().connect().unwrap();
+        let (miner, trader) = (node_miner(), node_trader());
+        let dir = std::env::temp_dir().join(format!("capstone-batch-run-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let address = trader.get_new_address(None, None).unwrap().assume_checked();
+        let path = dir.join("payments.json");
+        fs::write(
+            &path,
+            format!(r#"[{{"wallet": "Trader", "amount": 1.5}}, {{"address": "{address}", "amount": 2, "fee_rate": 2}}]"#),
+        )
+        .unwrap();
+        let options = Options {
+            out_path: dir.join("out.txt"),
+            celebrate: Duration::ZERO,
+            stdout_only: true,
+            ..Options::default()
+        };
+        let payments = load_payments(&path).unwrap();
+        let last = run_batch(
+            &Context::default(),
+            &options,
+            &payments,
+            &rpc,
+            &miner,
+            &[],
+            &trader,
+            &mut Metrics::new(),
+        )
+        .unwrap();
+
+        let first = fs::read_to_string(dir.join("out_1.txt")).unwrap();
+        let second = fs::read_to_string(dir.join("out_2.txt")).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+        assert_eq!(first.lines().nth(4), Some("1.50000000"));
+        assert_eq!(second.lines().nth(4), Some("2.00000000"));
+        assert_eq!(second.lines().next(), Some(last.txid.to_string().as_str()));
+        assert_eq!(last.trader_address, address.to_string());
+        // Mined to maturity once, then the second payment's confirming block sits right on the first one's
+        let heights: Vec<u64> = [&first, &second]
+            .iter()
+            .map(|report| report.lines().nth(8).unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(heights[1], heights[0] + 1);
+    }
+
+    #[test]
+    fn overlap_delivers_background_result_only_when_done() {
+        let block_exists = AtomicBool::new(false);
+        let (mined, prepared) = overlap(
+            || {
+                thread::sleep(Duration::from_millis(50));
+                block_exists.store(true, Ordering::SeqCst);
+                "block"
+            },
+            || "scaffolding",
+        );
+
+        // By the time the background result is in hand, its side effect must be visible
+        assert!(block_exists.load(Ordering::SeqCst));
+        assert_eq!((mined, prepared), ("block", "scaffolding"));
+    }
+
+    #[test]
+    fn clean_removes_only_our_stale_artifacts() {
+        let dir = std::env::temp_dir().join(format!("capstone-clean-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let options = Options {
+            clean: true,
+            out_path: dir.join("out.txt"),
+            event_log: Some(dir.join("events.log")),
+            ..Options::default()
+        };
+        let stale = ["out.txt.tmp", "out.json", "out_state.json", "events.log"];
+        let unrelated = ["notes.txt", "out.txt.bak", "other.json"];
+        for name in stale.iter().chain(&unrelated) {
+            fs::write(dir.join(name), "left over\n").unwrap();
+        }
+
+        let mut removed = clean_stale_artifacts(&options).unwrap();
+        removed.sort();
+        let mut expected: Vec<PathBuf> = stale.iter().map(|name| dir.join(name)).collect();
+        expected.sort();
+        assert_eq!(removed, expected);
+        for name in unrelated {
+            assert!(
+                dir.join(name).exists(),
+                "{name} should have been left alone"
+            );
+        }
+        assert!(clean_stale_artifacts(&options).unwrap().is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn every_sink_receives_the_same_report() {
+        let dir = std::env::temp_dir().join(format!("capstone-sinks-{}", std::process::id()));
+        let out = dir.join("out.txt");
+        let options = Options {
+            formats: vec![ReportFormat::Text, ReportFormat::Json, ReportFormat::Csv],
+            out_path: out.clone(),
+            ..Options::default()
+        };
+        let report = sample_report();
+        let recording = RecordingSink(Default::default());
+
+        let sinks = report_sinks(&options);
+        let formats: Vec<_> = sinks.iter().map(|sink| sink.format()).collect();
+        assert_eq!(formats, ["txt", "json", "csv"]);
+        for sink in sinks
+            .iter()
+            .map(Box::as_ref)
+            .chain([&recording as &dyn ReportSink])
+        {
+            sink.write(&report).unwrap();
+        }
+
+        let lines = report.lines();
+        assert_eq!(recording.0.borrow().len(), 1);
+        assert_eq!(recording.0.borrow()[0], lines);
+        let text = fs::read_to_string(&out).unwrap();
+        assert_eq!(text.lines().collect::<Vec<_>>(), lines);
+        let csv = fs::read_to_string(dir.join("out.csv")).unwrap();
+        let rows: Vec<Vec<&str>> = csv.lines().map(|row| row.split(',').collect()).collect();
+        assert_eq!(rows[0], OUTPUT_FIELDS);
+        assert_eq!(rows[1], lines);
+        let json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(dir.join("out.json")).unwrap()).unwrap();
+        assert_eq!(json["txid"], lines[0]);
+        assert_eq!(json["block_hash"], lines[9]);
+        fs::remove_dir_all(dir).unwrap();
+
+        assert_eq!(csv_cell("1.5"), "1.5");
+        assert_eq!(csv_cell("a,\"b\""), "\"a,\"\"b\"\"\"");
+    }
+
+    #[test]
+    fn display_decimals_shorten_the_terminal_but_not_the_file() {
+        let dir = std::env::temp_dir().join(format!("capstone-decimals-{}", std::process::id()));
+        let out = dir.join("out.txt");
+        let options = Options {
+            display_decimals: 2,
+            out_path: out.clone(),
+            ..Options::default()
+        };
+
+        let report = sample_report();
+        let decimals = options.display_decimals;
+        let terminal = format!("{report:.decimals$}");
+        assert!(terminal.contains("Miner Change Amount: 30.00 BTC"));
+        assert!(format!("{report}").contains("Miner Change Amount: 29.99998590 BTC"));
+
+        for sink in report_sinks(&options) {
+            sink.write(&report).unwrap();
+        }
+        let lines: Vec<String> = fs::read_to_string(&out)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        assert_eq!(lines, report.lines());
+        assert_eq!(lines[6], "29.99998590");
+        assert_eq!(lines[7], "0.00001410");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Runs the whole pipeline and compares its normalized out.json with `golden/out.json`, or overwrites it
+    /// under `UPDATE_GOLDEN=1`. Needs a *fresh* regtest node; golden/README.md has the steps.
+    #[test]
+    #[ignore = "needs a fresh regtest node"]
+    fn fresh_run_matches_the_golden_report() {
+        let rpc = RpcSettings::default().connect().unwrap();
+        let (miner, trader) = (node_miner(), node_trader());
+        assert_eq!(
+            rpc.get_block_count().unwrap(),
+            0,
+            "the golden run needs a fresh chain"
+        );
+        let dir = std::env::temp_dir().join(format!("capstone-golden-{}", std::process::id()));
+        let options = Options {
+            out_path: dir.join("out.txt"),
+            formats: vec![ReportFormat::Json],
+            normalize: true,
+            stdout_only: true,
+            ..Options::default()
+        };
+        run(
+            &Context::default(),
+            &options,
+            &miner,
+            &[],
+            &trader,
+            &mut Metrics::new(),
+        )
+        .unwrap();
+
+        let written = fs::read_to_string(dir.join("out.json")).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            fs::write(
+                Path::new(env!("CARGO_MANIFEST_DIR")).join("golden/out.json"),
+                &written,
+            )
+            .unwrap();
+            return;
+        }
+        let written: serde_json::Value = serde_json::from_str(&written).unwrap();
+        let golden: serde_json::Value = serde_json::from_str(GOLDEN_REPORT).unwrap();
+        assert_eq!(written, golden);
+    }
+
+    #[test]
+    #[ignore = "needs a running regtest node"]
+    fn round_trip_reports_trader_as_sender() {
+        let (miner, trader) = (node_miner(), node_trader());
+        let dir = std::env::temp_dir().join(format!("capstone-roundtrip-{}", std::process::id()));
+        let options = Options {
+            out_path: dir.join("out.txt"),
+            ..Options::default()
+        };
+        let miner_address = miner.get_new_address(None, None).unwrap().assume_checked();
+        miner.generate_to_address(101, &miner_address).unwrap();
+        let trader_address = trader.get_new_address(None, None).unwrap().assume_checked();
+        let paid = Amount::from_int_btc(20);
+        miner
+            .send_to_address(&trader_address, paid, None, None, None, None, None, None)
+            .unwrap();
+        miner.generate_to_address(1, &miner_address).unwrap();
+
+        let mut metrics = Metrics::new();
+        let report = round_trip(
+            &Context::default(),
+            &options,
+            &miner,
+            &trader,
+            paid,
+            &mut metrics,
+        )
+        .unwrap();
+        assert_eq!(metrics.blocks_mined, 1);
+
+        assert_eq!(report.trader_amount, Amount::from_int_btc(10)); // The Miner is the payee this time
+        let change = report
+            .change_address
+            .parse::<Address<NetworkUnchecked>>()
+            .unwrap();
+        assert!(trader.is_mine(&change.assume_checked()).unwrap());
+        assert!(report.check_balance().is_ok());
+        assert_eq!(
+            fs::read_to_string(dir.join("out_roundtrip.txt"))
+                .unwrap()
+                .lines()
+                .count(),
+            10
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn roundtrip_report_sits_next_to_out_txt() {
+        assert_eq!(
+            roundtrip_path(Path::new("../out.txt")),
+            PathBuf::from("../out_roundtrip.txt")
+        );
+        assert_eq!(
+            roundtrip_path(Path::new("report")),
+            PathBuf::from("report_roundtrip")
+        );
+    }
+
+    #[test]
+    fn run_state_is_kept_beside_out_txt_for_the_same_wallets() {
+        let dir = std::env::temp_dir().join(format!("capstone-state-{}", std::process::id()));
+        let options = Options {
+            out_path: dir.join("out.txt"),
+            ..Options::default()
+        };
+        assert_eq!(state_path(&options.out_path), dir.join("out_state.json"));
+        assert_eq!(RunState::load(&options), None);
+
+        let state = RunState {
+            miner_wallet: "Miner".to_string(),
+            trader_wallet: "Trader".to_string(),
+            settings: RunSettings::from(&options),
+            txid: payment_tx(&[(TRADER, 2_000_000_000)]).compute_txid(),
+            miner_address: MINER_INPUT.parse().unwrap(),
+            trader_address: TRADER.parse().unwrap(),
+            sent: Amount::from_int_btc(20),
+            commented: true,
+        };
+        state.save(&options).unwrap();
+        assert_eq!(RunState::load(&options), Some(state));
+        // Another wallet pair's run, one that paid something else, or a mangled file, is nothing to resume
+        let other = Options {
+            miner_wallet: "Alice".to_string(),
+            ..options.clone()
+        };
+        assert_eq!(RunState::load(&other), None);
+        for changed in [
+            Options {
+                amount: SendAmount::Absolute(Amount::from_int_btc(5)),
+                ..options.clone()
+            },
+            Options {
+                amount: SendAmount::Percent(50.0),
+                ..options.clone()
+            },
+            Options {
+                sweep: true,
+                ..options.clone()
+            },
+            Options {
+                trader_addresses: 2,
+                ..options.clone()
+            },
+            Options {
+                route: vec!["Miner".to_string(), "Exchange".to_string()],
+                ..options.clone()
+            },
+        ] {
+            assert_eq!(RunState::load(&changed), None);
+        }
+        fs::write(dir.join("out_state.json"), "{").unwrap();
+        assert_eq!(RunState::load(&options), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a running regtest node"]
+    fn rerun_finishes_the_earlier_payment_instead_of_paying_again() {
+        let rpc = RpcSettings::default().connect().unwrap();
+        let (miner, trader) = (node_miner(), node_trader());
+        let dir = std::env::temp_dir().join(format!("capstone-rerun-{}", std::process::id()));
+        let options = Options {
+            out_path: dir.join("out.txt"),
+            celebrate: Duration::ZERO,
+            stdout_only: true,
+            ..Options::default()
+        };
+        let first = run(
+            &Context::default(),
+            &options,
+            &miner,
+            &[],
+            &trader,
+            &mut Metrics::new(),
+        )
+        .unwrap();
+        let tip = rpc.get_block_count().unwrap();
+
+        let mut metrics = Metrics::new();
+        let again = run(
+            &Context::default(),
+            &options,
+            &miner,
+            &[],
+            &trader,
+            &mut metrics,
+        )
+        .unwrap();
+        assert_eq!(again.txid, first.txid);
+        assert_eq!(again.lines(), first.lines());
+        assert_eq!(metrics.blocks_mined, 0);
+        assert_eq!(rpc.get_block_count().unwrap(), tip);
+
+        // --fresh mines and pays all over again
+        let fresh = Options {
+            fresh: true,
+            ..options
+        };
+        let paid = run(
+            &Context::default(),
+            &fresh,
+            &miner,
+            &[],
+            &trader,
+            &mut Metrics::new(),
+        )
+        .unwrap();
+        assert_ne!(paid.txid, first.txid);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a running regtest node"]
+    fn route_forwards_the_payment_through_every_wallet() {
+        let rpc = RpcSettings::default().connect().unwrap();
+        let options = Options {
+            command: Command::Route,
+            extra_wallets: vec!["Exchange".to_string(), "ColdStorage".to_string()],
+            amount: SendAmount::Absolute(Amount::from_int_btc(3)),
+            ..Options::default()
+        };
+        let wallet_set = options.wallet_set().unwrap();
+        wallet_set.ensure_all(&Context::default(), &rpc).unwrap();
+        let clients = wallet_set.connect(&options.rpc).unwrap();
+        let cold = wallet_client(&clients, "ColdStorage").unwrap();
+        let before = cold.get_balance(None, None).unwrap();
+
+        route(&Context::default(), &options, &clients, &mut Metrics::new()).unwrap();
+
+        // Three hops, the two forwarded ones each paying their fee out of the coins
+        let received = cold.get_balance(None, None).unwrap() - before;
+        assert!(received < Amount::from_int_btc(3));
+        assert!(received > Amount::from_btc(2.999).unwrap());
+    }
+
+    #[test]
+    #[ignore = "needs a running regtest node"]
+    fn send_many_pays_every_recipient_in_one_transaction() {
+        let (miner, trader) = (node_miner(), node_trader());
+        let address = miner.get_new_address(None, None).unwrap().assume_checked();
+        mine_blocks(&Context::default(), &miner, COINBASE_MATURITY + 1, &address).unwrap();
+        let dir = std::env::temp_dir().join(format!("capstone-send-many-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let recipients: Vec<String> = (0..3)
+            .map(|_| {
+                trader
+                    .get_new_address(None, None)
+                    .unwrap()
+                    .assume_checked()
+                    .to_string()
+            })
+            .collect();
+        let path = dir.join("recipients.csv");
+        fs::write(
+            &path,
+            recipients
+                .iter()
+                .map(|address| format!("{address},0.5\n"))
+                .collect::<String>(),
+        )
+        .unwrap();
+        let options = Options {
+            command: Command::SendMany,
+            recipients_file: Some(path),
+            out_path: dir.join("out.txt"),
+            stdout_only: true,
+            ..Options::default()
+        };
+
+        let report = send_many_payment(
+            &Context::default(),
+            &options,
+            &miner,
+            &trader,
+            &mut Metrics::new(),
+        )
+        .unwrap();
+        assert_eq!(report.trader_amount, Amount::from_btc(1.5).unwrap());
+        let outputs = report.outputs.unwrap();
+        assert_eq!(outputs.iter().filter(|out| !out.change).count(), 3);
+        assert_eq!(outputs.iter().filter(|out| out.change).count(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[ignore = "needs a running regtest node"]
+    fn rerun_with_only_small_utxos_mines_and_still_spends_one_input() {
+        let (miner, trader) = (node_miner(), node_trader());
+        let address = miner.get_new_address(None, None).unwrap().assume_checked();
+        mine_blocks(&Context::default(), &miner, COINBASE_MATURITY + 1, &address).unwrap();
+        // Break the Miner's whole balance up evenly into outputs of at most 5 BTC, each too small to pay
+        // 20 BTC by itself
+        let balance = miner.get_balance(None, None).unwrap();
+        let pieces = balance.to_sat() / Amount::from_int_btc(5).to_sat() + 1;
+        let small: Vec<String> = (0..pieces)
+            .map(|_| {
+                miner
+                    .get_new_address(None, None)
+                    .unwrap()
+                    .assume_checked()
+                    .to_string()
+            })
+            .collect();
+        miner
+            .call::<serde_json::Value>("sendall", &[json!(small)])
+            .unwrap();
+        mine_blocks(&Context::default(), &miner, 1, &address).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("capstone-one-input-{}", std::process::id()));
+        let options = Options {
+            out_path: dir.join("out.txt"),
+            celebrate: Duration::ZERO,
+            stdout_only: true,
+            ..Options::default()
+        };
+        let one_input = |report: &TxReport| {
+            miner
+                .get_raw_transaction(&report.txid, None)
+                .unwrap()
+                .input
+                .len()
+        };
+        let mut metrics = Metrics::new();
+        let report = run(
+            &Context::default(),
+            &options,
+            &miner,
+            &[],
+            &trader,
+            &mut metrics,
+        )
+        .unwrap();
+        assert!(metrics.blocks_mined > COINBASE_MATURITY);
+        assert_eq!(one_input(&report), 1);
+
+        // The reward that run matured left enough change for the next run to reuse without mining
+        let again = Options {
+            out_path: dir.join("again.txt"),
+            ..options
+        };
+        let mut metrics = Metrics::new();
+        let report = run(
+            &Context::default(),
+            &again,
+            &miner,
+            &[],
+            &trader,
+            &mut metrics,
+        )
+        .unwrap();
+        assert_eq!(metrics.blocks_mined, 1); // Only the confirming block
+        assert_eq!(one_input(&report), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn input_file_payments_are_validated_before_any_send() {
+        let dir = std::env::temp_dir().join(format!("capstone-batch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("payments.json");
+        let load = |json: &str| {
+            fs::write(&path, json).unwrap();
+            load_payments(&path).map_err(|err| err.to_string())
+        };
+
+        let payments = load(&format!(
+            r#"[{{"wallet": "Trader", "amount": 1.5}}, {{"address": "{TRADER}", "amount": 2, "fee_rate": 3.5}}]"#
+        ))
+        .unwrap();
+        assert_eq!(payments.len(), 2);
+        assert_eq!(payments[0].amount, Amount::from_sat(150_000_000));
+        assert_eq!(payments[1].fee_rate, Some(3.5));
+
+        let rejected = [
+            r#"[]"#.to_string(),
+            r#"[{"wallet": "Trader", "amount": 0}]"#.to_string(),
+            r#"[{"wallet": "Trader", "amount": -1}]"#.to_string(),
+            r#"[{"amount": 1}]"#.to_string(),
+            format!(r#"[{{"wallet": "Trader", "address": "{TRADER}", "amount": 1}}]"#),
+            r#"[{"address": "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq", "amount": 1}]"#
+                .to_string(),
+            r#"[{"address": "not-an-address", "amount": 1}]"#.to_string(),
+            r#"[{"wallet": "Trader", "amount": 1, "fee_rate": 0}]"#.to_string(),
+            r#"[{"wallet": "Trader", "amount": 1, "fee": 2}]"#.to_string(),
+        ];
+        for json in &rejected {
+            assert!(load(json).is_err(), "accepted {json}");
+        }
+        assert!(
+            load(r#"[{"wallet": "Trader", "amount": 1}, {"amount": 1}]"#)
+                .unwrap_err()
+                .contains("payment 2")
+        );
+
+        // The same batch as TOML tables, held to the same rules
+        let toml_path = dir.join("payments.toml");
+        let load_toml = |toml: &str| {
+            fs::write(&toml_path, toml).unwrap();
+            load_payments(&toml_path).map_err(|err| err.to_string())
+        };
+        let from_toml = load_toml(&format!(
+            "[[payment]]\nwallet = \"Trader\"\namount = 1.5\n\n[[payment]]\naddress = \"{TRADER}\"\namount = 2\nfee_rate = 3.5\n"
+        ))
+        .unwrap();
+        assert_eq!(from_toml, payments);
+        assert!(load_toml("").unwrap_err().contains("no payments"));
+        assert!(load_toml("[[payment]]\nwallet = \"Trader\"\namount = 0\n")
+            .unwrap_err()
+            .contains("payment 1 has no amount"));
+        assert!(
+            load_toml("[[payment]]\nwallet = \"Trader\"\n\n[[payment]]\namount = 1\n")
+                .unwrap_err()
+                .contains("line 1")
+        );
+        fs::remove_dir_all(dir).unwrap();
+
+        let options = Options {
+            input_file: Some(PathBuf::from("payments.json")),
+            fee_file: Some(PathBuf::from("fee.txt")),
+            ..Options::default()
+        };
+
+        let first = batch_options(&options, &payments[0], 1, false);
+        assert_eq!(first.out_path, PathBuf::from("../out_1.txt"));
+        assert_eq!(first.fee_file, Some(PathBuf::from("fee_1.txt")));
+        assert_eq!(first.amount, SendAmount::Absolute(payments[0].amount));
+        assert_eq!(first.trader_address, None);
+        assert_eq!(first.celebrate, Duration::ZERO);
+        let second = batch_options(&options, &payments[1], 2, true);
+        assert_eq!(second.out_path, PathBuf::from("../out_2.txt"));
+        assert_eq!(second.trader_address, payments[1].address);
+        assert_eq!(second.fee_rate, Some(3.5));
+        assert_eq!(second.celebrate, options.celebrate);
+        assert_eq!(
+            numbered_path(Path::new("report"), 3),
+            PathBuf::from("report_3")
+        );
+    }
+
+    #[test]
+    fn celebration_frames_are_jittered() {
+        let jitter = Duration::from_millis(100)..=Duration::from_millis(200);
+        let delays: HashSet<Duration> = (0..200).map(jittered_delay).collect();
+        assert!(delays.len() > 1);
+        assert!(delays.iter().all(|delay| jitter.contains(delay)));
+
+        // Frames come in order, each followed by its jittered delay, until the delays fill the time
+        let mut frames = Vec::new();
+        let mut slept = Vec::new();
+        celebration_frames(
+            Duration::from_millis(500),
+            7,
+            || false,
+            |delay| slept.push(delay),
+            |frame| frames.push(frame),
+        );
+        assert!(frames.iter().copied().eq(0..frames.len()));
+        assert_eq!(slept.len(), frames.len());
+        assert_eq!(slept.iter().sum::<Duration>(), Duration::from_millis(500));
+        let (last, whole) = slept.split_last().unwrap();
+        assert!(
+            whole.iter().all(|delay| jitter.contains(delay)),
+            "{slept:?}"
+        );
+        assert!(*last <= *jitter.end());
+
+        // Stopping (Ctrl-C) ends them before the next frame
+        let drawn = std::cell::Cell::new(0);
+        celebration_frames(
+            DEFAULT_CELEBRATION,
+            7,
+            || drawn.get() == 2,
+            |_| {},
+            |_| drawn.set(drawn.get() + 1),
+        );
+        assert_eq!(drawn.get(), 2);
+    }
+
+    #[test]
+    fn recipients_load_from_json_or_csv() {
+        let dir = std::env::temp_dir().join(format!("capstone-recipients-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let json = dir.join("recipients.json");
+        fs::write(&json, format!(r#"{{"{TRADER}": 1.5, "{MINER_INPUT}": 2}}"#)).unwrap();
+        let csv = dir.join("recipients.csv");
+        fs::write(
+            &csv,
+            format!("address,amount\n{TRADER},1.5\n\n{MINER_INPUT},2\n"),
+        )
+        .unwrap();
+        let mut from_json = load_recipients(&json).unwrap();
+        let mut from_csv = load_recipients(&csv).unwrap();
+        from_json.sort_by_key(|(address, _)| address.to_string());
+        from_csv.sort_by_key(|(address, _)| address.to_string());
+        assert_eq!(from_json, from_csv);
+        assert_eq!(from_csv.len(), 2);
+        assert!(from_csv.contains(&(
+            TRADER
+                .parse::<Address<NetworkUnchecked>>()
+                .unwrap()
+                .assume_checked(),
+            Amount::from_btc(1.5).unwrap()
+        )));
+
+        for invalid in [
+            "",
+            "address,amount\n",
+            TRADER,
+            &format!("{TRADER},0"),
+            &format!("{TRADER},-1"),
+            &format!("{TRADER},1\n{TRADER},2"),
+            "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq,1",
+        ] {
+            fs::write(&csv, invalid).unwrap();
+            assert!(load_recipients(&csv).is_err(), "{invalid:?} was accepted");
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
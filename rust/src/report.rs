@@ -0,0 +1,2133 @@
+//! Building the payment report from the chain (tracing inputs, splitting outputs, finding the confirming
+//! block) and writing it as out.txt, JSON or CSV.
+
+use crate::{rpc::*, transfer::*, *};
+use bitcoin::hex::DisplayHex;
+use bitcoincore_rpc::bitcoin::amount::CheckedSum;
+use bitcoincore_rpc::bitcoin::{Amount, BlockHash, Script, Transaction, TxOut, Txid};
+use bitcoincore_rpc::json::{
+    GetBlockResult, GetMempoolEntryResult, GetRawTransactionResult, GetRawTransactionResultVout,
+    GetRawTransactionResultVoutScriptPubKey,
+};
+use bitcoincore_rpc::jsonrpc;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::result::Result;
+use tracing::{info, warn};
+
+/// Report files the run can produce; several may be requested at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Text, // The ten-line out.txt the grader reads
+    Json, // Everything in TxReport, written next to the text file with a .json extension
+    Csv, // The ten out.txt fields as a header row and a value row per run, appended next to the text file as .csv
+}
+
+impl std::str::FromStr for ReportFormat {
+    type Err = CapstoneError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "txt" | "text" => Ok(ReportFormat::Text),
+            "json" => Ok(ReportFormat::Json),
+            "csv" => Ok(ReportFormat::Csv),
+            other => Err(CapstoneError::UnknownChoice {
+                what: "report format",
+                value: other.to_string(),
+                expected: "txt, json or csv",
+            }),
+        }
+    }
+}
+
+/// How the amount lines of out.txt are written (`--units`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Units {
+    Btc, // Decimal BTC fixed at 8 places, what the grader expects (default)
+    Sat, // Integer satoshis via `.to_sat()`, exact with no float formatting involved
+}
+
+impl Units {
+    pub fn render(self, amount: Amount) -> String {
+        match self {
+            Units::Btc => format!("{:.8}", amount.to_btc()),
+            Units::Sat => amount.to_sat().to_string(),
+        }
+    }
+}
+
+impl std::str::FromStr for Units {
+    type Err = CapstoneError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "btc" => Ok(Units::Btc),
+            "sat" => Ok(Units::Sat),
+            other => Err(CapstoneError::UnknownChoice {
+                what: "units",
+                value: other.to_string(),
+                expected: "sat or btc",
+            }),
+        }
+    }
+}
+
+/// Writes the ten report lines to `path`, creating missing parent directories, and returns the absolute path written.
+pub fn write_report(
+    report: &TxReport,
+    path: &Path,
+    units: Units,
+) -> Result<PathBuf, CapstoneError> {
+    let write = || -> io::Result<PathBuf> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = File::create(path)?;
+        for line in report.lines_in(units) {
+            writeln!(file, "{line}")?;
+        }
+
+        //I placed a file flush here so that data will be written immediately
+        file.flush()?;
+
+        fs::canonicalize(path)
+    };
+    write().map_err(|err| report_write_error(path, err))
+}
+
+/// Writes only the fee, exactly as line 8 of out.txt has it (same units).
+pub fn write_fee_file(
+    report: &TxReport,
+    path: &Path,
+    units: Units,
+) -> Result<PathBuf, CapstoneError> {
+    let (_, fee) = &report.fields_in(units)[7];
+    write_file(path, format!("{fee}\n"))
+}
+
+// Writes `contents` to `path`, creating missing parent directories, and returns the absolute path written
+fn write_file(path: &Path, contents: String) -> Result<PathBuf, CapstoneError> {
+    let write = || -> io::Result<PathBuf> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, contents)?;
+        fs::canonicalize(path)
+    };
+    write().map_err(|err| report_write_error(path, err))
+}
+
+/// Writes the raw transaction as one line of hex, once it has decoded back to `txid` and re-encoded to
+/// the same bytes, so the file is exactly what the node holds.
+pub fn write_raw_tx_file(raw: &[u8], txid: &Txid, path: &Path) -> Result<PathBuf, CapstoneError> {
+    let tx: Transaction = bitcoin::consensus::deserialize(raw)?;
+    if tx.compute_txid() != *txid || bitcoin::consensus::encode::serialize(&tx) != raw {
+        return Err(CapstoneError::RawTxMismatch(*txid));
+    }
+    write_file(path, format!("{}\n", raw.to_lower_hex_string()))
+}
+
+/// Somewhere a finished report goes. Each `--format` is one sink; a new format only needs a new one.
+pub trait ReportSink {
+    /// The format's name as `--format` spells it.
+    fn format(&self) -> &'static str;
+    /// The file the sink writes.
+    fn path(&self) -> &Path;
+    fn write(&self, report: &TxReport) -> Result<(), CapstoneError>;
+}
+
+/// The grader's ten-line out.txt.
+pub struct TextSink {
+    pub path: PathBuf,
+    pub units: Units,
+}
+
+/// The whole `TxReport` as pretty JSON, optionally normalized (`--normalize`).
+pub struct JsonSink {
+    pub path: PathBuf,
+    pub normalize: bool,
+}
+
+/// The ten out.txt fields as CSV: a header row of field names, then one row of values per run, appended so
+/// many runs accumulate in one file.
+pub struct CsvSink {
+    pub path: PathBuf,
+    pub units: Units,
+}
+
+impl ReportSink for TextSink {
+    fn format(&self) -> &'static str {
+        "txt"
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn write(&self, report: &TxReport) -> Result<(), CapstoneError> {
+        write_report(report, &self.path, self.units).map(drop)
+    }
+}
+
+impl ReportSink for JsonSink {
+    fn format(&self) -> &'static str {
+        "json"
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn write(&self, report: &TxReport) -> Result<(), CapstoneError> {
+        write_json_report(report, &self.path, self.normalize).map(drop)
+    }
+}
+
+impl ReportSink for CsvSink {
+    fn format(&self) -> &'static str {
+        "csv"
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn write(&self, report: &TxReport) -> Result<(), CapstoneError> {
+        let fields = report.fields_in(self.units);
+        let row = |cells: Vec<&str>| {
+            cells
+                .into_iter()
+                .map(csv_cell)
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let header = row(fields.iter().map(|(name, _)| *name).collect());
+        let values = row(fields.iter().map(|(_, value)| value.as_str()).collect());
+        append_csv_row(&self.path, &header, &values)
+            .map_err(|err| report_write_error(&self.path, err))
+    }
+}
+
+// Appends `values` to the CSV at `path`, starting the file with `header` when it is new or empty. A file
+// with some other header (an older layout, or not ours) is left alone rather than mixed into.
+fn append_csv_row(path: &Path, header: &str, values: &str) -> io::Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    let existing = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err),
+    };
+    if existing.lines().next().is_some_and(|first| first != header) {
+        return Err(io::Error::other(
+            "its header row doesn't match the report fields; move it aside to start a new one",
+        ));
+    }
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    if existing.is_empty() {
+        writeln!(file, "{header}")?;
+    } else if !existing.ends_with('\n') {
+        writeln!(file)?;
+    }
+    writeln!(file, "{values}")?;
+    Ok(())
+}
+
+// A CSV field, quoted (with inner quotes doubled) only when it holds a comma, quote or line break
+pub fn csv_cell(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+pub fn report_write_error(path: &Path, err: impl std::fmt::Display) -> CapstoneError {
+    CapstoneError::ReportWrite {
+        path: path.to_path_buf(),
+        message: err.to_string(),
+    }
+}
+
+/// Serializes the full report (including the optional mempool snapshot) as pretty JSON to `path`; the one
+/// out.json schema, whether `--format json` asked for it or it came along with out.txt.
+pub fn write_json_report(
+    report: &TxReport,
+    path: &Path,
+    normalize: bool,
+) -> Result<PathBuf, CapstoneError> {
+    let mut json = serde_json::to_value(report).map_err(|err| report_write_error(path, err))?;
+    if normalize {
+        normalize_report(&mut json);
+    }
+    let text = serde_json::to_string_pretty(&json).map_err(|err| report_write_error(path, err))?;
+    write_file(path, text + "\n")
+}
+
+// JSON report fields that differ between otherwise identical runs: keys, addresses and hashes are random,
+// times follow the clock, the wallet's anti-fee-sniping locktime is sometimes randomly lowered, and the
+// weight moves with the signature's length
+pub const NORMALIZED_FIELDS: [&str; 8] = [
+    "txid",
+    "input_address",
+    "trader_address",
+    "change_address",
+    "block_hash",
+    "block_time",
+    "block_time_iso",
+    "locktime",
+];
+
+/// Blanks (sets to null) the run-specific fields of a serialized `TxReport`, leaving the structure and
+/// the deterministic values (amounts, height, script type, ...) to compare against `golden/out.json`.
+pub fn normalize_report(json: &mut serde_json::Value) {
+    for field in NORMALIZED_FIELDS {
+        if let Some(value) = json.get_mut(field) {
+            *value = serde_json::Value::Null;
+        }
+    }
+    if let Some(weight) = json.pointer_mut("/mempool/weight") {
+        *weight = serde_json::Value::Null;
+    }
+}
+
+// How many times the confirming block is re-derived before giving up on a chain that keeps reorganizing
+pub const REORG_RECHECKS: usize = 3;
+
+/// Selected `getmempoolentry` fields, captured while the transaction was still unconfirmed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MempoolSnapshot {
+    pub vsize: u64,
+    pub weight: Option<u64>, // Only reported by Core 0.19+
+    #[serde(with = "bitcoin::amount::serde::as_btc")]
+    pub base_fee: Amount,
+    pub ancestor_count: u64,
+    pub descendant_count: u64,
+}
+
+impl From<&GetMempoolEntryResult> for MempoolSnapshot {
+    fn from(entry: &GetMempoolEntryResult) -> Self {
+        MempoolSnapshot {
+            vsize: entry.vsize,
+            weight: entry.weight,
+            base_fee: entry.fees.base,
+            ancestor_count: entry.ancestor_count,
+            descendant_count: entry.descendant_count,
+        }
+    }
+}
+
+/// Fee = inputs − outputs, with every step checked. Outputs exceeding inputs can't happen in a valid
+/// transaction, so it means the input total was computed wrong; that is reported instead of hidden as a zero fee.
+pub fn compute_fee(inputs: Amount, outputs: &[TxOut]) -> Result<Amount, CapstoneError> {
+    let total_output = outputs
+        .iter()
+        .map(|out| out.value)
+        .checked_sum()
+        .ok_or(CapstoneError::AmountOverflow("output total"))?;
+    inputs
+        .checked_sub(total_output)
+        .ok_or(CapstoneError::FeeUnderflow {
+            inputs,
+            outputs: total_output,
+        })
+}
+
+/// Blocks between broadcast and confirmation: 1 when the very next block confirmed it.
+/// `broadcast_height` is the tip when the tx was sent, so its first chance to confirm is the block after.
+pub fn confirmation_distance(broadcast_height: u64, confirmed_height: u64) -> u64 {
+    confirmed_height.saturating_sub(broadcast_height)
+}
+
+// Enforces --assert-confirmed-in; always 1 on regtest where we mine the block ourselves
+pub fn check_confirmed_within(distance: u64, limit: Option<u64>) -> Result<(), CapstoneError> {
+    match limit {
+        Some(limit) if distance > limit => Err(CapstoneError::ConfirmedTooLate { distance, limit }),
+        _ => Ok(()),
+    }
+}
+
+/// Recomputes vsize from the decoded transaction's weight (weight / 4, rounded up) and compares it with what the
+/// node reported. A difference of one vbyte is tolerated for rounding; anything more means we decoded it wrong.
+pub fn verify_vsize(tx: &Transaction, reported: u64) -> Result<u64, CapstoneError> {
+    let weight = tx.weight();
+    let computed = weight.to_vbytes_ceil();
+    info!("Transaction weight {weight}, vsize {computed} vB (node: {reported} vB)");
+    if computed.abs_diff(reported) > 1 {
+        return Err(CapstoneError::VsizeMismatch { computed, reported });
+    }
+    Ok(computed)
+}
+
+/// Everything we extract from the confirmed Miner → Trader transaction; the first ten fields are the lines of out.txt.
+#[derive(Debug, Clone, Serialize)]
+pub struct TxReport {
+    pub txid: Txid,
+    pub input_address: String,
+    #[serde(with = "bitcoin::amount::serde::as_btc")]
+    pub input_amount: Amount, // Sum of every input, not just the first one
+    pub trader_address: String,
+    #[serde(with = "bitcoin::amount::serde::as_btc")]
+    pub trader_amount: Amount,
+    pub change_address: String,
+    #[serde(with = "bitcoin::amount::serde::as_btc")]
+    pub change_amount: Amount,
+    #[serde(with = "bitcoin::amount::serde::as_btc")]
+    pub fee: Amount,
+    pub block_height: usize,
+    pub block_hash: BlockHash,
+    pub trader_script_type: &'static str, // p2wpkh, p2tr, ...; JSON only
+    pub block_time: u64, // Header timestamp of the confirming block, Unix seconds (JSON only)
+    pub block_time_iso: String, // The same instant as ISO-8601 UTC, for reading runs against wall-clock time
+    pub confirmations: u32,     // At least 1 once confirmed; JSON and terminal only
+    pub inputs_pruned: bool, // Degraded report: input address, amount and fee are unknown, written as PRUNED_PLACEHOLDER (zero in out.json)
+    pub rbf_signaled: bool, // Some input opts in to replacement (BIP 125), so `bump` can replace it; JSON and terminal only
+    pub locktime: u32, // nLockTime as a block height or Unix time; Core's wallet sets the tip height by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mempool: Option<MempoolSnapshot>, // Only set when the tx was seen in the mempool before confirmation
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input_scripts: Option<Vec<InputScripts>>, // Only with --show-scripts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outputs: Option<Vec<PaidOutput>>, // Only for `send-many`: every output, recipients and change alike
+}
+
+/// The human-readable summary printed after extraction, one labelled field per line.
+impl std::fmt::Display for TxReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // values are formatted to 8 decimal places for Bitcoin precision, unless the caller asks for fewer
+        // with a precision of its own (`{report:.4}`, --display-decimals)
+        let decimals = f.precision().unwrap_or(8);
+        writeln!(f, "Transaction Details:")?;
+        writeln!(f, "Transaction ID: {}", self.txid)?;
+        if self.inputs_pruned {
+            writeln!(f, "Miner Input Address: {PRUNED_PLACEHOLDER}")?;
+            writeln!(f, "Miner Input Amount: {PRUNED_PLACEHOLDER}")?;
+        } else {
+            writeln!(f, "Miner Input Address: {}", self.input_address)?;
+            writeln!(
+                f,
+                "Miner Input Amount: {:.decimals$} BTC",
+                self.input_amount.to_btc()
+            )?;
+        }
+        writeln!(f, "Trader Output Address: {}", self.trader_address)?;
+        writeln!(
+            f,
+            "Trader Output Amount: {:.decimals$} BTC",
+            self.trader_amount.to_btc()
+        )?;
+        writeln!(f, "Miner Change Address: {}", self.change_address)?;
+        writeln!(
+            f,
+            "Miner Change Amount: {:.decimals$} BTC",
+            self.change_amount.to_btc()
+        )?;
+        if self.inputs_pruned {
+            writeln!(f, "Fee: {PRUNED_PLACEHOLDER}")?;
+        } else {
+            writeln!(f, "Fee: {:.decimals$} BTC", self.fee.to_btc())?;
+        }
+        writeln!(f, "Block Height: {}", self.block_height)?;
+        writeln!(f, "Block Hash: {}", self.block_hash)?;
+        write!(f, "Confirmations: {}", self.confirmations)
+    }
+}
+
+/// The grader contract: the fields of out.txt, one per line, in this order. Named after the `TxReport`
+/// fields (and so the JSON keys) they come from.
+pub const OUTPUT_FIELDS: [&str; 10] = [
+    "txid",
+    "input_address",
+    "input_amount",
+    "trader_address",
+    "trader_amount",
+    "change_address",
+    "change_amount",
+    "fee",
+    "block_height",
+    "block_hash",
+];
+
+/// What each line of out.txt means, in `OUTPUT_FIELDS` order; `--explain` prints them beside the values.
+pub const FIELD_EXPLANATIONS: [&str; 10] = [
+    "Transaction ID: the hash that names the Miner → Trader payment",
+    "Input address: where the coins being spent were held, the Miner's matured coinbase output",
+    "Input amount: the value of every input together, what the transaction has to spend",
+    "Trader's address: the fresh \"Received\" address the payment goes to",
+    "Trader's amount: what the Trader receives",
+    "Miner's change address: the Miner's own address that takes back what is left over",
+    "Miner's change amount: inputs minus the payment and the fee, returned to the Miner",
+    "Fee: the difference between inputs and outputs, paid to the miner of the confirming block",
+    "Block height: how many blocks precede the block that confirmed the transaction",
+    "Block hash: the hash that names that confirming block",
+];
+
+impl TxReport {
+    /// Each out.txt field paired with its value, in `OUTPUT_FIELDS` order. Amounts have a fixed 8 decimals, as
+    /// `Units::render` writes BTC.
+    pub fn fields(&self) -> [(&'static str, String); 10] {
+        self.fields_in(Units::Btc)
+    }
+
+    /// Like `fields`, with the amount lines in the given units.
+    pub fn fields_in(&self, units: Units) -> [(&'static str, String); 10] {
+        // An input-derived field, or the placeholder when the inputs couldn't be traced
+        let traced = |value: String| {
+            if self.inputs_pruned {
+                PRUNED_PLACEHOLDER.to_string()
+            } else {
+                value
+            }
+        };
+        let values = [
+            self.txid.to_string(),
+            traced(self.input_address.clone()),
+            traced(units.render(self.input_amount)),
+            self.trader_address.clone(),
+            units.render(self.trader_amount),
+            self.change_address.clone(),
+            units.render(self.change_amount),
+            traced(units.render(self.fee)),
+            self.block_height.to_string(),
+            self.block_hash.to_string(),
+        ];
+        let mut names = OUTPUT_FIELDS.into_iter();
+        values.map(|value| (names.next().expect("ten field names"), value))
+    }
+
+    /// The ten lines of out.txt, in grader order.
+    pub fn lines(&self) -> [String; 10] {
+        self.lines_in(Units::Btc)
+    }
+
+    pub fn lines_in(&self, units: Units) -> [String; 10] {
+        self.fields_in(units).map(|(_, value)| value)
+    }
+
+    /// The ten values, each prefixed with what it means (`--explain`); out.txt itself stays plain.
+    pub fn explained_lines(&self, units: Units) -> [String; 10] {
+        let mut explanations = FIELD_EXPLANATIONS.into_iter();
+        self.lines_in(units).map(|value| {
+            let explanation = explanations.next().expect("ten explanations");
+            format!("{explanation}\n    {value}")
+        })
+    }
+
+    /// Checks that `input == trader + change + fee` within `DUST_TOLERANCE`.
+    /// A mismatch means the extraction is wrong (e.g. only the first of several inputs was counted). A
+    /// degraded report (`inputs_pruned`) has no input amount or fee to balance, so it is let through with a
+    /// warning.
+    pub fn check_balance(&self) -> Result<(), CapstoneError> {
+        if self.inputs_pruned {
+            warn!(
+                "⚠️ Inputs of {} are pruned; skipping the balance check",
+                self.txid
+            );
+            return Ok(());
+        }
+        let accounted = self.trader_amount + self.change_amount + self.fee;
+        let delta = if accounted > self.input_amount {
+            accounted - self.input_amount
+        } else {
+            self.input_amount - accounted
+        };
+        if delta > DUST_TOLERANCE {
+            return Err(CapstoneError::AccountingMismatch {
+                input: self.input_amount,
+                trader: self.trader_amount,
+                change: self.change_amount,
+                fee: self.fee,
+            });
+        }
+        Ok(())
+    }
+
+    /// A sweep leaves nothing behind: no change address and no change amount, the Trader output is all there is.
+    pub fn check_swept(&self) -> Result<(), CapstoneError> {
+        if self.change_address.is_empty() && self.change_amount == Amount::ZERO {
+            return Ok(());
+        }
+        Err(CapstoneError::SweepLeftChange {
+            change: self.change_amount,
+            address: self.change_address.clone(),
+        })
+    }
+
+    /// Checks the Trader got exactly `sent`, to the satoshi, or `sent - fee` when `fee_from_recipient`: the
+    /// fee comes out of the Miner's change unless the payment was told to carry it, so any other difference
+    /// means the send mode isn't the one we think or extraction chose the wrong output. A degraded report has
+    /// no fee to take off, so with `fee_from_recipient` it is let through with a warning.
+    pub fn check_trader_amount(
+        &self,
+        sent: Amount,
+        fee_from_recipient: bool,
+    ) -> Result<(), CapstoneError> {
+        if fee_from_recipient && self.inputs_pruned {
+            warn!(
+                "⚠️ Inputs of {} are pruned; skipping the Trader amount check, which needs the fee",
+                self.txid
+            );
+            return Ok(());
+        }
+        let expected = if fee_from_recipient {
+            sent.checked_sub(self.fee).unwrap_or(Amount::ZERO)
+        } else {
+            sent
+        };
+        if self.trader_amount != expected {
+            return Err(CapstoneError::TraderAmountMismatch {
+                expected,
+                received: self.trader_amount,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// The part of `TxReport` that comes from the transaction alone, before its confirming block is known.
+#[derive(Debug, Clone)]
+pub struct TxDetails {
+    pub txid: Txid,
+    pub input_address: String,
+    pub input_amount: Amount,
+    pub trader_address: String,
+    pub trader_amount: Amount,
+    pub trader_script_type: &'static str,
+    pub change_address: String,
+    pub change_amount: Amount,
+    pub fee: Amount,
+    pub inputs_pruned: bool, // Some spent output could not be looked up; input amount and fee are unknown
+    pub rbf_signaled: bool,
+    pub locktime: u32,
+}
+
+impl TxDetails {
+    pub fn confirmed_at(self, block: ConfirmingBlock) -> TxReport {
+        TxReport {
+            txid: self.txid,
+            input_address: self.input_address,
+            input_amount: self.input_amount,
+            trader_address: self.trader_address,
+            trader_amount: self.trader_amount,
+            trader_script_type: self.trader_script_type,
+            change_address: self.change_address,
+            change_amount: self.change_amount,
+            fee: self.fee,
+            inputs_pruned: self.inputs_pruned,
+            rbf_signaled: self.rbf_signaled,
+            locktime: self.locktime,
+            block_height: block.height,
+            block_hash: block.hash,
+            block_time: block.time,
+            block_time_iso: iso8601(block.time),
+            confirmations: block.confirmations,
+            mempool: None,
+            input_scripts: None,
+            outputs: None,
+        }
+    }
+}
+
+// Formats Unix seconds as an ISO-8601 UTC timestamp, e.g. 2023-11-14T22:13:20Z (civil-from-days, no date crate needed)
+pub fn iso8601(unix: u64) -> String {
+    let (days, secs) = (unix / 86_400, unix % 86_400);
+    let z = days as i64 + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3_600,
+        secs % 3_600 / 60,
+        secs % 60
+    )
+}
+
+// Standard output types `script_type` recognises, as accepted by --expect-trader-type
+pub const SCRIPT_TYPES: [&str; 5] = ["p2pkh", "p2sh", "p2wpkh", "p2wsh", "p2tr"];
+
+// Names the standard template an output script follows, "nonstandard" for anything else (e.g. OP_RETURN)
+pub fn script_type(script: &Script) -> &'static str {
+    if script.is_p2pkh() {
+        "p2pkh"
+    } else if script.is_p2sh() {
+        "p2sh"
+    } else if script.is_p2wpkh() {
+        "p2wpkh"
+    } else if script.is_p2wsh() {
+        "p2wsh"
+    } else if script.is_p2tr() {
+        "p2tr"
+    } else {
+        "nonstandard"
+    }
+}
+
+/// What spending one input took (`--show-scripts`): its scriptSig and witness stack as hex, and the kind of
+/// output they unlock as far as their shape tells.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct InputScripts {
+    pub outpoint: String,
+    pub script_type: &'static str,
+    pub script_sig: String,   // Hex; empty for native segwit spends
+    pub witness: Vec<String>, // Hex of each stack item; empty for legacy spends
+}
+
+impl std::fmt::Display for InputScripts {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "Input {} ({})", self.outpoint, self.script_type)?;
+        write!(f, "  scriptSig: {}", self.script_sig)?;
+        for (i, item) in self.witness.iter().enumerate() {
+            write!(f, "\n  witness[{i}]: {item}")?;
+        }
+        Ok(())
+    }
+}
+
+pub fn input_scripts(tx: &Transaction) -> Vec<InputScripts> {
+    tx.input
+        .iter()
+        .map(|input| InputScripts {
+            outpoint: input.previous_output.to_string(),
+            script_type: input_script_type(input),
+            script_sig: input.script_sig.to_hex_string(),
+            witness: input
+                .witness
+                .iter()
+                .map(|item| item.to_lower_hex_string())
+                .collect(),
+        })
+        .collect()
+}
+
+// The spend type an input's own data points to, without looking up the output it spends: a signature plus
+// 33-byte key is p2wpkh in the witness (p2pkh in the scriptSig), a lone 64/65-byte item a taproot key path,
+// a scriptSig in front of a witness a p2sh-wrapped segwit spend.
+pub fn input_script_type(input: &bitcoin::TxIn) -> &'static str {
+    let witness: Vec<&[u8]> = input.witness.iter().collect();
+    let sig_and_key = |items: &[&[u8]]| items.len() == 2 && items[1].len() == 33;
+    if input.previous_output.is_null() {
+        return "coinbase";
+    }
+    match (input.script_sig.is_empty(), witness.is_empty()) {
+        (true, true) => "unknown",
+        (false, true) => {
+            let pushes: Vec<usize> = input
+                .script_sig
+                .instructions()
+                .filter_map(|instruction| Some(instruction.ok()?.push_bytes()?.len()))
+                .collect();
+            if pushes.len() == 2 && matches!(pushes[1], 33 | 65) {
+                "p2pkh"
+            } else {
+                "p2sh"
+            }
+        }
+        (false, false) if sig_and_key(&witness) => "p2sh-p2wpkh",
+        (false, false) => "p2sh-p2wsh",
+        (true, false) if sig_and_key(&witness) => "p2wpkh",
+        (true, false) if witness.len() == 1 && matches!(witness[0].len(), 64 | 65) => "p2tr",
+        (true, false) => "p2wsh",
+    }
+}
+
+// Turns an output script into a regtest address string, empty when the script has no address form
+pub fn script_address(script: &Script) -> String {
+    bitcoin::Address::from_script(script, bitcoin::Network::Regtest)
+        .map(|addr| addr.to_string())
+        .unwrap_or_default()
+}
+
+/// Asks the sending wallet (`node`) which outputs of `tx` pay its own addresses; those are the change.
+pub fn sender_owned(
+    node: &dyn NodeApi,
+    tx: &Transaction,
+) -> Result<HashSet<String>, CapstoneError> {
+    let mut owned = HashSet::new();
+    for output in tx.output.iter() {
+        if let Ok(address) =
+            bitcoin::Address::from_script(&output.script_pubkey, bitcoin::Network::Regtest)
+        {
+            if node.is_mine(&address)? {
+                owned.insert(address.to_string());
+            }
+        }
+    }
+    Ok(owned)
+}
+
+/// One output of a payment: where it went, how much, and whether it came back to the sender as change.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PaidOutput {
+    pub vout: u32,
+    pub address: String,
+    #[serde(with = "bitcoin::amount::serde::as_btc")]
+    pub amount: Amount,
+    pub change: bool,
+}
+
+/// Every output of `tx` in order, however many recipients it pays; the ones paying an address in
+/// `sender_owned` are change. `split_outputs` folds the same outputs into the Trader-and-change shape of out.txt.
+pub fn paid_outputs(tx: &Transaction, sender_owned: &HashSet<String>) -> Vec<PaidOutput> {
+    (0..)
+        .zip(&tx.output)
+        .map(|(vout, output)| {
+            let address = script_address(&output.script_pubkey);
+            PaidOutput {
+                vout,
+                change: sender_owned.contains(&address),
+                address,
+                amount: output.value,
+            }
+        })
+        .collect()
+}
+
+/// Splits the outputs of `tx` into (trader address, trader amount, change address, change amount).
+/// Every output paying one of `trader_addresses` counts towards the trader amount (`--trader-addresses`
+/// splits the payment); the reported trader address is the first of them that was paid.
+/// Change is decided by ownership: only outputs paying an address in `sender_owned` count as change,
+/// so an external trader address (one the sending wallet doesn't own) is never mistaken for it.
+pub fn split_outputs(
+    tx: &Transaction,
+    trader_addresses: &[&str],
+    sender_owned: &HashSet<String>,
+) -> (String, Amount, String, Amount) {
+    let mut paid = HashSet::new();
+    let mut trader_output_amount = Amount::ZERO;
+    let mut miner_change_address = String::new();
+    let mut miner_change_amount = Amount::ZERO;
+
+    // Match address to identify which is Trader and which is change back to Miner
+    for output in tx.output.iter() {
+        let address = script_address(&output.script_pubkey);
+        if trader_addresses.contains(&address.as_str()) {
+            trader_output_amount += output.value;
+            paid.insert(address);
+        } else if sender_owned.contains(&address) {
+            miner_change_address = address;
+            miner_change_amount = output.value;
+        }
+    }
+    let trader_output_address = trader_addresses
+        .iter()
+        .find(|address| paid.contains(**address))
+        .map_or_else(String::new, |address| address.to_string());
+
+    (
+        trader_output_address,
+        trader_output_amount,
+        miner_change_address,
+        miner_change_amount,
+    )
+}
+
+impl TxReport {
+    /// Builds the report for a confirmed transaction: traces it, then pins down its confirming block.
+    /// `node` must be the sending wallet's client, since change is identified by that wallet's ownership.
+    pub fn from_txid(
+        ctx: &Context,
+        node: &dyn NodeApi,
+        txid: &Txid,
+        trader_addresses: &[&str],
+    ) -> Result<TxReport, CapstoneError> {
+        let raw = node.raw_transaction(txid)?;
+        let tx = raw.transaction()?;
+        verify_vsize(&tx, raw.vsize as u64)?;
+        let details = trace_transaction(ctx, node, &tx, trader_addresses)?;
+
+        // Get block info from raw transaction result, re-checked against the active chain
+        let block = confirmed_block(node, txid, raw.blockhash)?;
+        Ok(details.confirmed_at(block))
+    }
+}
+
+/// The report of the confirmed payment `txid`, for callers outside the binary: the same extraction as
+/// [`TxReport::from_txid`], through any [`NodeApi`]. `node` must be the sending wallet, which tells the change
+/// apart; `trader_addresses` are the outputs that count as paid to the Trader.
+pub fn extract_tx_details(
+    ctx: &Context,
+    node: &impl NodeApi,
+    txid: &Txid,
+    trader_addresses: &[&str],
+) -> Result<TxReport, CapstoneError> {
+    TxReport::from_txid(ctx, node, txid, trader_addresses)
+}
+
+/// Traces every input back to the output it spends and splits the outputs into trader and change.
+/// Nothing here depends on the confirming block, so it also works on a still-unconfirmed transaction.
+pub fn trace_transaction(
+    ctx: &Context,
+    node: &dyn NodeApi,
+    decoded_tx: &Transaction,
+    trader_addresses: &[&str],
+) -> Result<TxDetails, CapstoneError> {
+    // Trace miner's tx input address using the vin source
+    if decoded_tx.input.is_empty() {
+        return Err(CapstoneError::NoInputs(decoded_tx.compute_txid()));
+    }
+
+    // Fetch every distinct previous transaction up front, in one round trip where the node allows it.
+    // Anything but pruned block data is fatal here (a -5 "No such transaction" included); pruned ones fall
+    // back to the wallet in previous_output.
+    let mut prev_txids: Vec<Txid> = Vec::new();
+    for input in &decoded_tx.input {
+        if !prev_txids.contains(&input.previous_output.txid) {
+            prev_txids.push(input.previous_output.txid);
+        }
+    }
+    let mut prev_txs = HashMap::new();
+    for (txid, prev_tx) in prev_txids
+        .iter()
+        .zip(node.raw_transactions(ctx, &prev_txids))
+    {
+        match prev_tx {
+            Err(err) if !is_pruned(&err) => return Err(err.into()),
+            prev_tx => prev_txs.insert(*txid, prev_tx),
+        };
+    }
+
+    // The reported input address is the first input's; the input amount is the sum over all inputs
+    let mut miner_input_address = String::new();
+    let mut miner_input_amount = Amount::ZERO;
+    let mut inputs_pruned = false;
+    for (i, input) in decoded_tx.input.iter().enumerate() {
+        let prev_tx = &prev_txs[&input.previous_output.txid];
+        let Some((address, value)) = previous_output(node, &input.previous_output, prev_tx)? else {
+            inputs_pruned = true;
+            continue;
+        };
+        if i == 0 {
+            miner_input_address = address;
+        }
+        miner_input_amount = miner_input_amount
+            .checked_add(value)
+            .ok_or(CapstoneError::AmountOverflow("input total"))?;
+    }
+
+    let sender_owned = sender_owned(node, decoded_tx)?;
+    let (trader_address, trader_amount, change_address, change_amount) =
+        split_outputs(decoded_tx, trader_addresses, &sender_owned);
+    let trader_script_type = decoded_tx
+        .output
+        .iter()
+        .find(|out| script_address(&out.script_pubkey) == trader_address)
+        .map_or("none", |out| script_type(&out.script_pubkey));
+
+    // Fee calculation with Amount types. Without every input value there is no fee to compute: the degraded
+    // report leaves the input fields empty and zero, check_balance lets it through, and the report writes
+    // PRUNED_PLACEHOLDER in their place
+    let (input_address, input_amount, fee) = if inputs_pruned {
+        warn!("⚠️ Some inputs are pruned and unknown to the wallet; input amount and fee are unavailable");
+        (String::new(), Amount::ZERO, Amount::ZERO)
+    } else {
+        let fee = compute_fee(miner_input_amount, &decoded_tx.output)?;
+        (miner_input_address, miner_input_amount, fee)
+    };
+
+    Ok(TxDetails {
+        txid: decoded_tx.compute_txid(),
+        input_address,
+        input_amount,
+        trader_address,
+        trader_amount,
+        trader_script_type,
+        change_address,
+        change_amount,
+        fee,
+        inputs_pruned,
+        rbf_signaled: signals_rbf(decoded_tx),
+        locktime: decoded_tx.lock_time.to_consensus_u32(),
+    })
+}
+
+// BIP 125 opt-in: any input with nSequence below 0xfffffffe makes the whole transaction replaceable
+pub fn signals_rbf(tx: &Transaction) -> bool {
+    tx.input.iter().any(|input| input.sequence.is_rbf())
+}
+
+// Stands in for input fields that a pruned node could no longer provide, on the terminal and in out.txt,
+// out.csv and the fee file alike
+pub const PRUNED_PLACEHOLDER: &str = "unavailable (pruned)";
+
+/// The address and value of the output `outpoint` spends. Normally read from `prev_tx`, the
+/// `getrawtransaction` result for its transaction; when that failed because the node has pruned the block,
+/// the wallet's own copy (`gettransaction`) is used instead, which it keeps for every transaction touching
+/// it. `None` means neither source has it any more: the degraded mode.
+pub fn previous_output(
+    node: &dyn NodeApi,
+    outpoint: &bitcoin::OutPoint,
+    prev_tx: &bitcoincore_rpc::Result<GetRawTransactionResult>,
+) -> Result<Option<(String, Amount)>, CapstoneError> {
+    match prev_tx {
+        Ok(prev_tx) => {
+            let prev_output = prev_tx
+                .vout
+                .get(outpoint.vout as usize)
+                .ok_or(CapstoneError::InvalidInputReference(*outpoint))?;
+            let script =
+                prev_output
+                    .script_pub_key
+                    .script()
+                    .map_err(|err| CapstoneError::Decode {
+                        what: "output script",
+                        message: err.to_string(),
+                    })?; // Unwrap script safely
+            let address = bitcoin::Address::from_script(&script, bitcoin::Network::Regtest)
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|_| "Unknown".to_string());
+            Ok(Some((address, prev_output.value)))
+        }
+        Err(err) => match node.wallet_transaction(&outpoint.txid) {
+            Ok(prev_tx) => {
+                info!("Input {outpoint} is pruned from the node; using the wallet's copy");
+                let prev_output = prev_tx
+                    .output
+                    .get(outpoint.vout as usize)
+                    .ok_or(CapstoneError::InvalidInputReference(*outpoint))?;
+                Ok(Some((
+                    script_address(&prev_output.script_pubkey),
+                    prev_output.value,
+                )))
+            }
+            Err(wallet_err) => {
+                warn!("⚠️ Input {outpoint} is pruned ({err}) and not in the wallet ({wallet_err})");
+                Ok(None)
+            }
+        },
+    }
+}
+
+/// What `getrawtransaction` (verbose) says about `tx` while it is unconfirmed, as far as extraction reads it:
+/// the hex and each output's value and script. For nodes that aren't a real node (`selftest`, tests).
+pub fn unconfirmed_raw(tx: &Transaction) -> GetRawTransactionResult {
+    GetRawTransactionResult {
+        in_active_chain: None,
+        hex: bitcoin::consensus::encode::serialize(tx),
+        txid: tx.compute_txid(),
+        hash: tx.compute_wtxid(),
+        size: tx.total_size(),
+        vsize: tx.vsize(),
+        version: tx.version.0 as u32,
+        locktime: tx.lock_time.to_consensus_u32(),
+        vin: Vec::new(), // Extraction decodes the hex instead
+        vout: tx
+            .output
+            .iter()
+            .enumerate()
+            .map(|(n, out)| GetRawTransactionResultVout {
+                value: out.value,
+                n: n as u32,
+                script_pub_key: GetRawTransactionResultVoutScriptPubKey {
+                    asm: String::new(),
+                    hex: out.script_pubkey.to_bytes(),
+                    req_sigs: None,
+                    type_: None,
+                    addresses: Vec::new(),
+                    address: None,
+                },
+            })
+            .collect(),
+        blockhash: None,
+        confirmations: None,
+        time: None,
+        blocktime: None,
+    }
+}
+
+// A lookup that failed because the node pruned the block: "Block not available (pruned data)". Other
+// errors, -5 "No such mempool or blockchain transaction" among them, are real failures
+pub fn is_pruned(err: &bitcoincore_rpc::Error) -> bool {
+    match err {
+        bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(rpc_err)) => {
+            rpc_err.message.contains("(pruned data)")
+        }
+        _ => false,
+    }
+}
+
+// Whether `txid` already sits in a block, e.g. because another process sharing the node mined one
+// between our send and our own confirming block
+pub fn confirmed_elsewhere(node: &dyn NodeApi, txid: &Txid) -> bitcoincore_rpc::Result<bool> {
+    Ok(node.wallet_confirmations(txid)? > 0)
+}
+
+/// Looks for `txid` in the mempool and, if it is still there, captures its `getmempoolentry` fields.
+/// Best-effort: the snapshot is only extra detail for the JSON report, so a missing tx or a failed
+/// lookup is logged and the run carries on to the confirmation step.
+pub fn observe_mempool(node: &dyn NodeApi, txid: &Txid) -> Option<MempoolSnapshot> {
+    match node.mempool_txids() {
+        Ok(txids) if txids.contains(txid) => info!("Transaction is in the mempool."),
+        Ok(_) => {
+            warn!("⚠️ Transaction not found in mempool.");
+            return None;
+        }
+        Err(err) => {
+            warn!("⚠️ Could not list the mempool ({err}); skipping mempool details.");
+            return None;
+        }
+    }
+
+    // Fetch the unconfirmed transaction from the node's mempool as requested in instructions (using getmempoolentry).
+    // The tx can confirm between the two calls, in which case its entry is already gone.
+    match node.mempool_entry(txid) {
+        Ok(mempool_entry) => {
+            info!("Mempool entry details: {mempool_entry:?}");
+            Some(MempoolSnapshot::from(&mempool_entry))
+        }
+        Err(err) => {
+            warn!("⚠️ Mempool entry for {txid} is gone ({err}); it probably just confirmed.");
+            None
+        }
+    }
+}
+
+/// The block a transaction was found in, as read back from the active chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConfirmingBlock {
+    pub height: usize,
+    pub hash: BlockHash,
+    pub time: u64,          // Header timestamp, Unix seconds
+    pub confirmations: u32, // 1 for the tip, growing as blocks are mined on top
+}
+
+impl From<&GetBlockResult> for ConfirmingBlock {
+    fn from(block_info: &GetBlockResult) -> Self {
+        ConfirmingBlock {
+            height: block_info.height,
+            hash: block_info.hash,
+            time: block_info.time as u64,
+            confirmations: block_info.confirmations.max(0) as u32,
+        }
+    }
+}
+
+// How many blocks past a block that should have confirmed the tx are searched before giving up on it
+pub const CONFIRM_SEARCH_AHEAD: usize = 3;
+
+// Looks for `txid` in the active-chain blocks right after `height`, stopping at the tip
+pub fn search_forward(
+    node: &dyn NodeApi,
+    txid: &Txid,
+    height: usize,
+) -> Result<Option<ConfirmingBlock>, CapstoneError> {
+    for next in height + 1..=height + CONFIRM_SEARCH_AHEAD {
+        let Ok(hash) = node.block_hash_at(next as u64) else {
+            break; // Past the tip
+        };
+        let block_info = node.block_info(&hash)?;
+        if block_info.tx.contains(txid) {
+            info!(
+                "Transaction {txid} confirmed {} block(s) later than expected, at height {next}",
+                next - height
+            );
+            return Ok(Some(ConfirmingBlock::from(&block_info)));
+        }
+    }
+    Ok(None)
+}
+
+/// Where to start looking for the confirming block: the hash our own `generatetoaddress` returned, checked
+/// against the `blockhash` of `getrawtransaction`. They agree in the normal flow. If they don't, something
+/// else happened in between (a reorg, or another miner confirming the tx first), so the node's index wins.
+pub fn confirming_hash(mined: Option<BlockHash>, indexed: Option<BlockHash>) -> Option<BlockHash> {
+    match (mined, indexed) {
+        (Some(mined), Some(indexed)) if mined != indexed => {
+            warn!("⚠️ Mined block {mined} but the node says block {indexed} confirms the transaction; using the latter");
+            Some(indexed)
+        }
+        (Some(mined), _) => Some(mined),
+        (None, indexed) => indexed,
+    }
+}
+
+/// Resolves the block confirming `txid`, starting from the hash `getrawtransaction` gave us.
+/// The block must still be on the active chain (`getblockhash(height)` agrees) and must list the txid;
+/// if a reorg replaced it, the block is re-derived from a fresh `getrawtransaction`.
+pub fn confirmed_block(
+    node: &dyn NodeApi,
+    txid: &Txid,
+    mut block_hash: Option<BlockHash>,
+) -> Result<ConfirmingBlock, CapstoneError> {
+    for _ in 0..REORG_RECHECKS {
+        let hash = block_hash.ok_or(CapstoneError::MissingBlock(*txid))?;
+        let block_info = node.block_info(&hash)?;
+        let on_active_chain =
+            block_info.confirmations >= 0 && node.block_hash_at(block_info.height as u64)? == hash;
+        if on_active_chain && block_info.tx.contains(txid) {
+            return Ok(ConfirmingBlock::from(&block_info));
+        }
+        if on_active_chain {
+            // A real block that just doesn't hold the tx, e.g. the one we mined while the tx landed later
+            if let Some(found) = search_forward(node, txid, block_info.height)? {
+                return Ok(found);
+            }
+        }
+
+        warn!("⚠️ Block {hash} no longer confirms {txid} (reorg?), re-reading the transaction");
+        block_hash = node.raw_transaction(txid)?.blockhash;
+    }
+    Err(CapstoneError::UnstableBlock(*txid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{OutPoint, ScriptBuf, Sequence, Witness};
+    use bitcoincore_rpc::jsonrpc::{Request, Response, Transport};
+    use bitcoincore_rpc::Client;
+    use bitcoincore_rpc::RpcApi;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::thread;
+
+    #[test]
+    fn write_report_creates_parent_directories() {
+        let dir = std::env::temp_dir().join(format!("capstone-out-{}", std::process::id()));
+        let path = dir.join("nested").join("out.txt");
+        let report = sample_report();
+
+        let written = write_report(&report, &path, Units::Btc).unwrap();
+
+        assert!(written.is_absolute());
+        assert_eq!(fs::read_to_string(&written).unwrap().lines().count(), 10);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn csv_sink_appends_a_row_per_run() {
+        let dir = std::env::temp_dir().join(format!("capstone-csv-{}", std::process::id()));
+        let sink = CsvSink {
+            path: dir.join("out.csv"),
+            units: Units::Btc,
+        };
+        let mut report = sample_report();
+
+        sink.write(&report).unwrap();
+        report.block_height += 1;
+        sink.write(&report).unwrap();
+
+        let csv = fs::read_to_string(&sink.path).unwrap();
+        let rows: Vec<Vec<&str>> = csv.lines().map(|row| row.split(',').collect()).collect();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0], OUTPUT_FIELDS);
+        assert_eq!(
+            rows[2][8],
+            (rows[1][8].parse::<usize>().unwrap() + 1).to_string()
+        );
+
+        // Someone else's CSV is not appended to
+        fs::write(&sink.path, "date,amount\n").unwrap();
+        assert!(matches!(
+            sink.write(&report),
+            Err(CapstoneError::ReportWrite { .. })
+        ));
+        assert_eq!(fs::read_to_string(&sink.path).unwrap(), "date,amount\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn report_lines_match_the_file() {
+        let dir = std::env::temp_dir().join(format!("capstone-lines-{}", std::process::id()));
+        let report = sample_report();
+
+        let written = write_report(&report, &dir.join("out.txt"), Units::Btc).unwrap();
+        let file = fs::read_to_string(written).unwrap();
+
+        assert_eq!(file.lines().collect::<Vec<_>>(), report.lines());
+        // Every amount line is fixed at 8 decimals, as the grader parses them
+        assert_eq!(report.lines()[2], "50.00000000");
+        assert_eq!(report.lines()[4], "20.00000000");
+        assert_eq!(report.lines()[6], "29.99998590");
+        assert_eq!(report.lines()[7], "0.00001410");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn out_json_holds_the_out_txt_fields_under_their_names() {
+        let dir = std::env::temp_dir().join(format!("capstone-fields-{}", std::process::id()));
+        let report = sample_report();
+
+        let written = write_json_report(&report, &dir.join("out.json"), false).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(written).unwrap()).unwrap();
+        let keys: HashSet<_> = json
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        assert!(keys.is_superset(&HashSet::from(OUTPUT_FIELDS)));
+        assert_eq!(json["txid"], report.txid.to_string());
+        assert_eq!(json["trader_amount"], 20.0);
+        assert_eq!(json["fee"], 0.0000141);
+        assert_eq!(json["block_height"], report.block_height);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn json_report_includes_mempool_snapshot_only_when_observed() {
+        let mut report = sample_report();
+        let json = serde_json::to_value(&report).unwrap();
+        assert!(json.get("mempool").is_none());
+        assert_eq!(json["trader_amount"], 20.0);
+
+        report.mempool = Some(MempoolSnapshot {
+            vsize: 141,
+            weight: Some(561),
+            base_fee: Amount::from_sat(1_410),
+            ancestor_count: 1,
+            descendant_count: 1,
+        });
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["mempool"]["vsize"], 141);
+        assert_eq!(json["mempool"]["base_fee"], 0.0000141);
+    }
+
+    // The committed snapshot of a fresh run's normalized out.json; see `fresh_run_matches_the_golden_report` in pipeline.rs
+    const GOLDEN_REPORT: &str = include_str!("../golden/out.json");
+
+    #[test]
+    fn normalized_report_has_the_golden_shape() {
+        let tx = payment_tx(&[(TRADER, 2_000_000_000), (CHANGE, 2_999_999_859)]);
+        let mut report = report_for(&tx, Amount::from_int_btc(50), Amount::from_sat(141));
+        report.mempool = Some(MempoolSnapshot {
+            vsize: 141,
+            weight: Some(561),
+            base_fee: Amount::from_sat(141),
+            ancestor_count: 1,
+            descendant_count: 1,
+        });
+        report.block_height = 102;
+        let mut json = serde_json::to_value(&report).unwrap();
+        normalize_report(&mut json);
+
+        assert!(json["txid"].is_null() && json["block_time_iso"].is_null());
+        assert!(json["mempool"]["weight"].is_null());
+        assert_eq!(json["trader_amount"], 20.0);
+        // The fixture is a fresh run up to the random parts, so it must match the snapshot exactly
+        let golden: serde_json::Value = serde_json::from_str(GOLDEN_REPORT).unwrap();
+        assert_eq!(json, golden);
+    }
+
+    #[test]
+    #[ignore = "needs a running regtest node"]
+    fn confirmed_block_follows_invalidate_and_reconsider() {
+        let miner = node_miner();
+        let address = miner.get_new_address(None, None).unwrap().assume_checked();
+        miner.generate_to_address(101, &address).unwrap();
+        let txid = miner
+            .send_to_address(
+                &address,
+                Amount::from_btc(1.0).unwrap(),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        let block = miner.generate_to_address(1, &address).unwrap()[0];
+
+        let confirming = confirmed_block(&miner, &txid, Some(block)).unwrap();
+        assert_eq!(confirming.hash, block);
+        assert!(confirming.confirmations >= 1);
+
+        // Once the block is invalidated the tx falls back to the mempool, so there is no confirming block
+        miner.invalidate_block(&block).unwrap();
+        assert!(confirmed_block(&miner, &txid, Some(block)).is_err());
+
+        miner.reconsider_block(&block).unwrap();
+        assert_eq!(
+            confirmed_block(&miner, &txid, Some(block)).unwrap(),
+            confirming
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a running regtest node"]
+    fn block_time_is_close_to_now_on_regtest() {
+        let miner = node_miner();
+        let address = miner.get_new_address(None, None).unwrap().assume_checked();
+        let block = miner.generate_to_address(1, &address).unwrap()[0];
+        let coinbase = miner.get_block_info(&block).unwrap().tx[0];
+
+        let time = confirmed_block(&miner, &coinbase, Some(block))
+            .unwrap()
+            .time;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        // Consensus allows block times up to two hours ahead; regtest blocks mined back to back may run ahead of now
+        assert!(
+            time.abs_diff(now) < 2 * 60 * 60,
+            "block time {time}, now {now}"
+        );
+    }
+
+    #[test]
+    fn iso8601_formats_utc() {
+        assert_eq!(iso8601(0), "1970-01-01T00:00:00Z");
+        assert_eq!(iso8601(951_825_600), "2000-02-29T12:00:00Z");
+        assert_eq!(iso8601(1_700_000_000), "2023-11-14T22:13:20Z");
+    }
+
+    #[test]
+    fn mock_node_report_computes_fee() {
+        let mut node = MockNode::owning(&[MINER_INPUT, CHANGE]);
+
+        let funding = payment_tx(&[(MINER_INPUT, 5_000_000_000)]);
+        let payment = tx_spending(
+            &[OutPoint::new(funding.compute_txid(), 0)],
+            &[(TRADER, 2_000_000_000), (CHANGE, 2_999_998_590)],
+        );
+        node.add_transaction(&funding);
+        node.add_transaction(&payment);
+        node.mine(&[funding.compute_txid()]);
+        let block = node.mine(&[payment.compute_txid()]);
+
+        let report = TxReport::from_txid(
+            &Context::default(),
+            &node,
+            &payment.compute_txid(),
+            &[TRADER],
+        )
+        .unwrap();
+
+        assert_eq!(report.fee, Amount::from_sat(1_410));
+        assert_eq!(report.input_address, MINER_INPUT);
+        assert_eq!(report.input_amount, Amount::from_btc(50.0).unwrap());
+        assert_eq!(report.change_address, CHANGE);
+        assert_eq!((report.block_height, report.block_hash), (1, block));
+        assert_eq!(report.block_time_iso, "2023-11-14T22:23:20Z");
+        assert!(report.confirmations >= 1);
+        assert!(report.check_balance().is_ok());
+    }
+
+    #[test]
+    fn vsize_must_match_within_one_vbyte() {
+        let tx = sample_tx();
+        let vsize = tx.vsize() as u64;
+        assert_eq!(verify_vsize(&tx, vsize), Ok(vsize));
+        assert!(verify_vsize(&tx, vsize + 1).is_ok());
+        assert_eq!(
+            verify_vsize(&tx, vsize + 2),
+            Err(CapstoneError::VsizeMismatch {
+                computed: vsize,
+                reported: vsize + 2,
+            })
+        );
+    }
+
+    #[test]
+    fn mempool_entry_vanishing_after_listing_is_not_fatal() {
+        let mut node = MockNode::default();
+        let txid = payment_tx(&[(TRADER, 2_000_000_000)]).compute_txid();
+
+        // Not in the mempool at all: nothing to capture
+        assert_eq!(observe_mempool(&node, &txid), None);
+
+        // Listed by getrawmempool, but confirmed before getmempoolentry ran
+        node.evicted.push(txid);
+        assert_eq!(observe_mempool(&node, &txid), None);
+    }
+
+    #[test]
+    fn confirmation_distance_is_checked_against_the_limit() {
+        assert_eq!(confirmation_distance(101, 102), 1);
+        assert_eq!(confirmation_distance(101, 105), 4);
+
+        assert!(check_confirmed_within(1, None).is_ok());
+        assert!(check_confirmed_within(4, None).is_ok());
+        assert!(check_confirmed_within(1, Some(1)).is_ok());
+        assert!(check_confirmed_within(4, Some(3)).is_err());
+    }
+
+    #[test]
+    fn standard_script_types_are_classified_from_raw_bytes() {
+        let script = |hex: &str| ScriptBuf::from_hex(hex).unwrap();
+        let (hash20, hash32) = ("11".repeat(20), "22".repeat(32));
+        assert_eq!(
+            script_type(&script(&format!("76a914{hash20}88ac"))),
+            "p2pkh"
+        );
+        assert_eq!(script_type(&script(&format!("a914{hash20}87"))), "p2sh");
+        assert_eq!(script_type(&script(&format!("0014{hash20}"))), "p2wpkh");
+        assert_eq!(script_type(&script(&format!("0020{hash32}"))), "p2wsh");
+        assert_eq!(script_type(&script(&format!("5120{hash32}"))), "p2tr");
+        assert_eq!(script_type(&script("6a0568656c6c6f")), "nonstandard"); // OP_RETURN "hello"
+        assert_eq!(script_type(&script_for(TRADER)), "p2wpkh");
+    }
+
+    #[test]
+    fn output_fields_follow_the_grader_order() {
+        assert_eq!(
+            OUTPUT_FIELDS,
+            [
+                "txid",
+                "input_address",
+                "input_amount",
+                "trader_address",
+                "trader_amount",
+                "change_address",
+                "change_amount",
+                "fee",
+                "block_height",
+                "block_hash",
+            ]
+        );
+
+        // Each name is a real report field, and the values come out paired with it in that order
+        let report = sample_report();
+        let json = serde_json::to_value(&report).unwrap();
+        for (name, (field, _)) in OUTPUT_FIELDS.iter().zip(report.fields()) {
+            assert_eq!(*name, field);
+            assert!(!json[name].is_null(), "{name} is not a TxReport field");
+        }
+        assert_eq!(report.fields()[3], ("trader_address", TRADER.to_string()));
+    }
+
+    #[test]
+    fn mined_block_hash_is_cross_checked_against_the_index() {
+        let mut node = MockNode::owning(&[CHANGE]);
+        let payment = sample_tx();
+        let txid = payment.compute_txid();
+        node.add_transaction(&payment);
+        let mined = node.mine(&[txid]); // What generatetoaddress would have returned
+        let indexed = node.raw_transaction(&txid).unwrap().blockhash;
+
+        // Normal flow: both name the same block, which is the one the report uses
+        assert_eq!(indexed, Some(mined));
+        assert_eq!(confirming_hash(Some(mined), indexed), Some(mined));
+        let block = confirmed_block(&node, &txid, confirming_hash(Some(mined), indexed)).unwrap();
+        assert_eq!(block.hash, mined);
+
+        // The mined hash stands in for a missing blockhash; on disagreement the index wins
+        assert_eq!(confirming_hash(Some(mined), None), Some(mined));
+        let other = BlockHash::hash(b"other");
+        assert_eq!(confirming_hash(Some(other), indexed), indexed);
+        assert_eq!(confirming_hash(None, indexed), indexed);
+    }
+
+    #[test]
+    fn transaction_mined_by_another_process_needs_no_confirming_block() {
+        let mut node = MockNode::owning(&[CHANGE]);
+        let payment = sample_tx();
+        let txid = payment.compute_txid();
+        node.add_transaction(&payment);
+        assert!(!confirmed_elsewhere(&node, &txid).unwrap());
+
+        // Someone else's generatetoaddress picks it up before ours runs
+        let hash = node.mine(&[txid]);
+        assert!(confirmed_elsewhere(&node, &txid).unwrap());
+        // And the report then points at their block
+        let block = confirmed_block(&node, &txid, Some(hash)).unwrap();
+        assert_eq!(block.hash, hash);
+    }
+
+    #[test]
+    fn pruned_input_falls_back_to_the_wallet_copy() {
+        let mut node = MockNode::owning(&[MINER_INPUT, CHANGE]);
+        let funding = payment_tx(&[(MINER_INPUT, 5_000_000_000)]);
+        let payment = tx_spending(
+            &[OutPoint::new(funding.compute_txid(), 0)],
+            &[(TRADER, 2_000_000_000), (CHANGE, 2_999_998_590)],
+        );
+        node.add_transaction(&payment);
+        node.pruned.insert(funding.compute_txid());
+        node.wallet.insert(funding.compute_txid(), funding.clone());
+
+        let details = trace_transaction(&Context::default(), &node, &payment, &[TRADER]).unwrap();
+        assert!(!details.inputs_pruned);
+        assert_eq!(details.input_address, MINER_INPUT);
+        assert_eq!(details.fee, Amount::from_sat(1_410));
+    }
+
+    /// A JSON-RPC transport standing in for a node `latency` away: answers `getrawtransaction` from `node`,
+    /// paying the latency once per HTTP round trip (a whole batch is one) and counting the round trips.
+    struct DistantNode {
+        node: MockNode,
+        latency: Duration,
+        round_trips: std::sync::Arc<AtomicU64>,
+    }
+
+    impl DistantNode {
+        fn answer(&self, request: &Request) -> Response {
+            let (txid, _verbose): (Txid, bool) =
+                serde_json::from_str(request.params.unwrap().get()).unwrap();
+            let (result, error) = match self.node.raw_transaction(&txid) {
+                Ok(raw) => (Some(jsonrpc::arg(raw)), None),
+                Err(err) => (
+                    None,
+                    Some(jsonrpc::error::RpcError {
+                        code: -5,
+                        message: err.to_string(),
+                        data: None,
+                    }),
+                ),
+            };
+            Response {
+                result,
+                error,
+                id: request.id.clone(),
+                jsonrpc: Some("2.0".to_string()),
+            }
+        }
+    }
+
+    impl Transport for DistantNode {
+        fn send_request(&self, request: Request) -> Result<Response, jsonrpc::Error> {
+            self.round_trips.fetch_add(1, Ordering::Relaxed);
+            thread::sleep(self.latency);
+            Ok(self.answer(&request))
+        }
+
+        fn send_batch(&self, requests: &[Request]) -> Result<Vec<Response>, jsonrpc::Error> {
+            self.round_trips.fetch_add(1, Ordering::Relaxed);
+            thread::sleep(self.latency);
+            Ok(requests
+                .iter()
+                .map(|request| self.answer(request))
+                .collect())
+        }
+
+        fn fmt_target(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "distant mock node")
+        }
+    }
+
+    /// A node knowing `count` funding transactions of about 5 BTC each, the previous transactions of a
+    /// `count`-input payment, and their txids.
+    fn many_input_payment(count: u64) -> (MockNode, Vec<Txid>) {
+        let mut node = MockNode::default();
+        let txids = (0..count)
+            .map(|i| {
+                let funding = payment_tx(&[(MINER_INPUT, 500_000_000 + i)]);
+                node.add_transaction(&funding);
+                funding.compute_txid()
+            })
+            .collect();
+        (node, txids)
+    }
+
+    fn distant_client(node: MockNode, latency: Duration) -> (Client, std::sync::Arc<AtomicU64>) {
+        let round_trips = std::sync::Arc::new(AtomicU64::new(0));
+        let transport = DistantNode {
+            node,
+            latency,
+            round_trips: round_trips.clone(),
+        };
+        let client = Client::from_jsonrpc(jsonrpc::Client::with_transport(transport));
+        (client, round_trips)
+    }
+
+    #[test]
+    fn previous_transactions_are_fetched_in_one_batch() {
+        let (node, txids) = many_input_payment(10);
+        let expected: Vec<_> = txids
+            .iter()
+            .map(|txid| node.raw_transaction(txid).unwrap())
+            .collect();
+        let (client, round_trips) = distant_client(node, Duration::ZERO);
+
+        let batched = client.raw_transactions(&Context::default(), &txids);
+        assert_eq!(round_trips.load(Ordering::Relaxed), 1);
+        let batched: Vec<_> = batched.into_iter().map(Result::unwrap).collect();
+        assert_eq!(batched, expected);
+
+        // Errors stay with their own txid; an unknown one is not mistaken for pruning
+        let unknown = payment_tx(&[(TRADER, 1)]).compute_txid();
+        let mixed = client.raw_transactions(&Context::default(), &[txids[0], unknown]);
+        assert_eq!(round_trips.load(Ordering::Relaxed), 2);
+        assert!(mixed[0].is_ok());
+        assert!(!is_pruned(mixed[1].as_ref().unwrap_err()));
+    }
+
+    #[test]
+    #[ignore = "benchmark; run with --ignored --nocapture to see the timings"]
+    fn batched_lookups_beat_serial_ones_on_ten_inputs() {
+        let latency = Duration::from_millis(20);
+        let (node, txids) = many_input_payment(10);
+        let (client, round_trips) = distant_client(node, latency);
+
+        let start = Instant::now();
+        for txid in &txids {
+            client.raw_transaction(txid).unwrap();
+        }
+        let serial = start.elapsed();
+        let start = Instant::now();
+        for raw in client.raw_transactions(&Context::default(), &txids) {
+            raw.unwrap();
+        }
+        let batched = start.elapsed();
+
+        println!("10 inputs at {latency:?} per round trip: serial {serial:?}, batched {batched:?}");
+        assert_eq!(round_trips.load(Ordering::Relaxed), 11);
+        assert!(batched * 5 < serial);
+    }
+
+    #[test]
+    fn rbf_is_signaled_by_any_input_below_final_minus_one() {
+        let mut tx = tx_spending(
+            &[OutPoint::null(), OutPoint::null()],
+            &[(TRADER, 2_000_000_000), (CHANGE, 2_999_998_590)],
+        );
+        assert!(signals_rbf(&tx)); // 0xfffffffd, what Core's wallet sends by default
+
+        for input in &mut tx.input {
+            input.sequence = Sequence::ENABLE_LOCKTIME_NO_RBF; // 0xfffffffe
+        }
+        assert!(!signals_rbf(&tx));
+        tx.input[1].sequence = Sequence::MAX;
+        assert!(!signals_rbf(&tx));
+
+        tx.input[1].sequence = Sequence::from_consensus(0xffff_fffd);
+        assert!(signals_rbf(&tx));
+    }
+
+    #[test]
+    fn input_pruned_everywhere_degrades_to_placeholders() {
+        let mut node = MockNode::owning(&[MINER_INPUT, CHANGE]);
+        let funding = payment_tx(&[(MINER_INPUT, 5_000_000_000)]);
+        let payment = tx_spending(
+            &[OutPoint::new(funding.compute_txid(), 0)],
+            &[(TRADER, 2_000_000_000), (CHANGE, 2_999_998_590)],
+        );
+        node.add_transaction(&payment);
+        node.pruned.insert(funding.compute_txid());
+
+        let report = trace_transaction(&Context::default(), &node, &payment, &[TRADER])
+            .unwrap()
+            .confirmed_at(ConfirmingBlock {
+                height: 102,
+                hash: BlockHash::all_zeros(),
+                time: 1_700_000_000,
+                confirmations: 1,
+            });
+        assert!(report.inputs_pruned);
+        let lines = report.lines();
+        assert_eq!(lines[1], PRUNED_PLACEHOLDER);
+        assert_eq!(lines[2], PRUNED_PLACEHOLDER);
+        assert_eq!(lines[4], "20.00000000"); // Output-side fields are unaffected
+        assert_eq!(lines[7], PRUNED_PLACEHOLDER);
+
+        // Nothing to balance without the input amounts, so the checks let it through; the files carry the
+        // placeholder, as the terminal does
+        assert!(report.check_balance().is_ok());
+        assert_eq!(
+            report.check_trader_amount(Amount::from_int_btc(21), true),
+            Ok(())
+        );
+        let dir = std::env::temp_dir().join(format!("capstone-pruned-{}", std::process::id()));
+        let out = write_report(&report, &dir.join("out.txt"), Units::Btc).unwrap();
+        let written: Vec<String> = fs::read_to_string(out)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        assert_eq!(written, lines);
+        let fee = write_fee_file(&report, &dir.join("fee.txt"), Units::Sat).unwrap();
+        assert_eq!(
+            fs::read_to_string(fee).unwrap(),
+            format!("{PRUNED_PLACEHOLDER}\n")
+        );
+        let csv = dir.join("out.csv");
+        CsvSink {
+            path: csv.clone(),
+            units: Units::Btc,
+        }
+        .write(&report)
+        .unwrap();
+        let row = fs::read_to_string(&csv).unwrap();
+        assert_eq!(
+            row.lines()
+                .nth(1)
+                .unwrap()
+                .matches(PRUNED_PLACEHOLDER)
+                .count(),
+            3
+        );
+        fs::remove_dir_all(dir).unwrap();
+
+        // Other lookup failures are still errors
+        node.pruned.clear();
+        assert!(trace_transaction(&Context::default(), &node, &payment, &[TRADER]).is_err());
+    }
+
+    #[test]
+    fn transaction_landing_two_blocks_later_is_found() {
+        let mut node = MockNode::default();
+        let payment = payment_tx(&[(TRADER, 2_000_000_000)]);
+        node.add_transaction(&payment);
+        node.mine(&[]);
+        let mined_by_us = node.mine(&[]); // The block we mined right after broadcast, without our tx
+        node.mine(&[]);
+        let later = node.mine(&[payment.compute_txid()]);
+
+        let block = confirmed_block(&node, &payment.compute_txid(), Some(mined_by_us)).unwrap();
+        assert_eq!((block.height, block.hash), (3, later));
+
+        // Beyond the search window it is not guessed at
+        let mut far = MockNode::default();
+        far.add_transaction(&payment);
+        let stale = far.mine(&[]);
+        for _ in 0..CONFIRM_SEARCH_AHEAD {
+            far.mine(&[]);
+        }
+        far.mine(&[payment.compute_txid()]);
+        far.transactions
+            .get_mut(&payment.compute_txid())
+            .unwrap()
+            .blockhash = Some(stale);
+        assert!(confirmed_block(&far, &payment.compute_txid(), Some(stale)).is_err());
+    }
+
+    #[test]
+    fn display_labels_every_field() {
+        let report = sample_report();
+        let shown = report.to_string();
+
+        for label in [
+            "Transaction ID: ",
+            "Miner Input Address: ",
+            "Miner Input Amount: 50.00000000 BTC",
+            &format!("Trader Output Address: {TRADER}"),
+            "Trader Output Amount: 20.00000000 BTC",
+            &format!("Miner Change Address: {CHANGE}"),
+            "Miner Change Amount: 29.99998590 BTC",
+            "Fee: 0.00001410 BTC",
+            "Block Height: 102",
+            "Block Hash: ",
+            "Confirmations: 1",
+        ] {
+            assert!(shown.contains(label), "missing `{label}` in:\n{shown}");
+        }
+    }
+
+    #[test]
+    fn fee_is_inputs_minus_outputs() {
+        let tx = sample_tx();
+        assert_eq!(
+            compute_fee(Amount::from_btc(50.0).unwrap(), &tx.output),
+            Ok(Amount::from_sat(1_410))
+        );
+    }
+
+    #[test]
+    fn outputs_exceeding_inputs_is_a_fee_underflow() {
+        let tx = payment_tx(&[(TRADER, 2_000_000_000), (CHANGE, 4_999_998_590)]);
+        assert_eq!(
+            compute_fee(Amount::from_btc(50.0).unwrap(), &tx.output),
+            Err(CapstoneError::FeeUnderflow {
+                inputs: Amount::from_btc(50.0).unwrap(),
+                outputs: Amount::from_sat(6_999_998_590),
+            })
+        );
+    }
+
+    #[test]
+    fn split_outputs_separates_trader_from_change() {
+        let tx = payment_tx(&[(CHANGE, 2_999_998_590), (TRADER, 2_000_000_000)]);
+        let (trader_address, trader_amount, change_address, change_amount) =
+            split_outputs(&tx, &[TRADER], &miner_owned());
+
+        assert_eq!(trader_address, TRADER);
+        assert_eq!(trader_amount, Amount::from_btc(20.0).unwrap());
+        assert_eq!(change_address, CHANGE);
+        assert_eq!(change_amount, Amount::from_sat(2_999_998_590));
+    }
+
+    #[test]
+    fn external_trader_address_is_not_change() {
+        // An address no wallet of ours owns, supplied via --trader-address
+        let external = "bcrt1qv5plgft75j0hegtvf6zs5pajh7k0gxg2dhj224";
+        let tx = payment_tx(&[(external, 2_000_000_000), (CHANGE, 2_999_998_590)]);
+        let (trader_address, trader_amount, change_address, _) =
+            split_outputs(&tx, &[external], &miner_owned());
+
+        assert_eq!(trader_address, external);
+        assert_eq!(trader_amount, Amount::from_btc(20.0).unwrap());
+        assert_eq!(change_address, CHANGE);
+    }
+
+    #[test]
+    fn split_payment_aggregates_every_trader_output() {
+        let second = "bcrt1qe2tczyk2rw7u47kzxxee5g7ufkncdmlcf5v0dv";
+        let third = "bcrt1q8c37s9sq89v55vuffajkfcd3xj9m67sqa87v0a";
+        let tx = payment_tx(&[
+            (third, 666_666_666),
+            (CHANGE, 2_999_998_590),
+            (TRADER, 666_666_668),
+            (second, 666_666_666),
+        ]);
+        let (trader_address, trader_amount, change_address, change_amount) =
+            split_outputs(&tx, &[TRADER, second, third], &miner_owned());
+
+        // The first listed address is the one reported, whatever its output index
+        assert_eq!(trader_address, TRADER);
+        assert_eq!(trader_amount, Amount::from_btc(20.0).unwrap());
+        assert_eq!(change_address, CHANGE);
+        assert_eq!(change_amount, Amount::from_sat(2_999_998_590));
+    }
+
+    #[test]
+    fn balanced_report_passes_accounting_check() {
+        let report = sample_report();
+
+        assert!(report.check_balance().is_ok());
+    }
+
+    #[test]
+    fn undercounted_inputs_fail_accounting_check() {
+        // Two 50 BTC inputs funding a 70 BTC spend, but only the first input was counted
+        let tx = payment_tx(&[(TRADER, 2_000_000_000), (CHANGE, 4_999_998_590)]);
+        let report = report_for(&tx, Amount::from_btc(50.0).unwrap(), Amount::ZERO);
+
+        assert!(report.check_balance().is_err());
+    }
+
+    #[test]
+    fn trader_receives_exactly_the_sent_amount() {
+        let sent = Amount::from_btc(20.0).unwrap();
+        let report = sample_report();
+
+        assert_eq!(report.check_trader_amount(sent, false), Ok(()));
+    }
+
+    #[test]
+    fn fee_from_recipient_fails_the_trader_amount_check() {
+        // subtractfeefromamount: the Trader output pays the fee and the change is a round 30 BTC
+        let sent = Amount::from_btc(20.0).unwrap();
+        let tx = payment_tx(&[(TRADER, 1_999_998_590), (CHANGE, 3_000_000_000)]);
+        let report = report_for(
+            &tx,
+            Amount::from_btc(50.0).unwrap(),
+            Amount::from_sat(1_410),
+        );
+
+        // The books still balance, so only this check notices
+        assert!(report.check_balance().is_ok());
+        assert_eq!(
+            report.check_trader_amount(sent, false),
+            Err(CapstoneError::TraderAmountMismatch {
+                expected: sent,
+                received: Amount::from_sat(1_999_998_590),
+            })
+        );
+    }
+
+    #[test]
+    fn paid_outputs_lists_every_recipient_and_the_change() {
+        let tx = payment_tx(&[
+            (TRADER, 100_000_000),
+            (CHANGE, 2_699_998_000),
+            (MINER_INPUT, 200_000_000),
+        ]);
+        let outputs = paid_outputs(&tx, &miner_owned());
+        assert_eq!(
+            outputs
+                .iter()
+                .map(|out| (
+                    out.vout,
+                    out.address.as_str(),
+                    out.amount.to_sat(),
+                    out.change
+                ))
+                .collect::<Vec<_>>(),
+            [
+                (0, TRADER, 100_000_000, false),
+                (1, CHANGE, 2_699_998_000, true),
+                (2, MINER_INPUT, 200_000_000, false),
+            ]
+        );
+        // out.txt's view of the same payment: both recipients count as the Trader
+        let (first, paid, change, change_amount) =
+            split_outputs(&tx, &[TRADER, MINER_INPUT], &miner_owned());
+        assert_eq!((first.as_str(), paid.to_sat()), (TRADER, 300_000_000));
+        assert_eq!(
+            (change.as_str(), change_amount.to_sat()),
+            (CHANGE, 2_699_998_000)
+        );
+    }
+
+    #[test]
+    fn raw_tx_file_round_trips_to_the_txid() {
+        let dir = std::env::temp_dir().join(format!("capstone-hex-{}", std::process::id()));
+        let tx = sample_tx();
+        let raw = bitcoin::consensus::encode::serialize(&tx);
+
+        let written = write_raw_tx_file(&raw, &tx.compute_txid(), &dir.join("tx.hex")).unwrap();
+        let hex = fs::read_to_string(written).unwrap();
+        let decoded: Transaction =
+            bitcoin::consensus::encode::deserialize_hex(hex.trim_end()).unwrap();
+        assert_eq!(decoded, tx);
+
+        // Bytes of another transaction, or truncated bytes, are refused rather than written
+        let other = payment_tx(&[(TRADER, 1_000_000_000)]).compute_txid();
+        assert!(write_raw_tx_file(&raw, &other, &dir.join("other.hex")).is_err());
+        assert!(write_raw_tx_file(
+            &raw[..raw.len() - 1],
+            &tx.compute_txid(),
+            &dir.join("cut.hex")
+        )
+        .is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn fee_file_matches_line_eight_of_the_report() {
+        let dir = std::env::temp_dir().join(format!("capstone-fee-{}", std::process::id()));
+        let report = sample_report();
+
+        for units in [Units::Btc, Units::Sat] {
+            let out = write_report(&report, &dir.join("out.txt"), units).unwrap();
+            let fee = write_fee_file(&report, &dir.join("fee.txt"), units).unwrap();
+            let line_8 = fs::read_to_string(out)
+                .unwrap()
+                .lines()
+                .nth(7)
+                .unwrap()
+                .to_string();
+            assert_eq!(fs::read_to_string(fee).unwrap(), format!("{line_8}\n"));
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn explained_lines_pair_each_value_with_its_meaning() {
+        let report = sample_report();
+        let explained = report.explained_lines(Units::Btc);
+        assert!(explained[7].starts_with("Fee: "));
+        assert!(explained[7].ends_with("\n    0.00001410"));
+        assert!(report.explained_lines(Units::Sat)[7].ends_with("\n    1410"));
+        // The plain lines are what out.txt gets, explanations or not
+        for (explained, plain) in explained.iter().zip(report.lines()) {
+            assert!(explained.ends_with(&format!("    {plain}")));
+        }
+    }
+
+    #[test]
+    fn segwit_input_scripts_show_the_witness() {
+        let funding = payment_tx(&[(MINER_INPUT, 5_000_000_000)]);
+        let mut tx = tx_spending(
+            &[
+                OutPoint::new(funding.compute_txid(), 0),
+                OutPoint::new(funding.compute_txid(), 1),
+            ],
+            &[(TRADER, 2_000_000_000), (CHANGE, 2_999_998_590)],
+        );
+        // A p2wpkh spend: 71-byte DER signature with sighash byte, then the 33-byte compressed key
+        let (signature, key) = ([0x30; 71], [0x02; 33]);
+        tx.input[0].witness = Witness::from_slice(&[&signature[..], &key[..]]);
+        // And a legacy p2pkh one, both in the scriptSig
+        tx.input[1].script_sig = bitcoin::blockdata::script::Builder::new()
+            .push_slice(signature)
+            .push_slice(key)
+            .into_script();
+
+        let scripts = input_scripts(&tx);
+        assert_eq!(scripts[0].script_type, "p2wpkh");
+        assert_eq!(scripts[0].script_sig, "");
+        assert_eq!(
+            scripts[0].witness,
+            [signature.to_lower_hex_string(), key.to_lower_hex_string()]
+        );
+        assert_eq!(scripts[0].outpoint, format!("{}:0", funding.compute_txid()));
+        assert_eq!(scripts[1].script_type, "p2pkh");
+        assert!(scripts[1].witness.is_empty());
+        assert!(scripts[1].script_sig.ends_with(&key.to_lower_hex_string()));
+        assert!(scripts[0].to_string().contains("witness[1]: 0202"));
+
+        // Only reports that asked for the section carry it in JSON
+        let mut report = report_for(
+            &tx,
+            Amount::from_btc(50.0).unwrap(),
+            Amount::from_sat(1_410),
+        );
+        assert!(serde_json::to_value(&report)
+            .unwrap()
+            .get("input_scripts")
+            .is_none());
+        report.input_scripts = Some(scripts);
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["input_scripts"][0]["script_type"], "p2wpkh");
+        assert_eq!(
+            json["input_scripts"][0]["witness"][1],
+            key.to_lower_hex_string()
+        );
+    }
+
+    #[test]
+    fn satoshi_lines_round_trip_to_the_btc_lines() {
+        let report = sample_report();
+        let (btc, sat) = (report.lines(), report.lines_in(Units::Sat));
+
+        assert_eq!(sat[7], "1410");
+        for i in [2, 4, 6, 7] {
+            let from_sat = Amount::from_sat(sat[i].parse().unwrap());
+            assert_eq!(format!("{:.8}", from_sat.to_btc()), btc[i], "line {i}");
+        }
+        // Non-amount lines are the same in both units
+        for i in [0, 1, 3, 5, 8, 9] {
+            assert_eq!(sat[i], btc[i]);
+        }
+    }
+
+    #[test]
+    fn sweep_pays_one_output_to_the_trader_and_no_change() {
+        // sendall of a 50 BTC balance: a single output carrying everything but the fee
+        let balance = Amount::from_btc(50.0).unwrap();
+        let fee = Amount::from_sat(1_100);
+        let tx = payment_tx(&[(TRADER, 4_999_998_900)]);
+        let report = report_for(&tx, balance, fee);
+
+        assert_eq!(tx.output.len(), 1);
+        assert_eq!(report.trader_address, TRADER);
+        assert_eq!(report.change_address, "");
+        assert_eq!(report.change_amount, Amount::ZERO);
+        assert!(report.check_balance().is_ok());
+        assert_eq!(report.check_trader_amount(balance, true), Ok(()));
+        assert!(report.check_swept().is_ok());
+
+        assert!(sample_report().check_swept().is_err());
+    }
+
+    #[test]
+    fn subtract_fee_from_recipient_deducts_the_fee_from_the_trader_output() {
+        let sent = Amount::from_btc(20.0).unwrap();
+        let fee = Amount::from_sat(1_410);
+        let tx = payment_tx(&[(TRADER, 1_999_998_590), (CHANGE, 3_000_000_000)]);
+        let report = report_for(&tx, Amount::from_btc(50.0).unwrap(), fee);
+
+        assert_eq!(report.trader_amount, sent - fee);
+        assert_eq!(report.change_amount, Amount::from_btc(30.0).unwrap());
+        assert!(report.check_balance().is_ok());
+        assert_eq!(report.check_trader_amount(sent, true), Ok(()));
+
+        // A payment where the Miner paid after all is just as wrong in this mode
+        assert_eq!(
+            sample_report().check_trader_amount(sent, true),
+            Err(CapstoneError::TraderAmountMismatch {
+                expected: sent - fee,
+                received: sent,
+            })
+        );
+    }
+}
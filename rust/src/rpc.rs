@@ -0,0 +1,878 @@
+//! Talking to Bitcoin Core: connection settings, the counting/tracing transports, the `NodeApi` the report
+//! code reads the chain through, and the node preflight checks.
+
+use crate::{CapstoneError, Context};
+use bitcoincore_rpc::bitcoin::{Address, BlockHash, Transaction, Txid};
+use bitcoincore_rpc::json::{
+    GetBlockResult, GetBlockchainInfoResult, GetMempoolEntryResult, GetNetworkInfoResult,
+    GetRawTransactionResult, IndexStatus,
+};
+use bitcoincore_rpc::jsonrpc::simple_http::{self, SimpleHttpTransport};
+use bitcoincore_rpc::jsonrpc::{self, Request, Response, Transport};
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::Deserialize;
+use serde_json::json;
+use std::fmt::Debug;
+use std::result::Result;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+use std::{thread, time::Duration};
+use tracing::{debug, info, warn};
+
+// Node access params; the defaults for connecting to RPC core, which capstone.toml can override
+pub const RPC_URL: &str = "http://127.0.0.1:18443"; // Default regtest RPC port
+pub const RPC_USER: &str = "alice";
+pub const RPC_PASS: &str = "password";
+
+// How often a lookup that says "No such mempool or blockchain transaction" right after mining is retried
+pub const LOOKUP_RETRIES: usize = 3;
+pub const LOOKUP_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// How long to keep retrying a node that isn't answering yet (bitcoind just started, or still loading its
+// block index): attempts in all, and the most spent waiting between them
+pub const CONNECT_ATTEMPTS: u32 = 8;
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+const BACKOFF_BASE: Duration = Duration::from_millis(250); // The first pause; each one after doubles
+const BACKOFF_CAP: Duration = Duration::from_secs(8);
+
+// How often `wait_for_node_ready` re-reads the chain while the node catches up with its headers
+const READY_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// RPC_IN_WARMUP: the node is up but still loading (block index, wallets, verifying blocks)
+const RPC_IN_WARMUP: i32 = -28;
+
+/// A wallet passphrase. Its `Debug` output is redacted so it can't leak through `{options:?}` or an error.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+pub struct Passphrase(pub String);
+
+impl Debug for Passphrase {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("Passphrase(<redacted>)")
+    }
+}
+
+/// Where and as whom to reach Bitcoin Core's RPC: the RPC_* constants unless the config file says otherwise.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RpcSettings {
+    pub url: String,
+    pub user: String,
+    pub pass: Passphrase,
+    pub trace: bool, // --trace-rpc: every client logs its JSON-RPC traffic to stderr
+    pub retry: RetryPolicy, // How long to wait out a node that is starting up or, on any call, warming up
+}
+
+impl Default for RpcSettings {
+    fn default() -> Self {
+        RpcSettings {
+            url: RPC_URL.to_string(),
+            user: RPC_USER.to_string(),
+            pass: Passphrase(RPC_PASS.to_string()),
+            trace: false,
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+/// How patiently `connect_with_retry`, and every call through `WarmupRetryTransport`, waits for a node that
+/// isn't ready (`--connect-attempts`, `--connect-timeout`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub timeout: Duration, // Total time spent sleeping between attempts
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: CONNECT_ATTEMPTS,
+            timeout: CONNECT_TIMEOUT,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The pause after failed attempt `attempt` (1-based): `BACKOFF_BASE` doubled per attempt up to
+    /// `BACKOFF_CAP`, of which the upper half is jitter picked by `seed`, so several runs started together
+    /// don't retry in lockstep.
+    pub fn backoff(&self, attempt: u32, seed: u64) -> Duration {
+        let full = BACKOFF_BASE
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(BACKOFF_CAP);
+        let half = full / 2;
+        half + Duration::from_millis(seed % (half.as_millis() as u64 + 1))
+    }
+}
+
+/// Connects to the node and waits (`wait_for_node_ready`) until it is reachable, warmed up and synced,
+/// retrying with `settings.retry`.
+pub fn connect_with_retry(
+    settings: &RpcSettings,
+) -> Result<(Client, GetBlockchainInfoResult, GetNetworkInfoResult), CapstoneError> {
+    let rpc = settings.connect()?;
+    let (blockchain_info, network_info) = wait_for_node_ready(&rpc, &settings.retry)?;
+    Ok((rpc, blockchain_info, network_info))
+}
+
+/// Polls `getblockchaininfo` until the node has every block it has a header for, then reads
+/// `getnetworkinfo`. Both calls go through `retry_transient`, so an unreachable node and warmup (loading the
+/// block index or wallets, -28) are waited out too. Gives up after `policy.timeout` of syncing.
+pub fn wait_for_node_ready(
+    rpc: &Client,
+    policy: &RetryPolicy,
+) -> Result<(GetBlockchainInfoResult, GetNetworkInfoResult), CapstoneError> {
+    let mut waited = Duration::ZERO;
+    loop {
+        let info = retry_transient(policy, thread::sleep, || rpc.get_blockchain_info())?;
+        match sync_progress(info.blocks, info.headers) {
+            None => {
+                let network = retry_transient(policy, thread::sleep, || rpc.get_network_info())?;
+                return Ok((info, network));
+            }
+            Some(progress) if waited >= policy.timeout => {
+                return Err(CapstoneError::NodeNotReady { waited, progress })
+            }
+            Some(progress) => {
+                info!("⏳ Waiting for the node: {progress}");
+                thread::sleep(READY_POLL_INTERVAL);
+                waited += READY_POLL_INTERVAL;
+            }
+        }
+    }
+}
+
+// What the node still has to catch up on, if anything. `initialblockdownload` isn't used: a fresh regtest
+// chain reports it until the first block is mined, so waiting on it would wait forever.
+pub fn sync_progress(blocks: u64, headers: u64) -> Option<String> {
+    (blocks < headers).then(|| format!("validated {blocks} of {headers} blocks"))
+}
+
+/// Calls `call` until it succeeds or fails with something other than a transient error (see `is_transient`),
+/// sleeping `policy.backoff` between attempts. Gives up with the last error after `policy.max_attempts`
+/// attempts, or once the next pause would exceed `policy.timeout` in all.
+pub fn retry_transient<T>(
+    policy: &RetryPolicy,
+    mut sleep: impl FnMut(Duration),
+    mut call: impl FnMut() -> bitcoincore_rpc::Result<T>,
+) -> bitcoincore_rpc::Result<T> {
+    let mut waited = Duration::ZERO;
+    let mut attempt = 1;
+    loop {
+        match call() {
+            Err(err) if is_transient(&err) && attempt < policy.max_attempts => {
+                let seed = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map_or(0, |now| now.subsec_nanos() as u64);
+                let delay = policy.backoff(attempt, seed);
+                if waited + delay > policy.timeout {
+                    return Err(err);
+                }
+                warn!(
+                    "⚠️ Node not ready ({err}); retry {attempt}/{} in {:.1}s",
+                    policy.max_attempts - 1,
+                    delay.as_secs_f64()
+                );
+                sleep(delay);
+                waited += delay;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+// Whether `err` may clear up by itself: the node refused or dropped the connection (docker's port proxy
+// accepts and closes it empty until the node listens), or is still in warmup. HTTP errors (a 401 for wrong
+// credentials) won't, so they aren't retried.
+pub fn is_transient(err: &bitcoincore_rpc::Error) -> bool {
+    match err {
+        bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Transport(transport)) => matches!(
+            transport.downcast_ref::<simple_http::Error>(),
+            Some(
+                simple_http::Error::SocketError(_)
+                    | simple_http::Error::HttpResponseTooShort { actual: 0, .. }
+            )
+        ),
+        bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(rpc_err)) => {
+            rpc_err.code == RPC_IN_WARMUP
+        }
+        _ => false,
+    }
+}
+
+impl RpcSettings {
+    /// A node-level client, for chain and wallet-management calls.
+    pub fn connect(&self) -> Result<Client, CapstoneError> {
+        self.connect_url(&self.url)
+    }
+
+    /// A client for the named wallet's `/wallet/<name>` endpoint.
+    pub fn connect_wallet(&self, name: &str) -> Result<Client, CapstoneError> {
+        self.connect_url(&wallet_url(&self.url, name)?)
+    }
+
+    pub fn connect_url(&self, url: &str) -> Result<Client, CapstoneError> {
+        let transport = SimpleHttpTransport::builder()
+            .url(url)
+            .map_err(|err| CapstoneError::InvalidRpcUrl {
+                url: url.to_string(),
+                message: format!("is not usable ({err})"),
+            })?
+            .auth(&self.user, Some(&self.pass.0))
+            .build();
+        let transport = WarmupRetryTransport {
+            inner: CountingTransport(transport),
+            policy: self.retry,
+        };
+        let client = if self.trace {
+            jsonrpc::Client::with_transport(TracingTransport(transport))
+        } else {
+            jsonrpc::Client::with_transport(transport)
+        };
+        Ok(Client::from_jsonrpc(client))
+    }
+}
+
+// Number of JSON-RPC requests sent by every client so far, for the metrics summary
+pub static RPC_CALLS: AtomicU64 = AtomicU64::new(0);
+
+/// The plain HTTP transport `Client::new` would use, plus a tally of every request in `RPC_CALLS`.
+pub struct CountingTransport(pub SimpleHttpTransport);
+
+impl Transport for CountingTransport {
+    fn send_request(&self, request: Request) -> Result<Response, jsonrpc::Error> {
+        RPC_CALLS.fetch_add(1, Ordering::Relaxed);
+        self.0.send_request(request)
+    }
+
+    fn send_batch(&self, requests: &[Request]) -> Result<Vec<Response>, jsonrpc::Error> {
+        RPC_CALLS.fetch_add(requests.len() as u64, Ordering::Relaxed);
+        self.0.send_batch(requests)
+    }
+
+    fn fmt_target(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.0.fmt_target(f)
+    }
+}
+
+/// Transport decorator that resends a call the node rejected with RPC_IN_WARMUP (-28), under `policy`. The
+/// node can drop back into warmup mid-run (loading a wallet, reindexing), and it refuses the call before
+/// running it, so resending is safe even for sends. Connection errors are passed on untouched: a call whose
+/// connection dropped may well have gone through.
+pub struct WarmupRetryTransport<T> {
+    pub inner: T,
+    pub policy: RetryPolicy,
+}
+
+impl<T: Transport> Transport for WarmupRetryTransport<T> {
+    fn send_request(&self, request: Request) -> Result<Response, jsonrpc::Error> {
+        let outcome = retry_transient(&self.policy, thread::sleep, || {
+            match self.inner.send_request(request.clone()) {
+                Ok(Response {
+                    error: Some(err), ..
+                }) if err.code == RPC_IN_WARMUP => {
+                    Err(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(err)))
+                }
+                sent => Ok(sent),
+            }
+        });
+        match outcome {
+            Ok(sent) => sent,
+            Err(bitcoincore_rpc::Error::JsonRpc(err)) => Err(err),
+            Err(err) => unreachable!("only warmup rejections are retried: {err}"),
+        }
+    }
+
+    // A batch is sent once: resending the whole of it for one warmup rejection could repeat the others
+    fn send_batch(&self, requests: &[Request]) -> Result<Vec<Response>, jsonrpc::Error> {
+        self.inner.send_batch(requests)
+    }
+
+    fn fmt_target(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.inner.fmt_target(f)
+    }
+}
+
+// Traced responses longer than this are cut off; a `getblock` or `listunspent` result can run to many kilobytes
+pub const TRACE_RESPONSE_LIMIT: usize = 400;
+
+// Calls whose params carry secrets (passphrases, private keys); their params are never printed
+pub const REDACTED_METHODS: &[&str] = &[
+    "walletpassphrase",
+    "walletpassphrasechange",
+    "encryptwallet",
+    "importprivkey",
+    "signrawtransactionwithkey",
+];
+
+/// Transport decorator behind --trace-rpc: logs method, params and (truncated) raw response of each call as
+/// debug events, the response one with the call's `elapsed_ms`. The Authorization header is added inside
+/// `SimpleHttpTransport`, below this layer, so it never reaches the log.
+pub struct TracingTransport<T>(pub T);
+
+impl<T: Transport> TracingTransport<T> {
+    pub fn log_request(request: &Request) {
+        let params = match request.params {
+            _ if REDACTED_METHODS.contains(&request.method) => "<redacted>",
+            Some(params) => params.get(),
+            None => "[]",
+        };
+        debug!(method = request.method, "→ {} {params}", request.method);
+    }
+
+    pub fn log_response(
+        method: &str,
+        elapsed: Duration,
+        response: &Result<Response, jsonrpc::Error>,
+    ) {
+        let elapsed_ms = elapsed.as_millis() as u64;
+        match response {
+            Ok(response) => {
+                let raw = serde_json::to_string(response).unwrap_or_default();
+                debug!(
+                    method,
+                    elapsed_ms,
+                    "← {}",
+                    truncate(&raw, TRACE_RESPONSE_LIMIT)
+                );
+            }
+            Err(err) => debug!(method, elapsed_ms, "← transport error: {err}"),
+        }
+    }
+}
+
+impl<T: Transport> Transport for TracingTransport<T> {
+    fn send_request(&self, request: Request) -> Result<Response, jsonrpc::Error> {
+        Self::log_request(&request);
+        let method = request.method.to_string();
+        let start = Instant::now();
+        let response = self.0.send_request(request);
+        Self::log_response(&method, start.elapsed(), &response);
+        response
+    }
+
+    fn send_batch(&self, requests: &[Request]) -> Result<Vec<Response>, jsonrpc::Error> {
+        requests.iter().for_each(Self::log_request);
+        let start = Instant::now();
+        let responses = self.0.send_batch(requests);
+        // The batch is one round trip, so every response carries its whole time
+        let elapsed = start.elapsed();
+        match &responses {
+            Ok(responses) => {
+                for (request, response) in requests.iter().zip(responses) {
+                    Self::log_response(request.method, elapsed, &Ok(response.clone()));
+                }
+            }
+            Err(err) => debug!(
+                method = "batch",
+                elapsed_ms = elapsed.as_millis() as u64,
+                "← transport error: {err}"
+            ),
+        }
+        responses
+    }
+
+    fn fmt_target(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.0.fmt_target(f)
+    }
+}
+
+// Cuts `text` to at most `limit` bytes (on a char boundary) and notes how much was dropped
+pub fn truncate(text: &str, limit: usize) -> String {
+    if text.len() <= limit {
+        return text.to_string();
+    }
+    let mut end = limit;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}… ({} more bytes)", &text[..end], text.len() - end)
+}
+
+/// Builds the `<node url>/wallet/<name>` endpoint for a wallet-specific client.
+/// Trailing slashes on the node URL are dropped; a URL that already points at a wallet is rejected
+/// rather than producing `/wallet/A/wallet/B`.
+pub fn wallet_url(rpc_url: &str, wallet_name: &str) -> Result<String, CapstoneError> {
+    let base = rpc_url.trim_end_matches('/');
+    if base.contains("/wallet/") || base.ends_with("/wallet") {
+        return Err(CapstoneError::InvalidRpcUrl {
+            url: rpc_url.to_string(),
+            message: "already contains a wallet path; pass the bare node URL".to_string(),
+        });
+    }
+    Ok(format!("{base}/wallet/{wallet_name}"))
+}
+
+/// Retries `lookup` while the node answers RPC_INVALID_ADDRESS_OR_KEY (-5, "No such mempool or blockchain
+/// transaction"), which can happen briefly after mining while -txindex catches up. Any other error, or the
+/// same one after `LOOKUP_RETRIES` retries, is returned unchanged.
+pub fn retry_not_found<T>(
+    lookup: impl Fn() -> bitcoincore_rpc::Result<T>,
+) -> bitcoincore_rpc::Result<T> {
+    let mut retries = 0;
+    loop {
+        match lookup() {
+            Err(err) if is_not_found(&err) && retries < LOOKUP_RETRIES => {
+                retries += 1;
+                warn!("⚠️ Transaction not indexed yet; retry {retries}/{LOOKUP_RETRIES}");
+                thread::sleep(LOOKUP_RETRY_DELAY);
+            }
+            result => return result,
+        }
+    }
+}
+
+// Whether the node rejected the call with RPC_INVALID_ADDRESS_OR_KEY (-5), its "not found" code
+pub fn is_not_found(err: &bitcoincore_rpc::Error) -> bool {
+    matches!(
+        err,
+        bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(rpc_err)) if rpc_err.code == -5
+    )
+}
+
+/// Checks the node can do what the run needs before anything is mined: it must be a regtest node, and
+/// `-txindex` should be on, because input tracing looks up arbitrary earlier transactions with
+/// `getrawtransaction`. A missing index only warns, since wallet transactions can still be found without it.
+pub fn preflight(
+    rpc: &Client,
+    blockchain_info: &GetBlockchainInfoResult,
+) -> Result<(), CapstoneError> {
+    if blockchain_info.chain != bitcoin::Network::Regtest {
+        return Err(CapstoneError::NotRegtest(blockchain_info.chain));
+    }
+    match rpc.get_index_info() {
+        Ok(indexes) => match txindex_warning(indexes.txindex.as_ref()) {
+            Some(warning) => warn!("⚠️ {warning}"),
+            None => info!("Preflight: regtest node with a synced -txindex"),
+        },
+        // getindexinfo arrived in Core 0.21; older nodes just can't tell us
+        Err(err) => warn!(
+            "⚠️ Could not query getindexinfo ({err}); make sure bitcoind runs with -txindex=1"
+        ),
+    }
+    Ok(())
+}
+
+// What's wrong with the txindex as `getindexinfo` reports it, if anything
+pub fn txindex_warning(txindex: Option<&IndexStatus>) -> Option<String> {
+    match txindex {
+        None => Some(
+            "-txindex is off: tracing inputs needs getrawtransaction for earlier transactions. \
+             Restart bitcoind with -txindex=1 (or add txindex=1 to bitcoin.conf)"
+                .to_string(),
+        ),
+        Some(status) if !status.synced => Some(format!(
+            "-txindex is still syncing (at height {}); lookups of recent transactions may fail until it catches up",
+            status.best_block_height
+        )),
+        Some(_) => None,
+    }
+}
+
+// First Bitcoin Core release with the `createwallet` RPC (0.17.0), in `getnetworkinfo` version encoding
+pub const MIN_CREATEWALLET_VERSION: usize = 170_000;
+
+// Renders a `getnetworkinfo` version number: 210100 -> "0.21.1", 260000 -> "26.0" (the 0. prefix was dropped at 22.0)
+pub fn core_version(version: usize) -> String {
+    let (major, minor, patch) = (version / 10_000, version / 100 % 100, version % 100);
+    match (major < 22, patch) {
+        (true, _) => format!("0.{major}.{minor}"),
+        (false, 0) => format!("{major}.{minor}"),
+        (false, _) => format!("{major}.{minor}.{patch}"),
+    }
+}
+
+// Wallets can only be created over RPC from 0.17 on; older nodes get a clear error instead of "Method not found"
+pub fn check_createwallet_support(version: usize) -> Result<(), CapstoneError> {
+    if version < MIN_CREATEWALLET_VERSION {
+        return Err(CapstoneError::CoreTooOld { version });
+    }
+    Ok(())
+}
+
+/// The node calls report extraction relies on. `Client` implements it against a real node;
+/// tests implement it with canned fixtures so extraction can be checked without bitcoind.
+pub trait NodeApi {
+    fn raw_transaction(&self, txid: &Txid) -> bitcoincore_rpc::Result<GetRawTransactionResult>;
+    // `raw_transaction` for each of `txids`, in order; nodes that can batch requests do it in one round trip
+    // unless `ctx` turned batching off
+    fn raw_transactions(
+        &self,
+        _ctx: &Context,
+        txids: &[Txid],
+    ) -> Vec<bitcoincore_rpc::Result<GetRawTransactionResult>> {
+        txids
+            .iter()
+            .map(|txid| self.raw_transaction(txid))
+            .collect()
+    }
+    fn block_info(&self, hash: &BlockHash) -> bitcoincore_rpc::Result<GetBlockResult>;
+    fn block_hash_at(&self, height: u64) -> bitcoincore_rpc::Result<BlockHash>;
+    fn mempool_txids(&self) -> bitcoincore_rpc::Result<Vec<Txid>>;
+    fn mempool_entry(&self, txid: &Txid) -> bitcoincore_rpc::Result<GetMempoolEntryResult>;
+    // Whether the wallet behind this client owns `address` (used to recognise change)
+    fn is_mine(&self, address: &Address) -> bitcoincore_rpc::Result<bool>;
+    // The wallet's own copy of a transaction (`gettransaction`), which survives pruning of its block
+    fn wallet_transaction(&self, txid: &Txid) -> bitcoincore_rpc::Result<Transaction>;
+    // Confirmations the wallet sees for one of its transactions (`gettransaction`); 0 while in the mempool
+    fn wallet_confirmations(&self, txid: &Txid) -> bitcoincore_rpc::Result<i32>;
+}
+
+impl NodeApi for Client {
+    fn raw_transaction(&self, txid: &Txid) -> bitcoincore_rpc::Result<GetRawTransactionResult> {
+        self.get_raw_transaction_info(txid, None)
+    }
+
+    /// Sends every `getrawtransaction` as one JSON-RPC batch. Falls back to serial calls when batching is
+    /// off (--no-rpc-batch), pointless (a single txid) or the batch itself fails; a txid the reply leaves
+    /// out is looked up on its own.
+    fn raw_transactions(
+        &self,
+        ctx: &Context,
+        txids: &[Txid],
+    ) -> Vec<bitcoincore_rpc::Result<GetRawTransactionResult>> {
+        let serial = || {
+            txids
+                .iter()
+                .map(|txid| self.raw_transaction(txid))
+                .collect()
+        };
+        if txids.len() < 2 || !ctx.rpc_batch {
+            return serial();
+        }
+        let client = self.get_jsonrpc_client();
+        let params: Vec<_> = txids
+            .iter()
+            .map(|txid| jsonrpc::arg([json!(txid), json!(true)]))
+            .collect();
+        let requests: Vec<Request> = params
+            .iter()
+            .map(|params| client.build_request("getrawtransaction", Some(params)))
+            .collect();
+        match client.send_batch(&requests) {
+            Ok(responses) => responses
+                .into_iter()
+                .zip(txids)
+                .map(|(response, txid)| match response {
+                    Some(response) => Ok(response.result()?),
+                    None => self.raw_transaction(txid),
+                })
+                .collect(),
+            Err(err) => {
+                info!("JSON-RPC batch failed ({err}); looking the inputs up one by one");
+                serial()
+            }
+        }
+    }
+
+    fn block_info(&self, hash: &BlockHash) -> bitcoincore_rpc::Result<GetBlockResult> {
+        self.get_block_info(hash)
+    }
+
+    fn block_hash_at(&self, height: u64) -> bitcoincore_rpc::Result<BlockHash> {
+        self.get_block_hash(height)
+    }
+
+    fn mempool_txids(&self) -> bitcoincore_rpc::Result<Vec<Txid>> {
+        self.get_raw_mempool()
+    }
+
+    fn mempool_entry(&self, txid: &Txid) -> bitcoincore_rpc::Result<GetMempoolEntryResult> {
+        self.get_mempool_entry(txid)
+    }
+
+    fn is_mine(&self, address: &Address) -> bitcoincore_rpc::Result<bool> {
+        Ok(self.get_address_info(address)?.is_mine == Some(true))
+    }
+
+    fn wallet_transaction(&self, txid: &Txid) -> bitcoincore_rpc::Result<Transaction> {
+        self.get_transaction(txid, None)?
+            .transaction()
+            .map_err(|err| bitcoincore_rpc::Error::ReturnedError(err.to_string()))
+    }
+
+    fn wallet_confirmations(&self, txid: &Txid) -> bitcoincore_rpc::Result<i32> {
+        Ok(self.get_transaction(txid, None)?.info.confirmations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::*;
+    use std::cell::Cell;
+    use std::sync::Arc;
+
+    #[test]
+    fn traced_responses_are_truncated_on_char_boundaries() {
+        assert_eq!(truncate("short", 10), "short");
+        assert_eq!(truncate("0123456789", 4), "0123… (6 more bytes)");
+        // "₿" is three bytes; cutting inside it backs off to the previous boundary
+        assert_eq!(truncate("a₿c", 2), "a… (4 more bytes)");
+    }
+
+    #[test]
+    fn txindex_warnings_are_actionable() {
+        assert!(txindex_warning(None).unwrap().contains("-txindex=1"));
+        let syncing = IndexStatus {
+            synced: false,
+            best_block_height: 42,
+        };
+        assert!(txindex_warning(Some(&syncing)).unwrap().contains("42"));
+        let synced = IndexStatus {
+            synced: true,
+            best_block_height: 102,
+        };
+        assert_eq!(txindex_warning(Some(&synced)), None);
+    }
+
+    #[test]
+    fn node_is_ready_once_it_has_every_block_it_has_a_header_for() {
+        assert_eq!(
+            sync_progress(40, 101).as_deref(),
+            Some("validated 40 of 101 blocks")
+        );
+        assert_eq!(sync_progress(101, 101), None);
+        // A fresh regtest chain is ready, even though it still reports initial block download
+        assert_eq!(sync_progress(0, 0), None);
+    }
+
+    #[test]
+    fn core_versions_are_rendered_and_gated() {
+        assert_eq!(core_version(160_300), "0.16.3");
+        assert_eq!(core_version(210_100), "0.21.1");
+        assert_eq!(core_version(260_000), "26.0");
+        assert_eq!(core_version(280_100), "28.1");
+
+        assert!(check_createwallet_support(160_300).is_err());
+        assert!(check_createwallet_support(170_000).is_ok());
+        assert!(check_createwallet_support(290_000).is_ok());
+    }
+
+    #[test]
+    fn wallet_url_appends_wallet_path() {
+        assert_eq!(
+            wallet_url("http://127.0.0.1:18443", "Miner").unwrap(),
+            "http://127.0.0.1:18443/wallet/Miner"
+        );
+        assert_eq!(
+            wallet_url("http://127.0.0.1:18443//", "Trader").unwrap(),
+            "http://127.0.0.1:18443/wallet/Trader"
+        );
+    }
+
+    #[test]
+    fn wallet_url_rejects_existing_wallet_path() {
+        assert!(wallet_url("http://127.0.0.1:18443/wallet/Miner", "Trader").is_err());
+        assert!(wallet_url("http://127.0.0.1:18443/wallet/", "Trader").is_err());
+    }
+
+    #[test]
+    fn lookup_retries_a_transient_not_found() {
+        let calls = Cell::new(0);
+        let found = retry_not_found(|| {
+            calls.set(calls.get() + 1);
+            if calls.get() == 1 {
+                return Err(no_such_transaction());
+            }
+            Ok("tx")
+        });
+
+        assert_eq!(found.unwrap(), "tx");
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn lookup_returns_the_original_error_once_retries_run_out() {
+        let calls = Cell::new(0);
+        let result: bitcoincore_rpc::Result<()> = retry_not_found(|| {
+            calls.set(calls.get() + 1);
+            Err(no_such_transaction())
+        });
+        assert!(is_not_found(&result.unwrap_err()));
+        assert_eq!(calls.get(), LOOKUP_RETRIES + 1);
+
+        // Anything but "not found" is not worth waiting for
+        calls.set(0);
+        let result: bitcoincore_rpc::Result<()> = retry_not_found(|| {
+            calls.set(calls.get() + 1);
+            Err(node_busy())
+        });
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn connecting_retries_a_starting_node_with_backoff() {
+        let refused = || {
+            bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Transport(Box::new(
+                jsonrpc::simple_http::Error::SocketError(
+                    std::io::ErrorKind::ConnectionRefused.into(),
+                ),
+            )))
+        };
+        let warming_up = || {
+            bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(jsonrpc::error::RpcError {
+                code: -28,
+                message: "Loading block index…".to_string(),
+                data: None,
+            }))
+        };
+        let policy = RetryPolicy::default();
+
+        // Refused, then warming up, then ready; the pauses grow
+        let calls = Cell::new(0);
+        let mut pauses = Vec::new();
+        let result = retry_transient(
+            &policy,
+            |delay| pauses.push(delay),
+            || {
+                calls.set(calls.get() + 1);
+                match calls.get() {
+                    1 => Err(refused()),
+                    2 | 3 => Err(warming_up()),
+                    _ => Ok("ready"),
+                }
+            },
+        );
+        assert_eq!(result.unwrap(), "ready");
+        assert_eq!(pauses.len(), 3);
+        for (attempt, pause) in (1..).zip(&pauses) {
+            let full = Duration::from_millis(250 * 2u64.pow(attempt - 1));
+            assert!(*pause >= full / 2 && *pause <= full, "{pause:?}");
+        }
+        // Capped however long the node takes
+        assert!((0..100).all(|seed| policy.backoff(20, seed) <= Duration::from_secs(8)));
+
+        // Wrong credentials and other RPC errors fail at once
+        let unauthorized = || {
+            bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Transport(Box::new(
+                jsonrpc::simple_http::Error::HttpErrorCode(401),
+            )))
+        };
+        let calls = Cell::new(0);
+        let result: bitcoincore_rpc::Result<()> = retry_transient(
+            &policy,
+            |_| {},
+            || {
+                calls.set(calls.get() + 1);
+                Err(unauthorized())
+            },
+        );
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 1);
+
+        // A node that never comes up: stops at the attempt limit, or sooner at the timeout
+        let calls = Cell::new(0);
+        let capped = RetryPolicy {
+            max_attempts: 3,
+            ..policy
+        };
+        let result: bitcoincore_rpc::Result<()> = retry_transient(
+            &capped,
+            |_| {},
+            || {
+                calls.set(calls.get() + 1);
+                Err(refused())
+            },
+        );
+        assert!(result.is_err());
+        assert_eq!(calls.get(), 3);
+        let calls = Cell::new(0);
+        let impatient = RetryPolicy {
+            timeout: Duration::from_millis(400),
+            ..policy
+        };
+        let result: bitcoincore_rpc::Result<()> = retry_transient(
+            &impatient,
+            |_| {},
+            || {
+                calls.set(calls.get() + 1);
+                Err(refused())
+            },
+        );
+        assert!(result.is_err());
+        assert!(calls.get() <= 2, "{}", calls.get());
+    }
+
+    // Rejects the first `rejections` calls with -28, as a node reloading its wallets mid-run does, then
+    // answers getblockcount with 101; `refuse` makes every call fail to connect instead
+    struct WarmingUp {
+        rejections: u32,
+        refuse: bool,
+        sent: Arc<AtomicU64>,
+    }
+
+    impl Transport for WarmingUp {
+        fn send_request(&self, request: Request) -> Result<Response, jsonrpc::Error> {
+            let sent = self.sent.fetch_add(1, Ordering::SeqCst);
+            if self.refuse {
+                return Err(jsonrpc::Error::Transport(Box::new(
+                    simple_http::Error::SocketError(std::io::ErrorKind::ConnectionReset.into()),
+                )));
+            }
+            let warming_up = sent < u64::from(self.rejections);
+            Ok(Response {
+                result: (!warming_up).then(|| jsonrpc::arg(101)),
+                error: warming_up.then(|| jsonrpc::error::RpcError {
+                    code: -28,
+                    message: "Loading wallet…".to_string(),
+                    data: None,
+                }),
+                id: request.id.clone(),
+                jsonrpc: Some("2.0".to_string()),
+            })
+        }
+
+        fn send_batch(&self, _: &[Request]) -> Result<Vec<Response>, jsonrpc::Error> {
+            unimplemented!("not batched")
+        }
+
+        fn fmt_target(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "warming-up mock node")
+        }
+    }
+
+    fn client_for(node: WarmingUp, max_attempts: u32) -> Client {
+        let policy = RetryPolicy {
+            max_attempts,
+            ..RetryPolicy::default()
+        };
+        Client::from_jsonrpc(jsonrpc::Client::with_transport(WarmupRetryTransport {
+            inner: node,
+            policy,
+        }))
+    }
+
+    #[test]
+    fn a_call_rejected_mid_run_by_warmup_is_resent() {
+        let sent = Arc::new(AtomicU64::new(0));
+        let node = |rejections, refuse| {
+            sent.store(0, Ordering::SeqCst);
+            WarmingUp {
+                rejections,
+                refuse,
+                sent: sent.clone(),
+            }
+        };
+        let sent = || sent.load(Ordering::SeqCst);
+
+        let rpc = client_for(node(1, false), 3);
+        assert_eq!(rpc.get_block_count().unwrap(), 101);
+        assert_eq!(sent(), 2);
+
+        // Still warming up after the last attempt: the -28 itself comes back
+        let rpc = client_for(node(5, false), 2);
+        match rpc.get_block_count() {
+            Err(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(err))) => {
+                assert_eq!(err.code, -28)
+            }
+            other => panic!("expected the warmup error, got {other:?}"),
+        }
+        assert_eq!(sent(), 2);
+
+        // A dropped connection may have carried out the call, so it is never resent
+        let rpc = client_for(node(0, true), 3);
+        assert!(rpc.get_block_count().is_err());
+        assert_eq!(sent(), 1);
+    }
+}
@@ -0,0 +1,251 @@
+//! `selftest`: report extraction over built-in fixture payments, against a stand-in node, so it runs
+//! without Bitcoin Core.
+
+use crate::report::*;
+use crate::rpc::NodeApi;
+use crate::{CapstoneError, Context};
+use bitcoin::absolute::LockTime;
+use bitcoincore_rpc::bitcoin::address::NetworkUnchecked;
+use bitcoincore_rpc::bitcoin::{Address, Amount, BlockHash, OutPoint, Transaction, TxOut, Txid};
+use bitcoincore_rpc::json::{GetBlockResult, GetMempoolEntryResult, GetRawTransactionResult};
+use std::collections::HashMap;
+use std::result::Result;
+use tracing::{error, info};
+
+// Regtest addresses the self-test fixtures pay: the Trader, the Miner's change, and the Miner's coinbase
+const FIXTURE_TRADER: &str = "bcrt1qak6gpu2p6zjpwrhvd4dvdnp4rt3ysm9rpst3wu";
+const FIXTURE_CHANGE: &str = "bcrt1qps9jhqgyd6gt8766um0zj3h94untejgycqzs3c";
+const FIXTURE_MINER: &str = "bcrt1qp54kssdsuvw8ppwh75fv90pja5ltaw8x0n5s4f";
+
+/// A payment the self-test extracts a report from, with the values extraction must find (in satoshis).
+struct Fixture {
+    name: &'static str,
+    inputs: &'static [u64], // Each spends its own earlier output to FIXTURE_MINER of this value
+    outputs: &'static [(&'static str, u64)],
+    fee: u64,
+    change: u64,
+}
+
+const FIXTURES: [Fixture; 3] = [
+    Fixture {
+        name: "single input with change",
+        inputs: &[5_000_000_000],
+        outputs: &[
+            (FIXTURE_TRADER, 2_000_000_000),
+            (FIXTURE_CHANGE, 2_999_999_859),
+        ],
+        fee: 141,
+        change: 2_999_999_859,
+    },
+    Fixture {
+        name: "two inputs summed",
+        inputs: &[5_000_000_000, 2_500_000_000],
+        outputs: &[
+            (FIXTURE_CHANGE, 1_499_999_792),
+            (FIXTURE_TRADER, 6_000_000_000),
+        ],
+        fee: 208,
+        change: 1_499_999_792,
+    },
+    Fixture {
+        name: "no change output",
+        inputs: &[2_000_000_110],
+        outputs: &[(FIXTURE_TRADER, 2_000_000_000)],
+        fee: 110,
+        change: 0,
+    },
+];
+
+/// The node `selftest` extracts from: it knows the fixture's transactions and owns the Miner's addresses.
+pub struct FixtureNode {
+    transactions: HashMap<Txid, Transaction>,
+}
+
+impl NodeApi for FixtureNode {
+    fn raw_transaction(&self, txid: &Txid) -> bitcoincore_rpc::Result<GetRawTransactionResult> {
+        self.transactions
+            .get(txid)
+            .map(unconfirmed_raw)
+            .ok_or_else(|| {
+                bitcoincore_rpc::Error::ReturnedError(format!("fixture has no transaction {txid}"))
+            })
+    }
+
+    fn block_info(&self, _hash: &BlockHash) -> bitcoincore_rpc::Result<GetBlockResult> {
+        Err(bitcoincore_rpc::Error::ReturnedError(
+            "fixtures have no blocks".to_string(),
+        ))
+    }
+
+    fn block_hash_at(&self, _height: u64) -> bitcoincore_rpc::Result<BlockHash> {
+        Err(bitcoincore_rpc::Error::ReturnedError(
+            "fixtures have no blocks".to_string(),
+        ))
+    }
+
+    fn mempool_txids(&self) -> bitcoincore_rpc::Result<Vec<Txid>> {
+        Ok(Vec::new())
+    }
+
+    fn mempool_entry(&self, _txid: &Txid) -> bitcoincore_rpc::Result<GetMempoolEntryResult> {
+        Err(bitcoincore_rpc::Error::ReturnedError(
+            "fixtures have no mempool".to_string(),
+        ))
+    }
+
+    fn is_mine(&self, address: &Address) -> bitcoincore_rpc::Result<bool> {
+        let address = address.to_string();
+        Ok(address == FIXTURE_CHANGE || address == FIXTURE_MINER)
+    }
+
+    fn wallet_transaction(&self, txid: &Txid) -> bitcoincore_rpc::Result<Transaction> {
+        self.transactions.get(txid).cloned().ok_or_else(|| {
+            bitcoincore_rpc::Error::ReturnedError(format!("fixture has no transaction {txid}"))
+        })
+    }
+
+    fn wallet_confirmations(&self, _txid: &Txid) -> bitcoincore_rpc::Result<i32> {
+        Ok(0)
+    }
+}
+
+/// `selftest`: extracts a report from every fixture and checks fee, change detection and the input and
+/// output sums, without a node. Fails (non-zero exit) if any fixture disagrees.
+pub fn selftest(ctx: &Context) -> Result<(), CapstoneError> {
+    let mut failed = 0;
+    for fixture in &FIXTURES {
+        match check_fixture(ctx, fixture) {
+            Ok(()) => info!("ok    {}", fixture.name),
+            Err(err) => {
+                error!("FAIL  {}: {err}", fixture.name);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(CapstoneError::SelfTestFailed {
+            failed,
+            total: FIXTURES.len(),
+        });
+    }
+    info!("All {} self-test fixtures pass", FIXTURES.len());
+    Ok(())
+}
+
+// The first value extraction gets wrong, described
+fn check_fixture(ctx: &Context, fixture: &Fixture) -> Result<(), String> {
+    use bitcoin::hashes::Hash;
+
+    let pay =
+        |inputs: Vec<OutPoint>, outputs: &[(&str, u64)]| -> Result<Transaction, CapstoneError> {
+            let output = outputs
+                .iter()
+                .map(|(address, sats)| {
+                    Ok(TxOut {
+                        value: Amount::from_sat(*sats),
+                        script_pubkey: address
+                            .parse::<Address<NetworkUnchecked>>()?
+                            .require_network(bitcoin::Network::Regtest)?
+                            .script_pubkey(),
+                    })
+                })
+                .collect::<Result<_, CapstoneError>>()?;
+            Ok(Transaction {
+                version: bitcoin::transaction::Version::TWO,
+                lock_time: LockTime::ZERO,
+                input: inputs
+                    .into_iter()
+                    .map(|previous_output| bitcoin::TxIn {
+                        previous_output,
+                        sequence: bitcoin::Sequence::ENABLE_RBF_NO_LOCKTIME,
+                        ..Default::default()
+                    })
+                    .collect(),
+                output,
+            })
+        };
+
+    let mut node = FixtureNode {
+        transactions: HashMap::new(),
+    };
+    let mut spent = Vec::new();
+    for (i, value) in fixture.inputs.iter().enumerate() {
+        // A distinct dummy input per funding transaction keeps their txids apart
+        let funding = pay(
+            vec![OutPoint::new(Txid::from_byte_array([i as u8 + 1; 32]), 0)],
+            &[(FIXTURE_MINER, *value)],
+        )
+        .map_err(|err| err.to_string())?;
+        spent.push(OutPoint::new(funding.compute_txid(), 0));
+        node.transactions.insert(funding.compute_txid(), funding);
+    }
+    let payment = pay(spent, fixture.outputs).map_err(|err| err.to_string())?;
+    let details = trace_transaction(ctx, &node, &payment, &[FIXTURE_TRADER])
+        .map_err(|err| err.to_string())?;
+
+    let expect = |what: &str, found: Amount, expected: u64| -> Result<(), String> {
+        if found == Amount::from_sat(expected) {
+            return Ok(());
+        }
+        Err(format!(
+            "{what} is {} sat, expected {expected}",
+            found.to_sat()
+        ))
+    };
+    expect(
+        "input amount",
+        details.input_amount,
+        fixture.inputs.iter().sum(),
+    )?;
+    expect("fee", details.fee, fixture.fee)?;
+    expect("change amount", details.change_amount, fixture.change)?;
+    let trader: u64 = fixture
+        .outputs
+        .iter()
+        .filter(|(address, _)| *address == FIXTURE_TRADER)
+        .map(|(_, sats)| sats)
+        .sum();
+    expect("trader amount", details.trader_amount, trader)?;
+    let expected_change = if fixture.change == 0 {
+        ""
+    } else {
+        FIXTURE_CHANGE
+    };
+    if details.change_address != expected_change {
+        return Err(format!(
+            "change address is `{}`, expected `{expected_change}`",
+            details.change_address
+        ));
+    }
+    if details.input_address != FIXTURE_MINER {
+        return Err(format!(
+            "input address is {}, expected {FIXTURE_MINER}",
+            details.input_address
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selftest_fixtures_pass_and_a_wrong_expectation_fails() {
+        assert!(selftest(&Context::default()).is_ok());
+
+        let wrong_fee = Fixture {
+            name: "fee off by one",
+            fee: 142,
+            ..FIXTURES[0]
+        };
+        let err = check_fixture(&Context::default(), &wrong_fee).unwrap_err();
+        assert_eq!(err, "fee is 141 sat, expected 142");
+        let missed_change = Fixture {
+            name: "change expected where there is none",
+            change: 1,
+            ..FIXTURES[2]
+        };
+        assert!(check_fixture(&Context::default(), &missed_change).is_err());
+    }
+}
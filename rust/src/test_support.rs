@@ -0,0 +1,251 @@
+//! Fixtures shared by the library's unit tests: hand-built transactions and [`MockNode`], an in-memory
+//! [`NodeApi`] that the report, wallet and mining code can be exercised against without a node.
+
+use crate::{report::*, rpc::*, wallet::*, Context};
+use bitcoin::absolute::LockTime;
+use bitcoin::hashes::Hash;
+use bitcoin::transaction::Version;
+use bitcoin::{
+    Address, Amount, BlockHash, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid,
+    Witness,
+};
+use bitcoincore_rpc::json::{GetBlockResult, GetMempoolEntryResult, GetRawTransactionResult};
+use bitcoincore_rpc::jsonrpc;
+use bitcoincore_rpc::Client;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+pub(crate) const TRADER: &str = "bcrt1qak6gpu2p6zjpwrhvd4dvdnp4rt3ysm9rpst3wu";
+pub(crate) const CHANGE: &str = "bcrt1qps9jhqgyd6gt8766um0zj3h94untejgycqzs3c";
+pub(crate) const MINER_INPUT: &str = "bcrt1qp54kssdsuvw8ppwh75fv90pja5ltaw8x0n5s4f";
+
+pub(crate) fn script_for(address: &str) -> ScriptBuf {
+    bitcoin::Address::from_str(address)
+        .unwrap()
+        .require_network(bitcoin::Network::Regtest)
+        .unwrap()
+        .script_pubkey()
+}
+
+pub(crate) fn payment_tx(outputs: &[(&str, u64)]) -> Transaction {
+    tx_spending(&[OutPoint::null()], outputs)
+}
+
+pub(crate) fn tx_spending(inputs: &[OutPoint], outputs: &[(&str, u64)]) -> Transaction {
+    Transaction {
+        version: Version::TWO,
+        lock_time: LockTime::ZERO,
+        input: inputs
+            .iter()
+            .map(|previous_output| TxIn {
+                previous_output: *previous_output,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new(),
+            })
+            .collect(),
+        output: outputs
+            .iter()
+            .map(|(address, sat)| TxOut {
+                value: Amount::from_sat(*sat),
+                script_pubkey: script_for(address),
+            })
+            .collect(),
+    }
+}
+
+pub(crate) fn node_busy() -> bitcoincore_rpc::Error {
+    bitcoincore_rpc::Error::ReturnedError("node busy".to_string())
+}
+
+pub(crate) fn no_such_transaction() -> bitcoincore_rpc::Error {
+    bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(jsonrpc::error::RpcError {
+        code: -5,
+        message: "No such mempool or blockchain transaction".to_string(),
+        data: None,
+    }))
+}
+
+/// In-memory stand-in for the node: canned transactions and blocks, and the addresses the wallet owns.
+#[derive(Default)]
+pub(crate) struct MockNode {
+    pub(crate) transactions: HashMap<Txid, GetRawTransactionResult>,
+    pub(crate) blocks: Vec<GetBlockResult>, // Indexed by height
+    pub(crate) mempool: HashMap<Txid, GetMempoolEntryResult>,
+    pub(crate) evicted: Vec<Txid>, // Still listed by getrawmempool, but the entry is gone (confirmed in between)
+    pub(crate) owned: HashSet<String>,
+    pub(crate) pruned: HashSet<Txid>, // Block data gone: getrawtransaction fails for these
+    pub(crate) wallet: HashMap<Txid, Transaction>, // The wallet's own copies (gettransaction)
+}
+
+impl MockNode {
+    pub(crate) fn owning(addresses: &[&str]) -> Self {
+        MockNode {
+            owned: addresses.iter().map(|a| a.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn add_transaction(&mut self, tx: &Transaction) {
+        let raw = unconfirmed_raw(tx);
+        self.transactions.insert(raw.txid, raw);
+    }
+
+    /// Appends a block confirming `txids` and returns its hash.
+    pub(crate) fn mine(&mut self, txids: &[Txid]) -> BlockHash {
+        let height = self.blocks.len();
+        let hash = BlockHash::hash(&height.to_le_bytes());
+        for txid in txids {
+            let raw = self.transactions.get_mut(txid).unwrap();
+            raw.blockhash = Some(hash);
+            raw.confirmations = Some(1);
+        }
+        self.blocks.push(GetBlockResult {
+            hash,
+            confirmations: 1,
+            size: 0,
+            strippedsize: None,
+            weight: 0,
+            height,
+            version: 0,
+            version_hex: None,
+            merkleroot: bitcoin::TxMerkleNode::all_zeros(),
+            tx: txids.to_vec(),
+            time: 1_700_000_000 + height * 600,
+            mediantime: None,
+            nonce: 0,
+            bits: String::new(),
+            difficulty: 0.0,
+            chainwork: Vec::new(),
+            n_tx: txids.len(),
+            previousblockhash: None,
+            nextblockhash: None,
+        });
+        hash
+    }
+}
+
+pub(crate) fn not_found(what: &str) -> bitcoincore_rpc::Error {
+    bitcoincore_rpc::Error::ReturnedError(format!("{what} not found"))
+}
+
+impl NodeApi for MockNode {
+    fn raw_transaction(&self, txid: &Txid) -> bitcoincore_rpc::Result<GetRawTransactionResult> {
+        if self.pruned.contains(txid) {
+            return Err(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(
+                jsonrpc::error::RpcError {
+                    code: -1,
+                    message: "Block not available (pruned data)".to_string(),
+                    data: None,
+                },
+            )));
+        }
+        self.transactions
+            .get(txid)
+            .cloned()
+            .ok_or_else(|| not_found("transaction"))
+    }
+
+    fn block_info(&self, hash: &BlockHash) -> bitcoincore_rpc::Result<GetBlockResult> {
+        self.blocks
+            .iter()
+            .find(|block| block.hash == *hash)
+            .cloned()
+            .ok_or_else(|| not_found("block"))
+    }
+
+    fn block_hash_at(&self, height: u64) -> bitcoincore_rpc::Result<BlockHash> {
+        self.blocks
+            .get(height as usize)
+            .map(|block| block.hash)
+            .ok_or_else(|| not_found("height"))
+    }
+
+    fn mempool_txids(&self) -> bitcoincore_rpc::Result<Vec<Txid>> {
+        Ok(self.mempool.keys().chain(&self.evicted).copied().collect())
+    }
+
+    fn mempool_entry(&self, txid: &Txid) -> bitcoincore_rpc::Result<GetMempoolEntryResult> {
+        self.mempool
+            .get(txid)
+            .cloned()
+            .ok_or_else(|| not_found("mempool entry"))
+    }
+
+    fn is_mine(&self, address: &Address) -> bitcoincore_rpc::Result<bool> {
+        Ok(self.owned.contains(&address.to_string()))
+    }
+
+    fn wallet_transaction(&self, txid: &Txid) -> bitcoincore_rpc::Result<Transaction> {
+        self.wallet
+            .get(txid)
+            .cloned()
+            .ok_or_else(|| not_found("wallet transaction"))
+    }
+
+    fn wallet_confirmations(&self, txid: &Txid) -> bitcoincore_rpc::Result<i32> {
+        self.transactions
+            .get(txid)
+            .map(|raw| raw.confirmations.unwrap_or(0) as i32)
+            .ok_or_else(|| not_found("wallet transaction"))
+    }
+}
+
+// The addresses the Miner wallet owns in these fixtures
+pub(crate) fn miner_owned() -> HashSet<String> {
+    HashSet::from([CHANGE.to_string()])
+}
+
+pub(crate) fn report_for(tx: &Transaction, input_amount: Amount, fee: Amount) -> TxReport {
+    let (trader_address, trader_amount, change_address, change_amount) =
+        split_outputs(tx, &[TRADER], &miner_owned());
+    TxReport {
+        txid: tx.compute_txid(),
+        input_address: CHANGE.to_string(),
+        input_amount,
+        trader_address,
+        trader_amount,
+        trader_script_type: "p2wpkh",
+        change_address,
+        change_amount,
+        fee,
+        block_height: 102,
+        confirmations: 1,
+        inputs_pruned: false,
+        rbf_signaled: true,
+        locktime: tx.lock_time.to_consensus_u32(),
+        block_hash: BlockHash::all_zeros(),
+        block_time: 1_700_000_000,
+        block_time_iso: iso8601(1_700_000_000),
+        mempool: None,
+        input_scripts: None,
+        outputs: None,
+    }
+}
+
+// The fixtures' payment: 20 BTC to the Trader and the rest of a 50 BTC coinbase back as change
+pub(crate) fn sample_tx() -> Transaction {
+    payment_tx(&[(TRADER, 2_000_000_000), (CHANGE, 2_999_998_590)])
+}
+
+/// The report of [`sample_tx`], spending a 50 BTC coinbase with a 1,410 sat fee.
+pub(crate) fn sample_report() -> TxReport {
+    report_for(
+        &sample_tx(),
+        Amount::from_btc(50.0).unwrap(),
+        Amount::from_sat(1_410),
+    )
+}
+
+// Miner wallet client for the tests that need a live regtest node (see docker-compose.yaml)
+pub(crate) fn node_miner() -> Client {
+    let rpc = RpcSettings::default().connect().unwrap();
+    ensure_wallet_exists(&Context::default(), &rpc, "Miner").unwrap();
+    RpcSettings::default().connect_wallet("Miner").unwrap()
+}
+
+pub(crate) fn node_trader() -> Client {
+    let rpc = RpcSettings::default().connect().unwrap();
+    ensure_wallet_exists(&Context::default(), &rpc, "Trader").unwrap();
+    RpcSettings::default().connect_wallet("Trader").unwrap()
+}
@@ -0,0 +1,746 @@
+//! Sending from the Miner: the plain, split, sweep, pinned-input, locktime and fixed-fee-rate payments.
+
+use crate::{mining::*, *};
+use bitcoin::absolute::LockTime;
+use bitcoincore_rpc::bitcoin::{Address, Amount, OutPoint, Transaction, Txid};
+use bitcoincore_rpc::json::{
+    CreateRawTransactionInput, ListUnspentResultEntry, WalletCreateFundedPsbtOptions,
+};
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::result::Result;
+use tracing::{info, warn};
+
+// Most blocks mined waiting for the median time past to pass a time-based --locktime
+pub const LOCKTIME_MAX_BLOCKS: u64 = 100;
+
+// Percentages above this would leave too little of the balance to pay the fee
+pub const MAX_SEND_PERCENT: f64 = 95.0;
+
+/// How much `run` sends to the Trader: a fixed amount, or a share of the Miner's balance after mining.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SendAmount {
+    Absolute(Amount),
+    Percent(f64),
+}
+
+impl SendAmount {
+    /// The amount to send given the Miner's spendable `balance`; percentages are rounded down to the satoshi.
+    pub fn resolve(self, balance: Amount) -> Amount {
+        match self {
+            SendAmount::Absolute(amount) => amount,
+            SendAmount::Percent(percent) => {
+                Amount::from_sat((balance.to_sat() as f64 * percent / 100.0) as u64)
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for SendAmount {
+    type Err = CapstoneError;
+
+    /// Accepts BTC (`20`, `0.5`) or a percentage of the balance (`50%`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(percent) = s.strip_suffix('%') {
+            let percent: f64 = percent.parse().map_err(|err: std::num::ParseFloatError| {
+                CapstoneError::InvalidAmount {
+                    value: s.to_string(),
+                    message: err.to_string(),
+                }
+            })?;
+            if !(percent > 0.0 && percent <= MAX_SEND_PERCENT) {
+                return Err(CapstoneError::PercentOutOfRange(s.to_string()));
+            }
+            return Ok(SendAmount::Percent(percent));
+        }
+        let amount = Amount::from_str_in(s, bitcoin::Denomination::Bitcoin).map_err(|err| {
+            CapstoneError::InvalidAmount {
+                value: s.to_string(),
+                message: err.to_string(),
+            }
+        })?;
+        if amount == Amount::ZERO {
+            return Err(CapstoneError::ZeroAmount);
+        }
+        if amount > Amount::MAX_MONEY {
+            return Err(CapstoneError::AboveMaxMoney(s.to_string()));
+        }
+        Ok(SendAmount::Absolute(amount))
+    }
+}
+
+// Written the way config files give it back: a bare number of BTC, or the percentage as --amount takes it
+impl Serialize for SendAmount {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            SendAmount::Absolute(amount) => serializer.serialize_f64(amount.to_btc()),
+            SendAmount::Percent(percent) => serializer.collect_str(&format_args!("{percent}%")),
+        }
+    }
+}
+
+// Config files give the amount as --amount takes it, or as a bare number of BTC
+impl<'de> Deserialize<'de> for SendAmount {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Setting {
+            Btc(f64),
+            Text(String),
+        }
+        let text = match Setting::deserialize(deserializer)? {
+            Setting::Btc(btc) => btc.to_string(),
+            Setting::Text(text) => text,
+        };
+        text.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Checks before anything is sent that the Miner's `spendable` balance covers `amount`: all of it may go
+/// when the recipient pays the fee (`fee_from_recipient`), otherwise some has to be left for the fee.
+pub fn check_spendable(
+    amount: Amount,
+    spendable: Amount,
+    fee_from_recipient: bool,
+) -> Result<(), CapstoneError> {
+    let covered = if fee_from_recipient {
+        amount <= spendable
+    } else {
+        amount < spendable
+    };
+    if covered {
+        return Ok(());
+    }
+    Err(CapstoneError::InsufficientFunds {
+        requested: amount,
+        spendable,
+        fee_on_top: !fee_from_recipient,
+    })
+}
+
+/// Pays `amount` to `address` like `send_to_address`, but at `fee_rate` sat/vB instead of the wallet's
+/// estimate; the client's typed call has no fee_rate argument, so this goes through `sendtoaddress` directly.
+pub fn send_at_fee_rate(
+    wallet: &Client,
+    address: &Address,
+    amount: Amount,
+    comment: &str,
+    subtract_fee: bool,
+    fee_rate: f64,
+) -> bitcoincore_rpc::Result<Txid> {
+    let args = [
+        json!(address.to_string()),
+        json!(amount.to_btc()),
+        json!(comment),
+        json!(null), // comment_to
+        json!(subtract_fee),
+        json!(null), // replaceable
+        json!(null), // conf_target
+        json!(null), // estimate_mode
+        json!(null), // avoid_reuse
+        json!(fee_rate),
+    ];
+    wallet.call("sendtoaddress", &args)
+}
+
+/// Fails with `CapstoneError::FeeTooHigh` if `fee` is above `max`, or only warns when `allow_high_fee` is set.
+pub fn check_fee(fee: Amount, max: Amount, allow_high_fee: bool) -> Result<(), CapstoneError> {
+    if fee <= max {
+        return Ok(());
+    }
+    let err = CapstoneError::FeeTooHigh { fee, max };
+    if allow_high_fee {
+        warn!("⚠️ {err}; continuing because of --allow-high-fee");
+        return Ok(());
+    }
+    Err(err)
+}
+
+/// Splits `amount` into `count` equal shares for `--trader-addresses`, the first share taking the satoshis
+/// that don't divide evenly so the shares still add up to `amount`. Every share must be above dust.
+pub fn split_shares(amount: Amount, count: usize) -> Result<Vec<Amount>, CapstoneError> {
+    let share = Amount::from_sat(amount.to_sat() / count as u64);
+    if share < DUST_TOLERANCE {
+        return Err(CapstoneError::DustShares {
+            amount,
+            count,
+            share,
+        });
+    }
+    let mut shares = vec![share; count];
+    shares[0] += Amount::from_sat(amount.to_sat() % count as u64);
+    Ok(shares)
+}
+
+/// Pays each of `addresses` its share in one transaction (`sendmany`), with the wallet's usual change.
+/// With `subtract_fee` the fee is split evenly between the shares instead.
+pub fn send_split(
+    wallet: &Client,
+    addresses: &[Address],
+    shares: &[Amount],
+    comment: &str,
+    subtract_fee: bool,
+) -> bitcoincore_rpc::Result<Txid> {
+    let recipients: Vec<(Address, Amount)> = addresses
+        .iter()
+        .cloned()
+        .zip(shares.iter().copied())
+        .collect();
+    send_many(wallet, &recipients, comment, subtract_fee)
+}
+
+/// Pays each `(address, amount)` of `recipients` in one transaction with `sendmany`, the change going back to
+/// `wallet`. Under `subtract_fee` the recipients split the fee between them instead. The addresses must differ.
+pub fn send_many(
+    wallet: &Client,
+    recipients: &[(Address, Amount)],
+    comment: &str,
+    subtract_fee: bool,
+) -> bitcoincore_rpc::Result<Txid> {
+    let subtract_from: Vec<String> = if subtract_fee {
+        recipients
+            .iter()
+            .map(|(address, _)| address.to_string())
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let amounts: serde_json::Map<String, serde_json::Value> = recipients
+        .iter()
+        .map(|(address, amount)| (address.to_string(), json!(amount.to_btc())))
+        .collect();
+    wallet.call(
+        "sendmany",
+        &[
+            json!(""),
+            json!(amounts),
+            json!(null),
+            json!(comment),
+            json!(subtract_from),
+        ],
+    )
+}
+
+/// Sends the wallet's entire spendable balance to `address` with `sendall`: every UTXO goes in, the fee
+/// comes out of the one output, and there is no change.
+pub fn sweep_to(wallet: &Client, address: &Address) -> bitcoincore_rpc::Result<Txid> {
+    #[derive(Deserialize)]
+    struct SendAllResult {
+        complete: bool,
+        txid: Option<Txid>,
+    }
+    let result = wallet.call::<SendAllResult>("sendall", &[json!([address.to_string()])])?;
+    match result.txid {
+        Some(txid) if result.complete => Ok(txid),
+        _ => Err(bitcoincore_rpc::Error::ReturnedError(
+            "sendall did not produce a complete transaction".to_string(),
+        )),
+    }
+}
+
+/// Pays `amount` to `address` funded by exactly `utxos` (coin control), using the `send` RPC with
+/// `add_inputs` off so the wallet can't top the inputs up; change goes back to the wallet as usual.
+pub fn send_from_utxos(
+    wallet: &Client,
+    address: &Address,
+    amount: Amount,
+    utxos: &[OutPoint],
+) -> bitcoincore_rpc::Result<Txid> {
+    let inputs: Vec<_> = utxos
+        .iter()
+        .map(|utxo| json!({ "txid": utxo.txid, "vout": utxo.vout }))
+        .collect();
+    let args = [
+        json!([{ address.to_string(): amount.to_btc() }]),
+        json!(null), // conf_target
+        json!(null), // estimate_mode
+        json!(null), // fee_rate
+        json!({ "inputs": inputs, "add_inputs": false }),
+    ];
+
+    #[derive(Deserialize)]
+    struct SendResult {
+        complete: bool,
+        txid: Option<Txid>,
+    }
+    let result = wallet.call::<SendResult>("send", &args)?;
+    match result.txid {
+        Some(txid) if result.complete => Ok(txid),
+        _ => Err(bitcoincore_rpc::Error::ReturnedError(
+            "send with the pinned UTXOs did not produce a complete transaction".to_string(),
+        )),
+    }
+}
+
+/// Builds, funds and signs the payment of `amount` to `address` the way a send would (wallet coin selection
+/// and fee), without broadcasting it or locking its coins, for `--dry-run`. Returns the transaction and its fee.
+pub fn build_unbroadcast(
+    wallet: &Client,
+    address: &Address,
+    amount: Amount,
+    subtract_fee: bool,
+) -> bitcoincore_rpc::Result<(Transaction, Amount)> {
+    let funded = wallet.wallet_create_funded_psbt(
+        &[],
+        &HashMap::from([(address.to_string(), amount)]),
+        None,
+        Some(WalletCreateFundedPsbtOptions {
+            lock_unspent: Some(false),
+            subtract_fee_from_outputs: if subtract_fee { vec![0] } else { vec![] },
+            ..Default::default()
+        }),
+        None,
+    )?;
+    let signed = wallet.wallet_process_psbt(&funded.psbt, Some(true), None, None)?;
+    let hex = wallet
+        .finalize_psbt(&signed.psbt, Some(true))?
+        .hex
+        .ok_or_else(|| {
+            bitcoincore_rpc::Error::ReturnedError("PSBT could not be finalized".to_string())
+        })?;
+    let tx = bitcoin::consensus::deserialize(&hex)
+        .map_err(|err| bitcoincore_rpc::Error::ReturnedError(err.to_string()))?;
+    Ok((tx, funded.fee))
+}
+
+/// The PSBT send path: funds (from `utxos` only, if any), signs and finalizes a payment with nLockTime
+/// `locktime`, then mines to `miner_address` until the transaction is final and broadcasts it. A node
+/// refuses non-final transactions, so a future locktime has to be reached before the send, not after.
+#[allow(clippy::too_many_arguments)]
+pub fn send_with_locktime(
+    ctx: &Context,
+    wallet: &Client,
+    miner_address: &Address,
+    address: &Address,
+    amount: Amount,
+    locktime: LockTime,
+    utxos: &[OutPoint],
+    metrics: &mut Metrics,
+) -> bitcoincore_rpc::Result<Txid> {
+    let inputs: Vec<_> = utxos
+        .iter()
+        .map(|utxo| CreateRawTransactionInput {
+            txid: utxo.txid,
+            vout: utxo.vout,
+            sequence: None, // The wallet's default is non-final, which nLockTime needs to apply
+        })
+        .collect();
+    let funded = wallet.wallet_create_funded_psbt(
+        &inputs,
+        &HashMap::from([(address.to_string(), amount)]),
+        Some(locktime.to_consensus_u32().into()),
+        Some(WalletCreateFundedPsbtOptions {
+            add_inputs: Some(utxos.is_empty()),
+            ..Default::default()
+        }),
+        None,
+    )?;
+    let signed = wallet.wallet_process_psbt(&funded.psbt, Some(true), None, None)?;
+    let hex = wallet
+        .finalize_psbt(&signed.psbt, Some(true))?
+        .hex
+        .ok_or_else(|| {
+            bitcoincore_rpc::Error::ReturnedError("PSBT could not be finalized".to_string())
+        })?;
+    let tx: Transaction = bitcoin::consensus::deserialize(&hex)
+        .map_err(|err| bitcoincore_rpc::Error::ReturnedError(err.to_string()))?;
+    info!("Payment signed with locktime {locktime}");
+
+    match locktime {
+        LockTime::Blocks(_) => {
+            let needed = blocks_until_final(locktime, wallet.get_block_count()?);
+            if needed > 0 {
+                info!("Mining {needed} block(s) until locktime {locktime} is reached");
+                metrics.blocks_mined +=
+                    mine_blocks(ctx, wallet, needed, miner_address)?.len() as u64;
+            }
+        }
+        LockTime::Seconds(time) => {
+            let mut mined = 0;
+            while wallet.get_blockchain_info()?.median_time <= u64::from(time.to_consensus_u32()) {
+                if mined == LOCKTIME_MAX_BLOCKS {
+                    return Err(bitcoincore_rpc::Error::ReturnedError(format!(
+                        "Median time past still before locktime {locktime} after {mined} blocks; use --block-time-start to move block time"
+                    )));
+                }
+                mine_blocks(ctx, wallet, 1, miner_address)?;
+                mined += 1;
+            }
+            metrics.blocks_mined += mined;
+        }
+    }
+    wallet.send_raw_transaction(&tx)
+}
+
+// Blocks to mine on top of `tip_height` before a height-locked transaction may enter the mempool: it can
+// go into block `locktime + 1`, the next block once the tip has reached the locktime height
+pub fn blocks_until_final(locktime: LockTime, tip_height: u64) -> u64 {
+    match locktime {
+        LockTime::Blocks(height) => u64::from(height.to_consensus_u32()).saturating_sub(tip_height),
+        LockTime::Seconds(_) => 0,
+    }
+}
+
+/// Checks every `--spend-utxo` against the wallet's `listunspent` and returns their total value.
+/// An outpoint that is missing (already spent, unconfirmed or someone else's) or not spendable is an error.
+pub fn pinned_input_total(
+    unspent: &[ListUnspentResultEntry],
+    utxos: &[OutPoint],
+) -> Result<Amount, CapstoneError> {
+    let mut total = Amount::ZERO;
+    for utxo in utxos {
+        let entry = unspent
+            .iter()
+            .find(|entry| entry.txid == utxo.txid && entry.vout == utxo.vout)
+            .ok_or(CapstoneError::UnknownUtxo(*utxo))?;
+        if !entry.spendable {
+            return Err(CapstoneError::UnspendableUtxo(*utxo));
+        }
+        total += entry.amount;
+    }
+    Ok(total)
+}
+
+/// The smallest spendable output in `unspent` that covers `needed` by itself, for paying from coins an earlier
+/// run left behind with a single input, as a payment funded by a fresh block reward has. `None` when the
+/// balance is only spread over smaller outputs.
+pub fn single_covering_utxo(
+    unspent: &[ListUnspentResultEntry],
+    needed: Amount,
+) -> Option<OutPoint> {
+    unspent
+        .iter()
+        .filter(|entry| entry.spendable && entry.amount >= needed)
+        .min_by_key(|entry| entry.amount)
+        .map(|entry| OutPoint::new(entry.txid, entry.vout))
+}
+
+/// The most recently matured output to `address` (the fewest confirmations) that covers `needed` by itself:
+/// the block reward a run just mined, rather than an older reward or change that also sits at the address.
+pub fn newest_reward(
+    unspent: &[ListUnspentResultEntry],
+    address: &str,
+    needed: Amount,
+) -> Option<OutPoint> {
+    unspent
+        .iter()
+        .filter(|entry| entry.spendable && entry.amount >= needed)
+        .filter(|entry| {
+            entry
+                .address
+                .as_ref()
+                .is_some_and(|paid| paid.clone().assume_checked().to_string() == address)
+        })
+        .min_by_key(|entry| entry.confirmations)
+        .map(|entry| OutPoint::new(entry.txid, entry.vout))
+}
+
+// Largest accounting mismatch we tolerate before declaring the extraction broken (the standard dust limit)
+pub const DUST_TOLERANCE: Amount = Amount::from_sat(546);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::DEFAULT_MAX_FEE;
+    use crate::test_support::*;
+    use bitcoin::ScriptBuf;
+
+    #[test]
+    fn percentage_resolves_against_balance() {
+        let balance = Amount::from_btc(50.0).unwrap();
+        assert_eq!(
+            SendAmount::Percent(40.0).resolve(balance),
+            Amount::from_btc(20.0).unwrap()
+        );
+        assert_eq!(
+            SendAmount::Absolute(Amount::ONE_BTC).resolve(balance),
+            Amount::ONE_BTC
+        );
+    }
+
+    #[test]
+    fn send_amounts_serialize_as_config_files_give_them() {
+        for (amount, json) in [
+            (
+                SendAmount::Absolute(Amount::from_sat(12_345_678)),
+                "0.12345678",
+            ),
+            (SendAmount::Percent(33.5), "\"33.5%\""),
+        ] {
+            assert_eq!(serde_json::to_string(&amount).unwrap(), json);
+            assert_eq!(serde_json::from_str::<SendAmount>(json).unwrap(), amount);
+        }
+    }
+
+    #[test]
+    #[ignore = "needs a running regtest node"]
+    fn future_height_locktime_is_mined_towards_before_sending() {
+        let (miner, trader) = (node_miner(), node_trader());
+        let miner_address = miner.get_new_address(None, None).unwrap().assume_checked();
+        miner.generate_to_address(101, &miner_address).unwrap();
+        let trader_address = trader.get_new_address(None, None).unwrap().assume_checked();
+        let tip = miner.get_block_count().unwrap();
+        let locktime = LockTime::from_height((tip + 5) as u32).unwrap();
+
+        let mut metrics = Metrics::new();
+        let txid = send_with_locktime(
+            &Context::default(),
+            &miner,
+            &miner_address,
+            &trader_address,
+            Amount::from_int_btc(1),
+            locktime,
+            &[],
+            &mut metrics,
+        )
+        .unwrap();
+
+        assert_eq!(metrics.blocks_mined, 5);
+        assert_eq!(miner.get_block_count().unwrap(), tip + 5);
+        let tx = miner
+            .get_transaction(&txid, None)
+            .unwrap()
+            .transaction()
+            .unwrap();
+        assert_eq!(tx.lock_time, locktime);
+        assert!(miner.get_raw_mempool().unwrap().contains(&txid));
+    }
+
+    #[test]
+    #[ignore = "needs a running regtest node"]
+    fn payment_spends_exactly_the_pinned_utxo() {
+        let (miner, trader) = (node_miner(), node_trader());
+        let miner_address = miner.get_new_address(None, None).unwrap().assume_checked();
+        miner.generate_to_address(101, &miner_address).unwrap();
+        let unspent = miner
+            .list_unspent(Some(100), None, None, None, None)
+            .unwrap();
+        let pinned = OutPoint::new(unspent[0].txid, unspent[0].vout);
+        let trader_address = trader.get_new_address(None, None).unwrap().assume_checked();
+
+        let txid =
+            send_from_utxos(&miner, &trader_address, Amount::from_int_btc(1), &[pinned]).unwrap();
+
+        let tx = miner
+            .get_transaction(&txid, None)
+            .unwrap()
+            .transaction()
+            .unwrap();
+        let inputs: Vec<OutPoint> = tx.input.iter().map(|input| input.previous_output).collect();
+        assert_eq!(inputs, [pinned]);
+    }
+
+    #[test]
+    fn payment_splits_into_equal_shares_above_dust() {
+        let shares = split_shares(Amount::from_btc(20.0).unwrap(), 3).unwrap();
+        assert_eq!(
+            shares,
+            [
+                Amount::from_sat(666_666_668),
+                Amount::from_sat(666_666_666),
+                Amount::from_sat(666_666_666)
+            ]
+        );
+        assert_eq!(
+            split_shares(Amount::from_btc(20.0).unwrap(), 1).unwrap(),
+            [Amount::from_btc(20.0).unwrap()]
+        );
+        assert!(split_shares(Amount::from_sat(1_000), 2).is_err());
+    }
+
+    #[test]
+    #[ignore = "needs a running regtest node"]
+    fn dry_run_signs_the_payment_but_broadcasts_nothing() {
+        let (miner, trader) = (node_miner(), node_trader());
+        let address = miner.get_new_address(None, None).unwrap().assume_checked();
+        mine_blocks(&Context::default(), &miner, COINBASE_MATURITY + 1, &address).unwrap();
+        let trader_address = trader.get_new_address(None, None).unwrap().assume_checked();
+        let mempool = miner.get_raw_mempool().unwrap();
+        let unspent = miner
+            .list_unspent(None, None, None, None, None)
+            .unwrap()
+            .len();
+
+        let amount = Amount::from_int_btc(1);
+        let (tx, fee) = build_unbroadcast(&miner, &trader_address, amount, false).unwrap();
+        assert!(fee > Amount::ZERO);
+        assert!(tx
+            .output
+            .iter()
+            .any(|out| out.value == amount && out.script_pubkey == trader_address.script_pubkey()));
+        assert!(miner.get_raw_transaction(&tx.compute_txid(), None).is_err());
+        assert_eq!(miner.get_raw_mempool().unwrap(), mempool);
+        // Its inputs stay spendable: nothing was locked for a payment that never happens
+        let still_unspent = miner.list_unspent(None, None, None, None, None).unwrap();
+        assert_eq!(still_unspent.len(), unspent);
+        let locked: Vec<serde_json::Value> = miner.call("listlockunspent", &[]).unwrap();
+        assert!(locked.is_empty());
+    }
+
+    #[test]
+    fn a_rerun_pays_from_one_earlier_utxo_or_none() {
+        let funding = payment_tx(&[(MINER_INPUT, 100_000_000), (CHANGE, 2_999_999_000)]);
+        let txid = funding.compute_txid();
+        let entry = |vout: u32, sats: u64, spendable: bool| ListUnspentResultEntry {
+            txid,
+            vout,
+            address: None,
+            label: None,
+            redeem_script: None,
+            witness_script: None,
+            script_pub_key: ScriptBuf::new(),
+            amount: Amount::from_sat(sats),
+            confirmations: 101,
+            spendable,
+            solvable: true,
+            descriptor: None,
+            safe: true,
+        };
+        let unspent = [
+            entry(0, 5_000_000_000, false),
+            entry(1, 2_999_999_000, true),
+            entry(2, 100_000_000, true),
+            entry(3, 2_100_000_000, true),
+        ];
+        let needed = Amount::from_int_btc(20) + Amount::from_btc(0.01).unwrap();
+        // The smallest spendable output that covers the payment alone
+        assert_eq!(
+            single_covering_utxo(&unspent, needed),
+            Some(OutPoint::new(txid, 3))
+        );
+        // Only smaller ones left: the run mines a fresh reward instead
+        assert_eq!(
+            single_covering_utxo(&unspent, Amount::from_int_btc(30)),
+            None
+        );
+    }
+
+    #[test]
+    fn the_newest_reward_is_picked_over_older_coins_and_change() {
+        let txid = payment_tx(&[(MINER_INPUT, 5_000_000_000)]).compute_txid();
+        let at = |address: &str| Some(address.parse().unwrap());
+        let entry =
+            |vout: u32, address: &str, sats: u64, confirmations: u32| ListUnspentResultEntry {
+                txid,
+                vout,
+                address: at(address),
+                label: None,
+                redeem_script: None,
+                witness_script: None,
+                script_pub_key: ScriptBuf::new(),
+                amount: Amount::from_sat(sats),
+                confirmations,
+                spendable: true,
+                solvable: true,
+                descriptor: None,
+                safe: true,
+            };
+        let unspent = [
+            entry(0, CHANGE, 2_999_998_590, 3), // An earlier run's change, newer but elsewhere
+            entry(1, MINER_INPUT, 5_000_000_000, 150), // An older reward
+            entry(2, MINER_INPUT, 5_000_000_000, 101), // The reward that just matured
+            entry(3, MINER_INPUT, 100_000_000, 5), // Too small to pay alone
+        ];
+        let needed = Amount::from_int_btc(20) + Amount::from_btc(0.01).unwrap();
+        assert_eq!(
+            newest_reward(&unspent, MINER_INPUT, needed),
+            Some(OutPoint::new(txid, 2))
+        );
+        assert_eq!(newest_reward(&unspent, TRADER, needed), None);
+    }
+
+    #[test]
+    fn amount_parses_btc_or_percentage() {
+        assert_eq!(
+            "0.5".parse(),
+            Ok(SendAmount::Absolute(Amount::from_sat(50_000_000)))
+        );
+        assert_eq!("50%".parse(), Ok(SendAmount::Percent(50.0)));
+        assert!("96%".parse::<SendAmount>().is_err());
+        assert!("0%".parse::<SendAmount>().is_err());
+        assert!("0".parse::<SendAmount>().is_err());
+        assert!("twenty".parse::<SendAmount>().is_err());
+    }
+
+    #[test]
+    fn fee_above_the_limit_aborts_unless_allowed() {
+        let max = DEFAULT_MAX_FEE;
+        assert_eq!(check_fee(Amount::from_sat(141), max, false), Ok(()));
+        assert_eq!(check_fee(max, max, false), Ok(()));
+        let fee = Amount::from_btc(0.5).unwrap();
+        assert_eq!(
+            check_fee(fee, max, false),
+            Err(CapstoneError::FeeTooHigh { fee, max })
+        );
+        assert_eq!(check_fee(fee, max, true), Ok(()));
+    }
+
+    #[test]
+    fn height_locktime_needs_the_tip_to_reach_it() {
+        // Final once the tip is at the locktime height: it then fits in the next block
+        let locktime = LockTime::from_height(250).unwrap();
+        assert_eq!(blocks_until_final(locktime, 101), 149);
+        assert_eq!(blocks_until_final(locktime, 250), 0);
+        assert_eq!(blocks_until_final(locktime, 300), 0);
+        assert_eq!(blocks_until_final(LockTime::ZERO, 0), 0);
+    }
+
+    #[test]
+    fn pinned_utxos_must_be_unspent_and_spendable() {
+        let funding = payment_tx(&[(MINER_INPUT, 5_000_000_000), (CHANGE, 100_000_000)]);
+        let txid = funding.compute_txid();
+        let entry = |vout: u32, sats: u64, spendable: bool| ListUnspentResultEntry {
+            txid,
+            vout,
+            address: None,
+            label: None,
+            redeem_script: None,
+            witness_script: None,
+            script_pub_key: funding.output[vout as usize].script_pubkey.clone(),
+            amount: Amount::from_sat(sats),
+            confirmations: 101,
+            spendable,
+            solvable: true,
+            descriptor: None,
+            safe: true,
+        };
+        let unspent = [entry(0, 5_000_000_000, true), entry(1, 100_000_000, false)];
+
+        assert_eq!(
+            pinned_input_total(&unspent, &[OutPoint::new(txid, 0)]),
+            Ok(Amount::from_int_btc(50))
+        );
+        assert_eq!(
+            pinned_input_total(&unspent, &[OutPoint::new(txid, 1)]),
+            Err(CapstoneError::UnspendableUtxo(OutPoint::new(txid, 1)))
+        );
+        assert_eq!(
+            pinned_input_total(&unspent, &[OutPoint::new(txid, 2)]),
+            Err(CapstoneError::UnknownUtxo(OutPoint::new(txid, 2)))
+        );
+    }
+
+    #[test]
+    fn fee_comes_on_top_of_the_amount_unless_the_recipient_pays_it() {
+        let fifty = Amount::from_int_btc(50);
+        assert_eq!(
+            check_spendable(Amount::from_int_btc(20), fifty, false),
+            Ok(())
+        );
+        assert_eq!(check_spendable(fifty, fifty, true), Ok(()));
+        let err = check_spendable(fifty, fifty, false).unwrap_err();
+        assert_eq!(
+            err,
+            CapstoneError::InsufficientFunds {
+                requested: fifty,
+                spendable: fifty,
+                fee_on_top: true,
+            }
+        );
+        assert!(err.to_string().contains("plus the fee"));
+        assert!(check_spendable(Amount::from_int_btc(60), fifty, true).is_err());
+    }
+}
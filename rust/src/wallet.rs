@@ -0,0 +1,569 @@
+//! Wallet housekeeping: creating and loading wallets, fresh and labelled addresses, unlocking for a send,
+//! and the checks that the wallets ended up holding what the report says.
+
+use crate::{rpc::*, *};
+use bitcoincore_rpc::bitcoin::{Address, Amount, Txid};
+use bitcoincore_rpc::json::{GetAddressInfoResultLabel, ScanTxOutRequest, Utxo};
+use bitcoincore_rpc::jsonrpc;
+use bitcoincore_rpc::{Client, RpcApi};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::result::Result;
+use std::time::Instant;
+use std::{thread, time::Duration};
+use tracing::{info, warn};
+
+// How many receiving addresses we try before giving up on finding one that was never paid
+pub const ADDRESS_ATTEMPTS: usize = 3;
+
+// How long `walletpassphrase` unlocks the Miner wallet for; the send takes well under a second and we relock anyway
+pub const UNLOCK_SECONDS: u64 = 60;
+
+/// Takes addresses from `next_address` until one has never received anything according to `received`.
+/// A fresh wallet address should always qualify; a used one means the wallet handed out an old address.
+pub fn first_unused_address(
+    mut next_address: impl FnMut() -> Result<Address, CapstoneError>,
+    received: impl Fn(&Address) -> Result<Amount, CapstoneError>,
+) -> Result<Address, CapstoneError> {
+    for _ in 0..ADDRESS_ATTEMPTS {
+        let address = next_address()?;
+        let already_received = received(&address)?;
+        if already_received == Amount::ZERO {
+            return Ok(address);
+        }
+        warn!(
+            "⚠️ Address {address} already received {} BTC in an earlier run; generating another",
+            already_received.to_btc()
+        );
+    }
+    Err(CapstoneError::NoUnusedAddress {
+        attempts: ADDRESS_ATTEMPTS,
+    })
+}
+
+/// Unlocks the Miner wallet for the send if it is encrypted and locked. Returns whether we unlocked it,
+/// i.e. whether the caller must `walletlock` afterwards. The passphrase itself is never logged.
+pub fn unlock_for_send(
+    wallet: &Client,
+    passphrase: Option<&Passphrase>,
+) -> Result<bool, CapstoneError> {
+    // `unlocked_until` is only present on encrypted wallets, and 0 means locked
+    match (wallet.get_wallet_info()?.unlocked_until, passphrase) {
+        (None, Some(_)) => {
+            info!("Miner wallet is not encrypted; ignoring --wallet-passphrase");
+            Ok(false)
+        }
+        (None, None) => Ok(false),
+        (Some(0), None) => Err(CapstoneError::WalletLocked("Miner")),
+        (Some(_), None) => Ok(false), // Already unlocked by someone else; leave it as they set it
+        (Some(_), Some(Passphrase(passphrase))) => {
+            wallet.call::<()>(
+                "walletpassphrase",
+                &[json!(passphrase), json!(UNLOCK_SECONDS)],
+            )?;
+            info!("Miner wallet unlocked for {UNLOCK_SECONDS}s to send.");
+            Ok(true)
+        }
+    }
+}
+
+/// The externally funded path of `run`: polls `balance` every `interval` until it can cover `amount`
+/// (more than an absolute amount, since the fee comes on top; anything for a percentage) and returns it.
+/// Gives up with `CapstoneError::FundsTimeout` once `timeout` has passed.
+pub fn wait_for_funds(
+    mut balance: impl FnMut() -> Result<Amount, CapstoneError>,
+    amount: SendAmount,
+    timeout: Duration,
+    interval: Duration,
+) -> Result<Amount, CapstoneError> {
+    let started = Instant::now();
+    loop {
+        let current = balance()?;
+        let enough = match amount {
+            SendAmount::Absolute(amount) => current > amount,
+            SendAmount::Percent(_) => current > Amount::ZERO,
+        };
+        if enough {
+            return Ok(current);
+        }
+        let waited = started.elapsed();
+        if waited >= timeout {
+            return Err(CapstoneError::FundsTimeout {
+                waited,
+                balance: current,
+            });
+        }
+        thread::sleep(interval);
+    }
+}
+
+/// Wallet names for `count` miners: the Miner itself, then Miner2, Miner3, ... for the extra `--miners`
+/// (`base` being the Miner's wallet name).
+pub fn miner_wallet_names(base: &str, count: usize) -> Vec<String> {
+    (1..=count)
+        .map(|n| match n {
+            1 => base.to_string(),
+            n => format!("{base}{n}"),
+        })
+        .collect()
+}
+
+/// The wallets a run works with, in the order they are declared: the Miner (and the extra `--miners`), the
+/// Trader, then any others the config or `--wallet` adds (an Exchange, ColdStorage, ...). Names are unique.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WalletSet {
+    names: Vec<String>,
+}
+
+impl WalletSet {
+    /// A set of `names`, refusing an empty name or the same wallet declared twice.
+    pub fn new(names: impl IntoIterator<Item = String>) -> Result<Self, CapstoneError> {
+        let mut set = WalletSet { names: Vec::new() };
+        for name in names {
+            if name.trim().is_empty() {
+                return Err(CapstoneError::EmptyWalletName);
+            }
+            if set.contains(&name) {
+                return Err(CapstoneError::DuplicateWallet(name));
+            }
+            set.names.push(name);
+        }
+        Ok(set)
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.names.iter().any(|known| known == name)
+    }
+
+    /// Creates or loads each wallet of the set that the node doesn't have loaded yet.
+    pub fn ensure_all(&self, ctx: &Context, rpc: &Client) -> Result<(), CapstoneError> {
+        for name in &self.names {
+            ensure_wallet_exists(ctx, rpc, name)?;
+        }
+        Ok(())
+    }
+
+    /// A wallet client for each wallet of the set, in the set's order.
+    pub fn connect(&self, settings: &RpcSettings) -> Result<Vec<(String, Client)>, CapstoneError> {
+        self.names
+            .iter()
+            .map(|name| Ok((name.clone(), settings.connect_wallet(name)?)))
+            .collect()
+    }
+}
+
+/// The client `WalletSet::connect` made for the wallet called `name`.
+pub fn wallet_client<'a>(clients: &'a [(String, Client)], name: &str) -> Option<&'a Client> {
+    clients
+        .iter()
+        .find(|(known, _)| known == name)
+        .map(|(_, client)| client)
+}
+
+// Ensure 'Miner' and 'Trader' wallets exist; this function is to ensure a wallet exists. If not, create it.
+// A wallet left on disk by an earlier `--unload-on-exit` run is loaded again instead of re-created.
+pub fn ensure_wallet_exists(
+    ctx: &Context,
+    rpc: &Client,
+    wallet_name: &str,
+) -> Result<(), CapstoneError> {
+    let loaded_wallets = rpc.list_wallets()?;
+    if loaded_wallets.contains(&wallet_name.to_string()) {
+        info!("Wallet already exists: {wallet_name}");
+    } else if rpc.list_wallet_dir()?.contains(&wallet_name.to_string()) {
+        info!("Loading wallet: {wallet_name}");
+        rpc.load_wallet(wallet_name)?;
+    } else {
+        info!("Creating wallet: {wallet_name}");
+        let version = rpc.get_network_info()?.version;
+        check_createwallet_support(version)?;
+        // Only the name is sent (the client drops trailing defaults), which every createwallet since 0.17
+        // accepts; later additions like avoid_reuse, descriptors or load_on_startup keep their node defaults.
+        rpc.create_wallet(wallet_name, None, None, None, None)
+            .map_err(|err| CapstoneError::CreateWallet {
+                wallet: wallet_name.to_string(),
+                core: core_version(version),
+                message: err.to_string(),
+            })?;
+        ctx.log_event("wallet_created", json!({ "wallet": wallet_name }));
+    }
+    Ok(())
+}
+
+/// The addresses `wallet` has under `label`, sorted; empty when the label has never been used.
+pub fn addresses_by_label(wallet: &Client, label: &str) -> bitcoincore_rpc::Result<Vec<String>> {
+    // getaddressesbylabel answers with {address: {purpose}}, or RPC_WALLET_INVALID_LABEL_NAME (-11) for an unknown label
+    match wallet.call::<HashMap<String, serde_json::Value>>("getaddressesbylabel", &[json!(label)])
+    {
+        Ok(addresses) => {
+            let mut addresses: Vec<String> = addresses.into_keys().collect();
+            addresses.sort();
+            Ok(addresses)
+        }
+        Err(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(err))) if err.code == -11 => {
+            Ok(Vec::new())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+// Which of the addresses already carrying the mining label to mine to again: the same one every run
+// (the lowest, as they come sorted), or none, meaning a new address is needed
+pub fn reusable_address(existing: &[String]) -> Option<&str> {
+    existing.first().map(String::as_str)
+}
+
+/// The comment `wallet` stored for one of its transactions (`gettransaction`'s `comment`, which the client's
+/// typed result leaves out); `None` when it has none.
+pub fn wallet_comment(wallet: &Client, txid: &Txid) -> bitcoincore_rpc::Result<Option<String>> {
+    #[derive(Deserialize)]
+    struct Commented {
+        comment: Option<String>,
+    }
+    Ok(wallet
+        .call::<Commented>("gettransaction", &[json!(txid)])?
+        .comment)
+}
+
+/// Checks the payment's stored comment is the one we sent with it. Comments are local to the sending
+/// wallet, so a mismatch means the send went through some other wallet than the Miner client we hold.
+/// Core stores no comment at all for an empty one.
+pub fn check_comment(stored: Option<&str>, expected: &str) -> Result<(), CapstoneError> {
+    if stored.unwrap_or_default() == expected {
+        return Ok(());
+    }
+    Err(CapstoneError::CommentMismatch {
+        stored: stored.map(str::to_string),
+        expected: expected.to_string(),
+    })
+}
+
+/// Reads the labels `wallet` has for `address` back with `getaddressinfo` and warns if `expected` isn't
+/// among them. Descriptor and legacy wallets have reported labels differently across Core versions, so a
+/// label that didn't stick shows up here rather than as a failing grader check.
+pub fn check_label(
+    wallet: &Client,
+    wallet_name: &str,
+    address: &Address,
+    expected: &str,
+) -> Result<(), CapstoneError> {
+    let info = wallet.get_address_info(address)?;
+    let found = address_labels(&info.labels);
+    if found.iter().any(|label| label == expected) {
+        info!("{wallet_name} address {address} carries label \"{expected}\"");
+    } else {
+        warn!("⚠️ {wallet_name} address {address} should be labelled \"{expected}\" but has {found:?}");
+    }
+    Ok(())
+}
+
+// The label names in `getaddressinfo`'s `labels` array, which is a list of strings on current Core
+// and of {name, purpose} objects before 0.21 (the single `label` field is deprecated since 0.20)
+pub fn address_labels(labels: &[GetAddressInfoResultLabel]) -> Vec<String> {
+    labels
+        .iter()
+        .map(|label| match label {
+            GetAddressInfoResultLabel::Simple(name) => name.clone(),
+            GetAddressInfoResultLabel::WithPurpose { name, .. } => name.clone(),
+        })
+        .collect()
+}
+
+/// Cross-checks the report against the chain state: `scantxoutset` must find an unspent output of this tx
+/// paying the trader address the reported amount. If there is none, the funds never actually arrived.
+pub fn verify_trader_utxo(node: &Client, report: &TxReport) -> Result<(), CapstoneError> {
+    let scan = node.scan_tx_out_set_blocking(&[ScanTxOutRequest::Single(format!(
+        "addr({})",
+        report.trader_address
+    ))])?;
+    let utxo =
+        matching_utxo(&scan.unspents, &report.txid, report.trader_amount).ok_or_else(|| {
+            CapstoneError::TraderUtxoMissing {
+                txid: report.txid,
+                amount: report.trader_amount,
+                address: report.trader_address.clone(),
+            }
+        })?;
+    info!(
+        "Trader UTXO confirmed on chain: {}:{} ({} BTC, height {})",
+        utxo.txid,
+        utxo.vout,
+        utxo.amount.to_btc(),
+        utxo.height
+    );
+    Ok(())
+}
+
+// The unspent output created by `txid` carrying exactly `amount`, if the scan found one
+pub fn matching_utxo<'a>(unspents: &'a [Utxo], txid: &Txid, amount: Amount) -> Option<&'a Utxo> {
+    unspents
+        .iter()
+        .find(|utxo| utxo.txid == *txid && utxo.amount == amount)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::{PAYMENT_COMMENT, RECEIVED_LABEL};
+    use crate::test_support::*;
+    use bitcoincore_rpc::RpcApi;
+    use std::cell::Cell;
+    use std::str::FromStr;
+
+    #[test]
+    fn waiting_for_funds_returns_once_the_send_is_covered() {
+        // The faucet payment shows up on the third poll
+        let polls = Cell::new(0);
+        let balance = wait_for_funds(
+            || {
+                polls.set(polls.get() + 1);
+                Ok(if polls.get() >= 3 {
+                    Amount::from_int_btc(25)
+                } else {
+                    Amount::ZERO
+                })
+            },
+            SendAmount::Absolute(Amount::from_int_btc(20)),
+            Duration::from_secs(3600),
+            Duration::ZERO,
+        )
+        .unwrap();
+        assert_eq!((balance, polls.get()), (Amount::from_int_btc(25), 3));
+
+        // Exactly the amount leaves nothing for the fee; a percentage takes any balance
+        let exact = || Ok(Amount::from_int_btc(20));
+        let twenty = SendAmount::Absolute(Amount::from_int_btc(20));
+        let err = wait_for_funds(exact, twenty, Duration::ZERO, Duration::ZERO).unwrap_err();
+        assert!(matches!(
+            err,
+            CapstoneError::FundsTimeout { balance, .. } if balance == Amount::from_int_btc(20)
+        ));
+        assert!(wait_for_funds(
+            exact,
+            SendAmount::Percent(50.0),
+            Duration::ZERO,
+            Duration::ZERO
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn address_labels_merge_both_getaddressinfo_forms() {
+        use bitcoincore_rpc::json::GetAddressInfoResultLabelPurpose;
+        let labels = [
+            GetAddressInfoResultLabel::Simple("Received".to_string()),
+            GetAddressInfoResultLabel::WithPurpose {
+                name: "Savings".to_string(),
+                purpose: GetAddressInfoResultLabelPurpose::Receive,
+            },
+        ];
+        assert_eq!(address_labels(&labels), ["Received", "Savings"]);
+        assert!(address_labels(&[]).is_empty());
+    }
+
+    fn regtest_address(address: &str) -> Address {
+        Address::from_str(address)
+            .unwrap()
+            .require_network(bitcoin::Network::Regtest)
+            .unwrap()
+    }
+
+    #[test]
+    fn reused_receiving_address_is_skipped() {
+        // The wallet first hands back an address that was paid in an earlier run
+        let mut candidates = vec![regtest_address(TRADER), regtest_address(CHANGE)].into_iter();
+        let used = regtest_address(TRADER);
+
+        let address = first_unused_address(
+            || Ok(candidates.next().unwrap()),
+            |address| {
+                Ok(if *address == used {
+                    Amount::from_int_btc(20)
+                } else {
+                    Amount::ZERO
+                })
+            },
+        )
+        .unwrap();
+
+        assert_eq!(address, regtest_address(CHANGE));
+    }
+
+    #[test]
+    fn only_reused_addresses_is_an_error() {
+        let result = first_unused_address(
+            || Ok(regtest_address(TRADER)),
+            |_| Ok(Amount::from_int_btc(20)),
+        );
+        assert_eq!(
+            result.unwrap_err(),
+            CapstoneError::NoUnusedAddress {
+                attempts: ADDRESS_ATTEMPTS
+            }
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a running regtest node"]
+    fn encrypted_wallet_needs_the_passphrase_to_send() {
+        let rpc = RpcSettings::default().connect().unwrap();
+        let name = format!("Encrypted-{}", std::process::id());
+        rpc.create_wallet(&name, None, None, None, None).unwrap();
+        let wallet = RpcSettings::default().connect_wallet(&name).unwrap();
+        wallet.encrypt_wallet("correct horse").unwrap();
+
+        let locked = unlock_for_send(&wallet, None).unwrap_err();
+        assert_eq!(locked, CapstoneError::WalletLocked("Miner"));
+        assert!(unlock_for_send(&wallet, Some(&Passphrase("wrong".to_string()))).is_err());
+
+        let passphrase = Passphrase("correct horse".to_string());
+        assert!(unlock_for_send(&wallet, Some(&passphrase)).unwrap());
+        assert!(wallet.get_wallet_info().unwrap().unlocked_until > Some(0));
+        wallet.call::<()>("walletlock", &[]).unwrap();
+        assert_eq!(wallet.get_wallet_info().unwrap().unlocked_until, Some(0));
+
+        rpc.unload_wallet(Some(&name)).unwrap();
+    }
+
+    #[test]
+    fn mining_address_is_reused_when_the_label_exists() {
+        assert_eq!(reusable_address(&[]), None);
+        let existing = [MINER_INPUT.to_string(), CHANGE.to_string()];
+        let mut sorted = existing.clone();
+        sorted.sort();
+        assert_eq!(reusable_address(&sorted), Some(MINER_INPUT));
+        // Same choice whatever order the wallet listed them in
+        let mut reversed = existing;
+        reversed.reverse();
+        reversed.sort();
+        assert_eq!(reusable_address(&reversed), reusable_address(&sorted));
+    }
+
+    #[test]
+    #[ignore = "needs a running regtest node"]
+    fn labelled_addresses_are_found_again() {
+        let miner = node_miner();
+        let label = format!("Reuse-{}", std::process::id());
+        assert!(addresses_by_label(&miner, &label).unwrap().is_empty());
+        let address = miner
+            .get_new_address(Some(&label), None)
+            .unwrap()
+            .assume_checked();
+
+        let existing = addresses_by_label(&miner, &label).unwrap();
+        assert_eq!(
+            reusable_address(&existing),
+            Some(address.to_string().as_str())
+        );
+        // With several under the label, the lowest is reused, whichever was created first
+        miner.get_new_address(Some(&label), None).unwrap();
+        let existing = addresses_by_label(&miner, &label).unwrap();
+        assert_eq!(existing.len(), 2);
+        assert_eq!(
+            reusable_address(&existing),
+            existing.iter().min().map(String::as_str)
+        );
+    }
+
+    #[test]
+    #[ignore = "needs a running regtest node"]
+    fn payment_comment_persists_in_the_sending_wallet() {
+        let (miner, trader) = (node_miner(), node_trader());
+        let miner_address = miner.get_new_address(None, None).unwrap().assume_checked();
+        miner.generate_to_address(101, &miner_address).unwrap();
+        let trader_address = trader.get_new_address(None, None).unwrap().assume_checked();
+        let comment = format!("comment-{}", std::process::id());
+        let txid = miner
+            .send_to_address(
+                &trader_address,
+                Amount::from_int_btc(1),
+                Some(&comment),
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        miner.generate_to_address(1, &miner_address).unwrap();
+
+        let stored = wallet_comment(&miner, &txid).unwrap();
+        assert_eq!(stored.as_deref(), Some(comment.as_str()));
+        assert_eq!(check_comment(stored.as_deref(), &comment), Ok(()));
+        // Wallet-local: the Trader received the same transaction but knows no comment
+        assert_eq!(wallet_comment(&trader, &txid).unwrap(), None);
+    }
+
+    #[test]
+    fn trader_utxo_must_match_txid_and_amount() {
+        let tx = payment_tx(&[(TRADER, 2_000_000_000)]);
+        let older = payment_tx(&[(TRADER, 100_000_000)]);
+        let utxo = |txid: Txid, sats: u64| Utxo {
+            txid,
+            vout: 0,
+            script_pub_key: script_for(TRADER),
+            descriptor: format!("addr({TRADER})"),
+            amount: Amount::from_sat(sats),
+            height: 102,
+        };
+        let unspents = [
+            utxo(older.compute_txid(), 100_000_000),
+            utxo(tx.compute_txid(), 2_000_000_000),
+        ];
+
+        let found = matching_utxo(&unspents, &tx.compute_txid(), Amount::from_int_btc(20));
+        assert_eq!(found, Some(&unspents[1]));
+        assert_eq!(
+            matching_utxo(&unspents, &tx.compute_txid(), Amount::ONE_BTC),
+            None
+        );
+        assert_eq!(
+            matching_utxo(&unspents[..1], &tx.compute_txid(), Amount::from_int_btc(20)),
+            None
+        );
+    }
+
+    #[test]
+    fn extra_miners_are_named_after_the_miner() {
+        assert_eq!(miner_wallet_names("Miner", 1), ["Miner"]);
+        assert_eq!(
+            miner_wallet_names("Miner", 3),
+            ["Miner", "Miner2", "Miner3"]
+        );
+        assert_eq!(miner_wallet_names("Alice", 2), ["Alice", "Alice2"]);
+    }
+
+    #[test]
+    fn wallet_set_refuses_a_name_twice() {
+        assert_eq!(
+            WalletSet::new(["A".to_string(), "B".to_string(), "A".to_string()]),
+            Err(CapstoneError::DuplicateWallet("A".to_string()))
+        );
+    }
+
+    #[test]
+    fn payment_comment_must_be_the_one_sent() {
+        assert_eq!(check_comment(Some("rent"), "rent"), Ok(()));
+        assert_eq!(check_comment(None, ""), Ok(()));
+        assert!(check_comment(None, "rent").is_err());
+        assert!(check_comment(Some(PAYMENT_COMMENT), "rent").is_err());
+    }
+
+    #[test]
+    #[ignore = "needs a running regtest node"]
+    fn generated_addresses_carry_their_labels() {
+        let trader = node_trader();
+        let address = trader
+            .get_new_address(Some(RECEIVED_LABEL), None)
+            .unwrap()
+            .assume_checked();
+        let info = trader.get_address_info(&address).unwrap();
+        assert!(address_labels(&info.labels).contains(&RECEIVED_LABEL.to_string()));
+        assert!(check_label(&trader, "Trader", &address, RECEIVED_LABEL).is_ok());
+    }
+}
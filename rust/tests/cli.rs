@@ -0,0 +1,15 @@
+//! The binary as a shell sees it: what it prints and how it exits, for invocations that need no node.
+
+use std::process::Command;
+
+#[test]
+fn help_prints_the_usage_and_succeeds() {
+    let output = Command::new(env!("CARGO_BIN_EXE_rust"))
+        .arg("--help")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout)
+        .unwrap()
+        .starts_with("Usage: rust"));
+}
@@ -0,0 +1,35 @@
+//! The library API as an outside crate sees it: the helpers `main` is built from, without a node.
+
+use bitcoincore_rpc::bitcoin::Amount;
+use rust::mining::{mine_until_spendable, total_subsidy};
+use rust::transfer::split_shares;
+
+#[test]
+fn maturity_loop_and_split_work_from_outside_the_binary() {
+    let mut mined = 0;
+    let blocks = mine_until_spendable(
+        101,
+        150,
+        1,
+        |_| {
+            mined += 1;
+            Ok(())
+        },
+        |blocks| {
+            Ok(if blocks > 100 {
+                Amount::from_int_btc(50)
+            } else {
+                Amount::ZERO
+            })
+        },
+    )
+    .unwrap();
+    assert_eq!((blocks, mined), (101, 101));
+    assert_eq!(total_subsidy(blocks), Amount::from_int_btc(5050));
+
+    let shares = split_shares(Amount::from_int_btc(20), 3).unwrap();
+    assert_eq!(
+        shares.iter().copied().sum::<Amount>(),
+        Amount::from_int_btc(20)
+    );
+}